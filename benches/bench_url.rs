@@ -30,6 +30,23 @@ fn request_parsing_throughput(c: &mut Criterion) {
     group.throughput(Throughput::Elements(requests_len));
     group.sample_size(10);
 
+    let first_party_requests = requests.clone();
+    group.bench_function(
+        "create first-party",
+        move |b| {
+            b.iter(|| {
+                let mut successful = 0;
+                first_party_requests.iter().for_each(|r| {
+                    let req: Result<Request, _> =
+                        Request::from_urls(&r.url, &r.url, &r.cpt);
+                    if req.is_ok() {
+                        successful += 1;
+                    }
+                })
+            })
+        },
+    );
+
     group.bench_function(
         "create",
         move |b| {
@@ -362,6 +362,12 @@ fn build_filter_format_enum<'a, C: Context<'a>>(cx: &mut C) -> JsResult<'a, JsOb
     };
     filter_format_enum.set(cx, "HOSTS", hosts)?;
 
+    let adguard = match neon_serde::to_value(cx, &FilterFormat::Adguard) {
+        Ok(v) => v,
+        Err(e) => cx.throw_error(e.to_string())?,
+    };
+    filter_format_enum.set(cx, "ADGUARD", adguard)?;
+
     Ok(filter_format_enum)
 }
 
@@ -2,23 +2,56 @@
 
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 #[cfg(feature = "object-pooling")]
-use lifeguard::Pool;
+use lifeguard::{Pool, RcRecycled};
 
-use crate::filters::network::{NetworkFilter, NetworkMatchable};
-use crate::request::Request;
+use crate::filters::network::{CompiledRegex, DomainInterner, FilterId, FilterPart, NetworkFilter, NetworkFilterMask, NetworkMatchable, ToDomains};
+use crate::lists::ListId;
+use crate::request::{Request, RequestType, TokenizedRequest};
 use crate::utils::{fast_hash, Hash};
-use crate::optimizer;
-use crate::resources::{Resource, RedirectResourceStorage, RedirectResource};
+use crate::optimizer::{self, OptimizationReport};
+use crate::resources::{Resource, RedirectResourceStorage, RedirectResource, MimeType, EMPTY_REDIRECT_RESOURCE};
 use crate::utils;
 
 pub struct BlockerOptions {
     pub enable_optimizations: bool,
 }
 
+/// Tokens found in nearly every URL, which are never used to index filters by default. See
+/// `Blocker::new_with_stop_tokens`.
+pub const DEFAULT_STOP_TOKENS: &[&str] = &["http", "https", "www", "com"];
+
+static DEFAULT_STOP_TOKEN_HASHES: Lazy<HashSet<Hash>> = Lazy::new(|| stop_token_hashes(DEFAULT_STOP_TOKENS));
+
+fn stop_token_hashes(stop_tokens: &[&str]) -> HashSet<Hash> {
+    stop_tokens.iter().map(|token| fast_hash(token)).collect()
+}
+
+pub(crate) fn default_stop_token_hashes() -> HashSet<Hash> {
+    DEFAULT_STOP_TOKEN_HASHES.clone()
+}
+
+/// Explains why a network request was not blocked. See `Engine::check_with_reason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllowReason {
+    /// No filter matched the request.
+    NoMatch,
+    /// A blocking filter matched, but an exception with this `NetworkFilter::filter_id` overrode
+    /// it.
+    Exception(FilterId),
+    /// The request was made by a document on the engine's allowlist.
+    Allowlisted,
+    /// A generic filter matched, but the request's document has a `$genericblock` exception.
+    GenericBlockSuppressed,
+    /// A filter matched, but its tag is not enabled.
+    TagDisabled,
+}
+
 /// Determines what should be loaded instead of a particular network request if the request also
 /// matched a blocking filter.
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
@@ -31,8 +64,48 @@ pub enum Redirection {
     Url(String),
 }
 
-#[derive(Debug, Serialize)]
+/// A token of a network filter, as reported by `Blocker::filter_tokenization`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterToken {
+    pub hash: Hash,
+    /// The text of the token, if it could be recovered from the filter. The fallback bucket, with
+    /// a `hash` of `0`, has no name.
+    pub name: Option<String>,
+}
+
+/// How a network filter is indexed, for debugging filters that don't match as expected. Each
+/// filter is stored in the bucket of one of its tokens, and is only checked against requests whose
+/// URL contains that token. Filters without any usable token end up in the fallback bucket, which
+/// is checked for every request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterTokenization {
+    /// The candidate tokens of the filter. Filters with a `$domain` option and no other tokens
+    /// have one set per domain, and all others have a single set.
+    pub tokens: Vec<Vec<FilterToken>>,
+    /// The buckets the filter is stored in, chosen from `tokens` as the least used ones when the
+    /// blocker was built. Empty if the blocker doesn't contain the filter, e.g. because it was
+    /// combined with others by optimization or has a `$tag` that isn't enabled.
+    pub buckets: Vec<FilterToken>,
+}
+
+/// Determines what to serve when a `$redirect` names a resource of unknown MIME type, i.e.
+/// `MimeType::Unknown`, which can't be checked against the type of the request.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum UnknownResourcePolicy {
+    /// Serve the resource's data with a `text/plain` content type.
+    ServeAsText,
+    /// Don't redirect, leaving the request blocked.
+    Reject,
+    /// Serve a built-in no-op resource appropriate for the request type instead, or leave the
+    /// request blocked if there is none. See `Blocker::set_redirect_fallback`.
+    #[default]
+    ServeEmpty,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct BlockerResult {
+    /// Whether the request should be blocked, i.e. a blocking filter matched and no exception
+    /// applied to it. See `blocked` and `applied_redirect` for how this relates to `redirect`.
     pub matched: bool,
     /// Important is used to signal that a rule with the `important` option
     /// matched. An `important` match means that exceptions should not apply
@@ -53,21 +126,46 @@ pub struct BlockerResult {
     /// Note that the presence of a redirect does _not_ imply that the request
     /// should be blocked. The `redirect-rule` option can produce a redirection
     /// that's only applied if another blocking filter matches a request.
+    ///
+    /// A resource whose MIME type doesn't suit the request type, like a script for an image
+    /// request, is never served. An empty resource of a suitable type is used in its place if
+    /// there is one, and otherwise there is no redirect.
     pub redirect: Option<Redirection>,
+    /// Contains the name of the requested resource if a matching filter specified a redirect to a
+    /// resource that has not been loaded into the blocker. `redirect` will be `None` in that case,
+    /// since there is nothing to redirect to.
+    pub missing_redirect: Option<String>,
+    /// The query string of the request URL, without the leading `?`, if there is a `redirect` and
+    /// query preservation is enabled with `Blocker::set_preserve_redirect_query`. Embedders serving
+    /// parametrized resources can pass it on to the replacement. It is already appended to
+    /// `Redirection::Url` redirects.
+    pub redirect_query: Option<String>,
     /// Exception is `Some` when the blocker matched on an exception rule.
     /// Effectively this means that there was a match, but the request should
     /// not be blocked. It is a non-empty string if the blocker was initialized
     /// from a list of rules with debugging enabled, otherwise the original
     /// string representation is discarded to reduce memory use.
     pub exception: Option<String>,
+    /// The `filter_id` of the exception rule that matched, if any. This is `Some` regardless of
+    /// whether debugging is enabled, so it can be used to tell a request that was actively
+    /// allowed apart from one that no rule matched at all. Exceptions combined by the optimizer
+    /// have an id of their own that may not correspond to any single rule.
+    pub matched_exception: Option<FilterId>,
     /// Filter--similarly to exception--includes the string representation of
     /// the rule when there is a match and debugging is enabled. Otherwise, on
     /// a match, it is `Some`.
     pub filter: Option<String>,
+    /// The `filter_id` of the blocking rule that matched, if any. Like `matched_exception`, this
+    /// is `Some` regardless of whether debugging is enabled.
+    pub matched_filter: Option<FilterId>,
     /// The `error` field is only used to signal that there was an error in
     /// parsing the provided URLs when using the simpler
     /// [`crate::engine::Engine::check_network_urls`] method.
     pub error: Option<String>,
+    /// The type of the request as used for matching. This is the type given when creating the
+    /// `Request`, unless it was inferred, e.g. `RequestType::Websocket` for `wss://` URLs. `None`
+    /// if the request could not be checked because of an error.
+    pub request_type: Option<RequestType>,
 }
 
 impl Default for BlockerResult {
@@ -76,9 +174,34 @@ impl Default for BlockerResult {
             matched: false,
             important: false,
             redirect: None,
+            missing_redirect: None,
+            redirect_query: None,
             exception: None,
+            matched_exception: None,
             filter: None,
+            matched_filter: None,
             error: None,
+            request_type: None,
+        }
+    }
+}
+
+impl BlockerResult {
+    /// Whether the original request should not be loaded. If `applied_redirect` is `Some`, it
+    /// should be replaced with the redirect, and otherwise it should be cancelled. This is the
+    /// same as `matched`.
+    pub fn blocked(&self) -> bool {
+        self.matched
+    }
+
+    /// The redirect that replaces the original request. Unlike `redirect`, this is only `Some` if
+    /// the request is `blocked`, so a redirect always implies that the original request is blocked
+    /// and replaced. A redirect for a request allowed by an exception is left out.
+    pub fn applied_redirect(&self) -> Option<&Redirection> {
+        if self.matched {
+            self.redirect.as_ref()
+        } else {
+            None
         }
     }
 }
@@ -87,11 +210,146 @@ impl Default for BlockerResult {
 pub enum BlockerError {
     SerializationError,
     DeserializationError,
+    /// The serialized data was recognized, but decoding it failed at byte `offset`, e.g. because
+    /// it was truncated. `message` describes the decoding error.
+    CorruptedData {
+        offset: usize,
+        message: String,
+    },
     OptimizedFilterExistence,
     BadFilterAddUnsupported,
     FilterExists,
 }
 
+impl std::fmt::Display for BlockerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::SerializationError => write!(f, "failed to serialize the engine"),
+            Self::DeserializationError => write!(f, "failed to deserialize the engine"),
+            Self::CorruptedData { offset, message } => write!(f, "failed to deserialize the engine at byte {}: {}", offset, message),
+            Self::OptimizedFilterExistence => write!(f, "cannot check for the existence of optimized filters"),
+            Self::BadFilterAddUnsupported => write!(f, "`$badfilter` rules cannot be added to an existing blocker"),
+            Self::FilterExists => write!(f, "filter already exists"),
+        }
+    }
+}
+
+impl std::error::Error for BlockerError {}
+
+/// A filter whose evaluation exceeded the configured slow filter budget at least once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowFilter {
+    /// String representation of the filter, if debug information was retained.
+    pub filter: String,
+    /// Number of evaluations of this filter that exceeded the budget.
+    pub count: usize,
+    /// Longest single evaluation of this filter observed so far.
+    pub max_duration: Duration,
+}
+
+/// Outcome of eagerly compiling the regexes of a `Blocker`'s filters with `Blocker::warmup`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RegexWarmup {
+    /// Number of regexes compiled. Filters whose regex was already compiled are not counted.
+    pub compiled: usize,
+    /// String representations of filters whose regex failed to compile. These filters can never
+    /// match a request.
+    pub failed: Vec<String>,
+}
+
+/// Memory used by the text of `$domain` and `$to` entries across a `Blocker`'s filters, as
+/// returned by `Blocker::domain_memory_report`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DomainMemoryReport {
+    /// Number of entries across all filters.
+    pub entries: usize,
+    /// Number of distinct strings stored for those entries.
+    pub strings: usize,
+    /// Bytes of text stored, counting each distinct string once.
+    pub bytes: usize,
+    /// Bytes of text that would be stored if each entry kept its own copy.
+    pub uninterned_bytes: usize,
+}
+
+/// Records filters whose evaluation takes longer than `budget`. Only present on a `Blocker` when
+/// slow filter telemetry has been explicitly enabled.
+pub(crate) struct SlowFilterTracker {
+    budget: Duration,
+    offenders: Mutex<HashMap<Hash, SlowFilter>>,
+}
+
+impl SlowFilterTracker {
+    fn new(budget: Duration) -> Self {
+        Self {
+            budget,
+            offenders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn matches(&self, filter: &NetworkFilter, request: &Request) -> bool {
+        let start = Instant::now();
+        let matched = filter.matches(request);
+        let elapsed = start.elapsed();
+
+        if elapsed > self.budget {
+            if let Ok(mut offenders) = self.offenders.lock() {
+                let entry = offenders.entry(filter.id).or_insert_with(|| SlowFilter {
+                    filter: filter.to_string(),
+                    count: 0,
+                    max_duration: elapsed,
+                });
+                entry.count += 1;
+                entry.max_duration = entry.max_duration.max(elapsed);
+            }
+        }
+
+        matched
+    }
+
+    fn slow_filters(&self) -> Vec<SlowFilter> {
+        self.offenders.lock()
+            .map(|offenders| offenders.values().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Aggregate counts of the work done by network request checks, showing how effective token
+/// bucketing is on real traffic. Only recorded while enabled with `Blocker::enable_bucket_stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BucketStats {
+    /// Number of network requests checked.
+    pub checks: u64,
+    /// Number of filter buckets looked up, across all checks.
+    pub buckets_visited: u64,
+    /// Number of filters compared against a request, across all checks.
+    pub filters_compared: u64,
+    /// Number of filters that may be compared against a network request, at the time the stats
+    /// were read. Any filter not in a visited bucket is skipped, so each check skips roughly
+    /// `filters_indexed - filters_compared / checks` filters.
+    pub filters_indexed: u64,
+}
+
+/// Counts filter comparisons during request checks. Only present on a `Blocker` when bucket stats
+/// have been explicitly enabled.
+#[derive(Default)]
+pub(crate) struct BucketStatsTracker {
+    checks: AtomicU64,
+    buckets_visited: AtomicU64,
+    filters_compared: AtomicU64,
+}
+
+impl BucketStatsTracker {
+    fn record_check(&self) {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.checks.store(0, Ordering::Relaxed);
+        self.buckets_visited.store(0, Ordering::Relaxed);
+        self.filters_compared.store(0, Ordering::Relaxed);
+    }
+}
+
 #[cfg(feature = "object-pooling")]
 pub struct TokenPool {
     pub pool: Pool<Vec<utils::Hash>>
@@ -109,6 +367,35 @@ impl Default for TokenPool {
     }
 }
 
+#[cfg(feature = "object-pooling")]
+thread_local! {
+    static TOKEN_POOL: TokenPool = TokenPool::default();
+}
+
+/// Hands out token buffers for request matching.
+///
+/// With the `object-pooling` feature, buffers are reused from a `TokenPool` kept per thread, so a
+/// `Blocker` can be shared between threads without contending on a single pool. Without it, a new
+/// buffer is allocated for every call.
+#[derive(Default)]
+pub(crate) struct TokenBuffers {
+    _private: (),
+}
+
+#[cfg(feature = "object-pooling")]
+impl TokenBuffers {
+    pub(crate) fn get(&self) -> RcRecycled<Vec<utils::Hash>> {
+        TOKEN_POOL.with(|pool| pool.pool.new_rc())
+    }
+}
+
+#[cfg(not(feature = "object-pooling"))]
+impl TokenBuffers {
+    pub(crate) fn get(&self) -> Vec<utils::Hash> {
+        Vec::with_capacity(utils::TOKENS_BUFFER_SIZE)
+    }
+}
+
 /// Stores network filters for efficient querying.
 pub struct Blocker {
     pub(crate) csp: NetworkFilterList,
@@ -118,18 +405,38 @@ pub struct Blocker {
     pub(crate) filters_tagged: NetworkFilterList,
     pub(crate) filters: NetworkFilterList,
     pub(crate) generic_hide: NetworkFilterList,
+    pub(crate) generic_block: NetworkFilterList,
 
     // Enabled tags are not serialized - when deserializing, tags of the existing
     // instance (the one we are recreating lists into) are maintained
     pub(crate) tags_enabled: HashSet<String>,
     pub(crate) tagged_filters_all: Vec<NetworkFilter>,
 
+    // `$badfilter` rules have already cancelled their filters, and are only kept to cancel those
+    // of another blocker merged into this one
+    pub(crate) badfilters: Vec<NetworkFilter>,
+
     pub(crate) enable_optimizations: bool,
 
     pub(crate) resources: RedirectResourceStorage,
     // Not serialized
-    #[cfg(feature = "object-pooling")]
-    pub(crate) pool: TokenPool,
+    pub(crate) pool: TokenBuffers,
+    // Not serialized
+    pub(crate) slow_filters: Option<SlowFilterTracker>,
+    // Not serialized
+    pub(crate) bucket_stats: Option<BucketStatsTracker>,
+    // Not serialized
+    pub(crate) redirect_fallback: bool,
+    // Not serialized
+    pub(crate) unknown_resource_policy: UnknownResourcePolicy,
+    // Not serialized
+    pub(crate) preserve_redirect_query: bool,
+    // Not serialized
+    pub(crate) disabled_lists: HashSet<ListId>,
+    // Not serialized
+    pub(crate) stop_tokens: HashSet<Hash>,
+    // Not serialized
+    pub(crate) domain_interner: DomainInterner,
 }
 
 impl Blocker {
@@ -139,41 +446,142 @@ impl Blocker {
         self.check_parameterised(request, false, false)
     }
 
-    pub fn check_generic_hide(&self, hostname_request: &Request) -> bool {
-        let mut request_tokens;
-        #[cfg(feature = "object-pooling")]
-        {
-            request_tokens = self.pool.pool.new();
+    /// Returns why `request` was not blocked, given the `result` of checking it, or `None` if it
+    /// was blocked. This may check the request again, so it is meant for debugging rather than
+    /// for every request.
+    pub fn allow_reason(&self, request: &Request, result: &BlockerResult) -> Option<AllowReason> {
+        if result.matched {
+            return None;
         }
-        #[cfg(not(feature = "object-pooling"))]
-        {
-            request_tokens = Vec::with_capacity(utils::TOKENS_BUFFER_SIZE);
+        if let Some(exception) = result.matched_exception {
+            return Some(AllowReason::Exception(exception));
         }
+        if request.genericblock {
+            let mut request = request.clone();
+            request.genericblock = false;
+            if self.check(&request).matched {
+                return Some(AllowReason::GenericBlockSuppressed);
+            }
+        }
+        let tag_disabled = self.tagged_filters_all.iter().any(|filter| {
+            filter.tag.as_ref().map(|tag| !self.tags_enabled.contains(tag)).unwrap_or(false)
+                && list_enabled(filter, &self.disabled_lists)
+                && filter.matches(request)
+        });
+        if tag_disabled {
+            return Some(AllowReason::TagDisabled);
+        }
+        Some(AllowReason::NoMatch)
+    }
+
+    /// Like `check`, but uses the tokens already computed for the request, so that the same
+    /// `TokenizedRequest` can be checked against several blockers.
+    pub fn check_tokenized(&self, request: &TokenizedRequest) -> BlockerResult {
+        self.check_with_tokens(request.request(), request.tokens(), false, false, &self.filters_tagged, &self.tags_enabled)
+    }
+
+    pub fn check_generic_hide(&self, hostname_request: &Request) -> bool {
+        self.generic_hide_exception(hostname_request, &self.tags_enabled).is_some()
+    }
+
+    /// Checks whether a document has an `$elemhide` exception, in which case no cosmetic rules
+    /// should be applied to it at all. Such documents also pass `check_generic_hide`.
+    pub fn check_elem_hide(&self, hostname_request: &Request) -> bool {
+        self.elem_hide_exception(hostname_request, &self.tags_enabled).is_some()
+    }
+
+    /// Like `check_generic_hide`, but returns the matching `$generichide` or `$elemhide`
+    /// exception, using the provided enabled tags instead of the ones stored in this `Blocker`.
+    pub(crate) fn generic_hide_exception(&self, hostname_request: &Request, tags_enabled: &HashSet<String>) -> Option<&NetworkFilter> {
+        let mut request_tokens = self.pool.get();
+        hostname_request.get_tokens(&mut request_tokens);
+
+        self.generic_hide.check_tracked(hostname_request, &request_tokens, tags_enabled, self.slow_filters.as_ref(), None, &self.disabled_lists)
+    }
+
+    /// Like `check_elem_hide`, but returns the matching `$elemhide` exception, using the provided
+    /// enabled tags instead of the ones stored in this `Blocker`.
+    pub(crate) fn elem_hide_exception(&self, hostname_request: &Request, tags_enabled: &HashSet<String>) -> Option<&NetworkFilter> {
+        let mut request_tokens = self.pool.get();
         hostname_request.get_tokens(&mut request_tokens);
 
-        self.generic_hide.check(hostname_request, &request_tokens, &HashSet::new()).is_some()
+        self.generic_hide.check_all_tracked(hostname_request, &request_tokens, tags_enabled, self.slow_filters.as_ref(), None, &self.disabled_lists)
+            .into_iter()
+            .find(|filter| filter.is_elem_hide())
+    }
+
+    pub(crate) fn has_generic_block_exceptions(&self) -> bool {
+        !self.generic_block.filter_map.is_empty()
+    }
+
+    /// Checks whether a document has a `$genericblock` exception, in which case requests it makes
+    /// should have `Request::genericblock` set so that generic blocking filters are ignored.
+    pub fn check_generic_block(&self, document_request: &Request) -> bool {
+        if !self.has_generic_block_exceptions() {
+            return false;
+        }
+
+        self.generic_block_exception(document_request, &self.tags_enabled).is_some()
+    }
+
+    /// Like `check_generic_block`, but returns the matching `$genericblock` exception, using the
+    /// provided enabled tags instead of the ones stored in this `Blocker`.
+    pub(crate) fn generic_block_exception(&self, document_request: &Request, tags_enabled: &HashSet<String>) -> Option<&NetworkFilter> {
+        if self.generic_block.filter_map.is_empty() {
+            return None;
+        }
+
+        let mut request_tokens = self.pool.get();
+        document_request.get_tokens(&mut request_tokens);
+
+        self.generic_block.check_tracked(document_request, &request_tokens, tags_enabled, self.slow_filters.as_ref(), None, &self.disabled_lists)
     }
 
     pub fn check_parameterised(&self, request: &Request, matched_rule: bool, force_check_exceptions: bool) -> BlockerResult {
+        self.check_parameterised_with_tags(request, matched_rule, force_check_exceptions, &self.filters_tagged, &self.tags_enabled)
+    }
+
+    /// Like `check_parameterised`, but uses the provided tagged filters and enabled tags instead
+    /// of the ones stored in this `Blocker`.
+    pub(crate) fn check_parameterised_with_tags(
+        &self,
+        request: &Request,
+        matched_rule: bool,
+        force_check_exceptions: bool,
+        filters_tagged: &NetworkFilterList,
+        tags_enabled: &HashSet<String>,
+    ) -> BlockerResult {
+        let mut request_tokens = self.pool.get();
+        request.get_tokens(&mut request_tokens);
+
+        self.check_with_tokens(request, &request_tokens, matched_rule, force_check_exceptions, filters_tagged, tags_enabled)
+    }
+
+    fn check_with_tokens(
+        &self,
+        request: &Request,
+        request_tokens: &[Hash],
+        matched_rule: bool,
+        force_check_exceptions: bool,
+        filters_tagged: &NetworkFilterList,
+        tags_enabled: &HashSet<String>,
+    ) -> BlockerResult {
         if !request.is_supported {
-            return BlockerResult::default();
+            return BlockerResult {
+                request_type: Some(request.request_type.clone()),
+                ..Default::default()
+            };
+        }
+
+        let bucket_stats = self.bucket_stats.as_ref();
+        if let Some(stats) = bucket_stats {
+            stats.record_check();
         }
 
         // only check for tags in tagged and exception rule buckets,
         // pass empty set for the rest
         static NO_TAGS: Lazy<HashSet<String>> = Lazy::new(HashSet::new);
 
-        let mut request_tokens;
-        #[cfg(feature = "object-pooling")]
-        {
-            request_tokens = self.pool.pool.new();
-        }
-        #[cfg(not(feature = "object-pooling"))]
-        {
-            request_tokens = Vec::with_capacity(utils::TOKENS_BUFFER_SIZE);
-        }
-        request.get_tokens(&mut request_tokens);
-
         // Check the filters in the following order:
         // 1. $important (not subject to exceptions)
         // 2. redirection ($redirect=resource)
@@ -185,17 +593,17 @@ impl Blocker {
         // Always check important filters
         let important_filter = self
             .importants
-            .check(request, &request_tokens, &NO_TAGS);
+            .check_tracked(request, request_tokens, &NO_TAGS, self.slow_filters.as_ref(), bucket_stats, &self.disabled_lists);
 
         // only check the rest of the rules if not previously matched
         let filter = if important_filter.is_none() && !matched_rule {
             #[cfg(feature = "metrics")]
             print!("tagged\t");
-            self.filters_tagged.check(request, &request_tokens, &self.tags_enabled)
+            filters_tagged.check_tracked(request, request_tokens, tags_enabled, self.slow_filters.as_ref(), bucket_stats, &self.disabled_lists)
                 .or_else(|| {
                     #[cfg(feature = "metrics")]
                     print!("filters\t");
-                    self.filters.check(request, &request_tokens, &NO_TAGS)
+                    self.filters.check_tracked(request, request_tokens, &NO_TAGS, self.slow_filters.as_ref(), bucket_stats, &self.disabled_lists)
                 })
         } else {
             important_filter
@@ -206,7 +614,7 @@ impl Blocker {
             None if matched_rule || force_check_exceptions => {
                 #[cfg(feature = "metrics")]
                 print!("exceptions\t");
-                self.exceptions.check(request, &request_tokens, &self.tags_enabled)
+                self.exceptions.check_tracked(request, request_tokens, tags_enabled, self.slow_filters.as_ref(), bucket_stats, &self.disabled_lists)
             }
             None => None,
             // If matched an important filter, exceptions don't atter
@@ -217,33 +625,44 @@ impl Blocker {
                 // Set `bug` of request
                 let mut request_bug = request.clone();
                 request_bug.bug = f.bug;
-                self.exceptions.check(&request_bug, &request_tokens, &self.tags_enabled)
+                self.exceptions.check_tracked(&request_bug, request_tokens, tags_enabled, self.slow_filters.as_ref(), bucket_stats, &self.disabled_lists)
             }
             Some(_) => {
                 #[cfg(feature = "metrics")]
                 print!("exceptions\t");
-                self.exceptions.check(request, &request_tokens, &self.tags_enabled)
+                self.exceptions.check_tracked(request, request_tokens, tags_enabled, self.slow_filters.as_ref(), bucket_stats, &self.disabled_lists)
             }
         };
 
         #[cfg(feature = "metrics")]
         println!();
 
-        let redirect_filters = self.redirects.check_all(request, &request_tokens, &NO_TAGS);
+        // The target of a redirect is never redirected again, to avoid redirect loops.
+        let redirect_filters = if request.is_redirect_target {
+            vec![]
+        } else {
+            self.redirects.check_all_tracked(request, request_tokens, &NO_TAGS, self.slow_filters.as_ref(), bucket_stats, &self.disabled_lists)
+        };
 
         // Extract the highest priority redirect directive.
         // So far, priority specifiers are not supported, which means:
-        // 1. Exceptions - can bail immediately if found
+        // 1. Exceptions - `@@$redirect=name` cancels redirects to `name`, and `@@$redirect=*`
+        //    cancels all redirects
         // 2. Redirect URLs
         // 3. Redirect resources
         let redirect_option = {
+            let excepted_redirects = redirect_filters.iter()
+                .filter(|filter| filter.is_exception())
+                .filter_map(|filter| filter.redirect.as_deref())
+                .collect::<Vec<_>>();
+            let is_excepted = |name: &str| excepted_redirects.iter().any(|excepted| *excepted == "*" || *excepted == name);
+
             // (true, s) implies s is a URL.
             // (false, s) implies s is the name of a resource to lookup.
             let mut redirect: Option<(bool, &str)> = None;
             for redirect_filter in redirect_filters {
-                if redirect_filter.is_exception() {
-                    redirect = None;
-                    break;
+                if redirect_filter.is_exception() || redirect_filter.redirect.as_deref().map(is_excepted).unwrap_or(false) {
+                    continue;
                 } else if redirect_filter.is_redirect_url() {
                     // Unconditionally write to `redirect` - it's the highest priority option that
                     // does not break the loop.
@@ -257,58 +676,133 @@ impl Blocker {
             redirect
         };
 
+        let mut missing_redirect = None;
         let redirect: Option<Redirection> = redirect_option.and_then(|(is_url, redirect_identifier)| {
             if is_url {
                 Some(Redirection::Url(redirect_identifier.to_string()))
             } else if let Some(resource) = self.resources.get_resource(redirect_identifier) {
                 // Only match resource redirects if a matching resource exists
-                let data_url = format!("data:{};base64,{}", resource.content_type, &resource.data);
-                Some(Redirection::Resource(data_url.trim().to_owned()))
+                if is_unknown_content_type(&resource.content_type) {
+                    match self.unknown_resource_policy {
+                        UnknownResourcePolicy::ServeAsText => {
+                            let data_url = format!("data:{};base64,{}", String::from(MimeType::TextPlain), &resource.data);
+                            Some(Redirection::Resource(data_url.trim().to_owned()))
+                        }
+                        UnknownResourcePolicy::Reject => None,
+                        UnknownResourcePolicy::ServeEmpty => fallback_redirect(&request.request_type).map(|data_url| Redirection::Resource(data_url.to_owned())),
+                    }
+                } else if resource_fits_request(&resource.content_type, &request.request_type) {
+                    let data_url = format!("data:{};base64,{}", resource.content_type, &resource.data);
+                    Some(Redirection::Resource(data_url.trim().to_owned()))
+                } else {
+                    // Never serve a resource the request can't use, like a script in place of
+                    // an image. Serve an empty resource of the right type instead, or just block
+                    // the request if there is none.
+                    fallback_redirect(&request.request_type).map(|data_url| Redirection::Resource(data_url.to_owned()))
+                }
+            } else if redirect_identifier == EMPTY_REDIRECT_RESOURCE {
+                // Serve the built-in empty resource with a type appropriate for the request
+                let content_type = match request.request_type {
+                    RequestType::Script => MimeType::ApplicationJavascript,
+                    _ => MimeType::TextPlain,
+                };
+                Some(Redirection::Resource(format!("data:{};base64,", String::from(content_type))))
             } else {
-                // It's acceptable to pass no redirection if no matching resource is loaded.
-                // TODO - it may be useful to return a status flag to indicate that this occurred.
+                // It's acceptable to pass no redirection if no matching resource is loaded, but
+                // the name of the missing resource is reported.
                 #[cfg(test)]
                 eprintln!("Matched rule with redirect option but did not find corresponding resource to send");
-                None
+                missing_redirect = Some(redirect_identifier.to_owned());
+                if self.redirect_fallback {
+                    fallback_redirect(&request.request_type).map(|data_url| Redirection::Resource(data_url.to_owned()))
+                } else {
+                    None
+                }
             }
         });
 
+        let redirect_query = if self.preserve_redirect_query && redirect.is_some() {
+            request_query(request).map(|query| query.to_owned())
+        } else {
+            None
+        };
+        let redirect = match (redirect, &redirect_query) {
+            (Some(Redirection::Url(url)), Some(query)) => {
+                let separator = if url.contains('?') { '&' } else { '?' };
+                Some(Redirection::Url(format!("{}{}{}", url, separator, query)))
+            }
+            (redirect, _) => redirect,
+        };
+
         // If something has already matched before but we don't know what, still return a match
         let matched = exception.is_none() && (filter.is_some() || matched_rule);
         BlockerResult {
             matched,
             important: filter.is_some() && filter.as_ref().map(|f| f.is_important()).unwrap_or_else(|| false),
             redirect,
+            missing_redirect,
+            redirect_query,
             exception: exception.as_ref().map(|f| f.to_string()), // copy the exception
+            matched_exception: exception.as_ref().map(|f| f.filter_id()),
             filter: filter.as_ref().map(|f| f.to_string()),       // copy the filter
+            matched_filter: filter.as_ref().map(|f| f.filter_id()),
             error: None,
+            request_type: Some(request.request_type.clone()),
         }
     }
 
     /// Given a "main_frame" or "subdocument" request, check if some content security policies
-    /// should be injected in the page.
+    /// should be injected in the page. Nothing is injected into a document that is blocked
+    /// outright, since it is never loaded.
     pub fn get_csp_directives(&self, request: &Request) -> Option<String> {
-        use crate::request::RequestType;
+        self.get_csp_directives_with_tags(request, &self.filters_tagged, &self.tags_enabled)
+    }
 
-        if request.request_type != RequestType::Document && request.request_type != RequestType::Subdocument {
-            return None;
+    /// Like `get_csp_directives`, but uses the provided tagged filters and enabled tags instead of
+    /// the ones stored in this `Blocker`.
+    pub(crate) fn get_csp_directives_with_tags(&self, request: &Request, filters_tagged: &NetworkFilterList, tags_enabled: &HashSet<String>) -> Option<String> {
+        let policies = self.get_csp_policies_with_tags(request, filters_tagged, tags_enabled);
+        if policies.is_empty() {
+            None
+        } else {
+            Some(policies.join(","))
         }
+    }
 
-        let mut request_tokens;
-        #[cfg(feature = "object-pooling")]
-        {
-            request_tokens = self.pool.pool.new();
+    /// Like `get_csp_directives`, but returns each policy separately rather than joined by commas,
+    /// e.g. to be sent as separate headers. Each remaining policy from a matching filter is one
+    /// entry, sorted for stability. Returns an empty `Vec` if there are no policies to add.
+    pub fn get_csp_policies(&self, request: &Request) -> Vec<String> {
+        self.get_csp_policies_with_tags(request, &self.filters_tagged, &self.tags_enabled)
+    }
+
+    /// Like `get_csp_policies`, but uses the provided tagged filters and enabled tags instead of
+    /// the ones stored in this `Blocker`.
+    pub(crate) fn get_csp_policies_with_tags(&self, request: &Request, filters_tagged: &NetworkFilterList, tags_enabled: &HashSet<String>) -> Vec<String> {
+        self.get_csp_policies_unless_blocked(request, tags_enabled, |request| {
+            self.check_parameterised_with_tags(request, false, false, filters_tagged, tags_enabled).matched
+        })
+    }
+
+    /// Like `get_csp_policies_with_tags`, but uses `is_blocked` to decide whether the document is
+    /// blocked, e.g. to take filters from outside this `Blocker` into account.
+    pub(crate) fn get_csp_policies_unless_blocked<F: FnOnce(&Request) -> bool>(&self, request: &Request, tags_enabled: &HashSet<String>, is_blocked: F) -> Vec<String> {
+        if request.request_type != RequestType::Document && request.request_type != RequestType::Subdocument {
+            return vec![];
         }
-        #[cfg(not(feature = "object-pooling"))]
-        {
-            request_tokens = Vec::with_capacity(utils::TOKENS_BUFFER_SIZE);
+
+        // A blocked document is never loaded, so there is no response to add policies to
+        if is_blocked(request) {
+            return vec![];
         }
+
+        let mut request_tokens = self.pool.get();
         request.get_tokens(&mut request_tokens);
 
-        let filters = self.csp.check_all(request, &request_tokens, &self.tags_enabled);
+        let filters = self.csp.check_all_tracked(request, &request_tokens, tags_enabled, self.slow_filters.as_ref(), None, &self.disabled_lists);
 
         if filters.is_empty() {
-            return None;
+            return vec![];
         }
 
         let mut disabled_directives: HashSet<&str> = HashSet::new();
@@ -321,30 +815,41 @@ impl Blocker {
                 } else {
                     // Exception filters with empty `csp` options will disable all CSP injections for
                     // matching pages.
-                    return None
+                    return vec![]
                 }
             } else if let Some(directive) = &filter.csp {
                 enabled_directives.insert(directive);
             }
         }
 
-        let mut remaining_directives = enabled_directives.difference(&disabled_directives);
+        let mut remaining_directives: Vec<&str> = enabled_directives.difference(&disabled_directives).copied().collect();
+        remaining_directives.sort_unstable();
 
-        let mut merged = if let Some(directive) = remaining_directives.next() {
-            String::from(*directive)
-        } else {
-            return None;
-        };
+        // Reporting directives are passed through verbatim, but only the first occurrence of each
+        // is kept so that merged policies don't report the same violation more than once.
+        let mut reporting_directives: HashSet<String> = HashSet::new();
 
-        remaining_directives.for_each(|directive| {
-            merged.push(',');
-            merged.push_str(directive);
-        });
-
-        Some(merged)
+        remaining_directives.into_iter()
+            .map(|directive| dedup_csp_reporting_directives(directive, &mut reporting_directives))
+            .filter(|policy| !policy.is_empty())
+            .map(|policy| policy.into_owned())
+            .collect()
     }
 
     pub fn new(network_filters: Vec<NetworkFilter>, options: &BlockerOptions) -> Blocker {
+        Self::new_with_stop_tokens(network_filters, options, DEFAULT_STOP_TOKENS)
+    }
+
+    /// Like `new`, but never indexes filters by any of the given `stop_tokens` rather than the
+    /// `DEFAULT_STOP_TOKENS`. Tokens like `com` that appear in nearly every URL make for large
+    /// buckets of filters that need to be checked against most requests. A filter whose only
+    /// tokens are stop tokens is stored in the fallback bucket instead, which is checked for
+    /// every request, so matching is not affected.
+    ///
+    /// The stop tokens also apply to filters added later on. They are not serialized, but are kept
+    /// when deserializing into an existing `Engine`.
+    pub fn new_with_stop_tokens(mut network_filters: Vec<NetworkFilter>, options: &BlockerOptions, stop_tokens: &[&str]) -> Blocker {
+        let stop_tokens = stop_token_hashes(stop_tokens);
         // Capacity of filter subsets estimated based on counts in EasyList and EasyPrivacy - if necessary
         // the Vectors will grow beyond the pre-set capacity, but it is more efficient to allocate all at once
         // $csp=
@@ -361,16 +866,24 @@ impl Blocker {
         let mut badfilters = Vec::with_capacity(100);
         // $generichide
         let mut generic_hide = Vec::with_capacity(4000);
+        // $genericblock
+        let mut generic_block = Vec::with_capacity(100);
         // All other filters
         let mut filters = Vec::with_capacity(network_filters.len());
 
         // Injections
         // TODO: resource handling
 
+        // Domain strings are shared between filters
+        let mut domain_interner = DomainInterner::default();
+        for filter in network_filters.iter_mut() {
+            domain_interner.intern_filter(filter);
+        }
+
         if !network_filters.is_empty() {
             for filter in network_filters.iter() {
                 if filter.is_badfilter() {
-                    badfilters.push(filter);
+                    badfilters.push(filter.clone());
                 }
             }
             let badfilter_ids: HashSet<Hash> = badfilters.iter().map(|f| f.get_id_without_badfilter()).collect();
@@ -388,8 +901,13 @@ impl Blocker {
 
                 if filter.is_csp() {
                     csp.push(filter);
-                } else if filter.is_generic_hide() {
-                    generic_hide.push(filter);
+                } else if filter.is_generic_hide() || filter.is_generic_block() {
+                    if filter.is_generic_block() {
+                        generic_block.push(filter.clone());
+                    }
+                    if filter.is_generic_hide() {
+                        generic_hide.push(filter);
+                    }
                 } else if filter.is_exception() {
                     exceptions.push(filter);
                 } else if filter.is_important() {
@@ -404,25 +922,167 @@ impl Blocker {
         }
 
         tagged_filters_all.shrink_to_fit();
-
-        Blocker {
-            csp: NetworkFilterList::new(csp, options.enable_optimizations),
-            exceptions: NetworkFilterList::new(exceptions, options.enable_optimizations),
-            importants: NetworkFilterList::new(importants, options.enable_optimizations),
-            redirects: NetworkFilterList::new(redirects, options.enable_optimizations),
-            filters_tagged: NetworkFilterList::new(Vec::new(), options.enable_optimizations),
-            filters: NetworkFilterList::new(filters, options.enable_optimizations),
-            generic_hide: NetworkFilterList::new(generic_hide, options.enable_optimizations),
+        badfilters.shrink_to_fit();
+
+        let mut blocker = Blocker {
+            csp: NetworkFilterList::new_with_stop_tokens(csp, options.enable_optimizations, &stop_tokens),
+            exceptions: NetworkFilterList::new_with_stop_tokens(exceptions, options.enable_optimizations, &stop_tokens),
+            importants: NetworkFilterList::new_with_stop_tokens(importants, options.enable_optimizations, &stop_tokens),
+            redirects: NetworkFilterList::new_with_stop_tokens(redirects, options.enable_optimizations, &stop_tokens),
+            filters_tagged: NetworkFilterList::new_with_stop_tokens(Vec::new(), options.enable_optimizations, &stop_tokens),
+            filters: NetworkFilterList::new_with_stop_tokens(filters, options.enable_optimizations, &stop_tokens),
+            generic_hide: NetworkFilterList::new_with_stop_tokens(generic_hide, options.enable_optimizations, &stop_tokens),
+            generic_block: NetworkFilterList::new_with_stop_tokens(generic_block, options.enable_optimizations, &stop_tokens),
             // Tags special case for enabling/disabling them dynamically
             tags_enabled: HashSet::new(),
             tagged_filters_all,
+            badfilters,
             // Options
             enable_optimizations: options.enable_optimizations,
 
             resources: RedirectResourceStorage::default(),
-            #[cfg(feature = "object-pooling")]
-            pool: TokenPool::default(),
+            pool: TokenBuffers::default(),
+            slow_filters: None,
+            bucket_stats: None,
+            redirect_fallback: false,
+            unknown_resource_policy: UnknownResourcePolicy::default(),
+            preserve_redirect_query: false,
+            disabled_lists: HashSet::new(),
+            stop_tokens,
+            domain_interner,
+        };
+
+        if options.enable_optimizations {
+            blocker.order_by_match_cost();
+        }
+
+        blocker
+    }
+
+    /// Reports which of `network_filters` would be fused together if optimizations were enabled,
+    /// without building an optimized `Blocker`. Tagged filters are reported as if all of their tags
+    /// were enabled.
+    pub fn optimization_report(network_filters: Vec<NetworkFilter>) -> OptimizationReport {
+        let blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: false });
+        let filters_tagged = blocker.build_tagged_filters(&blocker.available_tags().into_iter().collect());
+        let mut merged: Vec<_> = [
+            &blocker.csp,
+            &blocker.exceptions,
+            &blocker.importants,
+            &blocker.redirects,
+            &filters_tagged,
+            &blocker.filters,
+            &blocker.generic_hide,
+            &blocker.generic_block,
+        ].iter().flat_map(|list| list.optimization_dry_run()).collect();
+        merged.sort_by_key(|group| group.ids.clone());
+        OptimizationReport { merged }
+    }
+
+    /// Starts recording any filter whose evaluation takes longer than `budget`. Any previously
+    /// recorded slow filters are discarded.
+    pub fn enable_slow_filter_telemetry(&mut self, budget: Duration) {
+        self.slow_filters = Some(SlowFilterTracker::new(budget));
+    }
+
+    /// Stops recording slow filters, discarding any that were previously recorded.
+    pub fn disable_slow_filter_telemetry(&mut self) {
+        self.slow_filters = None;
+    }
+
+    /// Starts counting the filter buckets visited and filters compared by network request checks.
+    /// Any previously recorded counts are discarded.
+    pub fn enable_bucket_stats(&mut self) {
+        self.bucket_stats = Some(BucketStatsTracker::default());
+    }
+
+    /// Stops counting filter comparisons, discarding any recorded counts.
+    pub fn disable_bucket_stats(&mut self) {
+        self.bucket_stats = None;
+    }
+
+    /// Returns the counts recorded since bucket stats were enabled or last reset. All counts except
+    /// `filters_indexed` are zero if bucket stats are not enabled.
+    pub fn bucket_stats(&self) -> BucketStats {
+        let filters_indexed = [&self.importants, &self.redirects, &self.filters, &self.filters_tagged, &self.exceptions]
+            .iter()
+            .map(|list| vec_hashmap_len(&list.filter_map) as u64)
+            .sum();
+        match &self.bucket_stats {
+            Some(stats) => BucketStats {
+                checks: stats.checks.load(Ordering::Relaxed),
+                buckets_visited: stats.buckets_visited.load(Ordering::Relaxed),
+                filters_compared: stats.filters_compared.load(Ordering::Relaxed),
+                filters_indexed,
+            },
+            None => BucketStats { filters_indexed, ..Default::default() },
+        }
+    }
+
+    /// Sets all recorded bucket stats back to zero, without disabling them.
+    pub fn reset_bucket_stats(&self) {
+        if let Some(stats) = &self.bucket_stats {
+            stats.reset();
+        }
+    }
+
+    /// Enables or disables all filters parsed with the given `ParseOptions::list_id`. Filters from
+    /// a disabled list are skipped when matching, but are kept in the blocker so that the list can
+    /// be re-enabled at any time. All lists are enabled by default.
+    pub fn set_list_enabled(&mut self, list_id: ListId, enabled: bool) {
+        if enabled {
+            self.disabled_lists.remove(&list_id);
+        } else {
+            self.disabled_lists.insert(list_id);
+        }
+    }
+
+    /// If enabled, a `$redirect` naming a resource that has not been loaded will serve a built-in
+    /// no-op resource appropriate for the request type instead, if there is one. The missing
+    /// resource is still reported in `BlockerResult::missing_redirect`. Disabled by default.
+    pub fn set_redirect_fallback(&mut self, enabled: bool) {
+        self.redirect_fallback = enabled;
+    }
+
+    /// Sets what to serve for `$redirect`s to resources of unknown MIME type. Defaults to
+    /// `UnknownResourcePolicy::ServeEmpty`.
+    pub fn set_unknown_resource_policy(&mut self, policy: UnknownResourcePolicy) {
+        self.unknown_resource_policy = policy;
+    }
+
+    /// If enabled, the query string of a redirected request is reported in
+    /// `BlockerResult::redirect_query`, and appended to `Redirection::Url` redirects. Disabled by
+    /// default.
+    pub fn set_preserve_redirect_query(&mut self, enabled: bool) {
+        self.preserve_redirect_query = enabled;
+    }
+
+    /// Returns all filters recorded as exceeding the slow filter budget so far, if telemetry is
+    /// enabled. The order of the returned filters is unspecified.
+    pub fn slow_filters(&self) -> Vec<SlowFilter> {
+        self.slow_filters.as_ref().map(|t| t.slow_filters()).unwrap_or_default()
+    }
+
+    /// Compiles the regexes of all filters up front, rather than on the first request that needs
+    /// each one. This moves the compilation cost out of request checks, at the expense of memory
+    /// for regexes that may never be used.
+    pub fn warmup(&self) -> RegexWarmup {
+        let mut warmup = RegexWarmup::default();
+        for list in [
+            &self.csp,
+            &self.exceptions,
+            &self.importants,
+            &self.redirects,
+            &self.filters_tagged,
+            &self.filters,
+            &self.generic_hide,
+            &self.generic_block,
+        ].iter() {
+            list.warmup(&mut warmup);
         }
+        // Tagged filters share their regex cache with any copies used for enabled tags.
+        warmup_filters(self.tagged_filters_all.iter(), &mut warmup);
+        warmup
     }
 
     /// If optimizations are enabled, the `Blocker` will be configured to automatically optimize
@@ -437,6 +1097,21 @@ impl Blocker {
         self.filters_tagged.optimize();
         self.filters.optimize();
         self.generic_hide.optimize();
+        self.generic_block.optimize();
+        self.order_by_match_cost();
+        self.domain_interner.shrink();
+    }
+
+    /// Reorders the buckets of every list that stops at its first match, so that cheaper filters
+    /// are evaluated first. `csp` and `redirects` are left alone, since every matching filter is
+    /// collected from them and the first redirect resource found takes precedence.
+    fn order_by_match_cost(&mut self) {
+        self.exceptions.order_by_match_cost();
+        self.importants.order_by_match_cost();
+        self.filters_tagged.order_by_match_cost();
+        self.filters.order_by_match_cost();
+        self.generic_hide.order_by_match_cost();
+        self.generic_block.order_by_match_cost();
     }
 
     pub fn filter_exists(&self, filter: &NetworkFilter) -> bool {
@@ -444,6 +1119,8 @@ impl Blocker {
             self.csp.filter_exists(filter)
         } else if filter.is_generic_hide() {
             self.generic_hide.filter_exists(filter)
+        } else if filter.is_generic_block() {
+            self.generic_block.filter_exists(filter)
         } else if filter.is_exception() {
             self.exceptions.filter_exists(filter)
         } else if filter.is_important() {
@@ -451,43 +1128,51 @@ impl Blocker {
         } else if filter.is_redirect() {
             self.redirects.filter_exists(filter)
         } else if filter.tag.is_some() {
-            self.tagged_filters_all.iter().any(|f| f.id == filter.id)
+            self.tagged_filters_all.iter().any(|f| f.filter_id() == filter.filter_id())
         } else {
             self.filters.filter_exists(filter)
         }
     }
 
-    pub fn add_filter(&mut self, filter: NetworkFilter) -> Result<(), BlockerError> {
+    /// Adds a single filter, unless the same filter from the same list already exists, in which
+    /// case `BlockerError::FilterExists` is returned. Adding a filter that is already present from
+    /// a different list keeps both, so that disabling either list with `set_list_enabled` leaves
+    /// the other one in effect.
+    pub fn add_filter(&mut self, mut filter: NetworkFilter) -> Result<(), BlockerError> {
+        self.domain_interner.intern_filter(&mut filter);
         if filter.is_badfilter() {
-            Err(BlockerError::BadFilterAddUnsupported)
-        } else if self.filter_exists(&filter) {
-            Err(BlockerError::FilterExists)
-        } else if filter.is_csp() {
-            self.csp.add_filter(filter);
-            Ok(())
-        } else if filter.is_generic_hide() {
-            self.generic_hide.add_filter(filter);
-            Ok(())
+            return Err(BlockerError::BadFilterAddUnsupported);
+        }
+        let added = if filter.is_csp() {
+            self.csp.add_filter_with_stop_tokens(filter, &self.stop_tokens)
+        } else if filter.is_generic_hide() || filter.is_generic_block() {
+            let added_block = filter.is_generic_block()
+                && self.generic_block.add_filter_with_stop_tokens(filter.clone(), &self.stop_tokens);
+            let added_hide = filter.is_generic_hide()
+                && self.generic_hide.add_filter_with_stop_tokens(filter, &self.stop_tokens);
+            added_block || added_hide
         } else if filter.is_exception() {
-            self.exceptions.add_filter(filter);
-            Ok(())
+            self.exceptions.add_filter_with_stop_tokens(filter, &self.stop_tokens)
         } else if filter.is_important() {
-            self.importants.add_filter(filter);
-            Ok(())
-        } else if filter.is_redirect() {
-            self.redirects.add_filter(filter);
-            Ok(())
-        } else if filter.is_redirect_url() {
-            self.redirects.add_filter(filter);
-            Ok(())
+            self.importants.add_filter_with_stop_tokens(filter, &self.stop_tokens)
+        } else if filter.is_redirect() || filter.is_redirect_url() {
+            self.redirects.add_filter_with_stop_tokens(filter, &self.stop_tokens)
         } else if filter.tag.is_some() {
-            self.tagged_filters_all.push(filter);
-            let tags_enabled = self.tags_enabled().into_iter().collect::<HashSet<_>>();
-            self.tags_with_set(tags_enabled);
-            Ok(())
+            let exists = self.tagged_filters_all.iter()
+                .any(|f| f.filter_id() == filter.filter_id() && f.list_id == filter.list_id);
+            if !exists {
+                self.tagged_filters_all.push(filter);
+                let tags_enabled = self.tags_enabled().into_iter().collect::<HashSet<_>>();
+                self.tags_with_set(tags_enabled);
+            }
+            !exists
         } else {
-            self.filters.add_filter(filter);
+            self.filters.add_filter_with_stop_tokens(filter, &self.stop_tokens)
+        };
+        if added {
             Ok(())
+        } else {
+            Err(BlockerError::FilterExists)
         }
     }
 
@@ -513,18 +1198,226 @@ impl Blocker {
     }
 
     fn tags_with_set(&mut self, tags_enabled: HashSet<String>) {
+        self.filters_tagged = self.build_tagged_filters(&tags_enabled);
         self.tags_enabled = tags_enabled;
+    }
+
+    /// Rebuilds the active tagged filters of a deserialized blocker from `tagged_filters_all`. The
+    /// tags enabled when it was serialized aren't stored, so the serialized `filters_tagged` would
+    /// otherwise keep their filters active while none of the tags are enabled.
+    pub(crate) fn restore_tagged_filters(&mut self) {
+        self.filters_tagged = self.build_tagged_filters(&self.tags_enabled);
+    }
+
+    /// Builds the list of tagged filters that are active when exactly `tags_enabled` are enabled.
+    pub(crate) fn build_tagged_filters(&self, tags_enabled: &HashSet<String>) -> NetworkFilterList {
         let filters: Vec<NetworkFilter> = self.tagged_filters_all.iter()
-            .filter(|n| n.tag.is_some() && self.tags_enabled.contains(n.tag.as_ref().unwrap()))
+            .filter(|n| n.tag.is_some() && tags_enabled.contains(n.tag.as_ref().unwrap()))
             .cloned()
             .collect();
-        self.filters_tagged = NetworkFilterList::new(filters, self.enable_optimizations);
+        let mut filters_tagged = NetworkFilterList::new_with_stop_tokens(filters, self.enable_optimizations, &self.stop_tokens);
+        if self.enable_optimizations {
+            filters_tagged.order_by_match_cost();
+        }
+        filters_tagged
+    }
+
+    /// Returns the sorted, deduplicated tags of all network filters in this blocker, whether or
+    /// not they are currently enabled.
+    pub fn available_tags(&self) -> Vec<String> {
+        let lists = [&self.csp, &self.exceptions, &self.importants, &self.redirects, &self.generic_hide, &self.generic_block, &self.filters];
+        let tags = lists.iter()
+            .flat_map(|list| list.filter_map.values().flatten().map(|filter| &**filter))
+            .chain(self.tagged_filters_all.iter())
+            .filter_map(|filter| filter.tag.clone())
+            .collect::<std::collections::BTreeSet<_>>();
+        tags.into_iter().collect()
+    }
+
+    /// Returns the sorted, deduplicated ids of all `$redirect` filters referencing the resource
+    /// `name`, including exceptions. Resources are matched by the name written in each filter, so
+    /// filters using one of the resource's aliases are not included. `$redirect-url` filters never
+    /// reference a resource.
+    pub fn filters_using_resource(&self, name: &str) -> Vec<FilterId> {
+        let mut ids = self.redirects.filter_map.values()
+            .flatten()
+            .filter(|filter| !filter.is_redirect_url() && filter.redirect.as_deref() == Some(name))
+            .map(|filter| filter.filter_id())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// Reports how much memory the text of `$domain` and `$to` entries takes up, compared to
+    /// storing a separate copy for each entry. See `DomainMemoryReport`.
+    pub fn domain_memory_report(&self) -> DomainMemoryReport {
+        let lists = [&self.csp, &self.exceptions, &self.importants, &self.redirects, &self.filters_tagged, &self.filters, &self.generic_hide, &self.generic_block];
+        let mut seen_filters = HashSet::new();
+        let mut seen_strings = HashSet::new();
+        let mut report = DomainMemoryReport::default();
+        let filters = lists.iter()
+            .flat_map(|list| list.filter_map.values().flatten().map(|filter| &**filter))
+            .chain(self.tagged_filters_all.iter())
+            .filter(|filter| seen_filters.insert(*filter as *const NetworkFilter));
+        for filter in filters {
+            for domain in filter.opt_domains_text.iter().flatten().chain(filter.opt_to_domains.iter().flat_map(ToDomains::domains)) {
+                report.entries += 1;
+                report.uninterned_bytes += domain.len();
+                if seen_strings.insert(Arc::as_ptr(domain) as *const u8) {
+                    report.strings += 1;
+                    report.bytes += domain.len();
+                }
+            }
+        }
+        report
+    }
+
+    /// Writes out the network filters of this blocker as text rules, sorted and without
+    /// duplicates. Rules are reconstructed with `NetworkFilter::to_canonical_string`, so that
+    /// changes made after parsing, like a tag from `FilterSet::add_filter_list_with_tag`, are
+    /// included. Filters combined by the optimizer are written as the separate rules they were
+    /// made of. This includes tagged filters whether or not their tag is enabled, and `$badfilter`
+    /// rules.
+    ///
+    /// Filters with `$domain` options deserialized from a format that didn't keep domain names
+    /// can't be reconstructed. Their raw lines are used instead where they were kept, and they are
+    /// left out otherwise.
+    pub fn export_network_filters(&self) -> Vec<String> {
+        let lists = [&self.csp, &self.exceptions, &self.importants, &self.redirects, &self.filters, &self.generic_hide, &self.generic_block];
+        let mut rules: Vec<String> = lists.iter()
+            .flat_map(|list| list.filter_map.values().flatten().map(|filter| &**filter))
+            .chain(self.tagged_filters_all.iter())
+            .chain(self.badfilters.iter())
+            .flat_map(|filter| -> Vec<String> {
+                let raw_lines: Vec<&str> = filter.raw_line.as_deref()
+                    .map(|raw_line| raw_line.split(" <+> ").collect())
+                    .unwrap_or_default();
+                match &filter.filter {
+                    FilterPart::AnyOf(patterns) => patterns.iter()
+                        .enumerate()
+                        .filter_map(|(i, pattern)| {
+                            let mut part = filter.clone();
+                            part.filter = FilterPart::Simple(pattern.clone());
+                            part.to_canonical_string()
+                                .or_else(|| raw_lines.get(i).filter(|_| raw_lines.len() == patterns.len()).map(|raw| raw.to_string()))
+                        })
+                        .collect(),
+                    _ => filter.to_canonical_string()
+                        .or_else(|| filter.raw_line.clone())
+                        .into_iter()
+                        .collect(),
+                }
+            })
+            .collect();
+        rules.sort_unstable();
+        rules.dedup();
+        rules
     }
 
     pub fn tags_enabled(&self) -> Vec<String> {
         self.tags_enabled.iter().cloned().collect()
     }
 
+    /// Reports the tokens of `filter`, and the buckets it is stored under in this blocker. See
+    /// `FilterTokenization`.
+    pub fn filter_tokenization(&self, filter: &NetworkFilter) -> FilterTokenization {
+        let rule = filter.raw_line.clone().unwrap_or_else(|| filter.to_string());
+        let names = token_names(&rule);
+        let token = |hash: Hash| FilterToken {
+            hash,
+            name: names.get(&hash).cloned(),
+        };
+
+        let id = filter.get_id();
+        let lists = [&self.csp, &self.exceptions, &self.importants, &self.redirects, &self.filters_tagged, &self.generic_hide, &self.generic_block, &self.filters];
+        let mut buckets = lists.iter()
+            .flat_map(|list| list.filter_map.iter())
+            .filter(|(_, filters)| filters.iter().any(|f| f.get_id() == id))
+            .map(|(bucket, _)| *bucket)
+            .collect::<Vec<_>>();
+        buckets.sort_unstable();
+        buckets.dedup();
+
+        FilterTokenization {
+            tokens: filter.get_tokens().into_iter().map(|tokens| tokens.into_iter().map(token).collect()).collect(),
+            buckets: buckets.into_iter().map(token).collect(),
+        }
+    }
+
+    /// Adds the filters and redirect resources of `other` to this blocker, as if both had been
+    /// built from the same rules. The `$badfilter` rules of each blocker also cancel matching
+    /// filters of the other, including single filters that were fused with others by the
+    /// optimizer. Resources of `other` replace any with the same name. The settings and enabled
+    /// tags of this blocker are kept.
+    pub fn merge(&mut self, other: Blocker) {
+        let cancelled_ids = |badfilters: &[NetworkFilter]| badfilters.iter()
+            .map(|badfilter| badfilter.get_id_without_badfilter())
+            .collect::<HashSet<_>>();
+        let cancelled_here = cancelled_ids(&other.badfilters);
+        let cancelled_there = cancelled_ids(&self.badfilters);
+
+        for badfilter in &other.badfilters {
+            let mut cancelled = badfilter.clone();
+            cancelled.mask.set(NetworkFilterMask::BAD_FILTER, false);
+            for list in [
+                &mut self.csp,
+                &mut self.exceptions,
+                &mut self.importants,
+                &mut self.redirects,
+                &mut self.filters,
+                &mut self.generic_hide,
+                &mut self.generic_block,
+            ] {
+                list.remove_filter(&cancelled);
+            }
+        }
+        self.tagged_filters_all.retain(|filter| !cancelled_here.contains(&filter.get_id()));
+
+        // `filters_tagged` is rebuilt from `tagged_filters_all` below
+        for (list, other_list) in [
+            (&mut self.csp, other.csp),
+            (&mut self.exceptions, other.exceptions),
+            (&mut self.importants, other.importants),
+            (&mut self.redirects, other.redirects),
+            (&mut self.filters, other.filters),
+            (&mut self.generic_hide, other.generic_hide),
+            (&mut self.generic_block, other.generic_block),
+        ] {
+            for filter in other_list.filter_map.into_values().flatten() {
+                // Filters stored under several tokens are shared between their buckets
+                let filter = Arc::try_unwrap(filter).unwrap_or_else(|filter| (*filter).clone());
+                let mut filter = match filter.without_parts(|id| cancelled_there.contains(&id)) {
+                    Some(filter) => filter,
+                    None => continue,
+                };
+                self.domain_interner.intern_filter(&mut filter);
+                list.add_filter_with_stop_tokens(filter, &self.stop_tokens);
+            }
+        }
+        for mut filter in other.tagged_filters_all {
+            let id = filter.get_id();
+            if !cancelled_there.contains(&id) && !self.tagged_filters_all.iter().any(|existing| existing.get_id() == id && existing.list_id == filter.list_id) {
+                self.domain_interner.intern_filter(&mut filter);
+                self.tagged_filters_all.push(filter);
+            }
+        }
+        for badfilter in other.badfilters {
+            let id = badfilter.get_id();
+            if !self.badfilters.iter().any(|existing| existing.get_id() == id) {
+                self.badfilters.push(badfilter);
+            }
+        }
+
+        self.filters_tagged = self.build_tagged_filters(&self.tags_enabled);
+        self.resources.merge(other.resources);
+        if self.enable_optimizations {
+            self.order_by_match_cost();
+        }
+        // Filters cancelled by the merged `$badfilter`s may have been the last to use a domain
+        self.domain_interner.shrink();
+    }
+
     pub fn use_resources(&mut self, resources: &[Resource]) {
         let resources = RedirectResourceStorage::from_resources(resources);
         self.resources = resources;
@@ -539,14 +1432,105 @@ impl Blocker {
     }
 }
 
-#[derive(Serialize, Deserialize, Default)]
+/// Serialization is implemented in `data_format`, with its own version tag so that the layout of
+/// this struct can change independently of the engine format.
+#[derive(Default)]
 pub struct NetworkFilterList {
-    #[serde(serialize_with = "crate::data_format::utils::stabilize_hashmap_serialization")]
     pub(crate) filter_map: HashMap<Hash, Vec<Arc<NetworkFilter>>>,
 }
 
+/// Checks whether a redirect resource with the given MIME type can stand in for the response to a
+/// request of the given type. Images and media must be served as such, scripts as JavaScript,
+/// stylesheets as CSS and documents as HTML. Plain text is accepted for anything but images and
+/// media, and any other request types accept every resource.
+fn resource_fits_request(content_type: &str, request_type: &RequestType) -> bool {
+    let content_type = content_type.split(';').next().unwrap_or_default().trim();
+    if content_type == "text/plain" {
+        return !matches!(request_type, RequestType::Image | RequestType::Media);
+    }
+    match request_type {
+        RequestType::Image => content_type.starts_with("image/"),
+        RequestType::Media => content_type.starts_with("audio/") || content_type.starts_with("video/"),
+        RequestType::Script => content_type == "application/javascript" || content_type == "text/javascript",
+        RequestType::Stylesheet => content_type == "text/css",
+        RequestType::Document | RequestType::Subdocument => content_type == "text/html",
+        _ => true,
+    }
+}
+
+/// Maps the hash of each word in `rule` back to the word, for naming the tokens of the filter.
+/// Domains in options are hashed whole, so anything between option separators is included too.
+fn token_names(rule: &str) -> HashMap<Hash, String> {
+    let rule = rule.to_ascii_lowercase();
+    rule.split(|c: char| !c.is_ascii_alphanumeric() && c != '%')
+        .chain(rule.split(['$', ',', '|', '=', '~', '^', '/']))
+        .filter(|word| !word.is_empty())
+        .map(|word| (fast_hash(word), word.to_owned()))
+        .collect()
+}
+
+/// Returns the non-empty query string of the request URL, in its original case.
+fn request_query(request: &Request) -> Option<&str> {
+    let url = request.url_case_sensitive();
+    let url = url.split('#').next().unwrap_or(url);
+    url.split_once('?').map(|(_, query)| query).filter(|query| !query.is_empty())
+}
+
+/// Resources added as `MimeType::Unknown` are stored with its generic content type.
+fn is_unknown_content_type(content_type: &str) -> bool {
+    content_type.split(';').next().unwrap_or_default().trim() == String::from(MimeType::Unknown)
+}
+
+/// Built-in no-op stand-ins for missing redirect resources, used when redirect fallback is enabled,
+/// and in place of resources that don't suit the request type.
+fn fallback_redirect(request_type: &RequestType) -> Option<&'static str> {
+    match request_type {
+        // `(function() {})()`
+        RequestType::Script => Some("data:application/javascript;base64,KGZ1bmN0aW9uKCkge30pKCk="),
+        // A transparent 1x1 GIF
+        RequestType::Image => Some("data:image/gif;base64,R0lGODlhAQABAIAAAAAAAP///yH5BAEAAAAALAAAAAABAAEAAAIBRAA7"),
+        RequestType::Subdocument => Some("data:text/html;base64,"),
+        RequestType::Xmlhttprequest => Some("data:text/plain;base64,"),
+        _ => None,
+    }
+}
+
+/// Compiles the regex of each filter that needs one and hasn't compiled it yet.
+fn warmup_filters<'a>(filters: impl Iterator<Item = &'a NetworkFilter>, warmup: &mut RegexWarmup) {
+    for filter in filters {
+        if (!filter.is_regex() && !filter.is_complete_regex()) || filter.is_regex_compiled() {
+            continue;
+        }
+        match *filter.get_regex() {
+            CompiledRegex::RegexParsingError(_) => warmup.failed.push(filter.to_string()),
+            _ => warmup.compiled += 1,
+        }
+    }
+}
+
+/// A rough ranking of how expensive a filter is to evaluate against a request that shares its
+/// token. Hostname-anchored plain patterns come first: the hostname comparison is cheap and
+/// rejects most requests outright. Other plain patterns only need substring checks, while
+/// wildcard patterns and full regular expressions have to run a regex over the whole URL.
+fn match_cost(filter: &NetworkFilter) -> u8 {
+    if filter.is_complete_regex() {
+        3
+    } else if filter.is_regex() {
+        2
+    } else if filter.is_hostname_anchor() {
+        0
+    } else {
+        1
+    }
+}
+
 impl NetworkFilterList {
     pub fn new(filters: Vec<NetworkFilter>, optimize: bool) -> NetworkFilterList {
+        Self::new_with_stop_tokens(filters, optimize, &DEFAULT_STOP_TOKEN_HASHES)
+    }
+
+    /// Like `new`, but never indexes filters by any of the hashed `stop_tokens`.
+    pub(crate) fn new_with_stop_tokens(filters: Vec<NetworkFilter>, optimize: bool, stop_tokens: &HashSet<Hash>) -> NetworkFilterList {
         // Compute tokens for all filters
         let filter_tokens: Vec<_> = filters
             .into_iter()
@@ -565,7 +1549,7 @@ impl NetworkFilterList {
                 for tokens in multi_tokens {
                     let mut best_token: Hash = 0;
                     let mut min_count = total_number_of_tokens + 1;
-                    for token in tokens {
+                    for token in tokens.into_iter().filter(|token| !stop_tokens.contains(token)) {
                         match tokens_histogram.get(&token) {
                             None => {
                                 min_count = 0;
@@ -596,6 +1580,22 @@ impl NetworkFilterList {
         self_
     }
 
+    /// Returns the filters that `optimize` would fuse together, without modifying the list.
+    pub(crate) fn optimization_dry_run(&self) -> Vec<optimizer::MergedFilters> {
+        self.filter_map.values().flat_map(|filters| {
+            // Filters stored in multiple buckets are left as they are by `optimize`
+            let candidates: Vec<_> = filters.iter()
+                .filter(|f| Arc::strong_count(f) == 1)
+                .map(|f| f.as_ref())
+                .collect();
+            if candidates.len() > 1 {
+                optimizer::fusion_groups(&candidates)
+            } else {
+                vec![]
+            }
+        }).collect()
+    }
+
     pub fn optimize(&mut self) {
         let mut optimized_map = HashMap::with_capacity(self.filter_map.len());
         for (key, filters) in self.filter_map.drain() {
@@ -625,15 +1625,39 @@ impl NetworkFilterList {
         self.filter_map = optimized_map;
     }
 
+    /// Stable-sorts the filters within each bucket by `match_cost`. Whether a request matches the
+    /// list is unaffected, although the filter reported as matching may differ when several in the
+    /// same bucket match.
+    pub(crate) fn order_by_match_cost(&mut self) {
+        for filters in self.filter_map.values_mut() {
+            filters.sort_by_key(|filter| match_cost(filter));
+        }
+    }
+
+    /// Adds a single filter to the list. Filters that are already present from the same list, as
+    /// determined by `filter_exists_in_list`, are not added again.
     pub fn add_filter(&mut self, filter: NetworkFilter) {
+        self.add_filter_with_stop_tokens(filter, &DEFAULT_STOP_TOKEN_HASHES);
+    }
+
+    /// Like `add_filter`, but never indexes the filter by any of the hashed `stop_tokens`. Returns
+    /// whether the filter was added.
+    pub(crate) fn add_filter_with_stop_tokens(&mut self, filter: NetworkFilter, stop_tokens: &HashSet<Hash>) -> bool {
+        // The least used bucket can change between insertions, so duplicates would not
+        // necessarily end up in the same bucket.
+        if self.filter_exists_in_list(&filter) {
+            return false;
+        }
+
         let filter_tokens = filter.get_tokens();
         let total_rules = vec_hashmap_len(&self.filter_map);
         let filter_pointer = Arc::new(filter);
+        let mut buckets = Vec::with_capacity(filter_tokens.len());
 
         for tokens in filter_tokens {
             let mut best_token: Hash = 0;
             let mut min_count = total_rules + 1;
-            for token in tokens {
+            for token in tokens.into_iter().filter(|token| !stop_tokens.contains(token)) {
                 match self.filter_map.get(&token) {
                     None => {
                         min_count = 0;
@@ -647,24 +1671,99 @@ impl NetworkFilterList {
                 }
             }
 
-            insert_dup(&mut self.filter_map, best_token, Arc::clone(&filter_pointer));
+            // Duplicates were ruled out above, so this doesn't rely on the bucket being sorted by
+            // id like `insert_dup` does, which it no longer is after `order_by_match_cost`. The
+            // filter goes after those with a lower or equal match cost, keeping such order.
+            let bucket = self.filter_map.entry(best_token).or_default();
+            let cost = match_cost(&filter_pointer);
+            let position = bucket.partition_point(|filter| match_cost(filter) <= cost);
+            bucket.insert(position, Arc::clone(&filter_pointer));
+            buckets.push(best_token);
+        }
+
+        self.debug_check_buckets(&buckets);
+        true
+    }
+
+    /// Removes every copy of `filter` from the list, returning whether it was found. Filters are
+    /// compared by `NetworkFilter::filter_id`. If the optimizer fused `filter` with others, only
+    /// its own part of the fused filter is removed.
+    pub fn remove_filter(&mut self, filter: &NetworkFilter) -> bool {
+        // Same candidate buckets as `filter_exists`
+        let mut tokens: Vec<_> = filter.get_tokens().into_iter().flatten().collect();
+        tokens.push(0);
+        tokens.sort_unstable();
+        tokens.dedup();
+
+        let filter_id = filter.filter_id();
+        let mut removed = false;
+        for token in &tokens {
+            if let Some(filters) = self.filter_map.get_mut(token) {
+                *filters = std::mem::take(filters).into_iter().filter_map(|saved_filter| {
+                    let is_fused_part = saved_filter.fused_ids.as_ref().map(|ids| ids.contains(&filter_id)).unwrap_or(false);
+                    if saved_filter.filter_id() == filter_id || is_fused_part {
+                        removed = true;
+                        (*saved_filter).clone().without_parts(|id| id == filter_id).map(Arc::new)
+                    } else {
+                        Some(saved_filter)
+                    }
+                }).collect();
+                if filters.is_empty() {
+                    self.filter_map.remove(token);
+                }
+            }
+        }
+
+        self.debug_check_buckets(&tokens);
+        debug_assert!(!self.filter_exists(filter));
+        removed
+    }
+
+    /// In debug builds, checks that none of the buckets for `tokens` are empty, and that no filter
+    /// is stored twice in the same bucket.
+    fn debug_check_buckets(&self, tokens: &[Hash]) {
+        if cfg!(debug_assertions) {
+            for token in tokens {
+                if let Some(filters) = self.filter_map.get(token) {
+                    assert!(!filters.is_empty(), "Bucket {} is empty", token);
+                    let ids = filters.iter().map(|filter| (filter.id, filter.list_id)).collect::<HashSet<_>>();
+                    assert_eq!(ids.len(), filters.len(), "Bucket {} has duplicate filters", token);
+                }
+            }
         }
     }
 
+    /// Compiles the regex of every filter in this list that needs one and hasn't compiled it yet,
+    /// recording the results in `warmup`.
+    pub(crate) fn warmup(&self, warmup: &mut RegexWarmup) {
+        warmup_filters(self.filter_map.values().flatten().map(|filter| &**filter), warmup);
+    }
+
     pub fn filter_exists(&self, filter: &NetworkFilter) -> bool {
+        self.find_filter(filter, |_| true)
+    }
+
+    /// Like `filter_exists`, but only finds filters parsed from the same list as `filter`, i.e.
+    /// with the same `list_id`.
+    pub(crate) fn filter_exists_in_list(&self, filter: &NetworkFilter) -> bool {
+        self.find_filter(filter, |saved_filter| saved_filter.list_id == filter.list_id)
+    }
+
+    fn find_filter<F: Fn(&NetworkFilter) -> bool>(&self, filter: &NetworkFilter, also_matches: F) -> bool {
         // if self.optimized == Some(true) {
         //     return Err(BlockerError::OptimizedFilterExistence)
         // }
         let mut tokens: Vec<_> = filter.get_tokens().into_iter().flatten().collect();
 
-        if tokens.is_empty() {
-            tokens.push(0)
-        }
+        // Filters with only stop tokens are stored in the fallback bucket
+        tokens.push(0);
+
+        let filter_id = filter.filter_id();
 
         for token in tokens {
             if let Some(filters) = self.filter_map.get(&token) {
                 for saved_filter in filters {
-                    if saved_filter.id == filter.id {
+                    if saved_filter.filter_id() == filter_id && also_matches(saved_filter) {
                         return true;
                     }
                 }
@@ -680,9 +1779,13 @@ impl NetworkFilterList {
     /// filters match a certain request, it doesn't matter _which_ one is matched - the request
     /// will be excepted either way.
     pub fn check(&self, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>) -> Option<&NetworkFilter> {
-        #[cfg(feature = "metrics")]
+        self.check_tracked(request, request_tokens, active_tags, None, None, &HashSet::new())
+    }
+
+    /// Same as `check`, but records slow filter evaluations to `slow_filters` if provided, and
+    /// skips filters from any of the `disabled_lists`.
+    pub(crate) fn check_tracked(&self, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>, slow_filters: Option<&SlowFilterTracker>, bucket_stats: Option<&BucketStatsTracker>, disabled_lists: &HashSet<ListId>) -> Option<&NetworkFilter> {
         let mut filters_checked = 0;
-        #[cfg(feature = "metrics")]
         let mut filter_buckets = 0;
 
         #[cfg(not(feature = "metrics"))]
@@ -695,20 +1798,16 @@ impl NetworkFilterList {
         if let Some(source_hostname_hashes) = request.source_hostname_hashes.as_ref() {
             for token in source_hostname_hashes {
                 if let Some(filter_bucket) = self.filter_map.get(token) {
-                    #[cfg(feature = "metrics")]
-                    {
-                        filter_buckets += 1;
-                    }
+                    filter_buckets += 1;
 
                     for filter in filter_bucket {
-                        #[cfg(feature = "metrics")]
-                        {
-                            filters_checked += 1;
-                        }
+                        filters_checked += 1;
                         // if matched, also needs to be tagged with an active tag (or not tagged at all)
-                        if filter.matches(request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
+                        // and come from an enabled list (or no list at all)
+                        if filter_matches(filter, request, slow_filters) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) && list_enabled(filter, disabled_lists) {
                             #[cfg(feature = "metrics")]
                             print!("true\t{}\t{}\tskipped\t{}\t{}\t", filter_buckets, filters_checked, filter_buckets, filters_checked);
+                            record_bucket_stats(bucket_stats, filter_buckets, filters_checked);
                             return Some(filter);
                         }
                     }
@@ -721,19 +1820,15 @@ impl NetworkFilterList {
 
         for token in request_tokens {
             if let Some(filter_bucket) = self.filter_map.get(token) {
-                #[cfg(feature = "metrics")]
-                {
-                    filter_buckets += 1;
-                }
+                filter_buckets += 1;
                 for filter in filter_bucket {
-                    #[cfg(feature = "metrics")]
-                    {
-                        filters_checked += 1;
-                    }
+                    filters_checked += 1;
                     // if matched, also needs to be tagged with an active tag (or not tagged at all)
-                    if filter.matches(request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
+                    // and come from an enabled list (or no list at all)
+                    if filter_matches(filter, request, slow_filters) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) && list_enabled(filter, disabled_lists) {
                         #[cfg(feature = "metrics")]
                         print!("true\t{}\t{}\t", filter_buckets, filters_checked);
+                        record_bucket_stats(bucket_stats, filter_buckets, filters_checked);
                         return Some(filter);
                     }
                 }
@@ -743,6 +1838,7 @@ impl NetworkFilterList {
         #[cfg(feature = "metrics")]
         print!("false\t{}\t{}\t", filter_buckets, filters_checked);
 
+        record_bucket_stats(bucket_stats, filter_buckets, filters_checked);
         None
     }
 
@@ -751,9 +1847,13 @@ impl NetworkFilterList {
     /// `$csp` filters match a certain request, they may each carry a distinct CSP directive, and
     /// each directive should be combined for the final result.
     pub fn check_all(&self, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>) -> Vec<&NetworkFilter> {
-        #[cfg(feature = "metrics")]
+        self.check_all_tracked(request, request_tokens, active_tags, None, None, &HashSet::new())
+    }
+
+    /// Same as `check_all`, but records slow filter evaluations to `slow_filters` if provided, and
+    /// skips filters from any of the `disabled_lists`.
+    pub(crate) fn check_all_tracked(&self, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>, slow_filters: Option<&SlowFilterTracker>, bucket_stats: Option<&BucketStatsTracker>, disabled_lists: &HashSet<ListId>) -> Vec<&NetworkFilter> {
         let mut filters_checked = 0;
-        #[cfg(feature = "metrics")]
         let mut filter_buckets = 0;
 
         let mut filters = vec![];
@@ -768,18 +1868,13 @@ impl NetworkFilterList {
         if let Some(source_hostname_hashes) = request.source_hostname_hashes.as_ref() {
             for token in source_hostname_hashes {
                 if let Some(filter_bucket) = self.filter_map.get(token) {
-                    #[cfg(feature = "metrics")]
-                    {
-                        filter_buckets += 1;
-                    }
+                    filter_buckets += 1;
 
                     for filter in filter_bucket {
-                        #[cfg(feature = "metrics")]
-                        {
-                            filters_checked += 1;
-                        }
+                        filters_checked += 1;
                         // if matched, also needs to be tagged with an active tag (or not tagged at all)
-                        if filter.matches(request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
+                        // and come from an enabled list (or no list at all)
+                        if filter_matches(filter, request, slow_filters) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) && list_enabled(filter, disabled_lists) {
                             #[cfg(feature = "metrics")]
                             print!("true\t{}\t{}\tskipped\t{}\t{}\t", filter_buckets, filters_checked, filter_buckets, filters_checked);
                             filters.push(filter);
@@ -792,19 +1887,14 @@ impl NetworkFilterList {
         #[cfg(feature = "metrics")]
         print!("false\t{}\t{}\t", filter_buckets, filters_checked);
 
-        for token in request_tokens {
-            if let Some(filter_bucket) = self.filter_map.get(token) {
-                #[cfg(feature = "metrics")]
-                {
-                    filter_buckets += 1;
-                }
+        for token in request_tokens {
+            if let Some(filter_bucket) = self.filter_map.get(token) {
+                filter_buckets += 1;
                 for filter in filter_bucket {
-                    #[cfg(feature = "metrics")]
-                    {
-                        filters_checked += 1;
-                    }
+                    filters_checked += 1;
                     // if matched, also needs to be tagged with an active tag (or not tagged at all)
-                    if filter.matches(request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
+                    // and come from an enabled list (or no list at all)
+                    if filter_matches(filter, request, slow_filters) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) && list_enabled(filter, disabled_lists) {
                         #[cfg(feature = "metrics")]
                         print!("true\t{}\t{}\t", filter_buckets, filters_checked);
                         filters.push(filter);
@@ -816,10 +1906,68 @@ impl NetworkFilterList {
         #[cfg(feature = "metrics")]
         print!("false\t{}\t{}\t", filter_buckets, filters_checked);
 
+        record_bucket_stats(bucket_stats, filter_buckets, filters_checked);
         filters
     }
 }
 
+/// Returns `true` for CSP directives that only configure violation reporting rather than
+/// restricting content.
+fn is_csp_reporting_directive(directive: &str) -> bool {
+    let name = directive.split_whitespace().next().unwrap_or_default();
+    name.eq_ignore_ascii_case("report-uri") || name.eq_ignore_ascii_case("report-to")
+}
+
+/// Removes any reporting directives from `policy` that are already present in `seen`, and adds
+/// the remaining ones to `seen`. The policy is returned unchanged if nothing was removed.
+fn dedup_csp_reporting_directives<'a>(policy: &'a str, seen: &mut HashSet<String>) -> std::borrow::Cow<'a, str> {
+    let mut duplicated = false;
+    let mut kept: Vec<&str> = vec![];
+
+    for directive in policy.split(';').map(str::trim).filter(|d| !d.is_empty()) {
+        if is_csp_reporting_directive(directive) {
+            let normalized = directive.split_whitespace().collect::<Vec<_>>().join(" ").to_ascii_lowercase();
+            if !seen.insert(normalized) {
+                duplicated = true;
+                continue;
+            }
+        }
+        kept.push(directive);
+    }
+
+    if duplicated {
+        std::borrow::Cow::Owned(kept.join("; "))
+    } else {
+        std::borrow::Cow::Borrowed(policy)
+    }
+}
+
+/// Filters that don't belong to a list are always enabled.
+#[inline]
+fn list_enabled(filter: &NetworkFilter, disabled_lists: &HashSet<ListId>) -> bool {
+    filter.list_id.map(|id| !disabled_lists.contains(&id)).unwrap_or(true)
+}
+
+/// Adds the buckets visited and filters compared by a single list check to `bucket_stats`, if
+/// enabled.
+#[inline]
+fn record_bucket_stats(bucket_stats: Option<&BucketStatsTracker>, buckets_visited: usize, filters_compared: usize) {
+    if let Some(stats) = bucket_stats {
+        stats.buckets_visited.fetch_add(buckets_visited as u64, Ordering::Relaxed);
+        stats.filters_compared.fetch_add(filters_compared as u64, Ordering::Relaxed);
+    }
+}
+
+/// Checks a single filter against a request, timing the evaluation only if slow filter telemetry
+/// is enabled.
+#[inline]
+fn filter_matches(filter: &NetworkFilter, request: &Request, slow_filters: Option<&SlowFilterTracker>) -> bool {
+    match slow_filters {
+        None => filter.matches(request),
+        Some(tracker) => tracker.matches(filter, request),
+    }
+}
+
 /// Inserts a value into the `Vec` under the specified key in the `HashMap`. The entry will be
 /// created if it does not exist. If it already exists, it will be inserted in the `Vec` in a
 /// sorted order.
@@ -934,6 +2082,36 @@ mod tests {
         }
     }
 
+    #[test]
+    fn stop_tokens_use_fallback_bucket() {
+        let parse = |filter: &str| NetworkFilter::parse(filter, true, Default::default()).unwrap();
+
+        // `www` is a default stop token, so a filter without any other token is not indexed by it
+        let filter_list = NetworkFilterList::new(vec![parse("/www/page")], false);
+        assert!(!filter_list.filter_map.contains_key(&fast_hash("www")));
+        assert_eq!(filter_list.filter_map.get(&0).map(|bucket| bucket.len()), Some(1));
+
+        let options = BlockerOptions { enable_optimizations: false };
+        let mut blocker = Blocker::new_with_stop_tokens(vec![parse("/ads/script"), parse("/banner/ads/script")], &options, &["ads"]);
+        assert!(!blocker.filters.filter_map.contains_key(&fast_hash("ads")));
+        assert_eq!(blocker.filters.filter_map.get(&0).map(|bucket| bucket.len()), Some(1));
+        assert!(blocker.filters.filter_map.contains_key(&fast_hash("banner")));
+
+        // Filters added later on use the same stop tokens
+        blocker.add_filter(parse("/ads/top")).unwrap();
+        assert!(!blocker.filters.filter_map.contains_key(&fast_hash("ads")));
+        assert_eq!(blocker.add_filter(parse("/ads/script")), Err(BlockerError::FilterExists));
+
+        let request = Request::from_urls("https://example.com/ads/script.js", "https://example.com", "script").unwrap();
+        assert!(blocker.check(&request).matched);
+        let request = Request::from_urls("https://example.com/content/script.js", "https://example.com", "script").unwrap();
+        assert!(!blocker.check(&request).matched);
+
+        // Without any stop tokens, `ads` is the only and therefore best token
+        let blocker = Blocker::new_with_stop_tokens(vec![parse("/ads/script")], &options, &[]);
+        assert!(blocker.filters.filter_map.contains_key(&fast_hash("ads")));
+    }
+
     #[test]
     fn network_filter_list_new_works() {
         {
@@ -965,7 +2143,7 @@ mod tests {
                 1
             );
         }
-        // choses blacklisted token when no other choice
+        // choses blacklisted token when no other choice, unless it is a stop token
         {
             let filters = vec!["||foo.com", "||foo.com/bar", "||www"];
             let network_filters: Vec<_> = filters
@@ -973,7 +2151,7 @@ mod tests {
                 .map(|f| NetworkFilter::parse(&f, true, Default::default()))
                 .filter_map(Result::ok)
                 .collect();
-            let filter_list = NetworkFilterList::new(network_filters, false);
+            let filter_list = NetworkFilterList::new_with_stop_tokens(network_filters, false, &HashSet::new());
             assert!(
                 filter_list.filter_map.get(&fast_hash("www")).is_some(),
                 "Filter matching {} not found",
@@ -1208,6 +2386,113 @@ mod tests {
 
         test_requests_filters(&filters, &request_expectations);
     }
+
+    #[test]
+    fn network_filter_list_order_by_match_cost() {
+        let filters = [
+            "/ads/*/banner",
+            #[cfg(feature = "full-regex-handling")]
+            "/^https?:\\/\\/[a-z]+\\.com\\/ads\\//",
+            "/ads/*",
+            "||ads.example.com^",
+            "||cdn.com/ads/",
+        ];
+        let network_filters: Vec<_> = filters
+            .iter()
+            .map(|f| NetworkFilter::parse(f, true, Default::default()))
+            .filter_map(Result::ok)
+            .collect();
+
+        let unordered = NetworkFilterList::new(network_filters.clone(), false);
+        let mut ordered = NetworkFilterList::new(network_filters, false);
+        ordered.order_by_match_cost();
+
+        for filters in ordered.filter_map.values() {
+            let costs: Vec<_> = filters.iter().map(|f| match_cost(f)).collect();
+            let mut sorted = costs.clone();
+            sorted.sort_unstable();
+            assert_eq!(costs, sorted);
+        }
+
+        let urls = [
+            "https://ads.example.com/script.js",
+            "https://cdn.com/ads/",
+            "https://foo.com/ads/x/banner",
+            "https://bar.com/ads/",
+            "https://bar.com/static/",
+            "https://foo.com/content/banner",
+        ];
+        for url in urls.iter() {
+            let request = Request::from_url(url).unwrap();
+            let mut tokens = Vec::new();
+            request.get_tokens(&mut tokens);
+            assert_eq!(
+                unordered.check(&request, &tokens, &HashSet::new()).is_some(),
+                ordered.check(&request, &tokens, &HashSet::new()).is_some(),
+                "Ordering changed the result for {}", url,
+            );
+        }
+
+        // Filters added afterwards keep the order, and duplicates are still detected
+        let parse = |f: &str| NetworkFilter::parse(f, true, Default::default()).unwrap();
+        for filter in ["/ads/*/popup", "||ads.net/ads/", "/ads/*", "/ads/*/banner", "@@/ads/*"] {
+            ordered.add_filter(parse(filter));
+        }
+        for filters in ordered.filter_map.values() {
+            let costs: Vec<_> = filters.iter().map(|f| match_cost(f)).collect();
+            let mut sorted = costs.clone();
+            sorted.sort_unstable();
+            assert_eq!(costs, sorted);
+        }
+        assert_eq!(vec_hashmap_len(&ordered.filter_map), filters.len() + 3);
+    }
+
+    #[test]
+    fn network_filter_list_insert_and_remove() {
+        fn check(list: &NetworkFilterList, url: &str) -> bool {
+            let request = Request::from_url(url).unwrap();
+            let mut tokens = Vec::new();
+            request.get_tokens(&mut tokens);
+            list.check(&request, &tokens, &HashSet::new()).is_some()
+        }
+
+        fn assert_consistent(list: &NetworkFilterList) {
+            for (token, filters) in list.filter_map.iter() {
+                assert!(!filters.is_empty(), "Bucket {} is empty", token);
+            }
+        }
+
+        let parse = |f: &str| NetworkFilter::parse(f, true, Default::default()).unwrap();
+        let mut list = NetworkFilterList::new(vec![parse("||ads.example.com^")], false);
+
+        let added = parse("/banner/*/img^");
+        let implicit = parse("$script,domain=example.org");
+        assert!(!check(&list, "https://foo.com/banner/top/img?x=1"));
+        list.add_filter(added.clone());
+        list.add_filter(implicit.clone());
+        assert_consistent(&list);
+        assert!(list.filter_exists(&added));
+        assert!(list.filter_exists(&implicit));
+        assert!(check(&list, "https://foo.com/banner/top/img?x=1"));
+
+        // Adding the same filter again doesn't duplicate it
+        let buckets = list.filter_map.values().map(Vec::len).sum::<usize>();
+        list.add_filter(added.clone());
+        assert_eq!(list.filter_map.values().map(Vec::len).sum::<usize>(), buckets);
+
+        assert!(list.remove_filter(&added));
+        assert!(!list.remove_filter(&added));
+        assert!(list.remove_filter(&implicit));
+        assert_consistent(&list);
+        assert!(!list.filter_exists(&added));
+        assert!(!list.filter_exists(&implicit));
+        assert!(!check(&list, "https://foo.com/banner/top/img?x=1"));
+        assert!(check(&list, "https://ads.example.com/script.js"));
+
+        assert!(list.remove_filter(&parse("||ads.example.com^")));
+        assert!(list.filter_map.is_empty());
+        assert!(!check(&list, "https://ads.example.com/script.js"));
+    }
 }
 
 #[cfg(test)]
@@ -1215,7 +2500,7 @@ mod blocker_tests {
 
     use super::*;
     use crate::lists::{parse_filters, ParseOptions};
-    use crate::request::Request;
+    use crate::request::{Request, TokenizedRequest};
     use std::collections::HashSet;
     use std::iter::FromIterator;
 
@@ -1238,6 +2523,64 @@ mod blocker_tests {
         });
     }
 
+    #[test]
+    fn mixed_domain_options() {
+        let filters = vec![
+            String::from("/ad.js$domain=a.com|~b.a.com|c.*"),
+            String::from("/banner.png$domain=c.*"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+
+        for enable_optimizations in [true, false].iter() {
+            let blocker = Blocker::new(network_filters.clone(), &BlockerOptions { enable_optimizations: *enable_optimizations });
+            let cases = [
+                ("https://ads.net/ad.js", "https://www.a.com", true),
+                ("https://ads.net/ad.js", "https://b.a.com", false),
+                ("https://ads.net/ad.js", "https://c.co.uk", true),
+                ("https://ads.net/ad.js", "https://d.com", false),
+                ("https://ads.net/banner.png", "https://sub.c.org", true),
+                ("https://ads.net/banner.png", "https://c.d.com", false),
+            ];
+            for (url, source, matched) in cases.iter() {
+                let request = Request::from_urls(url, source, "script").unwrap();
+                assert_eq!(blocker.check(&request).matched, *matched, "Unexpected result for {} from {}", url, source);
+            }
+        }
+    }
+
+    #[test]
+    fn match_cost_ordering_preserves_precedence() {
+        let filters = vec![
+            String::from("/ads/*/banner"),
+            String::from("||example.com/ads/"),
+            String::from("/ads/*"),
+            String::from("@@||example.com/ads/allowed"),
+            String::from("@@/ads/*/allowed"),
+            String::from("||example.com/ads/allowed/important$important"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+
+        let optimized = Blocker::new(network_filters.clone(), &BlockerOptions { enable_optimizations: true });
+        let unoptimized = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: false });
+
+        let cases = [
+            ("https://example.com/ads/script.js", true, false),
+            ("https://example.com/ads/allowed", false, true),
+            ("https://foo.com/ads/x/allowed", false, true),
+            ("https://example.com/ads/allowed/important", true, false),
+            ("https://foo.com/ads/x/banner", true, false),
+            ("https://foo.com/content/", false, false),
+        ];
+        for (url, matched, excepted) in cases.iter() {
+            let request = Request::from_urls(url, "https://foo.com", "script").unwrap();
+            for blocker in [&optimized, &unoptimized].iter() {
+                let result = blocker.check(&request);
+                assert_eq!(result.matched, *matched, "Unexpected result for {}", url);
+                assert_eq!(result.exception.is_some(), *excepted, "Unexpected exception for {}", url);
+            }
+        }
+    }
+
     #[test]
     fn redirect_url_blocked() {
         let filters = vec![
@@ -1263,6 +2606,43 @@ mod blocker_tests {
         assert_eq!(matched_rule.error, None);
     }
 
+    #[test]
+    fn redirect_target_not_redirected_again() {
+        let filters = vec![
+            String::from("||cdn.com^$script,redirect-url=https://cdn.com/stub.js"),
+            String::from("||ads.com^$script,redirect=noopjs"),
+        ];
+        let opts = ParseOptions { include_redirect_urls: true, ..Default::default() };
+        let (network_filters, _) = parse_filters(&filters, true, opts);
+        let mut blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: false });
+        blocker.add_resource(&Resource {
+            name: "noopjs".to_owned(),
+            aliases: vec![],
+            kind: crate::resources::ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: base64::encode("(function() {})()"),
+        }).unwrap();
+
+        let request = Request::from_urls("https://cdn.com/lib.js", "https://example.com", "script").unwrap();
+        let matched_rule = blocker.check(&request);
+        assert_eq!(matched_rule.redirect, Some(Redirection::Url("https://cdn.com/stub.js".to_string())));
+
+        let target = Request::from_urls("https://cdn.com/stub.js", "https://example.com", "script").unwrap();
+        assert!(blocker.check(&target).redirect.is_some());
+        let matched_rule = blocker.check(&target.with_redirect_target());
+        assert!(matched_rule.matched);
+        assert_eq!(matched_rule.redirect, None);
+
+        // Resource redirects produce data URLs, which can't be made into requests, so they are
+        // never checked against any rules.
+        let request = Request::from_urls("https://ads.com/ad.js", "https://example.com", "script").unwrap();
+        let data_url = match blocker.check(&request).redirect {
+            Some(Redirection::Resource(data_url)) => data_url,
+            redirect => panic!("Expected a resource redirect, got {:?}", redirect),
+        };
+        assert!(data_url.starts_with("data:"));
+        assert!(matches!(Request::from_urls(&data_url, "https://example.com", "script"), Err(crate::request::RequestError::HostnameParseError)));
+    }
+
     #[test]
     fn redirect_url_not_recognized_without_parse_opt() {
         let filters = vec![
@@ -1401,6 +2781,35 @@ mod blocker_tests {
         assert_eq!(matched_rule.error, None);
     }
 
+    #[test]
+    fn redirect_exception_for_resource() {
+        let filters = vec![
+            String::from("||ads.com^$script,redirect=noopjs"),
+            String::from("@@||ads.com/keep/$redirect=noopjs"),
+            String::from("@@||ads.com/other/$redirect=noop.txt"),
+            String::from("@@||ads.com/none/$redirect=*"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+        let mut blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: false });
+        blocker.add_resource(&Resource {
+            name: "noopjs".to_string(),
+            aliases: vec![],
+            kind: crate::resources::ResourceType::Mime(crate::resources::MimeType::ApplicationJavascript),
+            content: base64::encode("(function() {})()"),
+        }).unwrap();
+
+        let check = |url: &str| blocker.check(&Request::from_urls(url, "https://example.com", "script").unwrap());
+
+        assert!(check("https://ads.com/ad.js").redirect.is_some());
+        assert!(check("https://ads.com/other/ad.js").redirect.is_some());
+
+        let result = check("https://ads.com/keep/ad.js");
+        assert_eq!(result.redirect, None);
+        assert_eq!(result.exception, Some("@@||ads.com/keep/$redirect=noopjs".to_string()));
+
+        assert_eq!(check("https://ads.com/none/ad.js").redirect, None);
+    }
+
     #[test]
     fn badfilter_does_not_match() {
         let filters = vec![
@@ -1549,6 +2958,167 @@ mod blocker_tests {
         }
     }
 
+    #[test]
+    fn get_csp_directives_blocked_document() {
+        let filters = vec![
+            String::from("||example.com^$csp=script-src 'none'"),
+            String::from("||example.com/blocked^$document"),
+            String::from("||example.com/frame^$subdocument"),
+            String::from("@@||example.com/blocked/allowed^$document"),
+        ];
+
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+        let blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: false });
+
+        let check = |url: &str, request_type: &str| {
+            let request = Request::from_urls(url, "https://example.com", request_type).unwrap();
+            (blocker.check(&request).matched, blocker.get_csp_directives(&request))
+        };
+
+        // Only the `$csp` rule matches, so its directives apply
+        assert_eq!(check("https://example.com/page", "document"), (false, Some(String::from("script-src 'none'"))));
+        assert_eq!(check("https://example.com/page", "subdocument"), (false, Some(String::from("script-src 'none'"))));
+        // Blocked documents are never loaded, so no directives are reported
+        assert_eq!(check("https://example.com/blocked", "document"), (true, None));
+        assert_eq!(check("https://example.com/frame", "subdocument"), (true, None));
+        assert!(blocker.get_csp_policies(&Request::from_urls("https://example.com/blocked", "https://example.com", "document").unwrap()).is_empty());
+        // Unless the block is excepted
+        assert_eq!(check("https://example.com/blocked/allowed", "document"), (false, Some(String::from("script-src 'none'"))));
+    }
+
+    #[test]
+    fn get_csp_directives_multiple_exceptions() {
+        let filters = vec![
+            String::from("||example.com^$csp=script-src 'none'"),
+            String::from("||example.com^$csp=worker-src 'none'"),
+            String::from("||example.com^$csp=frame-src 'none'"),
+            String::from("@@||example.com/page^$csp=script-src 'none'"),
+            String::from("@@||example.com/page^$csp=frame-src 'none'"),
+            String::from("@@||example.com/page/all^$csp"),
+        ];
+
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+
+        let blocker_options = BlockerOptions {
+            enable_optimizations: false,
+        };
+
+        let blocker = Blocker::new(network_filters, &blocker_options);
+        let directives = |url: &str| blocker.get_csp_directives(&Request::from_urls(url, url, "document").unwrap());
+
+        assert_eq!(directives("https://example.com/other"), Some(String::from("frame-src 'none',script-src 'none',worker-src 'none'")));
+        // Each exception only removes its own directive
+        assert_eq!(directives("https://example.com/page"), Some(String::from("worker-src 'none'")));
+        // An exception without a directive removes all of them
+        assert_eq!(directives("https://example.com/page/all"), None);
+    }
+
+    #[test]
+    fn get_csp_directives_inline_options() {
+        use crate::filters::network::{INLINE_FONT_CSP, INLINE_SCRIPT_CSP};
+
+        let filters = vec![
+            String::from("||example.com^$inline-script"),
+            String::from("||example.com^$inline-font"),
+            String::from("||example.com^$csp=worker-src 'none'"),
+            String::from("@@||example.com/fonts-allowed^$inline-font"),
+            String::from("@@||trusted.example.com^$inline-script"),
+        ];
+
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+
+        let blocker_options = BlockerOptions {
+            enable_optimizations: false,
+        };
+
+        let blocker = Blocker::new(network_filters, &blocker_options);
+
+        let mut expected = vec![INLINE_FONT_CSP, INLINE_SCRIPT_CSP, "worker-src 'none'"];
+        expected.sort_unstable();
+        let sorted_directives = |csp: Option<String>| {
+            let csp = csp.unwrap();
+            let mut directives = csp.split(',').map(str::to_owned).collect::<Vec<_>>();
+            directives.sort_unstable();
+            directives
+        };
+
+        {   // Frame navigations receive the same merged policy as top-level documents
+            let document = blocker.get_csp_directives(&Request::from_urls("https://example.com/page", "https://example.com", "document").unwrap());
+            let subdocument = blocker.get_csp_directives(&Request::from_urls("https://example.com/page", "https://other.com", "subdocument").unwrap());
+            assert_eq!(sorted_directives(document.clone()), expected);
+            assert_eq!(document, subdocument);
+        }
+        {   // Exceptions apply to frames as well
+            let csp = blocker.get_csp_directives(&Request::from_urls("https://example.com/fonts-allowed", "https://other.com", "subdocument").unwrap());
+            assert_eq!(sorted_directives(csp), vec![INLINE_SCRIPT_CSP, "worker-src 'none'"]);
+        }
+        {   // `@@$inline-script` only removes the inline script policy, on its own host
+            let csp = blocker.get_csp_directives(&Request::from_urls("https://trusted.example.com/page", "https://trusted.example.com", "document").unwrap());
+            assert_eq!(sorted_directives(csp), vec![INLINE_FONT_CSP, "worker-src 'none'"]);
+        }
+        {   // Other request types never receive a policy
+            assert_eq!(blocker.get_csp_directives(&Request::from_urls("https://example.com/script.js", "https://example.com", "script").unwrap()), None);
+            assert_eq!(blocker.get_csp_directives(&Request::from_urls("https://example.com/data", "https://example.com", "xhr").unwrap()), None);
+        }
+    }
+
+    #[test]
+    fn get_csp_policies() {
+        let filters = vec![
+            String::from("||example.com^$csp=script-src 'none'"),
+            String::from("||example.com^$csp=worker-src 'none'"),
+            String::from("||example.com^$csp=img-src 'self'"),
+            String::from("@@||example.com/images^$csp=img-src 'self'"),
+            String::from("@@||example.com/no-csp^$csp"),
+        ];
+
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+        let blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: false });
+
+        let policies = |url: &str| {
+            let request = Request::from_urls(url, "https://example.com", "document").unwrap();
+            let policies = blocker.get_csp_policies(&request);
+            // The merged form is made of the same policies
+            assert_eq!(blocker.get_csp_directives(&request), if policies.is_empty() { None } else { Some(policies.join(",")) });
+            policies
+        };
+
+        assert_eq!(policies("https://example.com/page"), vec!["img-src 'self'", "script-src 'none'", "worker-src 'none'"]);
+        assert_eq!(policies("https://example.com/images/page"), vec!["script-src 'none'", "worker-src 'none'"]);
+        assert!(policies("https://example.com/no-csp/page").is_empty());
+        assert!(policies("https://other.com/page").is_empty());
+    }
+
+    #[test]
+    fn get_csp_directives_reporting() {
+        let filters = vec![
+            String::from("||example.com^$csp=script-src 'none'; report-uri https://report.example.com/csp"),
+            String::from("||example.com^$csp=worker-src 'none'"),
+            String::from("||duplicate.com^$csp=script-src 'none'; report-uri https://report.example.com/csp"),
+            String::from("||duplicate.com^$csp=img-src 'self'; report-uri https://report.example.com/csp; report-to csp-endpoint"),
+            String::from("||duplicate.com^$csp=report-to csp-endpoint"),
+        ];
+
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+
+        let blocker_options = BlockerOptions {
+            enable_optimizations: false,
+        };
+
+        let blocker = Blocker::new(network_filters, &blocker_options);
+
+        {   // Reporting directives are passed through verbatim
+            let csp = blocker.get_csp_directives(&Request::from_urls("https://example.com", "https://example.com", "document").unwrap());
+            assert_eq!(csp, Some(String::from("script-src 'none'; report-uri https://report.example.com/csp,worker-src 'none'")));
+        }
+        {   // Reporting directives are not duplicated across merged policies
+            let csp = blocker.get_csp_directives(&Request::from_urls("https://duplicate.com", "https://duplicate.com", "document").unwrap()).unwrap();
+            assert_eq!(csp.matches("report-uri").count(), 1);
+            assert_eq!(csp.matches("report-to").count(), 1);
+            assert_eq!(csp, "img-src 'self'; report-uri https://report.example.com/csp; report-to csp-endpoint,script-src 'none'");
+        }
+    }
+
     #[test]
     fn tags_enable_works() {
         let filters = vec![
@@ -1708,6 +3278,21 @@ mod blocker_tests {
         }
     }
 
+    #[test]
+    fn add_filter_reports_duplicates() {
+        let mut blocker = Blocker::new(Vec::new(), &BlockerOptions { enable_optimizations: false });
+        for rule in ["||ads.example.com^", "/popup.$tag=annoyances", "@@||example.com^$genericblock,generichide"] {
+            let filter = NetworkFilter::parse(rule, true, Default::default()).unwrap();
+            blocker.add_filter(filter.clone()).unwrap();
+            assert_eq!(blocker.add_filter(filter.clone()), Err(BlockerError::FilterExists), "{}", rule);
+
+            // The same rule from another list is kept
+            let mut other_list = filter;
+            other_list.list_id = Some(1);
+            blocker.add_filter(other_list).unwrap();
+        }
+    }
+
     #[test]
     fn filter_add_tagged() {
         // Allow filter to be added twice when the engine is optimised
@@ -1757,6 +3342,137 @@ mod blocker_tests {
         assert!(matched_rule.exception.is_some());
     }
 
+    #[test]
+    fn domain_matching_mode_nested_frame() {
+        use crate::request::DomainMatchingMode;
+
+        let blocker_options: BlockerOptions = BlockerOptions {
+            enable_optimizations: true,
+        };
+
+        let mut blocker = Blocker::new(Vec::new(), &blocker_options);
+
+        blocker.add_filter(NetworkFilter::parse("||ads.net/top^$domain=top.com", true, Default::default()).unwrap()).unwrap();
+        blocker.add_filter(NetworkFilter::parse("||ads.net/frame^$domain=frame.com", true, Default::default()).unwrap()).unwrap();
+
+        // A request from an iframe at frame.com, embedded within a page at top.com
+        let request = |url: &str, mode| Request::from_urls(url, "https://frame.com/embed", "script")
+            .unwrap()
+            .with_ancestor_origins(&["https://top.com"], mode);
+
+        let immediate = DomainMatchingMode::ImmediateFrame;
+        assert!(!blocker.check(&request("https://ads.net/top", immediate)).matched);
+        assert!(blocker.check(&request("https://ads.net/frame", immediate)).matched);
+
+        let top = DomainMatchingMode::TopFrame;
+        assert!(blocker.check(&request("https://ads.net/top", top)).matched);
+        assert!(!blocker.check(&request("https://ads.net/frame", top)).matched);
+    }
+
+    #[test]
+    fn party_relative_to_top_frame() {
+        let blocker_options: BlockerOptions = BlockerOptions {
+            enable_optimizations: true,
+        };
+
+        let mut blocker = Blocker::new(Vec::new(), &blocker_options);
+
+        blocker.add_filter(NetworkFilter::parse("||frame.com/track^$third-party", true, Default::default()).unwrap()).unwrap();
+        blocker.add_filter(NetworkFilter::parse("||top.com/widget^$third-party", true, Default::default()).unwrap()).unwrap();
+
+        // Subresources of an iframe at frame.com, embedded within a page at top.com
+        let request = |url: &str| Request::from_urls(url, "https://frame.com/embed", "script").unwrap();
+
+        assert!(!blocker.check(&request("https://frame.com/track")).matched);
+        assert!(blocker.check(&request("https://top.com/widget")).matched);
+
+        let top = "https://top.com";
+        assert!(blocker.check(&request("https://frame.com/track").with_top_frame_party(top)).matched);
+        assert!(!blocker.check(&request("https://top.com/widget").with_top_frame_party(top)).matched);
+    }
+
+    #[test]
+    fn interned_domains() {
+        let blocker_options: BlockerOptions = BlockerOptions {
+            enable_optimizations: false,
+        };
+
+        let filters = [
+            "||ads.net/a^$domain=site.com|other.com",
+            "||ads.net/b^$domain=other.com|~sub.other.com",
+            "/collect^$from=other.com,to=ads.net",
+            "@@||ads.net/a/ok^$domain=site.com",
+        ];
+        let blocker = Blocker::new(
+            filters.iter().map(|f| NetworkFilter::parse(f, true, Default::default()).unwrap()).collect(),
+            &blocker_options,
+        );
+
+        let request = |url: &str, source: &str| Request::from_urls(url, source, "script").unwrap();
+        assert!(blocker.check(&request("https://ads.net/a", "https://site.com")).matched);
+        assert!(blocker.check(&request("https://ads.net/b", "https://other.com")).matched);
+        assert!(!blocker.check(&request("https://ads.net/b", "https://sub.other.com")).matched);
+        assert!(blocker.check(&request("https://ads.net/collect", "https://other.com")).matched);
+        assert!(!blocker.check(&request("https://ads.net/a/ok", "https://site.com")).matched);
+        assert!(!blocker.check(&request("https://ads.net/a", "https://elsewhere.com")).matched);
+
+        let report = blocker.domain_memory_report();
+        assert_eq!(report.entries, 7);
+        assert_eq!(report.strings, 4);
+        assert!(report.bytes < report.uninterned_bytes);
+    }
+
+    #[test]
+    fn domain_matching_in_workers() {
+        let blocker_options: BlockerOptions = BlockerOptions {
+            enable_optimizations: true,
+        };
+
+        let mut blocker = Blocker::new(Vec::new(), &blocker_options);
+
+        blocker.add_filter(NetworkFilter::parse("||tracker.net/collect^$domain=app.com", true, Default::default()).unwrap()).unwrap();
+
+        // A page at app.com making the request itself
+        let page = Request::from_urls("https://tracker.net/collect", "https://app.com/index.html", "xhr").unwrap();
+        assert!(blocker.check(&page).matched);
+
+        // A worker created by the same page, with the worker script given as the source
+        let worker = Request::from_urls("https://tracker.net/collect", "https://static.app-cdn.com/worker.js", "xhr").unwrap();
+        assert!(!blocker.check(&worker).matched);
+        assert!(blocker.check(&worker.with_worker_origin("https://app.com")).matched);
+
+        // A worker created by another page, even if its script is served from app.com
+        let other = Request::from_urls("https://tracker.net/collect", "https://app.com/worker.js", "xhr")
+            .unwrap()
+            .with_worker_origin("https://other.com");
+        assert!(!blocker.check(&other).matched);
+    }
+
+    #[test]
+    fn document_rules_only_block_navigations() {
+        let (network_filters, _) = parse_filters(&[
+            String::from("||ads.example.com^$document"),
+            String::from("||ads.example.com/report^$csp=script-src 'none'"),
+            String::from("@@||ads.example.com/allowed^$document"),
+        ], true, Default::default());
+        let blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: true });
+        let document = |url: &str| Request::from_urls(url, url, "document").unwrap();
+
+        let navigation = document("https://ads.example.com/page");
+        assert!(navigation.is_navigation);
+        assert!(blocker.check(&navigation).matched);
+
+        let fetch = document("https://ads.example.com/page").with_navigation(false);
+        let result = blocker.check(&fetch);
+        assert!(!result.matched);
+        assert_eq!(result.filter, None);
+
+        // Exceptions and CSP injections don't block anything, so they still apply
+        let allowed = blocker.check_parameterised(&document("https://ads.example.com/allowed").with_navigation(false), true, false);
+        assert!(allowed.exception.is_some());
+        assert!(blocker.get_csp_directives(&document("https://ads.example.com/report").with_navigation(false)).is_some());
+    }
+
     #[test]
     fn generichide() {
         let blocker_options: BlockerOptions = BlockerOptions {
@@ -1769,6 +3485,90 @@ mod blocker_tests {
 
         assert!(blocker.check_generic_hide(&Request::from_url("https://example.com").unwrap()));
     }
+
+    #[test]
+    fn token_buffers_are_reset_between_checks() {
+        let pool = TokenBuffers::default();
+        {
+            let mut tokens = pool.get();
+            tokens.extend([1, 2, 3]);
+        }
+        assert!(pool.get().is_empty());
+
+        let filters = [
+            "||ads.example.com^",
+            "/banner/*/img^",
+            "@@||ads.example.com/allowed^",
+            "||example.com^$csp=script-src 'self'",
+            "@@||generic.com^$generichide",
+            "@@||generic.com^$genericblock",
+            "/sponsored.",
+        ].iter().map(|rule| rule.to_string()).collect::<Vec<_>>();
+        let (network_filters, _) = parse_filters(&filters, true, Default::default());
+        let blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: true });
+
+        let requests = [
+            ("https://ads.example.com/ad.js", "https://example.com", "script", true),
+            ("https://ads.example.com/allowed/ad.js", "https://example.com", "script", false),
+            ("https://cdn.com/banner/top/img/ad.png", "https://example.com", "image", true),
+            ("https://cdn.com/content.js", "https://example.com", "script", false),
+            ("https://cdn.com/sponsored.js", "https://generic.com", "script", true),
+        ];
+
+        // The same results are expected with and without `object-pooling`, including once pooled
+        // buffers are being reused.
+        for _ in 0..3 {
+            for (url, source_url, request_type, expected) in requests.iter() {
+                let request = Request::from_urls(url, source_url, request_type).unwrap();
+                assert_eq!(blocker.check(&request).matched, *expected, "{}", url);
+            }
+            let document = Request::from_urls("https://generic.com", "https://generic.com", "document").unwrap();
+            assert!(blocker.check_generic_hide(&document));
+            assert!(blocker.check_generic_block(&document));
+            let document = Request::from_urls("https://example.com", "https://example.com", "document").unwrap();
+            assert!(!blocker.check_generic_hide(&document));
+            assert_eq!(blocker.get_csp_directives(&document), Some("script-src 'self'".to_string()));
+        }
+    }
+
+    #[test]
+    fn check_tokenized_matches_check() {
+        let blocker_options = BlockerOptions {
+            enable_optimizations: true,
+        };
+        let (old_filters, _) = parse_filters(&[
+            String::from("||ads.example.com^"),
+            String::from("/banner/*/img^"),
+        ], true, Default::default());
+        let (new_filters, _) = parse_filters(&[
+            String::from("||ads.example.com^"),
+            String::from("@@||ads.example.com/allowed^"),
+            String::from("||tracker.net^$third-party,redirect-url=https://example.com/empty.gif"),
+        ], true, Default::default());
+        let old = Blocker::new(old_filters, &blocker_options);
+        let new = Blocker::new(new_filters, &blocker_options);
+
+        let requests = [
+            ("https://ads.example.com/ad.js", "https://news.com", "script"),
+            ("https://ads.example.com/allowed/x.js", "https://news.com", "script"),
+            ("https://www.site.com/banner/top/img", "https://site.com", "image"),
+            ("https://tracker.net/pixel.gif", "https://news.com", "image"),
+            ("https://example.org/index.html", "https://example.org", "document"),
+            ("ftp://ads.example.com/file", "https://news.com", "other"),
+        ];
+        for (url, source_url, request_type) in requests.iter() {
+            let request = Request::from_urls(url, source_url, request_type).unwrap();
+            let tokenized = TokenizedRequest::new(request.clone());
+            for blocker in [&old, &new].iter() {
+                assert_eq!(format!("{:?}", blocker.check_tokenized(&tokenized)), format!("{:?}", blocker.check(&request)), "{}", url);
+            }
+        }
+
+        let tokenized = TokenizedRequest::from(Request::from_urls("https://ads.example.com/allowed/x.js", "https://news.com", "script").unwrap());
+        assert!(old.check_tokenized(&tokenized).matched);
+        assert!(!new.check_tokenized(&tokenized).matched);
+        assert_eq!(tokenized.into_request().url, "https://ads.example.com/allowed/x.js");
+    }
 }
 
 #[cfg(test)]
@@ -1800,19 +3600,17 @@ mod legacy_rule_parsing_tests {
 
     // number of expected EasyList cosmetic rules from old engine is 31144, but is incorrect as it skips a few particularly long rules that are nevertheless valid
     // easyList = { 24478, 31144, 0, 5589 };
-    // not handling (and not including) filters with the following options:
-    // - $popup
-    // - $elemhide
+    // this engine includes filters with `$popup` options, which the old one skipped.
     // difference from original counts caused by not handling document/subdocument options and possibly miscounting on the blocker side.
     // Printing all non-cosmetic, non-html, non-comment/-empty rules and ones with no unsupported options yields 29142 items
     // This engine also handles 3 rules that old one does not
-    const EASY_LIST: ListCounts = ListCounts { filters: 24065, cosmetic_filters: 31163, exceptions: 5796, duplicates: 0 };
+    const EASY_LIST: ListCounts = ListCounts { filters: 25264, cosmetic_filters: 31163, exceptions: 5902, duplicates: 0 };
     // easyPrivacy = { 11817, 0, 0, 1020 };
     // differences in counts explained by hashset size underreporting as detailed in the next two cases
     const EASY_PRIVACY: ListCounts = ListCounts { filters: 11889, cosmetic_filters: 0, exceptions: 1021, duplicates: 2 };
     // ublockUnbreak = { 4, 8, 0, 94 };
     // differences in counts explained by client.hostAnchoredExceptionHashSet->GetSize() underreporting when compared to client.numHostAnchoredExceptionFilters
-    const UBLOCK_UNBREAK: ListCounts = ListCounts { filters: 4, cosmetic_filters: 8, exceptions: 98, duplicates: 0 };
+    const UBLOCK_UNBREAK: ListCounts = ListCounts { filters: 4, cosmetic_filters: 8, exceptions: 99, duplicates: 0 };
     // braveUnbreak = { 31, 0, 0, 4 };
     // differences in counts explained by client.hostAnchoredHashSet->GetSize() underreporting when compared to client.numHostAnchoredFilters
     const BRAVE_UNBREAK: ListCounts = ListCounts { filters: 32, cosmetic_filters: 0, exceptions: 4, duplicates: 0 };
@@ -1,6 +1,6 @@
 //! Transforms filter rules into content blocking syntax used on iOS and MacOS.
 
-use crate::filters::network::{NetworkFilter, NetworkFilterMask};
+use crate::filters::network::{NetworkFilter, NetworkFilterMask, SEPARATOR_CLASS};
 use crate::filters::cosmetic::CosmeticFilter;
 use crate::lists::ParsedFilter;
 
@@ -155,6 +155,9 @@ pub enum CbRuleCreationFailure {
     NetworkBadFilterUnsupported,
     /// Network rules with csp options cannot be supported in content blocking syntax.
     NetworkCspUnsupported,
+    /// Network rules with header options depend on response headers, which content blocking
+    /// rules cannot match against.
+    NetworkHeaderUnsupported,
     /// Content blocking syntax only supports a subset of regex features, namely:
     /// - Matching any character with “.”.
     /// - Matching ranges with the range syntax [a-b].
@@ -176,6 +179,12 @@ pub enum CbRuleCreationFailure {
     /// Cosmetic rules with scriptlet injections (i.e. `+js(...)`) cannot be represented in content
     /// blocking syntax.
     ScriptletInjectionsNotSupported,
+    /// HTML filtering rules (i.e. `$$`) operate on a document's source and cannot be represented
+    /// in content blocking syntax.
+    HtmlFilteringNotSupported,
+    /// Cosmetic rules with procedural operators (e.g. `:has-text(...)`) cannot be represented in
+    /// content blocking syntax.
+    CosmeticProceduralRulesNotSupported,
 }
 
 impl TryFrom<ParsedFilter> for CbRuleEquivalent {
@@ -185,6 +194,7 @@ impl TryFrom<ParsedFilter> for CbRuleEquivalent {
         match v {
             ParsedFilter::Network(f) => f.try_into(),
             ParsedFilter::Cosmetic(f) => Ok(Self::SingleRule(f.try_into()?)),
+            ParsedFilter::Html(_) => Err(CbRuleCreationFailure::HtmlFilteringNotSupported),
         }
     }
 }
@@ -258,6 +268,9 @@ impl TryFrom<NetworkFilter> for CbRuleEquivalent {
             if v.mask.contains(NetworkFilterMask::IS_CSP) {
                 return Err(CbRuleCreationFailure::NetworkCspUnsupported);
             }
+            if v.opt_header.is_some() {
+                return Err(CbRuleCreationFailure::NetworkHeaderUnsupported);
+            }
             if v.mask.contains(NetworkFilterMask::IS_COMPLETE_REGEX) {
                 return Err(CbRuleCreationFailure::FullRegexUnsupported);
             }
@@ -277,7 +290,8 @@ impl TryFrom<NetworkFilter> for CbRuleEquivalent {
                 (crate::filters::network::FilterPart::Simple(part), Some(hostname)) => {
                     let without_trailing_separator = TRAILING_SEPARATOR.replace_all(&part, "");
                     let escaped_special_chars = SPECIAL_CHARS.replace_all(&without_trailing_separator, r##"\$1"##);
-                    let with_fixed_wildcards = REPLACE_WILDCARDS.replace_all(&escaped_special_chars, ".*");
+                    let with_separators = escaped_special_chars.replace(r"\^", &SEPARATOR_CLASS);
+                    let with_fixed_wildcards = REPLACE_WILDCARDS.replace_all(&with_separators, ".*");
 
                     let mut url_filter = format!("^[^:]+:(//)?([^/]+\\.)?{}", SPECIAL_CHARS.replace_all(&hostname, r##"\$1"##));
 
@@ -296,7 +310,8 @@ impl TryFrom<NetworkFilter> for CbRuleEquivalent {
                 (crate::filters::network::FilterPart::Simple(part), None) => {
                     let without_trailing_separator = TRAILING_SEPARATOR.replace_all(&part, "");
                     let escaped_special_chars = SPECIAL_CHARS.replace_all(&without_trailing_separator, r##"\$1"##);
-                    let with_fixed_wildcards = REPLACE_WILDCARDS.replace_all(&escaped_special_chars, ".*");
+                    let with_separators = escaped_special_chars.replace(r"\^", &SEPARATOR_CLASS);
+                    let with_fixed_wildcards = REPLACE_WILDCARDS.replace_all(&with_separators, ".*");
                     let mut url_filter = if v.mask.contains(NetworkFilterMask::IS_LEFT_ANCHOR) {
                         format!("^{}", with_fixed_wildcards)
                     } else {
@@ -417,7 +432,7 @@ impl TryFrom<NetworkFilter> for CbRuleEquivalent {
                 push_if_flag!(FROM_WEBSOCKET);
                 push_if_flag!(FROM_XMLHTTPREQUEST, Raw);
                 push_if_flag!(FROM_FONT, Font);
-                // TODO - Popup, Document when implemented
+                push_if_flag!(FROM_POPUP, Popup);
 
                 if !unsupported_flags.is_empty() && types.is_empty() {
                     return Err(CbRuleCreationFailure::NoSupportedNetworkOptions(unsupported_flags));
@@ -493,6 +508,9 @@ impl TryFrom<CosmeticFilter> for CbRule {
         if v.mask.contains(CosmeticFilterMask::SCRIPT_INJECT) {
             return Err(CbRuleCreationFailure::ScriptletInjectionsNotSupported);
         }
+        if v.procedural.is_some() {
+            return Err(CbRuleCreationFailure::CosmeticProceduralRulesNotSupported);
+        }
 
         if let Some(raw_line) = v.raw_line {
             let mut hostnames_vec = vec![];
@@ -761,7 +779,6 @@ mod ab2cb_tests {
         }]"####);
     }
 
-    /* TODO - `$popup` is currently unsupported by NetworkFilter
     #[test]
     fn popup_tests() {
         test_from_abp("||admngronline.com^$popup,third-party", r####"[{
@@ -769,7 +786,7 @@ mod ab2cb_tests {
                 "type": "block"
             },
             "trigger": {
-                "url-filter": "^https?://admngronline\\.com(?:[\\x00-\\x24\\x26-\\x2C\\x2F\\x3A-\\x40\\x5B-\\x5E\\x60\\x7B-\\x7F]|$)",
+                "url-filter": "^[^:]+:(//)?([^/]+\\.)?admngronline\\.com",
                 "load-type": [
                     "third-party"
                 ],
@@ -783,14 +800,13 @@ mod ab2cb_tests {
                 "type": "block"
             },
             "trigger": {
-                "url-filter": "^https?://bet365\\.com(?:[\\x00-\\x24\\x26-\\x2C\\x2F\\x3A-\\x40\\x5B-\\x5E\\x60\\x7B-\\x7F]|$).*affiliate=",
+                "url-filter": "^[^:]+:(//)?([^/]+\\.)?bet365\\.com[^0-9A-Za-z%._-].*affiliate=",
                 "resource-type": [
                     "popup"
                 ]
             }
         }]"####);
     }
-    */
 
     #[test]
     fn third_party() {
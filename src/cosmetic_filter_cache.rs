@@ -9,11 +9,15 @@
 //! relevant to a particular page.
 
 use crate::filters::cosmetic::CosmeticFilter;
+use crate::filters::cosmetic::CosmeticFilterLocationType;
 use crate::filters::cosmetic::CosmeticFilterMask;
-use crate::resources::{Resource, ScriptletResourceStorage};
+use crate::filters::cosmetic::ProceduralOperator;
+use crate::lists::ListId;
+use crate::resources::{normalized_scriptlet_args, Resource, ScriptletResourceStorage};
 use crate::utils::Hash;
 
 use std::collections::{HashSet, HashMap};
+use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
@@ -28,6 +32,14 @@ use serde::{Deserialize, Serialize};
 /// `exceptions` is a set of any class or id CSS selectors that should not have generic rules
 /// applied. In practice, these should be passed to `class_id_stylesheet` and not used otherwise.
 ///
+/// `remove_selectors` is a set of any CSS selector on the page whose matching elements should be
+/// removed from the page entirely, rather than just hidden.
+///
+/// `procedural_selectors` is a map of CSS selectors on the page to chains of procedural operators
+/// (like `:has-text(...)`) that further filter the matched elements. Any element remaining after
+/// applying all operators of a chain should be hidden. These must be evaluated by a content
+/// script.
+///
 /// `injected_script` is the Javascript code for any scriptlets that should be injected into the
 /// page.
 ///
@@ -39,6 +51,8 @@ pub struct UrlSpecificResources {
     pub hide_selectors: HashSet<String>,
     pub style_selectors: HashMap<String, Vec<String>>,
     pub exceptions: HashSet<String>,
+    pub remove_selectors: HashSet<String>,
+    pub procedural_selectors: HashMap<String, Vec<Vec<ProceduralOperator>>>,
     pub injected_script: String,
     pub generichide: bool,
 }
@@ -49,25 +63,53 @@ impl UrlSpecificResources {
             hide_selectors: HashSet::new(),
             style_selectors: HashMap::new(),
             exceptions: HashSet::new(),
+            remove_selectors: HashSet::new(),
+            procedural_selectors: HashMap::new(),
             injected_script: String::new(),
             generichide: false,
         }
     }
 }
 
-/// Splits the given hostname-specific rules into three collections:
-/// - a set of CSS selectors that should be hidden on all pages under the hostname
-/// - a mapping from CSS selectors to any additional (i.e. not `display: none`) CSS styles that
-///   should be applied to those elements
-/// - a list of any scriptlets that should be injected into the page's JavaScript context
-fn hostname_specific_rules(rules: &[&SpecificFilterType]) -> (HashSet<String>, HashMap<String, Vec<String>>, Vec<String>) {
+/// A hostname-specific cosmetic rule that applies to a hostname, along with the list it was parsed
+/// from. See `Engine::cosmetic_rules_for_host`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CosmeticRuleSource {
+    /// The rule as it was written in its list.
+    pub rule: String,
+    /// The list the rule was parsed from, if any. See `ParseOptions::list_id`.
+    pub list_id: Option<ListId>,
+}
+
+/// Hostname-specific rules that apply to a particular page, split by the action to be taken.
+#[derive(Default)]
+struct HostnameSpecificRules {
+    /// CSS selectors that should be hidden on all pages under the hostname
+    hide_selectors: HashSet<String>,
+    /// A mapping from CSS selectors to any additional (i.e. not `display: none`) CSS styles that
+    /// should be applied to those elements
+    style_selectors: HashMap<String, Vec<String>>,
+    /// Any scriptlets that should be injected into the page's JavaScript context
+    script_injections: Vec<String>,
+    /// CSS selectors that should be removed from all pages under the hostname
+    remove_selectors: HashSet<String>,
+    /// A mapping from CSS selectors to chains of procedural operators filtering the elements
+    /// that should be hidden
+    procedural_selectors: HashMap<String, Vec<Vec<ProceduralOperator>>>,
+}
+
+/// Splits the given hostname-specific rules into separate collections for each kind of action.
+fn hostname_specific_rules(rules: &[&SpecificFilterType]) -> HostnameSpecificRules {
     if rules.is_empty() {
-        (HashSet::default(), HashMap::default(), vec![])
+        HostnameSpecificRules::default()
     } else {
         let mut script_rules = Vec::with_capacity(10);
+        let mut seen_scripts = HashSet::new();
 
         let mut hide_rules = HashSet::with_capacity(rules.len());
         let mut style_rules: HashMap<String, Vec<String>> = HashMap::with_capacity(rules.len());
+        let mut remove_rules = HashSet::new();
+        let mut procedural_rules: HashMap<String, Vec<Vec<ProceduralOperator>>> = HashMap::new();
 
         rules.iter()
             .for_each(|rule| {
@@ -83,13 +125,32 @@ fn hostname_specific_rules(rules: &[&SpecificFilterType]) -> (HashSet<String>, H
                         }
                     }
                     SpecificFilterType::ScriptInject(sel) => {
-                        script_rules.push(sel.to_owned());
+                        // The same scriptlet may be injected by rules for several of the
+                        // hostname's labels; only inject it once.
+                        if seen_scripts.insert(normalized_scriptlet_args(sel)) {
+                            script_rules.push(sel.to_owned());
+                        }
+                    }
+                    SpecificFilterType::Remove(sel) => {
+                        remove_rules.insert(sel.to_owned());
+                    }
+                    SpecificFilterType::Procedural(sel, operators) => {
+                        let entry = procedural_rules.entry(sel.to_owned()).or_default();
+                        if !entry.contains(operators) {
+                            entry.push(operators.to_owned());
+                        }
                     }
                     _ => unreachable!()
                 }
             });
 
-        (hide_rules, style_rules, script_rules)
+        HostnameSpecificRules {
+            hide_selectors: hide_rules,
+            style_selectors: style_rules,
+            script_injections: script_rules,
+            remove_selectors: remove_rules,
+            procedural_selectors: procedural_rules,
+        }
     }
 }
 
@@ -123,6 +184,22 @@ pub(crate) struct CosmeticFilterCache {
     pub(crate) misc_generic_selectors: HashSet<String>,
 
     pub(crate) scriptlets: ScriptletResourceStorage,
+
+    /// The lists that each generic selector was added from, keyed by selector. `None` marks a
+    /// selector added by a rule without a list. Selectors only ever added without a list aren't
+    /// tracked.
+    #[serde(default)]
+    pub(crate) generic_rule_lists: HashMap<String, Vec<Option<ListId>>>,
+
+    /// Selectors excepted on all sites by generic `#@#` rules, along with the lists each
+    /// exception was added from. These only remove generic rules; hostname-specific rules for the
+    /// same selector still apply.
+    #[serde(default)]
+    pub(crate) generic_exceptions: HashMap<String, Vec<Option<ListId>>>,
+
+    // Not serialized
+    #[serde(skip)]
+    pub(crate) disabled_lists: HashSet<ListId>,
 }
 
 impl CosmeticFilterCache {
@@ -138,9 +215,24 @@ impl CosmeticFilterCache {
             misc_generic_selectors: HashSet::new(),
 
             scriptlets: Default::default(),
+
+            generic_rule_lists: HashMap::new(),
+            generic_exceptions: HashMap::new(),
+            disabled_lists: HashSet::new(),
         }
     }
 
+    /// Serializes the cache on its own, in a format distinct from that of a whole `Engine`. See
+    /// `Engine::serialize_cosmetic`.
+    pub fn serialize(&self) -> Result<Vec<u8>, crate::data_format::SerializationError> {
+        crate::data_format::serialize_cosmetic(self)
+    }
+
+    /// Loads a cache serialized by `CosmeticFilterCache::serialize`.
+    pub fn deserialize(serialized: &[u8]) -> Result<Self, crate::data_format::DeserializationError> {
+        crate::data_format::deserialize_cosmetic(serialized)
+    }
+
     pub fn from_rules(rules: Vec<CosmeticFilter>) -> Self {
         let mut self_ = Self {
             simple_class_rules: HashSet::with_capacity(rules.len() / 2),
@@ -153,12 +245,18 @@ impl CosmeticFilterCache {
             misc_generic_selectors: HashSet::with_capacity(rules.len() / 30),
 
             scriptlets: Default::default(),
+
+            generic_rule_lists: HashMap::new(),
+            generic_exceptions: HashMap::new(),
+            disabled_lists: HashSet::new(),
         };
 
         for rule in rules {
             self_.add_filter(rule)
         }
 
+        self_.remove_generically_excepted_selectors();
+
         self_
     }
 
@@ -168,6 +266,8 @@ impl CosmeticFilterCache {
                 self.add_generic_filter(generic_rule);
             }
             self.specific_rules.store_rule(rule);
+        } else if rule.mask.contains(CosmeticFilterMask::UNHIDE) {
+            self.generic_exceptions.entry(rule.selector).or_default().push(rule.list_id);
         } else {
             self.add_generic_filter(rule);
         }
@@ -175,6 +275,8 @@ impl CosmeticFilterCache {
 
     /// Add a filter, assuming it has already been determined to be a generic rule
     fn add_generic_filter(&mut self, rule: CosmeticFilter) {
+        self.record_generic_rule_list(&rule);
+
         if rule.mask.contains(CosmeticFilterMask::IS_CLASS_SELECTOR) {
             if let Some(key) = &rule.key {
                 let key = key.clone();
@@ -206,6 +308,168 @@ impl CosmeticFilterCache {
         }
     }
 
+    /// Adds the rules and scriptlets of `other`, as if both caches had been built from the same
+    /// rules. Scriptlets of `other` replace any with the same name. Lists disabled in this cache
+    /// stay disabled.
+    pub fn merge(&mut self, other: CosmeticFilterCache) {
+        // Selectors without a tracked list are enabled regardless of any list, which must be
+        // recorded explicitly once the same selector is tracked from the other cache.
+        let generic_rule_lists = {
+            let selectors = self.generic_selectors();
+            let other_selectors = other.generic_selectors();
+            let lists = |cache: &CosmeticFilterCache, selectors: &HashSet<String>, selector: &String| {
+                match cache.generic_rule_lists.get(selector) {
+                    Some(lists) => lists.clone(),
+                    None if selectors.contains(selector) => vec![None],
+                    None => vec![],
+                }
+            };
+            self.generic_rule_lists.keys().chain(other.generic_rule_lists.keys())
+                .map(|selector| {
+                    let mut merged = lists(self, &selectors, selector);
+                    merged.extend(lists(&other, &other_selectors, selector));
+                    (selector.clone(), merged)
+                })
+                .collect()
+        };
+        self.generic_rule_lists = generic_rule_lists;
+
+        self.simple_class_rules.extend(other.simple_class_rules);
+        self.simple_id_rules.extend(other.simple_id_rules);
+        for (buckets, other_buckets) in [
+            (&mut self.complex_class_rules, other.complex_class_rules),
+            (&mut self.complex_id_rules, other.complex_id_rules),
+        ] {
+            for (key, selectors) in other_buckets {
+                let bucket = buckets.entry(key).or_default();
+                for selector in selectors {
+                    if !bucket.contains(&selector) {
+                        bucket.push(selector);
+                    }
+                }
+            }
+        }
+        self.misc_generic_selectors.extend(other.misc_generic_selectors);
+        for (selector, lists) in other.generic_exceptions {
+            self.generic_exceptions.entry(selector).or_default().extend(lists);
+        }
+        self.specific_rules.merge(other.specific_rules);
+        self.scriptlets.merge(other.scriptlets);
+
+        self.remove_generically_excepted_selectors();
+    }
+
+    /// Returns every stored generic selector, in the form it was written in its rule.
+    fn generic_selectors(&self) -> HashSet<String> {
+        self.simple_class_rules.iter().map(|class| format!(".{}", class))
+            .chain(self.simple_id_rules.iter().map(|id| format!("#{}", id)))
+            .chain(self.complex_class_rules.values().flatten().cloned())
+            .chain(self.complex_id_rules.values().flatten().cloned())
+            .chain(self.misc_generic_selectors.iter().cloned())
+            .collect()
+    }
+
+    /// Remembers which list a generic rule came from, so that its selector can be left out once
+    /// every list providing it has been disabled.
+    fn record_generic_rule_list(&mut self, rule: &CosmeticFilter) {
+        if rule.list_id.is_none() && !self.generic_rule_lists.contains_key(&rule.selector) {
+            return;
+        }
+        if !self.generic_rule_lists.contains_key(&rule.selector) {
+            // The same selector may have been stored earlier by a rule without a list, which
+            // keeps it enabled regardless of any list.
+            let lists = if self.contains_generic_selector(rule) { vec![None] } else { vec![] };
+            self.generic_rule_lists.insert(rule.selector.clone(), lists);
+        }
+        if let Some(lists) = self.generic_rule_lists.get_mut(&rule.selector) {
+            lists.push(rule.list_id);
+        }
+    }
+
+    /// Drops generic selectors that are excepted on all sites once all rules have been added, so
+    /// that queries don't return them only to filter them out again. Exceptions from a list are
+    /// skipped, since the list may be disabled later; those are still checked at query time like
+    /// any exception added after construction.
+    fn remove_generically_excepted_selectors(&mut self) {
+        let excepted = self.generic_exceptions.iter()
+            .filter(|(_, lists)| lists.contains(&None))
+            .map(|(selector, _)| selector.as_str())
+            .collect::<HashSet<_>>();
+        if excepted.is_empty() {
+            return;
+        }
+
+        for selector in &excepted {
+            if let Some(class) = selector.strip_prefix('.') {
+                self.simple_class_rules.remove(class);
+            } else if let Some(id) = selector.strip_prefix('#') {
+                self.simple_id_rules.remove(id);
+            }
+            self.misc_generic_selectors.remove(*selector);
+            self.generic_rule_lists.remove(*selector);
+        }
+        for buckets in [&mut self.complex_class_rules, &mut self.complex_id_rules] {
+            buckets.retain(|_, bucket| {
+                bucket.retain(|selector| !excepted.contains(selector.as_str()));
+                !bucket.is_empty()
+            });
+        }
+    }
+
+    /// Checks whether the selector of a generic rule has already been stored.
+    fn contains_generic_selector(&self, rule: &CosmeticFilter) -> bool {
+        let complex_bucket_contains = |rules: &HashMap<String, Vec<String>>, key: &String| {
+            rules.get(key).map(|bucket| bucket.contains(&rule.selector)).unwrap_or(false)
+        };
+        match &rule.key {
+            Some(key) if rule.mask.contains(CosmeticFilterMask::IS_CLASS_SELECTOR) => {
+                if rule.mask.contains(CosmeticFilterMask::IS_SIMPLE) {
+                    self.simple_class_rules.contains(key)
+                } else {
+                    complex_bucket_contains(&self.complex_class_rules, key)
+                }
+            }
+            Some(key) if rule.mask.contains(CosmeticFilterMask::IS_ID_SELECTOR) => {
+                if rule.mask.contains(CosmeticFilterMask::IS_SIMPLE) {
+                    self.simple_id_rules.contains(key)
+                } else {
+                    complex_bucket_contains(&self.complex_id_rules, key)
+                }
+            }
+            _ => self.misc_generic_selectors.contains(&rule.selector),
+        }
+    }
+
+    /// A generic selector is enabled unless every list it was added from has been disabled.
+    fn generic_selector_enabled(&self, selector: &str) -> bool {
+        self.disabled_lists.is_empty() || self.generic_rule_lists.get(selector)
+            .map(|lists| any_list_enabled(lists, &self.disabled_lists))
+            .unwrap_or(true)
+    }
+
+    /// Checks whether a generic `#@#` exception from an enabled list applies to the selector.
+    fn generic_selector_excepted(&self, selector: &str) -> bool {
+        self.generic_exceptions.get(selector)
+            .map(|lists| any_list_enabled(lists, &self.disabled_lists))
+            .unwrap_or(false)
+    }
+
+    /// A generic selector is only applied if it's enabled and not excepted by a generic rule.
+    fn generic_selector_allowed(&self, selector: &str) -> bool {
+        self.generic_selector_enabled(selector) && !self.generic_selector_excepted(selector)
+    }
+
+    /// Enables or disables all rules parsed with the given `ParseOptions::list_id`. Rules from a
+    /// disabled list are left out of any returned selectors or scriptlets, and exceptions from a
+    /// disabled list no longer apply.
+    pub fn set_list_enabled(&mut self, list_id: ListId, enabled: bool) {
+        if enabled {
+            self.disabled_lists.remove(&list_id);
+        } else {
+            self.disabled_lists.insert(list_id);
+        }
+    }
+
     /// Generic class/id rules are by far the most common type of cosmetic filtering rule, and they
     /// apply to all sites. Rather than injecting all of these rules onto every page, which would
     /// blow up memory usage, we only inject rules based on classes and ids that actually appear on
@@ -218,7 +482,7 @@ impl CosmeticFilterCache {
     /// `display: none !important` CSS rule) that could possibly be or become relevant to the page
     /// given the new classes and ids that have appeared on the page. It guarantees that it will be
     /// safe to hide those elements on a particular page by taking into account the page's
-    /// hostname-specific set of exception rules.
+    /// hostname-specific set of exception rules, as well as any generic `#@#` exceptions.
     ///
     /// The exceptions should be returned directly as they appear in the page's
     /// `UrlSpecificResources`. The exceptions, along with the set of already-seen classes and ids,
@@ -230,22 +494,28 @@ impl CosmeticFilterCache {
         let mut complex_selectors = vec![];
 
         classes.iter().for_each(|class| {
-            if self.simple_class_rules.contains(class) && !exceptions.contains(&format!(".{}", class)) {
-                simple_classes.push(class);
+            if self.simple_class_rules.contains(class) {
+                let selector = format!(".{}", class);
+                if !exceptions.contains(&selector) && self.generic_selector_allowed(&selector) {
+                    simple_classes.push(class);
+                }
             }
             if let Some(bucket) = self.complex_class_rules.get(class) {
                 complex_selectors.extend(bucket.iter().filter(|sel| {
-                    !exceptions.contains(*sel)
+                    !exceptions.contains(*sel) && self.generic_selector_allowed(sel)
                 }));
             }
         });
         ids.iter().for_each(|id| {
-            if self.simple_id_rules.contains(id) && !exceptions.contains(&format!("#{}", id)) {
-                simple_ids.push(id);
+            if self.simple_id_rules.contains(id) {
+                let selector = format!("#{}", id);
+                if !exceptions.contains(&selector) && self.generic_selector_allowed(&selector) {
+                    simple_ids.push(id);
+                }
             }
             if let Some(bucket) = self.complex_id_rules.get(id) {
                 complex_selectors.extend(bucket.iter().filter(|sel| {
-                    !exceptions.contains(*sel)
+                    !exceptions.contains(*sel) && self.generic_selector_allowed(sel)
                 }));
             }
         });
@@ -268,6 +538,16 @@ impl CosmeticFilterCache {
     /// id selector, like `div[class*="Ads"]`), or any rule that is only applicable to a particular
     /// hostname or set of hostnames (like `example.com##.a-class`). The first category is always
     /// injected into every page, and makes up a relatively small number of rules in practice.
+    ///
+    /// Hostname-specific rules are collected for `hostname` and each of its parent domains, so
+    /// rules for `example.com` and `b.example.com` both apply on `a.b.example.com`.
+    ///
+    /// When rules for the same selector conflict, they are resolved in this order of precedence:
+    /// - a hostname-specific exception (`example.com#@#.ad`) removes both generic and specific
+    ///   rules for the selector on that hostname.
+    /// - a hostname-specific rule (`example.com##.ad`) hides the selector on that hostname, even
+    ///   if a generic exception (`#@#.ad`) exists for it.
+    /// - a generic exception removes the generic rule (`##.ad`) everywhere.
     pub fn hostname_cosmetic_resources(&self, hostname: &str, generichide: bool) -> UrlSpecificResources {
         let domain_str = {
             let (start, end) = crate::url_parser::get_host_domain(hostname);
@@ -278,10 +558,9 @@ impl CosmeticFilterCache {
 
         let mut rules_that_apply = vec![];
         for hash in request_entities.iter().chain(request_hostnames.iter()) {
-            if let Some(specific_rules) = self.specific_rules.retrieve(hash) {
-                rules_that_apply.extend(specific_rules);
-            }
+            rules_that_apply.extend(self.specific_rules.retrieve_enabled(hash, &self.disabled_lists));
         };
+        let rules_that_apply = without_exclusions(rules_that_apply);
 
         let mut exceptions = HostnameExceptionsBuilder::default();
 
@@ -293,12 +572,21 @@ impl CosmeticFilterCache {
             exceptions.allow_specific_rule(r)
         }).collect::<Vec<_>>();
 
-        let (hostname_hide_selectors, style_selectors, script_injections) = hostname_specific_rules(&rules_that_apply[..]);
+        let HostnameSpecificRules {
+            hide_selectors: hostname_hide_selectors,
+            style_selectors,
+            script_injections,
+            remove_selectors,
+            procedural_selectors,
+        } = hostname_specific_rules(&rules_that_apply[..]);
 
         let hide_selectors = if generichide {
             hostname_hide_selectors
         } else {
-            let mut hide_selectors = self.misc_generic_selectors.difference(&exceptions.hide_exceptions).cloned().collect::<HashSet<_>>();
+            let mut hide_selectors = self.misc_generic_selectors.difference(&exceptions.hide_exceptions)
+                .filter(|sel| self.generic_selector_allowed(sel))
+                .cloned()
+                .collect::<HashSet<_>>();
             hostname_hide_selectors.into_iter().for_each(|sel| { hide_selectors.insert(sel); });
             hide_selectors
         };
@@ -315,11 +603,60 @@ impl CosmeticFilterCache {
             hide_selectors,
             style_selectors,
             exceptions: exceptions.hide_exceptions,
+            remove_selectors,
+            procedural_selectors,
             injected_script,
             generichide,
         }
     }
 
+    /// Returns the hostname-specific rules, including exceptions and scriptlets, that apply to
+    /// `hostname` or any of its parent domains, along with the list each was parsed from. Rules
+    /// from disabled lists are left out. Only rules parsed in debug mode, before any
+    /// serialization, are returned, since others don't keep their original text.
+    pub fn hostname_rule_sources(&self, hostname: &str) -> Vec<CosmeticRuleSource> {
+        let domain_str = {
+            let (start, end) = crate::url_parser::get_host_domain(hostname);
+            &hostname[start..end]
+        };
+
+        let (request_entities, request_hostnames) = hostname_domain_hashes(hostname, domain_str);
+        let hashes = request_entities.into_iter().chain(request_hostnames).collect::<Vec<_>>();
+        self.specific_rules.retrieve_sources(&hashes, &self.disabled_lists)
+    }
+
+    /// Returns the hostnames and entities that have any hostname-specific rules, in alphabetical
+    /// order. Names are only known before any serialization.
+    pub fn specific_hostnames(&self) -> Vec<String> {
+        self.specific_rules.hostnames()
+    }
+
+    /// Cheaply checks whether `hostname_cosmetic_resources` could return anything for the given
+    /// hostname, without building any selectors. This is true whenever there are any generic rules
+    /// (unless `generichide` is set) or any hostname-specific rules other than exceptions that
+    /// apply to the hostname.
+    ///
+    /// A `true` result doesn't guarantee that any rules remain once exceptions are taken into
+    /// account, but a `false` result guarantees that there are none.
+    pub fn has_rules_for(&self, hostname: &str, generichide: bool) -> bool {
+        let has_generic_rules = !self.simple_class_rules.is_empty() || !self.simple_id_rules.is_empty()
+            || !self.complex_class_rules.is_empty() || !self.complex_id_rules.is_empty()
+            || !self.misc_generic_selectors.is_empty();
+        if has_generic_rules && !generichide {
+            return true;
+        }
+
+        let domain_str = {
+            let (start, end) = crate::url_parser::get_host_domain(hostname);
+            &hostname[start..end]
+        };
+        let (request_entities, request_hostnames) = hostname_domain_hashes(hostname, domain_str);
+        request_entities.iter().chain(request_hostnames.iter()).any(|hash| {
+            self.specific_rules.retrieve_enabled(hash, &self.disabled_lists)
+                .any(|rule| !rule.is_exception() && !matches!(rule, SpecificFilterType::Excluded(_)))
+        })
+    }
+
     /// Sets the internal resources to be those provided, silently discarding errors.
     ///
     /// Use `add_resource` if error information is required.
@@ -339,13 +676,47 @@ impl CosmeticFilterCache {
     }
 }
 
+/// Drops each `SpecificFilterType::Excluded` rule along with one occurrence of the rule it
+/// excludes, so that other rules for the same selector still apply.
+fn without_exclusions(rules: Vec<&SpecificFilterType>) -> Vec<&SpecificFilterType> {
+    let mut excluded: Vec<&SpecificFilterType> = rules.iter().filter_map(|rule| match rule {
+        SpecificFilterType::Excluded(excluded) => Some(excluded.as_ref()),
+        _ => None,
+    }).collect();
+    if excluded.is_empty() {
+        return rules;
+    }
+
+    rules.into_iter().filter(|rule| {
+        if matches!(rule, SpecificFilterType::Excluded(_)) {
+            return false;
+        }
+        match excluded.iter().position(|excluded| excluded == rule) {
+            Some(index) => {
+                excluded.swap_remove(index);
+                false
+            }
+            None => true,
+        }
+    }).collect()
+}
+
+/// Rules from no list at all are always enabled.
+fn any_list_enabled(lists: &[Option<ListId>], disabled_lists: &HashSet<ListId>) -> bool {
+    lists.iter().any(|list_id| list_id.map(|id| !disabled_lists.contains(&id)).unwrap_or(true))
+}
+
 /// Used internally to build hostname-specific rulesets by canceling out rules which match any
 /// exceptions
 #[derive(Default, Debug, PartialEq, Eq)]
 struct HostnameExceptionsBuilder {
     hide_exceptions: HashSet<String>,
     style_exceptions: HashSet<(String, String)>,
-    script_inject_exceptions: HashSet<String>,
+    /// Scriptlet injections in the form given by `normalized_scriptlet_args`. An empty entry,
+    /// from `#@#+js()`, disables all scriptlets.
+    script_inject_exceptions: HashSet<Vec<String>>,
+    remove_exceptions: HashSet<String>,
+    procedural_exceptions: HashSet<(String, Vec<ProceduralOperator>)>,
 }
 
 impl HostnameExceptionsBuilder {
@@ -354,7 +725,7 @@ impl HostnameExceptionsBuilder {
         use SpecificFilterType as Rule;
 
         match rule {
-            Rule::Hide(_) | Rule::Style(_, _) | Rule::ScriptInject(_) => (),
+            Rule::Hide(_) | Rule::Style(_, _) | Rule::ScriptInject(_) | Rule::Remove(_) | Rule::Procedural(_, _) | Rule::Excluded(_) => (),
             Rule::Unhide(sel) => {
                 self.hide_exceptions.insert(sel.clone());
             }
@@ -362,7 +733,13 @@ impl HostnameExceptionsBuilder {
                 self.style_exceptions.insert((sel.clone(), style.clone()));
             }
             Rule::UnhideScriptInject(script) => {
-                self.script_inject_exceptions.insert(script.clone());
+                self.script_inject_exceptions.insert(normalized_scriptlet_args(script));
+            }
+            Rule::UnhideRemove(sel) => {
+                self.remove_exceptions.insert(sel.clone());
+            }
+            Rule::UnhideProcedural(sel, operators) => {
+                self.procedural_exceptions.insert((sel.clone(), operators.clone()));
             }
         }
     }
@@ -379,7 +756,10 @@ impl HostnameExceptionsBuilder {
         match rule {
             SpecificFilterType::Hide(sel) => !self.hide_exceptions.contains(sel),
             SpecificFilterType::Style(sel, style) => !self.style_exceptions.contains(&(sel.to_string(), style.to_string())),
-            SpecificFilterType::ScriptInject(sel) => !self.script_inject_exceptions.contains(sel),
+            SpecificFilterType::ScriptInject(sel) => !self.script_inject_exceptions.contains(&Vec::new())
+                && !self.script_inject_exceptions.contains(&normalized_scriptlet_args(sel)),
+            SpecificFilterType::Remove(sel) => !self.remove_exceptions.contains(sel),
+            SpecificFilterType::Procedural(sel, operators) => !self.procedural_exceptions.contains(&(sel.to_string(), operators.to_vec())),
             _ => false,
         }
     }
@@ -393,51 +773,202 @@ impl HostnameExceptionsBuilder {
 /// that apply.
 #[derive(Deserialize, Serialize, Default)]
 pub(crate) struct HostnameRuleDb {
-    #[serde(serialize_with = "crate::data_format::utils::stabilize_hashmap_serialization")]
+    /// Rules of any kind for a hostname hash. Only the kinds understood by older versions of the
+    /// data format are serialized as part of this field; the rest are written separately by
+    /// `serialize_extended_rules`.
+    #[serde(serialize_with = "HostnameRuleDb::serialize_base_rules")]
     db: HashMap<Hash, Vec<SpecificFilterType>>,
+    /// The list of each rule in `db`, at the same index in the bucket with the same hostname.
+    /// Buckets only containing rules without a list aren't tracked, and missing trailing entries
+    /// are `None`.
+    ///
+    /// Serialized separately from `db`, at the end of the data format.
+    #[serde(skip)]
+    pub(crate) lists: HashMap<Hash, Vec<Option<ListId>>>,
+    /// The text of each hostname or entity in `db`, as written in a rule. Not serialized.
+    #[serde(skip)]
+    names: HashMap<Hash, String>,
+    /// The original text of each rule in `db`, at the same index in the bucket with the same
+    /// hostname. Only known for rules parsed in debug mode, and not serialized. Rules that only
+    /// exclude hostnames, like `~example.com##.ad`, aren't tracked under those hostnames.
+    #[serde(skip)]
+    raw_lines: HashMap<Hash, Vec<Option<Arc<str>>>>,
 }
 
 impl HostnameRuleDb {
     pub fn new() -> Self {
         HostnameRuleDb {
             db: HashMap::new(),
+            lists: HashMap::new(),
+            names: HashMap::new(),
+            raw_lines: HashMap::new(),
         }
     }
 
+    /// The number of hostnames with rules, and the total number of rules across all of them.
+    pub(crate) fn rule_counts(&self) -> (usize, usize) {
+        (self.db.len(), self.db.values().map(Vec::len).sum())
+    }
+
     pub fn store_rule(&mut self, rule: CosmeticFilter) {
         let kind = SpecificFilterType::from(&rule);
+        let list_id = rule.list_id;
+        let has_includes = rule.hostnames.is_some() || rule.entities.is_some();
+
+        if let Some(locations) = &rule.locations {
+            self.record_names(locations);
+        }
+        let raw_line: Option<Arc<str>> = rule.raw_line.map(Into::into);
 
         if let Some(hostnames) = rule.hostnames {
             hostnames.iter().for_each(|h| {
-                self.store(h, kind.clone())
+                self.store(h, kind.clone(), list_id, raw_line.clone())
             });
         }
         if let Some(entities) = rule.entities {
             entities.iter().for_each(|e| {
-                self.store(e, kind.clone())
+                self.store(e, kind.clone(), list_id, raw_line.clone())
             });
         }
 
-        let kind = kind.negated();
+        let (kind, raw_line) = if has_includes {
+            (SpecificFilterType::Excluded(Box::new(kind)), raw_line)
+        } else {
+            (kind.negated(), None)
+        };
 
         if let Some(not_hostnames) = rule.not_hostnames {
             not_hostnames.iter().for_each(|h| {
-                self.store(h, kind.clone())
+                self.store(h, kind.clone(), list_id, raw_line.clone())
             });
         }
         if let Some(not_entities) = rule.not_entities {
             not_entities.iter().for_each(|e| {
-                self.store(e, kind.clone())
+                self.store(e, kind.clone(), list_id, raw_line.clone())
             });
         }
     }
 
-    fn store(&mut self, hostname: &Hash, kind: SpecificFilterType) {
-        if let Some(bucket) = self.db.get_mut(hostname) {
+    /// Remembers the hostnames and entities of a rule, as in `CosmeticFilter::locations`, keyed by
+    /// the same hashes as the rule's buckets. Entities are named with their `.*` suffix.
+    fn record_names(&mut self, locations: &str) {
+        for (location_type, location) in CosmeticFilter::locations_before_sharp(locations, locations.len()) {
+            let hostname = if location.is_ascii() {
+                location.to_owned()
+            } else {
+                match idna::domain_to_ascii(location) {
+                    Ok(hostname) => hostname,
+                    Err(_) => continue,
+                }
+            };
+            let name = match location_type {
+                CosmeticFilterLocationType::Entity | CosmeticFilterLocationType::NotEntity => format!("{}.*", hostname),
+                CosmeticFilterLocationType::Hostname | CosmeticFilterLocationType::NotHostname => hostname.clone(),
+            };
+            self.names.entry(crate::utils::fast_hash(&hostname)).or_insert(name);
+        }
+    }
+
+    /// Returns the names of all hostnames and entities with specific rules, including
+    /// exceptions, in alphabetical order. Hostnames whose name is unknown are left out; see
+    /// `names`.
+    pub fn hostnames(&self) -> Vec<String> {
+        let mut hostnames = self.db.keys()
+            .filter_map(|hash| self.names.get(hash).cloned())
+            .collect::<Vec<_>>();
+        hostnames.sort();
+        hostnames.dedup();
+        hostnames
+    }
+
+    fn store(&mut self, hostname: &Hash, kind: SpecificFilterType, list_id: Option<ListId>, raw_line: Option<Arc<str>>) {
+        let index = if let Some(bucket) = self.db.get_mut(hostname) {
             bucket.push(kind);
+            bucket.len() - 1
         } else {
             self.db.insert(*hostname, vec![kind]);
+            0
+        };
+
+        if list_id.is_some() || self.lists.contains_key(hostname) {
+            let lists = self.lists.entry(*hostname).or_default();
+            lists.resize(index, None);
+            lists.push(list_id);
+        }
+        if raw_line.is_some() {
+            let raw_lines = self.raw_lines.entry(*hostname).or_default();
+            raw_lines.resize(index, None);
+            raw_lines.push(raw_line);
+        }
+    }
+
+    /// Returns the original text and list of each rule stored for any of the `hostnames`, leaving
+    /// out rules from the `disabled_lists` and rules excluded from one of the `hostnames`. Rules
+    /// without their original text are left out as well.
+    pub fn retrieve_sources(&self, hostnames: &[Hash], disabled_lists: &HashSet<ListId>) -> Vec<CosmeticRuleSource> {
+        let mut included = vec![];
+        let mut excluded = HashSet::new();
+        for hostname in hostnames {
+            let raw_lines = match self.raw_lines.get(hostname) {
+                Some(raw_lines) => raw_lines,
+                None => continue,
+            };
+            let bucket = self.retrieve(hostname).unwrap_or_default();
+            let lists = self.lists.get(hostname);
+            for (i, raw_line) in raw_lines.iter().enumerate() {
+                let raw_line = match raw_line {
+                    Some(raw_line) => raw_line,
+                    None => continue,
+                };
+                let list_id = lists.and_then(|lists| lists.get(i).copied().flatten());
+                if list_id.map(|id| disabled_lists.contains(&id)).unwrap_or(false) {
+                    continue;
+                }
+                if matches!(bucket[i], SpecificFilterType::Excluded(_)) {
+                    excluded.insert(raw_line);
+                } else {
+                    included.push((raw_line, list_id));
+                }
+            }
+        }
+
+        let mut seen = HashSet::new();
+        included.into_iter()
+            .filter(|(raw_line, _)| !excluded.contains(raw_line) && seen.insert(*raw_line))
+            .map(|(raw_line, list_id)| CosmeticRuleSource {
+                rule: raw_line.to_string(),
+                list_id,
+            })
+            .collect()
+    }
+
+    /// Adds the rules of `other` to the end of the buckets for the same hostnames, along with their
+    /// lists and original text.
+    pub fn merge(&mut self, other: HostnameRuleDb) {
+        for (hostname, rules) in other.db {
+            let lists = other.lists.get(&hostname);
+            let raw_lines = other.raw_lines.get(&hostname);
+            for (i, rule) in rules.into_iter().enumerate() {
+                let list_id = lists.and_then(|lists| lists.get(i).copied().flatten());
+                let raw_line = raw_lines.and_then(|raw_lines| raw_lines.get(i).cloned().flatten());
+                self.store(&hostname, rule, list_id, raw_line);
+            }
         }
+        for (hash, name) in other.names {
+            self.names.entry(hash).or_insert(name);
+        }
+    }
+
+    /// Like `retrieve`, but leaves out any rules from the `disabled_lists`.
+    pub fn retrieve_enabled<'a>(&'a self, hostname: &Hash, disabled_lists: &'a HashSet<ListId>) -> impl Iterator<Item = &'a SpecificFilterType> {
+        let lists = self.lists.get(hostname).filter(|_| !disabled_lists.is_empty());
+        self.retrieve(hostname).unwrap_or_default().iter().enumerate()
+            .filter(move |(i, _)| {
+                lists.and_then(|lists| lists.get(*i).copied().flatten())
+                    .map(|id| !disabled_lists.contains(&id))
+                    .unwrap_or(true)
+            })
+            .map(|(_, rule)| rule)
     }
 
     pub fn retrieve<'a>(&'a self, hostname: &Hash) -> Option<&'a[SpecificFilterType]> {
@@ -447,10 +978,49 @@ impl HostnameRuleDb {
             None
         }
     }
+
+    fn serialize_rules<S>(db: &HashMap<Hash, Vec<SpecificFilterType>>, extended: bool, s: S) -> Result<S::Ok, S::Error> where S: serde::Serializer {
+        let stabilized: std::collections::BTreeMap<&Hash, Vec<&SpecificFilterType>> = db.iter()
+            .map(|(hash, bucket)| (hash, bucket.iter().filter(|rule| rule.is_extended() == extended).collect::<Vec<_>>()))
+            .filter(|(_, bucket)| !bucket.is_empty())
+            .collect();
+        stabilized.serialize(s)
+    }
+
+    fn serialize_base_rules<S>(db: &HashMap<Hash, Vec<SpecificFilterType>>, s: S) -> Result<S::Ok, S::Error> where S: serde::Serializer {
+        Self::serialize_rules(db, false, s)
+    }
+
+    /// Serializes only the rules left out of the `db` field, so that they can be stored at the end
+    /// of the data format. `append_extended_rules` restores them.
+    pub(crate) fn serialize_extended_rules<S>(&self, s: S) -> Result<S::Ok, S::Error> where S: serde::Serializer {
+        Self::serialize_rules(&self.db, true, s)
+    }
+
+    /// Serializes `lists` in the order that rules are restored by `append_extended_rules`, i.e.
+    /// the lists of all base rules in a bucket followed by those of its extended rules.
+    pub(crate) fn serialize_lists<S>(&self, s: S) -> Result<S::Ok, S::Error> where S: serde::Serializer {
+        let stabilized: std::collections::BTreeMap<&Hash, Vec<Option<ListId>>> = self.lists.iter()
+            .map(|(hash, lists)| {
+                let bucket = self.retrieve(hash).unwrap_or_default();
+                let (extended, base): (Vec<_>, Vec<_>) = (0..bucket.len()).partition(|i| bucket[*i].is_extended());
+                (hash, base.into_iter().chain(extended).map(|i| lists.get(i).copied().flatten()).collect())
+            })
+            .collect();
+        stabilized.serialize(s)
+    }
+
+    /// Adds rules that were serialized separately by `serialize_extended_rules` back to their
+    /// buckets.
+    pub(crate) fn append_extended_rules(&mut self, extended: HashMap<Hash, Vec<SpecificFilterType>>) {
+        for (hash, rules) in extended {
+            self.db.entry(hash).or_default().extend(rules);
+        }
+    }
 }
 
 /// Each variant describes a single rule that is specific to a particular hostname.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum SpecificFilterType {
     /// A simple hostname-specific hide rule, e.g. `example.com##.ad`.
     ///
@@ -487,6 +1057,36 @@ pub enum SpecificFilterType {
     ///
     /// In practice, these rules are extremely rare in filter lists.
     UnhideScriptInject(String),
+
+    /// A hostname-specific rule to remove matching elements from the page, e.g.
+    /// `example.com##.ad:remove()`.
+    ///
+    /// The parameter is the rule's CSS selector, without the `:remove()` action.
+    Remove(String),
+    /// A hostname-specific exception rule for removing matching elements, e.g.
+    /// `example.com#@#.ad:remove()`.
+    ///
+    /// The parameter is the rule's CSS selector, without the `:remove()` action.
+    UnhideRemove(String),
+
+    /// A hostname-specific rule to hide elements filtered by procedural operators, e.g.
+    /// `example.com##div:has-text(Sponsored)`.
+    ///
+    /// The parameters are the rule's CSS selector and the operators following it.
+    Procedural(String, Vec<ProceduralOperator>),
+    /// A hostname-specific exception rule for a procedural rule, e.g.
+    /// `example.com#@#div:has-text(Sponsored)`.
+    ///
+    /// The parameters are the rule's CSS selector and the operators following it.
+    UnhideProcedural(String, Vec<ProceduralOperator>),
+
+    /// Keeps a rule from applying on a hostname or entity it was excluded from, such as
+    /// `example.org` in `example.*,~example.org##.ad`. Unlike an exception, this doesn't affect any
+    /// other rule for the same selector, e.g. `example.org##.ad` or `##.ad`.
+    ///
+    /// The parameter is the excluded rule. Rules with only excluded hostnames or entities, like
+    /// `~example.org##.ad`, are stored as exceptions instead.
+    Excluded(Box<SpecificFilterType>),
 }
 
 /// This implementation assumes the given rule has hostname or entity constraints, and that the
@@ -507,6 +1107,18 @@ impl From<&CosmeticFilter> for SpecificFilterType {
             } else {
                 SpecificFilterType::ScriptInject(rule.selector.clone())
             }
+        } else if let Some(ref operators) = rule.procedural {
+            if unhide {
+                SpecificFilterType::UnhideProcedural(rule.selector.clone(), operators.clone())
+            } else {
+                SpecificFilterType::Procedural(rule.selector.clone(), operators.clone())
+            }
+        } else if rule.mask.contains(CosmeticFilterMask::REMOVE) {
+            if unhide {
+                SpecificFilterType::UnhideRemove(rule.selector.clone())
+            } else {
+                SpecificFilterType::Remove(rule.selector.clone())
+            }
         } else {
             if unhide {
                 SpecificFilterType::Unhide(rule.selector.clone())
@@ -518,6 +1130,22 @@ impl From<&CosmeticFilter> for SpecificFilterType {
 }
 
 impl SpecificFilterType {
+    fn is_exception(&self) -> bool {
+        matches!(self,
+            SpecificFilterType::Unhide(_) | SpecificFilterType::UnhideStyle(..) |
+            SpecificFilterType::UnhideScriptInject(_) | SpecificFilterType::UnhideRemove(_) |
+            SpecificFilterType::UnhideProcedural(..))
+    }
+
+    /// Kinds of rules that were added after the data format was first released. These are
+    /// serialized separately so that older versions can still read the rest of the format.
+    fn is_extended(&self) -> bool {
+        matches!(self,
+            SpecificFilterType::Remove(_) | SpecificFilterType::UnhideRemove(_) |
+            SpecificFilterType::Procedural(..) | SpecificFilterType::UnhideProcedural(..) |
+            SpecificFilterType::Excluded(_))
+    }
+
     pub fn negated(self) -> Self {
         match self {
             SpecificFilterType::Hide(sel) => SpecificFilterType::Unhide(sel),
@@ -526,7 +1154,11 @@ impl SpecificFilterType {
             SpecificFilterType::UnhideStyle(sel, style) => SpecificFilterType::Style(sel, style),
             SpecificFilterType::ScriptInject(script) => SpecificFilterType::UnhideScriptInject(script),
             SpecificFilterType::UnhideScriptInject(script) => SpecificFilterType::ScriptInject(script),
-
+            SpecificFilterType::Remove(sel) => SpecificFilterType::UnhideRemove(sel),
+            SpecificFilterType::UnhideRemove(sel) => SpecificFilterType::Remove(sel),
+            SpecificFilterType::Procedural(sel, operators) => SpecificFilterType::UnhideProcedural(sel, operators),
+            SpecificFilterType::UnhideProcedural(sel, operators) => SpecificFilterType::Procedural(sel, operators),
+            SpecificFilterType::Excluded(rule) => SpecificFilterType::Excluded(rule),
         }
     }
 }
@@ -551,6 +1183,90 @@ mod cosmetic_cache_tests {
         CosmeticFilterCache::from_rules(parsed_rules)
     }
 
+    #[test]
+    fn extended_rules_serialized_separately() {
+        let cache = cache_from_rules(vec![
+            "example.com##.ad",
+            "example.com##.banner:remove()",
+            "example.com##div:has-text(Sponsored)",
+            "example.org##.banner:remove()",
+        ]);
+        let example_com = crate::utils::fast_hash("example.com");
+        let example_org = crate::utils::fast_hash("example.org");
+
+        let mut db: HostnameRuleDb = rmp_serde::from_slice(&rmp_serde::to_vec(&cache.specific_rules).unwrap()).unwrap();
+        assert!(matches!(db.retrieve(&example_com), Some([SpecificFilterType::Hide(sel)]) if sel == ".ad"));
+        assert!(db.retrieve(&example_org).is_none());
+
+        let mut extended = vec![];
+        cache.specific_rules.serialize_extended_rules(&mut rmp_serde::Serializer::new(&mut extended)).unwrap();
+        db.append_extended_rules(rmp_serde::from_slice(&extended).unwrap());
+        assert!(matches!(db.retrieve(&example_com), Some([
+            SpecificFilterType::Hide(_),
+            SpecificFilterType::Remove(sel),
+            SpecificFilterType::Procedural(..),
+        ]) if sel == ".banner"));
+        assert!(matches!(db.retrieve(&example_org), Some([SpecificFilterType::Remove(_)])));
+    }
+
+    #[test]
+    fn serialize_independently() {
+        let cache = cache_from_rules(vec![
+            "example.com##.ad",
+            "example.com##.styled:style(color: red)",
+            "example.com##.banner:remove()",
+            "example.com##div:has-text(Sponsored)",
+            "example.com##+js(set-constant, ads, false)",
+            "sub.example.com#@#.ad",
+            "##.generic",
+            "###generic-id .child",
+            "##a[href*=\"tracker\"]",
+            "#@#.excepted",
+        ]);
+        let deserialized = CosmeticFilterCache::deserialize(&cache.serialize().unwrap()).unwrap();
+
+        for hostname in &["example.com", "sub.example.com", "other.com"] {
+            assert_eq!(
+                deserialized.hostname_cosmetic_resources(hostname, false),
+                cache.hostname_cosmetic_resources(hostname, false),
+            );
+        }
+        let classes = ["generic".to_owned(), "excepted".to_owned()];
+        let ids = ["generic-id".to_owned()];
+        assert_eq!(
+            deserialized.hidden_class_id_selectors(&classes, &ids, &HashSet::new()),
+            cache.hidden_class_id_selectors(&classes, &ids, &HashSet::new()),
+        );
+
+        let mut serialized = cache.serialize().unwrap();
+        serialized[4] = 1;
+        assert!(matches!(CosmeticFilterCache::deserialize(&serialized), Err(crate::data_format::DeserializationError::UnsupportedFormatVersion(1))));
+        assert!(matches!(CosmeticFilterCache::deserialize(&serialized[..2]), Err(crate::data_format::DeserializationError::NoHeaderFound)));
+    }
+
+    #[test]
+    fn rules_from_parent_domains() {
+        let cfcache = cache_from_rules(vec![
+            "example.com##.level1",
+            "b.example.com##.level2",
+            "a.b.example.com##.level3",
+            "x.a.b.example.com##.level4",
+            "c.example.com##.sibling",
+        ]);
+
+        let out = cfcache.hostname_cosmetic_resources("a.b.example.com", false);
+        let mut expected = UrlSpecificResources::empty();
+        for selector in [".level1", ".level2", ".level3"] {
+            expected.hide_selectors.insert(selector.to_owned());
+        }
+        assert_eq!(out, expected);
+
+        let out = cfcache.hostname_cosmetic_resources("example.com", false);
+        let mut expected = UrlSpecificResources::empty();
+        expected.hide_selectors.insert(".level1".to_owned());
+        assert_eq!(out, expected);
+    }
+
     #[test]
     fn exceptions() {
         let cfcache = cache_from_rules(vec![
@@ -585,9 +1301,65 @@ mod cosmetic_cache_tests {
         expected.hide_selectors.insert(".item".to_owned());
         assert_eq!(out, expected);
 
+        // Excluding a hostname from a rule is not an exception for it
         let out = cfcache.hostname_cosmetic_resources("sub.example.com", false);
+        let expected = UrlSpecificResources::empty();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn remove_rules() {
+        let cfcache = cache_from_rules(vec![
+            "example.com##.ad:remove()",
+            "example.com,~sub.example.com##.banner:remove()",
+            "example.com##.hidden",
+        ]);
+
+        let out = cfcache.hostname_cosmetic_resources("test.com", false);
         let mut expected = UrlSpecificResources::empty();
-        expected.exceptions.insert(".item".into());
+        assert_eq!(out, expected);
+
+        let out = cfcache.hostname_cosmetic_resources("example.com", false);
+        expected.hide_selectors.insert(".hidden".to_owned());
+        expected.remove_selectors.insert(".ad".to_owned());
+        expected.remove_selectors.insert(".banner".to_owned());
+        assert_eq!(out, expected);
+
+        let out = cfcache.hostname_cosmetic_resources("sub.example.com", false);
+        expected.remove_selectors.remove(".banner");
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn procedural_rules() {
+        use crate::filters::cosmetic::TextMatcher;
+
+        let cfcache = cache_from_rules(vec![
+            "example.com##div:has-text(Sponsored)",
+            "example.com##div:has-text(/^ad$/i)",
+            "example.com,~sub.example.com##article:has-text(Promoted)",
+            "other.example.com#@#div:has-text(/^ad$/i)",
+        ]);
+
+        let out = cfcache.hostname_cosmetic_resources("test.com", false);
+        let mut expected = UrlSpecificResources::empty();
+        assert_eq!(out, expected);
+
+        let literal = |text: &str| vec![ProceduralOperator::HasText(TextMatcher::Literal(text.to_owned()))];
+        let regex = vec![ProceduralOperator::HasText(TextMatcher::Regex { pattern: "^ad$".to_owned(), flags: "i".to_owned() })];
+
+        let out = cfcache.hostname_cosmetic_resources("example.com", false);
+        expected.procedural_selectors.insert("div".to_owned(), vec![literal("Sponsored"), regex.clone()]);
+        expected.procedural_selectors.insert("article".to_owned(), vec![literal("Promoted")]);
+        assert_eq!(out, expected);
+
+        let out = cfcache.hostname_cosmetic_resources("sub.example.com", false);
+        expected.procedural_selectors.remove("article");
+        assert_eq!(out, expected);
+
+        let out = cfcache.hostname_cosmetic_resources("other.example.com", false);
+        expected.procedural_selectors.insert("div".to_owned(), vec![literal("Sponsored")]);
+        expected.procedural_selectors.insert("article".to_owned(), vec![literal("Promoted")]);
         assert_eq!(out, expected);
     }
 
@@ -683,6 +1455,56 @@ mod cosmetic_cache_tests {
         assert_eq!(out, expected);
     }
 
+    #[test]
+    fn multiple_scriptlets() {
+        use crate::resources::{ResourceType, MimeType};
+
+        let mut cfcache = cache_from_rules(vec![
+            "example.com##+js(set-constant, atob, trueFunc)",
+            "example.com##+js(nowebrtc.js)",
+            "sub.example.com##+js(window.open-defuser)",
+            "sub.example.com##+js(set-constant.js,atob,trueFunc)",
+            "sub.example.com#@#+js(nowebrtc)",
+            "nojs.example.com#@#+js()",
+        ]);
+        cfcache.use_resources(&[
+            Resource {
+                name: "set-constant.js".into(),
+                aliases: vec![],
+                kind: ResourceType::Template,
+                content: base64::encode("set-constant.js, {{1}}, {{2}}"),
+            },
+            Resource {
+                name: "nowebrtc.js".into(),
+                aliases: vec![],
+                kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+                content: base64::encode("nowebrtc.js"),
+            },
+            Resource {
+                name: "window.open-defuser.js".into(),
+                aliases: vec![],
+                kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+                content: base64::encode("window.open-defuser.js"),
+            },
+        ]);
+
+        let injected_script = cfcache.hostname_cosmetic_resources("example.com", false).injected_script;
+        let mut scripts = injected_script.lines().collect::<Vec<_>>();
+        scripts.sort_unstable();
+        assert_eq!(scripts, vec!["nowebrtc.js", "set-constant.js, atob, trueFunc"]);
+
+        // The repeated scriptlet is only injected once, and the order is stable
+        let injected_script = cfcache.hostname_cosmetic_resources("sub.example.com", false).injected_script;
+        let scripts = injected_script.lines().collect::<Vec<_>>();
+        assert_eq!(scripts.len(), 2);
+        assert!(scripts.contains(&"window.open-defuser.js"));
+        assert!(scripts.contains(&"set-constant.js, atob, trueFunc"));
+        assert_eq!(cfcache.hostname_cosmetic_resources("sub.example.com", false).injected_script, injected_script);
+
+        // An empty exception disables all scriptlets
+        assert_eq!(cfcache.hostname_cosmetic_resources("nojs.example.com", false).injected_script, "");
+    }
+
     #[test]
     fn matching_hidden_class_id_selectors() {
         let rules = [
@@ -778,6 +1600,165 @@ mod cosmetic_cache_tests {
         assert_eq!(hide_selectors, expected_hides);
     }
 
+    #[test]
+    fn specific_rules_override_generic_exceptions() {
+        let cfcache = cache_from_rules(vec![
+            "##.ad",
+            "##div.banner",
+            "##a[href=\"bad.com\"]",
+            "#@#.ad",
+            "#@#div.banner",
+            "#@#a[href=\"bad.com\"]",
+            "example.com##.ad",
+            "example.com##a[href=\"bad.com\"]",
+            "sub.example.com#@#.ad",
+        ]);
+
+        let resources = cfcache.hostname_cosmetic_resources("test.com", false);
+        assert!(resources.hide_selectors.is_empty());
+        let hide_selectors = cfcache.hidden_class_id_selectors(&["ad".into(), "banner".into()], &[], &resources.exceptions);
+        assert!(hide_selectors.is_empty());
+
+        let resources = cfcache.hostname_cosmetic_resources("example.com", false);
+        let mut expected_hides = HashSet::new();
+        expected_hides.insert(".ad".to_owned());
+        expected_hides.insert("a[href=\"bad.com\"]".to_owned());
+        assert_eq!(resources.hide_selectors, expected_hides);
+
+        // A specific exception still takes precedence over a specific rule.
+        let resources = cfcache.hostname_cosmetic_resources("sub.example.com", false);
+        let mut expected_hides = HashSet::new();
+        expected_hides.insert("a[href=\"bad.com\"]".to_owned());
+        assert_eq!(resources.hide_selectors, expected_hides);
+    }
+
+    #[test]
+    fn generic_exceptions_resolved_at_build() {
+        let cfcache = cache_from_rules(vec![
+            "##.ad",
+            "###banner",
+            "##.promo > div",
+            "##.promo a",
+            "##div[class^=\"sponsor\"]",
+            "##.kept",
+            "#@#.ad",
+            "#@##banner",
+            "#@#.promo > div",
+            "#@#div[class^=\"sponsor\"]",
+        ]);
+
+        assert!(!cfcache.simple_class_rules.contains("ad"));
+        assert!(cfcache.simple_class_rules.contains("kept"));
+        assert!(cfcache.simple_id_rules.is_empty());
+        assert_eq!(cfcache.complex_class_rules.get("promo"), Some(&vec![".promo a".to_owned()]));
+        assert!(cfcache.misc_generic_selectors.is_empty());
+        assert_eq!(cfcache.generic_exceptions.len(), 4);
+
+        // Exceptions from a list may be disabled later, so they are only applied at query time.
+        let mut exception = CosmeticFilter::parse("#@#.ad", false).unwrap();
+        exception.list_id = Some(1);
+        let mut cfcache = CosmeticFilterCache::from_rules(vec![CosmeticFilter::parse("##.ad", false).unwrap(), exception]);
+        assert!(cfcache.simple_class_rules.contains("ad"));
+        assert!(cfcache.hidden_class_id_selectors(&["ad".into()], &[], &HashSet::new()).is_empty());
+        cfcache.set_list_enabled(1, false);
+        assert_eq!(cfcache.hidden_class_id_selectors(&["ad".into()], &[], &HashSet::new()), [".ad"]);
+    }
+
+    #[test]
+    fn disabled_list_rules() {
+        let parse = |rule: &str, list_id| {
+            let mut filter = CosmeticFilter::parse(rule, false).unwrap();
+            filter.list_id = list_id;
+            filter
+        };
+        let mut cfcache = CosmeticFilterCache::from_rules(vec![
+            parse("##.unlisted", None),
+            parse("##.unlisted", Some(1)),
+            parse("##.ad", Some(1)),
+            parse("##div.ad", Some(1)),
+            parse("example.com##.banner", None),
+            parse("example.com#@#.banner", Some(1)),
+            parse("example.com##.promo", Some(1)),
+        ]);
+
+        let classes = ["unlisted".to_owned(), "ad".to_owned()];
+        cfcache.set_list_enabled(1, false);
+        assert_eq!(cfcache.hidden_class_id_selectors(&classes, &[], &HashSet::new()), vec![".unlisted".to_owned()]);
+
+        let mut expected = UrlSpecificResources::empty();
+        expected.hide_selectors.insert(".banner".to_owned());
+        expected.generichide = true;
+        assert_eq!(cfcache.hostname_cosmetic_resources("example.com", true), expected);
+
+        cfcache.set_list_enabled(1, true);
+        let mut selectors = cfcache.hidden_class_id_selectors(&classes, &[], &HashSet::new());
+        selectors.sort();
+        assert_eq!(selectors, vec![".ad".to_owned(), ".unlisted".to_owned()]);
+
+        let mut expected = UrlSpecificResources::empty();
+        expected.hide_selectors.insert(".promo".to_owned());
+        expected.exceptions.insert(".banner".to_owned());
+        expected.generichide = true;
+        assert_eq!(cfcache.hostname_cosmetic_resources("example.com", true), expected);
+    }
+
+    #[test]
+    fn entity_rules() {
+        let cfcache = cache_from_rules(vec![
+            "example.*##.ad",
+            "example.*,~example.org##.banner",
+            "~example.*##.generic-banner",
+        ]);
+
+        let mut expected = UrlSpecificResources::empty();
+        expected.hide_selectors.insert(".ad".to_owned());
+        expected.hide_selectors.insert(".banner".to_owned());
+        expected.exceptions.insert(".generic-banner".to_owned());
+        for hostname in &["example.com", "example.co.uk", "www.example.de", "example.github.io"] {
+            assert_eq!(cfcache.hostname_cosmetic_resources(hostname, false), expected, "{}", hostname);
+        }
+
+        let out = cfcache.hostname_cosmetic_resources("example.org", false);
+        expected.hide_selectors.remove(".banner");
+        assert_eq!(out, expected);
+
+        for hostname in &["exampleshop.com", "myexample.co.uk", "example.com.evil.net"] {
+            assert_eq!(cfcache.hostname_cosmetic_resources(hostname, false), UrlSpecificResources::empty(), "{}", hostname);
+        }
+    }
+
+    #[test]
+    fn entity_exclusions() {
+        let cfcache = cache_from_rules(vec![
+            "example.*,~example.org##.ad",
+            "example.*,~example.org##.promo",
+            "example.org##.promo",
+            "##div > .sidebar",
+            "example.*,~example.org##div > .sidebar",
+            "example.*,~example.org##.banner",
+            "www.example.org##.banner",
+        ]);
+
+        let out = cfcache.hostname_cosmetic_resources("example.com", false);
+        let mut expected = UrlSpecificResources::empty();
+        expected.hide_selectors.insert(".ad".to_owned());
+        expected.hide_selectors.insert("div > .sidebar".to_owned());
+        expected.hide_selectors.insert(".banner".to_owned());
+        expected.hide_selectors.insert(".promo".to_owned());
+        assert_eq!(out, expected);
+
+        // The exclusions only apply to their own rules
+        let out = cfcache.hostname_cosmetic_resources("example.org", false);
+        let mut expected = UrlSpecificResources::empty();
+        expected.hide_selectors.insert(".promo".to_owned());
+        expected.hide_selectors.insert("div > .sidebar".to_owned());
+        assert_eq!(out, expected);
+
+        let out = cfcache.hostname_cosmetic_resources("www.example.org", false);
+        expected.hide_selectors.insert(".banner".to_owned());
+        assert_eq!(out, expected);
+    }
+
     #[test]
     fn apply_to_tld() {
         use crate::resources::ResourceType;
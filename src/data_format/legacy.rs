@@ -20,8 +20,9 @@ use rmp_serde_legacy as rmps;
 use crate::blocker::{Blocker, NetworkFilterList};
 use crate::resources::{RedirectResourceStorage, ScriptletResourceStorage};
 use crate::filters::network::NetworkFilter;
-use crate::cosmetic_filter_cache::{CosmeticFilterCache, HostnameRuleDb};
-use crate::utils::is_eof_error;
+use crate::cosmetic_filter_cache::{CosmeticFilterCache, HostnameRuleDb, SpecificFilterType};
+use crate::lists::ListId;
+use crate::utils::{is_eof_error, Hash};
 
 use super::{DeserializationError, SerializationError};
 
@@ -158,6 +159,14 @@ struct SerializeFormatRest<'a> {
 
     #[serde(serialize_with = "serialize_legacy_network_filter_list")]
     generic_hide: &'a NetworkFilterList,
+
+    #[serde(serialize_with = "HostnameRuleDb::serialize_extended_rules")]
+    specific_extended_rules: &'a HostnameRuleDb,
+
+    generic_exceptions: &'a HashMap<String, Vec<Option<ListId>>>,
+
+    #[serde(serialize_with = "serialize_legacy_network_filter_list")]
+    generic_block: &'a NetworkFilterList,
 }
 
 /// `_fuzzy_signature` is no longer used, and is cleaned up from future format versions.
@@ -195,6 +204,14 @@ impl From<NetworkFilterLegacyDeserializeFmt> for NetworkFilter {
             id: v.id,
             opt_domains_union: v.opt_domains_union,
             opt_not_domains_union: v.opt_not_domains_union,
+            opt_domains_text: None,
+            list_id: None,
+            unsupported_options: None,
+            unsupported_reason: None,
+            opt_ip_ranges: None,
+            opt_to_domains: None,
+            opt_header: None,
+            fused_ids: None,
             regex: std::sync::Arc::new(std::sync::RwLock::new(None)),
         }
     }
@@ -284,6 +301,15 @@ struct DeserializeFormatRest {
 
     #[serde(default)]
     generic_hide: NetworkFilterListLegacyDeserializeFmt,
+
+    #[serde(default)]
+    specific_extended_rules: HashMap<Hash, Vec<SpecificFilterType>>,
+
+    #[serde(default)]
+    generic_exceptions: HashMap<String, Vec<Option<ListId>>>,
+
+    #[serde(default)]
+    generic_block: NetworkFilterListLegacyDeserializeFmt,
 }
 
 impl<'a> From<(&'a Blocker, &'a CosmeticFilterCache)> for SerializeFormat<'a> {
@@ -320,6 +346,12 @@ impl<'a> From<(&'a Blocker, &'a CosmeticFilterCache)> for SerializeFormat<'a> {
                 scriptlets: &cfc.scriptlets,
 
                 generic_hide: &blocker.generic_hide,
+
+                specific_extended_rules: &cfc.specific_rules,
+
+                generic_exceptions: &cfc.generic_exceptions,
+
+                generic_block: &blocker.generic_block,
             },
         }
     }
@@ -327,6 +359,9 @@ impl<'a> From<(&'a Blocker, &'a CosmeticFilterCache)> for SerializeFormat<'a> {
 
 impl From<DeserializeFormat> for (Blocker, CosmeticFilterCache) {
     fn from(v: DeserializeFormat) -> Self {
+        let mut specific_rules = v.rest.specific_rules;
+        specific_rules.append_extended_rules(v.rest.specific_extended_rules);
+
         (Blocker {
             csp: v.part1.csp.into(),
             exceptions: v.part1.exceptions.into(),
@@ -337,25 +372,38 @@ impl From<DeserializeFormat> for (Blocker, CosmeticFilterCache) {
 
             tags_enabled: Default::default(),
             tagged_filters_all: v.part1.tagged_filters_all.into_iter().map(|f| f.into()).collect(),
+            badfilters: Vec::new(),
 
             enable_optimizations: v.part1.enable_optimizations,
 
             resources: v.part1.resources,
-            #[cfg(feature = "object-pooling")]
             pool: Default::default(),
+            slow_filters: None,
+            bucket_stats: None,
+            redirect_fallback: false,
+            unknown_resource_policy: Default::default(),
+            preserve_redirect_query: false,
+            disabled_lists: Default::default(),
+            stop_tokens: crate::blocker::default_stop_token_hashes(),
+            domain_interner: Default::default(),
 
             generic_hide: v.rest.generic_hide.into(),
+            generic_block: v.rest.generic_block.into(),
         }, CosmeticFilterCache {
             simple_class_rules: v.rest.simple_class_rules,
             simple_id_rules: v.rest.simple_id_rules,
             complex_class_rules: v.rest.complex_class_rules,
             complex_id_rules: v.rest.complex_id_rules,
 
-            specific_rules: v.rest.specific_rules,
+            specific_rules,
 
             misc_generic_selectors: v.rest.misc_generic_selectors,
 
             scriptlets: v.rest.scriptlets,
+
+            generic_rule_lists: Default::default(),
+            generic_exceptions: v.rest.generic_exceptions,
+            disabled_lists: Default::default(),
         })
     }
 }
@@ -3,20 +3,62 @@
 //!
 //! In order to support multiple format versions simultaneously, this module wraps around different
 //! serialization/deserialization implementations and can automatically dispatch to the appropriate
-//! one.
+//! one. Use `inspect` to check whether serialized data can be loaded before deserializing it.
 
 mod legacy;
 mod v0;
+mod network_filter_list;
 
-pub mod utils;
+pub(crate) mod utils;
 
-use crate::blocker::Blocker;
+use std::collections::HashSet;
+
+use crate::blocker::{Blocker, BlockerError};
 use crate::cosmetic_filter_cache::CosmeticFilterCache;
+use crate::html_filter_cache::HtmlFilterCache;
+use crate::lists::ListFingerprint;
+use crate::utils::Hash;
 
 /// Newer formats start with this magic byte sequence.
 /// Calculated as the leading 4 bytes of `echo -n 'brave/adblock-rust' | sha512sum`.
 const ADBLOCK_RUST_DAT_MAGIC: [u8; 4] = [0xd1, 0xd9, 0x3a, 0xaf];
 
+/// Data holding only a cosmetic filter cache starts with this magic byte sequence instead, so that
+/// it can't be mistaken for a whole engine. Calculated as the leading 4 bytes of
+/// `echo -n 'brave/adblock-rust/cosmetic' | sha512sum`.
+const COSMETIC_DAT_MAGIC: [u8; 4] = [0x07, 0xf0, 0x4f, 0x68];
+
+/// adblock-rust has always used flate2 1.0.x for the legacy format, which has never changed the
+/// header sequence from these 10 bits when the GzEncoder is left uncustomized.
+const FLATE2_GZ_HEADER_BYTES: [u8; 10] = [31, 139, 8, 0, 0, 0, 0, 0, 0, 255];
+
+/// Describes serialized engine data, as read from its header by `inspect`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatInfo {
+    /// Version byte following the magic bytes. `None` for the legacy format, which predates
+    /// versioning.
+    pub version: Option<u8>,
+    /// Whether the data is gzip-compressed, which is only the case for the legacy format.
+    pub compressed: bool,
+    /// Whether this build of the crate supports the format. The data may still fail to
+    /// deserialize if it is corrupted.
+    pub loadable: bool,
+}
+
+/// Reads the header of serialized engine data without deserializing any of it, so that embedders
+/// can decide whether to rebuild the engine instead of loading the data. Fails with
+/// `DeserializationError::NoHeaderFound` if the data doesn't start with a known header.
+pub fn inspect(serialized: &[u8]) -> Result<FormatInfo, DeserializationError> {
+    if serialized.starts_with(&FLATE2_GZ_HEADER_BYTES) {
+        Ok(FormatInfo { version: None, compressed: true, loadable: true })
+    } else if serialized.starts_with(&ADBLOCK_RUST_DAT_MAGIC) {
+        let version = *serialized.get(ADBLOCK_RUST_DAT_MAGIC.len()).ok_or(DeserializationError::NoHeaderFound)?;
+        Ok(FormatInfo { version: Some(version), compressed: false, loadable: version == 0 })
+    } else {
+        Err(DeserializationError::NoHeaderFound)
+    }
+}
+
 /// Provides structural aggregration of referenced adblock engine data to allow for allocation-free
 /// serialization.
 ///
@@ -58,12 +100,57 @@ impl From<std::io::Error> for SerializationError {
     fn from(e: std::io::Error) -> Self { Self::GzError(e) }
 }
 
+impl std::fmt::Display for SerializationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::RmpSerdeError(e) => write!(f, "failed to encode serialized data: {}", e),
+            Self::GzError(e) => write!(f, "failed to compress serialized data: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SerializationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::RmpSerdeError(e) => Some(e),
+            Self::GzError(e) => Some(e),
+        }
+    }
+}
+
 impl<'a> SerializeFormat<'a> {
-    pub(crate) fn build(blocker: &'a Blocker, cfc: &'a CosmeticFilterCache, legacy: bool) -> Self {
+    /// HTML filtering rules and list fingerprints are not supported by the legacy format, and will
+    /// be omitted from it.
+    pub(crate) fn build(
+        blocker: &'a Blocker,
+        cfc: &'a CosmeticFilterCache,
+        html: &'a HtmlFilterCache,
+        list_fingerprint: Option<&'a ListFingerprint>,
+        legacy: bool,
+    ) -> Self {
         if legacy {
             Self::Legacy(legacy::SerializeFormat::from((blocker, cfc)))
         } else {
-            Self::V0(v0::SerializeFormat::from((blocker, cfc)))
+            Self::V0(v0::SerializeFormat::from((blocker, cfc, html, list_fingerprint)))
+        }
+    }
+
+    /// Leaves out redirect and scriptlet resources, so that they can be supplied separately when
+    /// deserializing. The data records that they were left out. Resources are always included in
+    /// the legacy format.
+    pub(crate) fn without_resources(self) -> Self {
+        match self {
+            Self::Legacy(v) => Self::Legacy(v),
+            Self::V0(v) => Self::V0(v.without_resources()),
+        }
+    }
+
+    /// Stores the hashes of allowlisted domains. The allowlist is not supported by the legacy
+    /// format, and will be omitted from it.
+    pub(crate) fn with_allowlist(self, allowlist: &'a HashSet<Hash>) -> Self {
+        match self {
+            Self::Legacy(v) => Self::Legacy(v),
+            Self::V0(v) => Self::V0(v.with_allowlist(allowlist)),
         }
     }
 
@@ -80,6 +167,9 @@ impl<'a> SerializeFormat<'a> {
 ///
 /// Note that this does not implement `Deserialize` directly, as it is composed of parts which must
 /// be deserialized independently. Instead, use the `deserialize` method.
+///
+/// Only ever built temporarily while deserializing, so the size of its variants doesn't matter.
+#[allow(clippy::large_enum_variant)]
 pub(crate) enum DeserializeFormat {
     Legacy(legacy::DeserializeFormat),
     V0(v0::DeserializeFormat),
@@ -88,10 +178,36 @@ pub(crate) enum DeserializeFormat {
 #[derive(Debug)]
 pub enum DeserializationError {
     RmpSerdeError(rmp_serde::decode::Error),
+    /// Decoding failed partway through the data, e.g. because it was truncated or corrupted.
+    /// `offset` is the position in the serialized data at which decoding stopped.
+    Corrupted {
+        offset: usize,
+        error: rmp_serde::decode::Error,
+    },
     UnsupportedFormatVersion(u8),
     NoHeaderFound,
 }
 
+impl std::fmt::Display for DeserializationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::RmpSerdeError(e) => write!(f, "failed to decode serialized data: {}", e),
+            Self::Corrupted { offset, error } => write!(f, "failed to decode serialized data at byte {}: {}", offset, error),
+            Self::UnsupportedFormatVersion(v) => write!(f, "unsupported serialized data format version {}", v),
+            Self::NoHeaderFound => write!(f, "serialized data has no recognized header"),
+        }
+    }
+}
+
+impl std::error::Error for DeserializationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::RmpSerdeError(e) | Self::Corrupted { error: e, .. } => Some(e),
+            Self::UnsupportedFormatVersion(_) | Self::NoHeaderFound => None,
+        }
+    }
+}
+
 /// Since two different versions of `rmp-serde` are being used, errors must be converted to a
 /// single implementation.
 impl From<rmp_serde_legacy::decode::Error> for DeserializationError {
@@ -118,33 +234,155 @@ impl From<rmp_serde::decode::Error> for DeserializationError {
     fn from(e: rmp_serde::decode::Error) -> Self { Self::RmpSerdeError(e) }
 }
 
+/// Keeps where corrupted data stopped decoding, so that callers of `Engine::deserialize` can tell
+/// corrupted data apart from data that was never serialized by this crate.
+impl From<DeserializationError> for BlockerError {
+    fn from(e: DeserializationError) -> Self {
+        match e {
+            DeserializationError::Corrupted { offset, error } => Self::CorruptedData { offset, message: error.to_string() },
+            _ => Self::DeserializationError,
+        }
+    }
+}
+
 impl DeserializeFormat {
-    pub(crate) fn build(self) -> (Blocker, CosmeticFilterCache) {
+    pub(crate) fn build(self) -> (Blocker, CosmeticFilterCache, HtmlFilterCache) {
         match self {
-            Self::Legacy(v) => v.into(),
+            Self::Legacy(v) => {
+                let (mut blocker, cfc): (Blocker, CosmeticFilterCache) = v.into();
+                blocker.restore_tagged_filters();
+                (blocker, cfc, HtmlFilterCache::new())
+            }
             Self::V0(v) => v.into(),
         }
     }
 
-    pub(crate) fn deserialize(serialized: &[u8]) -> Result<Self, DeserializationError> {
-        /// adblock-rust has always used flate2 1.0.x for the legacy format, which has never
-        /// changed the header sequence from these 10 bits when the GzEncoder is left uncustomized.
-        const FLATE2_GZ_HEADER_BYTES: [u8; 10] = [31, 139, 8, 0, 0, 0, 0, 0, 0, 255];
-
-        if serialized.starts_with(&FLATE2_GZ_HEADER_BYTES) {
-            Ok(Self::Legacy(legacy::DeserializeFormat::deserialize(serialized)?))
-        } else if serialized.starts_with(&ADBLOCK_RUST_DAT_MAGIC) {
-            let version = serialized[ADBLOCK_RUST_DAT_MAGIC.len()];
-            match version {
-                0 => Ok(Self::V0(v0::DeserializeFormat::deserialize(serialized)?)),
-                v => Err(DeserializationError::UnsupportedFormatVersion(v)),
-            }
+    /// Takes the list fingerprint stored with the serialized data, if any.
+    pub(crate) fn take_list_fingerprint(&mut self) -> Option<ListFingerprint> {
+        match self {
+            Self::Legacy(_) => None,
+            Self::V0(v) => v.list_fingerprint.take(),
+        }
+    }
+
+    /// Takes the allowlisted domain hashes stored with the serialized data, if any.
+    pub(crate) fn take_allowlist(&mut self) -> HashSet<Hash> {
+        match self {
+            Self::Legacy(_) => HashSet::new(),
+            Self::V0(v) => std::mem::take(&mut v.allowlist),
+        }
+    }
+
+    /// Whether the data was serialized without resources. See `SerializeFormat::without_resources`.
+    pub(crate) fn resources_excluded(&self) -> bool {
+        match self {
+            Self::Legacy(_) => false,
+            Self::V0(v) => v.resources_excluded,
+        }
+    }
+
+    /// Deserializes only the network filtering data, for embedders that don't use cosmetic
+    /// filtering. Cosmetic and HTML filtering data is skipped over in the current format, but the
+    /// legacy format must still be decoded in full, since it is compressed.
+    ///
+    /// Also returns the stored list fingerprint, whether resources were left out, and the stored
+    /// allowlist.
+    pub(crate) fn deserialize_network_only(serialized: &[u8]) -> Result<(Blocker, Option<ListFingerprint>, bool, HashSet<Hash>), DeserializationError> {
+        if serialized.starts_with(&ADBLOCK_RUST_DAT_MAGIC) && serialized.get(ADBLOCK_RUST_DAT_MAGIC.len()) == Some(&0) {
+            let mut format = v0::NetworkOnlyDeserializeFormat::deserialize(serialized)?;
+            let list_fingerprint = format.list_fingerprint.take();
+            let resources_excluded = format.resources_excluded;
+            let allowlist = std::mem::take(&mut format.allowlist);
+            Ok((format.into(), list_fingerprint, resources_excluded, allowlist))
         } else {
-            Err(DeserializationError::NoHeaderFound)
+            let mut format = Self::deserialize(serialized)?;
+            let list_fingerprint = format.take_list_fingerprint();
+            let resources_excluded = format.resources_excluded();
+            let allowlist = format.take_allowlist();
+            Ok((format.build().0, list_fingerprint, resources_excluded, allowlist))
+        }
+    }
+
+    pub(crate) fn deserialize(serialized: &[u8]) -> Result<Self, DeserializationError> {
+        match inspect(serialized)?.version {
+            None => Ok(Self::Legacy(legacy::DeserializeFormat::deserialize(serialized)?)),
+            Some(0) => Ok(Self::V0(v0::DeserializeFormat::deserialize(serialized)?)),
+            Some(v) => Err(DeserializationError::UnsupportedFormatVersion(v)),
+        }
+    }
+}
+
+/// Counts of filters and rules in each part of an engine, which must be preserved by a
+/// serialization round-trip. Each count is named, to tell which part diverged. `filters_tagged`
+/// is left out, since it is rebuilt from `tagged_filters_all` for the tags enabled after
+/// deserialization.
+fn round_trip_invariants(blocker: &Blocker, cfc: &CosmeticFilterCache) -> Vec<(&'static str, usize)> {
+    let mut invariants = vec![];
+    for (name, list) in [
+        ("csp", &blocker.csp),
+        ("exceptions", &blocker.exceptions),
+        ("importants", &blocker.importants),
+        ("redirects", &blocker.redirects),
+        ("filters", &blocker.filters),
+        ("generic_hide", &blocker.generic_hide),
+        ("generic_block", &blocker.generic_block),
+    ] {
+        invariants.push((name, list.filter_map.len()));
+        invariants.push((name, list.filter_map.values().map(Vec::len).sum()));
+    }
+    let (specific_hostnames, specific_rules) = cfc.specific_rules.rule_counts();
+    invariants.extend([
+        ("tagged_filters_all", blocker.tagged_filters_all.len()),
+        ("simple_class_rules", cfc.simple_class_rules.len()),
+        ("simple_id_rules", cfc.simple_id_rules.len()),
+        ("complex_class_rules", cfc.complex_class_rules.values().map(Vec::len).sum()),
+        ("complex_id_rules", cfc.complex_id_rules.values().map(Vec::len).sum()),
+        ("misc_generic_selectors", cfc.misc_generic_selectors.len()),
+        ("generic_exceptions", cfc.generic_exceptions.len()),
+        ("specific_hostnames", specific_hostnames),
+        ("specific_rules", specific_rules),
+    ]);
+    invariants
+}
+
+/// In debug builds, deserializes data that was just serialized from `blocker` and `cfc` by
+/// `SerializeFormat::serialize`, and panics if it can't be loaded or if any bucket or rule count
+/// differs, to catch format regressions early. Does nothing in release builds.
+pub(crate) fn debug_check_round_trip(blocker: &Blocker, cfc: &CosmeticFilterCache, serialized: &[u8]) {
+    if cfg!(debug_assertions) {
+        let deserialized = match DeserializeFormat::deserialize(serialized) {
+            Ok(deserialized) => deserialized,
+            Err(e) => panic!("Serialized engine could not be deserialized: {}", e),
+        };
+        let (deserialized_blocker, deserialized_cfc, _) = deserialized.build();
+        let expected = round_trip_invariants(blocker, cfc);
+        let actual = round_trip_invariants(&deserialized_blocker, &deserialized_cfc);
+        for ((name, expected), (_, actual)) in expected.iter().zip(actual.iter()) {
+            assert_eq!(expected, actual, "Serialization round-trip changed the size of `{}`", name);
         }
     }
 }
 
+/// Serializes a cosmetic filter cache on its own, so that it can be stored and loaded separately
+/// from the network blocker. The data has its own header, followed by a version byte like the
+/// current engine format.
+pub(crate) fn serialize_cosmetic(cfc: &CosmeticFilterCache) -> Result<Vec<u8>, SerializationError> {
+    v0::CosmeticSerializeFormat::from(cfc).serialize()
+}
+
+/// Deserializes a cosmetic filter cache serialized by `serialize_cosmetic`. Fails with
+/// `DeserializationError::NoHeaderFound` for any other data, including whole engines.
+pub(crate) fn deserialize_cosmetic(serialized: &[u8]) -> Result<CosmeticFilterCache, DeserializationError> {
+    if !serialized.starts_with(&COSMETIC_DAT_MAGIC) {
+        return Err(DeserializationError::NoHeaderFound);
+    }
+    match serialized.get(COSMETIC_DAT_MAGIC.len()) {
+        Some(0) => Ok(v0::CosmeticDeserializeFormat::deserialize(serialized)?.into()),
+        Some(v) => Err(DeserializationError::UnsupportedFormatVersion(*v)),
+        None => Err(DeserializationError::NoHeaderFound),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -160,5 +398,133 @@ mod tests {
         let result = hasher.finalize();
 
         assert!(result.starts_with(&ADBLOCK_RUST_DAT_MAGIC));
+
+        let mut hasher = sha2::Sha512::new();
+        hasher.update("brave/adblock-rust/cosmetic");
+        assert!(hasher.finalize().starts_with(&COSMETIC_DAT_MAGIC));
+    }
+
+    fn round_trip_engine(rules: &[&str]) -> (Blocker, CosmeticFilterCache) {
+        let rules = rules.iter().map(|rule| rule.to_string()).collect::<Vec<_>>();
+        let (network_filters, cosmetic_filters) = crate::lists::parse_filters(&rules, false, Default::default());
+        let blocker = Blocker::new(network_filters, &crate::blocker::BlockerOptions { enable_optimizations: true });
+        (blocker, CosmeticFilterCache::from_rules(cosmetic_filters))
+    }
+
+    const ROUND_TRIP_RULES: &[&str] = &[
+        "||ads.example.com^",
+        "@@||ads.example.com/ok^",
+        "||tracker.net^$important",
+        "||example.net^$csp=script-src 'none'",
+        "##.ad",
+        "example.com##.banner",
+        "example.com##.banner:remove()",
+    ];
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn round_trip_check_passes() {
+        let (blocker, cfc) = round_trip_engine(ROUND_TRIP_RULES);
+        let serialized = SerializeFormat::build(&blocker, &cfc, &HtmlFilterCache::new(), None, false).serialize().unwrap();
+        debug_check_round_trip(&blocker, &cfc, &serialized);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Serialization round-trip changed the size of `specific_rules`")]
+    fn round_trip_check_detects_lost_rules() {
+        let (blocker, cfc) = round_trip_engine(ROUND_TRIP_RULES);
+        let (_, lossy_cfc) = round_trip_engine(&ROUND_TRIP_RULES[..ROUND_TRIP_RULES.len() - 1]);
+        let serialized = SerializeFormat::build(&blocker, &lossy_cfc, &HtmlFilterCache::new(), None, false).serialize().unwrap();
+        debug_check_round_trip(&blocker, &cfc, &serialized);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Serialized engine could not be deserialized")]
+    fn round_trip_check_detects_corruption() {
+        let (blocker, cfc) = round_trip_engine(ROUND_TRIP_RULES);
+        let serialized = SerializeFormat::build(&blocker, &cfc, &HtmlFilterCache::new(), None, false).serialize().unwrap();
+        debug_check_round_trip(&blocker, &cfc, &serialized[..serialized.len() / 2]);
+    }
+
+    #[test]
+    fn inspect_header() {
+        let (network_filters, _) = crate::lists::parse_filters(&["||ads.example.com^".to_owned()], false, Default::default());
+        let blocker = Blocker::new(network_filters, &crate::blocker::BlockerOptions { enable_optimizations: true });
+        let mut serialized = SerializeFormat::build(&blocker, &CosmeticFilterCache::new(), &HtmlFilterCache::new(), None, false).serialize().unwrap();
+
+        assert_eq!(inspect(&serialized).unwrap(), FormatInfo { version: Some(0), compressed: false, loadable: true });
+
+        serialized[ADBLOCK_RUST_DAT_MAGIC.len()] = 7;
+        assert_eq!(inspect(&serialized).unwrap(), FormatInfo { version: Some(7), compressed: false, loadable: false });
+        assert!(matches!(DeserializeFormat::deserialize(&serialized), Err(DeserializationError::UnsupportedFormatVersion(7))));
+
+        assert_eq!(inspect(&FLATE2_GZ_HEADER_BYTES).unwrap(), FormatInfo { version: None, compressed: true, loadable: true });
+        assert!(matches!(inspect(&ADBLOCK_RUST_DAT_MAGIC), Err(DeserializationError::NoHeaderFound)));
+        assert!(matches!(inspect(b"[Adblock Plus 2.0]"), Err(DeserializationError::NoHeaderFound)));
+    }
+
+    #[test]
+    fn tagged_filters_restored() {
+        let (network_filters, _) = crate::lists::parse_filters(&[
+            "||ads.example.com^$tag=annoyances".to_owned(),
+            "||tracker.example.com^$tag=other".to_owned(),
+        ], false, Default::default());
+        let mut blocker = Blocker::new(network_filters, &crate::blocker::BlockerOptions { enable_optimizations: true });
+        blocker.use_tags(&["annoyances"]);
+
+        let request = |url: &str| crate::request::Request::from_urls(url, "https://example.com", "script").unwrap();
+        for legacy in [false, true].iter() {
+            let serialized = SerializeFormat::build(&blocker, &CosmeticFilterCache::new(), &HtmlFilterCache::new(), None, *legacy).serialize().unwrap();
+            let (mut deserialized, _, _) = DeserializeFormat::deserialize(&serialized).unwrap().build();
+
+            assert_eq!(deserialized.tagged_filters_all.iter().map(|f| f.tag.as_deref()).collect::<Vec<_>>(), vec![Some("annoyances"), Some("other")]);
+            assert!(deserialized.filters_tagged.filter_map.is_empty());
+            assert!(!deserialized.check(&request("https://ads.example.com/ad.js")).matched);
+
+            deserialized.use_tags(&["annoyances"]);
+            assert!(deserialized.check(&request("https://ads.example.com/ad.js")).matched);
+            assert!(!deserialized.check(&request("https://tracker.example.com/t.js")).matched);
+        }
+    }
+
+    #[test]
+    fn corrupted_data_reports_offset() {
+        let (network_filters, cosmetic_filters) = crate::lists::parse_filters(&[
+            "||ads.example.com^".to_owned(),
+            "/banner/*/img^".to_owned(),
+            "example.com##.ad".to_owned(),
+        ], false, Default::default());
+        let blocker = Blocker::new(network_filters, &crate::blocker::BlockerOptions { enable_optimizations: true });
+        let cfc = CosmeticFilterCache::from_rules(cosmetic_filters);
+        let html = HtmlFilterCache::new();
+        let mut serialized = SerializeFormat::build(&blocker, &cfc, &html, None, false).serialize().unwrap();
+
+        // 0xc1 is never used as a MessagePack marker, but corrupting string contents goes
+        // unnoticed, so look for the first position past the middle where it's detected.
+        let corrupted_at = (serialized.len() / 2..serialized.len()).find(|i| {
+            let mut corrupted = serialized.clone();
+            corrupted[*i] = 0xc1;
+            DeserializeFormat::deserialize(&corrupted).is_err()
+        }).unwrap();
+        let mut corrupted = serialized.clone();
+        corrupted[corrupted_at] = 0xc1;
+        let error = DeserializeFormat::deserialize(&corrupted).err().unwrap();
+        let message = error.to_string();
+        match error {
+            DeserializationError::Corrupted { offset, .. } => {
+                assert!(offset > corrupted_at && offset <= serialized.len());
+                assert_eq!(message.split(':').next(), Some(format!("failed to decode serialized data at byte {}", offset).as_str()));
+            }
+            e => panic!("Expected a corrupted data error, got {:?}", e),
+        }
+
+        serialized.truncate(corrupted_at);
+        match DeserializeFormat::deserialize(&serialized) {
+            Err(DeserializationError::Corrupted { offset, .. }) => assert_eq!(offset, corrupted_at),
+            Err(e) => panic!("Expected a corrupted data error, got {:?}", e),
+            Ok(_) => panic!("Expected truncated data to fail to deserialize"),
+        }
     }
 }
@@ -0,0 +1,177 @@
+//! Versioned serialization adapter for `NetworkFilterList`.
+//!
+//! `NetworkFilterList` is embedded in every engine format, so its layout is versioned separately
+//! from the engine. This allows its internal representation to evolve without requiring a new
+//! format for the entire `Engine`.
+//!
+//! Versioned lists are serialized as a sequence starting with a `u8` version tag followed by the
+//! version-specific fields. Lists serialized before the tag was introduced consist of a single
+//! map element, and are treated as version 0.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{SerializeTuple, Serializer};
+use serde::{Deserialize, Serialize};
+
+use crate::blocker::NetworkFilterList;
+use crate::filters::network::NetworkFilter;
+use crate::utils::Hash;
+
+use super::utils::stabilize_hashmap_serialization;
+
+/// Version tag written by the current `NetworkFilterList` serializer.
+pub(crate) const NETWORK_FILTER_LIST_FORMAT_VERSION: u8 = 1;
+
+type FilterMap = HashMap<Hash, Vec<Arc<NetworkFilter>>>;
+
+/// Serializes the filter map of a `NetworkFilterList` with stable ordering.
+struct StableFilterMap<'a>(&'a FilterMap);
+
+impl<'a> Serialize for StableFilterMap<'a> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        stabilize_hashmap_serialization(self.0, s)
+    }
+}
+
+impl Serialize for NetworkFilterList {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let mut tuple = s.serialize_tuple(2)?;
+        tuple.serialize_element(&NETWORK_FILTER_LIST_FORMAT_VERSION)?;
+        tuple.serialize_element(&StableFilterMap(&self.filter_map))?;
+        tuple.end()
+    }
+}
+
+/// The first element of a serialized `NetworkFilterList` is either a version tag, or the filter
+/// map itself for unversioned lists.
+enum ListHead {
+    Version(u8),
+    Unversioned(FilterMap),
+}
+
+impl<'de> Deserialize<'de> for ListHead {
+    fn deserialize<D>(d: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        struct ListHeadVisitor(PhantomData<ListHead>);
+
+        impl<'de> Visitor<'de> for ListHeadVisitor {
+            type Value = ListHead;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a NetworkFilterList version tag or filter map")
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<ListHead, E> where E: de::Error {
+                if v > u8::MAX as u64 {
+                    return Err(E::invalid_value(de::Unexpected::Unsigned(v), &self));
+                }
+                Ok(ListHead::Version(v as u8))
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<ListHead, A::Error> where A: MapAccess<'de> {
+                let mut filter_map = FilterMap::with_capacity(map.size_hint().unwrap_or(0));
+                while let Some((k, v)) = map.next_entry()? {
+                    filter_map.insert(k, v);
+                }
+                Ok(ListHead::Unversioned(filter_map))
+            }
+        }
+
+        d.deserialize_any(ListHeadVisitor(PhantomData))
+    }
+}
+
+impl<'de> Deserialize<'de> for NetworkFilterList {
+    fn deserialize<D>(d: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        struct NetworkFilterListVisitor;
+
+        impl<'de> Visitor<'de> for NetworkFilterListVisitor {
+            type Value = NetworkFilterList;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a serialized NetworkFilterList")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<NetworkFilterList, A::Error> where A: SeqAccess<'de> {
+                let head: ListHead = seq.next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+
+                let filter_map = match head {
+                    ListHead::Unversioned(filter_map) => filter_map,
+                    ListHead::Version(1) => seq.next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(1, &self))?,
+                    ListHead::Version(v) => return Err(de::Error::custom(
+                        format!("unsupported NetworkFilterList format version {}", v)
+                    )),
+                };
+
+                Ok(NetworkFilterList { filter_map })
+            }
+        }
+
+        d.deserialize_seq(NetworkFilterListVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Matches the derived representation of `NetworkFilterList` used before it was versioned.
+    #[derive(Serialize)]
+    struct UnversionedNetworkFilterList {
+        #[serde(serialize_with = "stabilize_hashmap_serialization")]
+        filter_map: FilterMap,
+    }
+
+    fn sample_filter_map() -> FilterMap {
+        let mut filter_map = FilterMap::new();
+        let filter = NetworkFilter::parse("||example.com^$script", true, Default::default()).unwrap();
+        filter_map.insert(crate::utils::fast_hash("example"), vec![Arc::new(filter)]);
+        filter_map
+    }
+
+    fn assert_same_filters(a: &FilterMap, b: &FilterMap) {
+        assert_eq!(a.len(), b.len());
+        for (k, filters) in a {
+            let other = b.get(k).expect("token should be present");
+            let ids: Vec<_> = filters.iter().map(|f| f.get_id()).collect();
+            let other_ids: Vec<_> = other.iter().map(|f| f.get_id()).collect();
+            assert_eq!(ids, other_ids);
+        }
+    }
+
+    #[test]
+    fn roundtrip_versioned() {
+        let list = NetworkFilterList { filter_map: sample_filter_map() };
+        let serialized = rmp_serde::to_vec(&list).unwrap();
+        let deserialized: NetworkFilterList = rmp_serde::from_read_ref(&serialized).unwrap();
+        assert_same_filters(&list.filter_map, &deserialized.filter_map);
+    }
+
+    #[test]
+    fn deserialize_unversioned() {
+        let old = UnversionedNetworkFilterList { filter_map: sample_filter_map() };
+        let serialized = rmp_serde::to_vec(&old).unwrap();
+        let deserialized: NetworkFilterList = rmp_serde::from_read_ref(&serialized).unwrap();
+        assert_same_filters(&old.filter_map, &deserialized.filter_map);
+    }
+
+    #[test]
+    fn deserialize_empty_unversioned() {
+        let old = UnversionedNetworkFilterList { filter_map: FilterMap::new() };
+        let serialized = rmp_serde::to_vec(&old).unwrap();
+        let deserialized: NetworkFilterList = rmp_serde::from_read_ref(&serialized).unwrap();
+        assert!(deserialized.filter_map.is_empty());
+    }
+
+    #[test]
+    fn reject_unknown_version() {
+        let serialized = rmp_serde::to_vec(&(255u8, FilterMap::new())).unwrap();
+        let deserialized: Result<NetworkFilterList, _> = rmp_serde::from_read_ref(&serialized);
+        assert!(deserialized.is_err());
+    }
+}
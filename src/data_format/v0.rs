@@ -3,16 +3,23 @@
 //! the actual `Engine` components.
 //!
 //! Any new fields should be added to the _end_ of both `SerializeFormat` and `DeserializeFormat`.
+//! New fields of `DeserializeFormat` need `#[serde(default)]`, so that data serialized before they
+//! were added can still be deserialized with default values for them.
 
 use std::collections::{HashSet, HashMap};
 
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use serde::de::IgnoredAny;
 use rmp_serde as rmps;
 
 use crate::blocker::{Blocker, NetworkFilterList};
 use crate::resources::{RedirectResourceStorage, ScriptletResourceStorage};
-use crate::filters::network::NetworkFilter;
-use crate::cosmetic_filter_cache::{CosmeticFilterCache, HostnameRuleDb};
+use crate::filters::network::{DomainInterner, NetworkFilter};
+use crate::cosmetic_filter_cache::{CosmeticFilterCache, HostnameRuleDb, SpecificFilterType};
+use crate::html_filter_cache::HtmlFilterCache;
+use crate::lists::{ListFingerprint, ListId};
+use crate::utils::Hash;
 
 use super::{DeserializationError, SerializationError};
 use super::utils::{stabilize_hashmap_serialization, stabilize_hashset_serialization};
@@ -50,9 +57,55 @@ pub(crate) struct SerializeFormat<'a> {
     misc_generic_selectors: &'a HashSet<String>,
 
     scriptlets: &'a ScriptletResourceStorage,
+
+    html_filters: &'a HtmlFilterCache,
+
+    #[serde(serialize_with = "stabilize_hashmap_serialization")]
+    generic_rule_lists: &'a HashMap<String, Vec<Option<ListId>>>,
+    #[serde(serialize_with = "HostnameRuleDb::serialize_lists")]
+    specific_rule_lists: &'a HostnameRuleDb,
+
+    #[serde(serialize_with = "HostnameRuleDb::serialize_extended_rules")]
+    specific_extended_rules: &'a HostnameRuleDb,
+
+    #[serde(serialize_with = "stabilize_hashmap_serialization")]
+    generic_exceptions: &'a HashMap<String, Vec<Option<ListId>>>,
+
+    generic_block: &'a NetworkFilterList,
+
+    list_fingerprint: Option<&'a ListFingerprint>,
+
+    resources_excluded: bool,
+
+    #[serde(serialize_with = "stabilize_hashset_serialization")]
+    allowlist: &'a HashSet<Hash>,
+
+    badfilters: &'a Vec<NetworkFilter>,
 }
 
+static NO_ALLOWLIST: Lazy<HashSet<Hash>> = Lazy::new(HashSet::new);
+static NO_RESOURCES: Lazy<RedirectResourceStorage> = Lazy::new(RedirectResourceStorage::default);
+static NO_SCRIPTLETS: Lazy<ScriptletResourceStorage> = Lazy::new(ScriptletResourceStorage::default);
+
 impl<'a> SerializeFormat<'a> {
+    /// Leaves out redirect and scriptlet resources, and marks them as absent.
+    pub fn without_resources(self) -> Self {
+        Self {
+            resources: &NO_RESOURCES,
+            scriptlets: &NO_SCRIPTLETS,
+            resources_excluded: true,
+            ..self
+        }
+    }
+
+    /// Stores the hashes of the engine's allowlisted domains.
+    pub fn with_allowlist(self, allowlist: &'a HashSet<Hash>) -> Self {
+        Self {
+            allowlist,
+            ..self
+        }
+    }
+
     pub fn serialize(&self) -> Result<Vec<u8>, SerializationError> {
         let mut output = super::ADBLOCK_RUST_DAT_MAGIC.to_vec();
         output.push(0);
@@ -89,20 +142,295 @@ pub(crate) struct DeserializeFormat {
     misc_generic_selectors: HashSet<String>,
 
     scriptlets: ScriptletResourceStorage,
+
+    #[serde(default)]
+    html_filters: HtmlFilterCache,
+
+    #[serde(default)]
+    generic_rule_lists: HashMap<String, Vec<Option<ListId>>>,
+    #[serde(default)]
+    specific_rule_lists: HashMap<Hash, Vec<Option<ListId>>>,
+
+    #[serde(default)]
+    specific_extended_rules: HashMap<Hash, Vec<SpecificFilterType>>,
+
+    #[serde(default)]
+    generic_exceptions: HashMap<String, Vec<Option<ListId>>>,
+
+    #[serde(default)]
+    generic_block: NetworkFilterList,
+
+    #[serde(default)]
+    pub(super) list_fingerprint: Option<ListFingerprint>,
+
+    #[serde(default)]
+    pub(super) resources_excluded: bool,
+
+    #[serde(default)]
+    pub(super) allowlist: HashSet<Hash>,
+
+    #[serde(default)]
+    badfilters: Vec<NetworkFilter>,
+
+    // Holds the domains shared between the deserialized filters.
+    #[serde(skip)]
+    domain_interner: DomainInterner,
 }
 
 impl DeserializeFormat {
     pub fn deserialize(serialized: &[u8]) -> Result<Self, DeserializationError> {
         assert!(serialized.starts_with(&super::ADBLOCK_RUST_DAT_MAGIC));
         assert!(serialized[super::ADBLOCK_RUST_DAT_MAGIC.len()] == 0);
-        let format: Self = rmps::decode::from_read(&serialized[super::ADBLOCK_RUST_DAT_MAGIC.len() + 1..])?;
+        let mut remaining = &serialized[super::ADBLOCK_RUST_DAT_MAGIC.len() + 1..];
+        let (format, domain_interner) = DomainInterner::deserializing(|| rmps::decode::from_read::<_, Self>(&mut remaining));
+        let mut format = format.map_err(|error| DeserializationError::Corrupted {
+            offset: serialized.len() - remaining.len(),
+            error,
+        })?;
+        format.domain_interner = domain_interner;
         Ok(format)
     }
 }
 
-impl<'a> From<(&'a Blocker, &'a CosmeticFilterCache)> for SerializeFormat<'a> {
-    fn from(v: (&'a Blocker, &'a CosmeticFilterCache)) -> Self {
-        let (blocker, cfc) = v;
+/// Same layout as `DeserializeFormat`, but skips over all cosmetic and HTML filtering data
+/// without keeping it in memory.
+#[derive(Deserialize)]
+pub(crate) struct NetworkOnlyDeserializeFormat {
+    csp: NetworkFilterList,
+    exceptions: NetworkFilterList,
+    importants: NetworkFilterList,
+    redirects: NetworkFilterList,
+    filters_tagged: NetworkFilterList,
+    filters: NetworkFilterList,
+    generic_hide: NetworkFilterList,
+
+    tagged_filters_all: Vec<NetworkFilter>,
+
+    enable_optimizations: bool,
+
+    resources: RedirectResourceStorage,
+
+    _simple_class_rules: IgnoredAny,
+    _simple_id_rules: IgnoredAny,
+    _complex_class_rules: IgnoredAny,
+    _complex_id_rules: IgnoredAny,
+
+    _specific_rules: IgnoredAny,
+
+    _misc_generic_selectors: IgnoredAny,
+
+    _scriptlets: IgnoredAny,
+
+    #[serde(default)]
+    _html_filters: IgnoredAny,
+
+    #[serde(default)]
+    _generic_rule_lists: IgnoredAny,
+    #[serde(default)]
+    _specific_rule_lists: IgnoredAny,
+
+    #[serde(default)]
+    _specific_extended_rules: IgnoredAny,
+
+    #[serde(default)]
+    _generic_exceptions: IgnoredAny,
+
+    #[serde(default)]
+    generic_block: NetworkFilterList,
+
+    #[serde(default)]
+    pub(super) list_fingerprint: Option<ListFingerprint>,
+
+    #[serde(default)]
+    pub(super) resources_excluded: bool,
+
+    #[serde(default)]
+    pub(super) allowlist: HashSet<Hash>,
+
+    #[serde(default)]
+    badfilters: Vec<NetworkFilter>,
+
+    // Holds the domains shared between the deserialized filters.
+    #[serde(skip)]
+    domain_interner: DomainInterner,
+}
+
+impl NetworkOnlyDeserializeFormat {
+    pub fn deserialize(serialized: &[u8]) -> Result<Self, DeserializationError> {
+        assert!(serialized.starts_with(&super::ADBLOCK_RUST_DAT_MAGIC));
+        assert!(serialized[super::ADBLOCK_RUST_DAT_MAGIC.len()] == 0);
+        let mut remaining = &serialized[super::ADBLOCK_RUST_DAT_MAGIC.len() + 1..];
+        let (format, domain_interner) = DomainInterner::deserializing(|| rmps::decode::from_read::<_, Self>(&mut remaining));
+        let mut format = format.map_err(|error| DeserializationError::Corrupted {
+            offset: serialized.len() - remaining.len(),
+            error,
+        })?;
+        format.domain_interner = domain_interner;
+        Ok(format)
+    }
+}
+
+impl From<NetworkOnlyDeserializeFormat> for Blocker {
+    fn from(v: NetworkOnlyDeserializeFormat) -> Self {
+        let mut blocker = Blocker {
+            csp: v.csp,
+            exceptions: v.exceptions,
+            importants: v.importants,
+            redirects: v.redirects,
+            filters_tagged: v.filters_tagged,
+            filters: v.filters,
+            generic_hide: v.generic_hide,
+            generic_block: v.generic_block,
+
+            tags_enabled: Default::default(),
+            tagged_filters_all: v.tagged_filters_all,
+            badfilters: v.badfilters,
+
+            enable_optimizations: v.enable_optimizations,
+
+            resources: v.resources,
+            pool: Default::default(),
+            slow_filters: None,
+            bucket_stats: None,
+            redirect_fallback: false,
+            unknown_resource_policy: Default::default(),
+            preserve_redirect_query: false,
+            disabled_lists: Default::default(),
+            stop_tokens: crate::blocker::default_stop_token_hashes(),
+            domain_interner: v.domain_interner,
+        };
+        blocker.restore_tagged_filters();
+        blocker
+    }
+}
+
+/// Cosmetic filtering data on its own, laid out like the corresponding fields of
+/// `SerializeFormat`. See `super::serialize_cosmetic`.
+#[derive(Serialize)]
+pub(crate) struct CosmeticSerializeFormat<'a> {
+    #[serde(serialize_with = "stabilize_hashset_serialization")]
+    simple_class_rules: &'a HashSet<String>,
+    #[serde(serialize_with = "stabilize_hashset_serialization")]
+    simple_id_rules: &'a HashSet<String>,
+    #[serde(serialize_with = "stabilize_hashmap_serialization")]
+    complex_class_rules: &'a HashMap<String, Vec<String>>,
+    #[serde(serialize_with = "stabilize_hashmap_serialization")]
+    complex_id_rules: &'a HashMap<String, Vec<String>>,
+
+    specific_rules: &'a HostnameRuleDb,
+
+    #[serde(serialize_with = "stabilize_hashset_serialization")]
+    misc_generic_selectors: &'a HashSet<String>,
+
+    scriptlets: &'a ScriptletResourceStorage,
+
+    #[serde(serialize_with = "stabilize_hashmap_serialization")]
+    generic_rule_lists: &'a HashMap<String, Vec<Option<ListId>>>,
+    #[serde(serialize_with = "HostnameRuleDb::serialize_lists")]
+    specific_rule_lists: &'a HostnameRuleDb,
+
+    #[serde(serialize_with = "HostnameRuleDb::serialize_extended_rules")]
+    specific_extended_rules: &'a HostnameRuleDb,
+
+    #[serde(serialize_with = "stabilize_hashmap_serialization")]
+    generic_exceptions: &'a HashMap<String, Vec<Option<ListId>>>,
+}
+
+impl CosmeticSerializeFormat<'_> {
+    pub fn serialize(&self) -> Result<Vec<u8>, SerializationError> {
+        let mut output = super::COSMETIC_DAT_MAGIC.to_vec();
+        output.push(0);
+        rmps::encode::write(&mut output, &self)?;
+        Ok(output)
+    }
+}
+
+/// Deserialized counterpart of `CosmeticSerializeFormat`.
+#[derive(Deserialize)]
+pub(crate) struct CosmeticDeserializeFormat {
+    simple_class_rules: HashSet<String>,
+    simple_id_rules: HashSet<String>,
+    complex_class_rules: HashMap<String, Vec<String>>,
+    complex_id_rules: HashMap<String, Vec<String>>,
+
+    specific_rules: HostnameRuleDb,
+
+    misc_generic_selectors: HashSet<String>,
+
+    scriptlets: ScriptletResourceStorage,
+
+    generic_rule_lists: HashMap<String, Vec<Option<ListId>>>,
+    specific_rule_lists: HashMap<Hash, Vec<Option<ListId>>>,
+
+    specific_extended_rules: HashMap<Hash, Vec<SpecificFilterType>>,
+
+    generic_exceptions: HashMap<String, Vec<Option<ListId>>>,
+}
+
+impl CosmeticDeserializeFormat {
+    pub fn deserialize(serialized: &[u8]) -> Result<Self, DeserializationError> {
+        assert!(serialized.starts_with(&super::COSMETIC_DAT_MAGIC));
+        assert!(serialized[super::COSMETIC_DAT_MAGIC.len()] == 0);
+        let mut remaining = &serialized[super::COSMETIC_DAT_MAGIC.len() + 1..];
+        rmps::decode::from_read(&mut remaining).map_err(|error| DeserializationError::Corrupted {
+            offset: serialized.len() - remaining.len(),
+            error,
+        })
+    }
+}
+
+impl<'a> From<&'a CosmeticFilterCache> for CosmeticSerializeFormat<'a> {
+    fn from(cfc: &'a CosmeticFilterCache) -> Self {
+        Self {
+            simple_class_rules: &cfc.simple_class_rules,
+            simple_id_rules: &cfc.simple_id_rules,
+            complex_class_rules: &cfc.complex_class_rules,
+            complex_id_rules: &cfc.complex_id_rules,
+
+            specific_rules: &cfc.specific_rules,
+
+            misc_generic_selectors: &cfc.misc_generic_selectors,
+
+            scriptlets: &cfc.scriptlets,
+
+            generic_rule_lists: &cfc.generic_rule_lists,
+            specific_rule_lists: &cfc.specific_rules,
+
+            specific_extended_rules: &cfc.specific_rules,
+
+            generic_exceptions: &cfc.generic_exceptions,
+        }
+    }
+}
+
+impl From<CosmeticDeserializeFormat> for CosmeticFilterCache {
+    fn from(v: CosmeticDeserializeFormat) -> Self {
+        let mut specific_rules = v.specific_rules;
+        specific_rules.append_extended_rules(v.specific_extended_rules);
+        specific_rules.lists = v.specific_rule_lists;
+
+        CosmeticFilterCache {
+            simple_class_rules: v.simple_class_rules,
+            simple_id_rules: v.simple_id_rules,
+            complex_class_rules: v.complex_class_rules,
+            complex_id_rules: v.complex_id_rules,
+
+            specific_rules,
+
+            misc_generic_selectors: v.misc_generic_selectors,
+
+            scriptlets: v.scriptlets,
+
+            generic_rule_lists: v.generic_rule_lists,
+            generic_exceptions: v.generic_exceptions,
+            disabled_lists: Default::default(),
+        }
+    }
+}
+
+impl<'a> From<(&'a Blocker, &'a CosmeticFilterCache, &'a HtmlFilterCache, Option<&'a ListFingerprint>)> for SerializeFormat<'a> {
+    fn from(v: (&'a Blocker, &'a CosmeticFilterCache, &'a HtmlFilterCache, Option<&'a ListFingerprint>)) -> Self {
+        let (blocker, cfc, html, list_fingerprint) = v;
         Self {
             csp: &blocker.csp,
             exceptions: &blocker.exceptions,
@@ -128,13 +456,36 @@ impl<'a> From<(&'a Blocker, &'a CosmeticFilterCache)> for SerializeFormat<'a> {
             misc_generic_selectors: &cfc.misc_generic_selectors,
 
             scriptlets: &cfc.scriptlets,
+
+            html_filters: html,
+
+            generic_rule_lists: &cfc.generic_rule_lists,
+            specific_rule_lists: &cfc.specific_rules,
+
+            specific_extended_rules: &cfc.specific_rules,
+
+            generic_exceptions: &cfc.generic_exceptions,
+
+            generic_block: &blocker.generic_block,
+
+            list_fingerprint,
+
+            resources_excluded: false,
+
+            allowlist: &NO_ALLOWLIST,
+
+            badfilters: &blocker.badfilters,
         }
     }
 }
 
-impl From<DeserializeFormat> for (Blocker, CosmeticFilterCache) {
+impl From<DeserializeFormat> for (Blocker, CosmeticFilterCache, HtmlFilterCache) {
     fn from(v: DeserializeFormat) -> Self {
-        (Blocker {
+        let mut specific_rules = v.specific_rules;
+        specific_rules.append_extended_rules(v.specific_extended_rules);
+        specific_rules.lists = v.specific_rule_lists;
+
+        let mut blocker = Blocker {
             csp: v.csp,
             exceptions: v.exceptions,
             importants: v.importants,
@@ -142,27 +493,130 @@ impl From<DeserializeFormat> for (Blocker, CosmeticFilterCache) {
             filters_tagged: v.filters_tagged,
             filters: v.filters,
             generic_hide: v.generic_hide,
+            generic_block: v.generic_block,
 
             tags_enabled: Default::default(),
             tagged_filters_all: v.tagged_filters_all,
+            badfilters: v.badfilters,
 
             enable_optimizations: v.enable_optimizations,
 
             resources: v.resources,
-            #[cfg(feature = "object-pooling")]
             pool: Default::default(),
-
-        }, CosmeticFilterCache {
+            slow_filters: None,
+            bucket_stats: None,
+            redirect_fallback: false,
+            unknown_resource_policy: Default::default(),
+            preserve_redirect_query: false,
+            disabled_lists: Default::default(),
+            stop_tokens: crate::blocker::default_stop_token_hashes(),
+            domain_interner: v.domain_interner,
+        };
+        blocker.restore_tagged_filters();
+
+        (blocker, CosmeticFilterCache {
             simple_class_rules: v.simple_class_rules,
             simple_id_rules: v.simple_id_rules,
             complex_class_rules: v.complex_class_rules,
             complex_id_rules: v.complex_id_rules,
 
-            specific_rules: v.specific_rules,
+            specific_rules,
 
             misc_generic_selectors: v.misc_generic_selectors,
 
             scriptlets: v.scriptlets,
-        })
+
+            generic_rule_lists: v.generic_rule_lists,
+            generic_exceptions: v.generic_exceptions,
+            disabled_lists: Default::default(),
+        }, v.html_filters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The layout of `SerializeFormat` before HTML filters and any later fields were added.
+    #[derive(Serialize)]
+    struct SerializeFormatBeforeHtmlFilters<'a> {
+        csp: &'a NetworkFilterList,
+        exceptions: &'a NetworkFilterList,
+        importants: &'a NetworkFilterList,
+        redirects: &'a NetworkFilterList,
+        filters_tagged: &'a NetworkFilterList,
+        filters: &'a NetworkFilterList,
+        generic_hide: &'a NetworkFilterList,
+
+        tagged_filters_all: &'a Vec<NetworkFilter>,
+
+        enable_optimizations: bool,
+
+        resources: &'a RedirectResourceStorage,
+
+        #[serde(serialize_with = "stabilize_hashset_serialization")]
+        simple_class_rules: &'a HashSet<String>,
+        #[serde(serialize_with = "stabilize_hashset_serialization")]
+        simple_id_rules: &'a HashSet<String>,
+        #[serde(serialize_with = "stabilize_hashmap_serialization")]
+        complex_class_rules: &'a HashMap<String, Vec<String>>,
+        #[serde(serialize_with = "stabilize_hashmap_serialization")]
+        complex_id_rules: &'a HashMap<String, Vec<String>>,
+
+        specific_rules: &'a HostnameRuleDb,
+
+        #[serde(serialize_with = "stabilize_hashset_serialization")]
+        misc_generic_selectors: &'a HashSet<String>,
+
+        scriptlets: &'a ScriptletResourceStorage,
+    }
+
+    #[test]
+    fn deserializes_blobs_without_trailing_fields() {
+        let (network_filters, cosmetic_filters) = crate::lists::parse_filters(&[
+            "||ads.example.com^".to_owned(),
+            "@@||example.com^$generichide".to_owned(),
+            "##.banner".to_owned(),
+            "example.com##.ad".to_owned(),
+        ], false, Default::default());
+        let blocker = Blocker::new(network_filters, &crate::blocker::BlockerOptions { enable_optimizations: true });
+        let cfc = CosmeticFilterCache::from_rules(cosmetic_filters);
+
+        let old = SerializeFormatBeforeHtmlFilters {
+            csp: &blocker.csp,
+            exceptions: &blocker.exceptions,
+            importants: &blocker.importants,
+            redirects: &blocker.redirects,
+            filters_tagged: &blocker.filters_tagged,
+            filters: &blocker.filters,
+            generic_hide: &blocker.generic_hide,
+            tagged_filters_all: &blocker.tagged_filters_all,
+            enable_optimizations: blocker.enable_optimizations,
+            resources: &blocker.resources,
+            simple_class_rules: &cfc.simple_class_rules,
+            simple_id_rules: &cfc.simple_id_rules,
+            complex_class_rules: &cfc.complex_class_rules,
+            complex_id_rules: &cfc.complex_id_rules,
+            specific_rules: &cfc.specific_rules,
+            misc_generic_selectors: &cfc.misc_generic_selectors,
+            scriptlets: &cfc.scriptlets,
+        };
+        let mut serialized = crate::data_format::ADBLOCK_RUST_DAT_MAGIC.to_vec();
+        serialized.push(0);
+        rmps::encode::write(&mut serialized, &old).unwrap();
+
+        let deserialized = DeserializeFormat::deserialize(&serialized).unwrap();
+        assert!(deserialized.list_fingerprint.is_none());
+        let (blocker, cfc, html): (Blocker, CosmeticFilterCache, HtmlFilterCache) = deserialized.into();
+        let request = crate::request::Request::from_urls("https://ads.example.com/ad.js", "https://example.com", "script").unwrap();
+        assert!(blocker.check(&request).matched);
+        assert!(blocker.check_generic_hide(&crate::request::Request::from_url("https://example.com").unwrap()));
+        assert!(blocker.generic_block.filter_map.is_empty());
+        assert!(cfc.simple_class_rules.contains("banner"));
+        assert!(cfc.generic_exceptions.is_empty());
+        assert!(html.hostname_html_filters("example.com").is_empty());
+
+        let blocker: Blocker = NetworkOnlyDeserializeFormat::deserialize(&serialized).unwrap().into();
+        assert!(blocker.check(&request).matched);
     }
 }
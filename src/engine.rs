@@ -1,15 +1,33 @@
-use crate::blocker::{Blocker, BlockerError, BlockerOptions, BlockerResult};
-use crate::cosmetic_filter_cache::{CosmeticFilterCache, UrlSpecificResources};
-use crate::lists::{FilterSet, ParseOptions};
-use crate::request::Request;
-use crate::resources::{Resource, RedirectResource};
+use crate::blocker::{AllowReason, Blocker, BlockerError, BlockerOptions, BlockerResult, BucketStats, FilterTokenization, NetworkFilterList, RegexWarmup, SlowFilter, UnknownResourcePolicy};
+use crate::cosmetic_filter_cache::{CosmeticFilterCache, CosmeticRuleSource, UrlSpecificResources};
+use crate::filters::html::HtmlFilter;
+use crate::filters::network::{FilterId, NetworkFilter, NetworkFilterError, NetworkFilterMask, NetworkMatchable, UnsupportedReason};
+use crate::html_filter_cache::HtmlFilterCache;
+use crate::lists::{FilterSet, ListFingerprint, ListId, ParseOptions};
+use crate::request::{Request, RequestType};
+use crate::resources::{LazyResource, MimeType, Resource, ResourceType, RedirectResource, RedirectResourceStorage};
+use crate::utils::{fast_hash, Hash};
 
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub use crate::data_format::FormatInfo;
 
 /// Main adblocking engine that allows efficient querying of resources to block.
 pub struct Engine {
     pub blocker: Blocker,
     cosmetic_cache: CosmeticFilterCache,
+    html_cache: HtmlFilterCache,
+    allowlist: HashSet<Hash>,
+    request_log: Option<RequestLog>,
+    list_fingerprint: Option<ListFingerprint>,
+    result_cache: Option<ResultCache>,
+    block_stream: Option<Mutex<Box<dyn Write + Send>>>,
+    user_blocker: Option<Blocker>,
 }
 
 impl Default for Engine {
@@ -32,6 +50,13 @@ impl Engine {
         Self {
             blocker: Blocker::new(vec![], &blocker_options),
             cosmetic_cache: CosmeticFilterCache::new(),
+            html_cache: HtmlFilterCache::new(),
+            allowlist: HashSet::new(),
+            request_log: None,
+            list_fingerprint: None,
+            result_cache: None,
+            block_stream: None,
+            user_blocker: None,
         }
     }
 
@@ -56,7 +81,7 @@ impl Engine {
     /// Loads rules from the given `FilterSet`. It is recommended to use a `FilterSet` when adding
     /// rules from multiple sources.
     pub fn from_filter_set(set: FilterSet, optimize: bool) -> Self {
-        let FilterSet { network_filters, cosmetic_filters, .. } = set;
+        let FilterSet { network_filters, cosmetic_filters, html_filters, .. } = set;
 
         let blocker_options = BlockerOptions {
             enable_optimizations: optimize,
@@ -65,18 +90,49 @@ impl Engine {
         Self {
             blocker: Blocker::new(network_filters, &blocker_options),
             cosmetic_cache: CosmeticFilterCache::from_rules(cosmetic_filters),
+            html_cache: HtmlFilterCache::from_rules(html_filters),
+            allowlist: HashSet::new(),
+            request_log: None,
+            list_fingerprint: None,
+            result_cache: None,
+            block_stream: None,
+            user_blocker: None,
         }
     }
 
     /// Serializes the `Engine` into a binary format so that it can be quickly reloaded later.
+    ///
+    /// In debug builds, the data is immediately deserialized again to check that no filters or
+    /// rules were lost, panicking otherwise.
     pub fn serialize_raw(&self) -> Result<Vec<u8>, BlockerError> {
         use crate::data_format::SerializeFormat;
 
-        let serialize_format = SerializeFormat::build(&self.blocker, &self.cosmetic_cache, false);
+        let serialize_format = SerializeFormat::build(&self.blocker, &self.cosmetic_cache, &self.html_cache, self.list_fingerprint.as_ref(), false)
+            .with_allowlist(&self.allowlist);
 
-        serialize_format.serialize().map_err(|_e| {
+        let serialized = serialize_format.serialize().map_err(|_e| {
             BlockerError::SerializationError
-        })
+        })?;
+        crate::data_format::debug_check_round_trip(&self.blocker, &self.cosmetic_cache, &serialized);
+        Ok(serialized)
+    }
+
+    /// Like `serialize_raw`, but leaves out redirect and scriptlet resources, which tend to be
+    /// large and are often updated separately from filter lists. Deserializing the result keeps
+    /// the resources the engine already has, which can also be loaded afterwards with
+    /// `use_resources`.
+    pub fn serialize_raw_without_resources(&self) -> Result<Vec<u8>, BlockerError> {
+        use crate::data_format::SerializeFormat;
+
+        let serialize_format = SerializeFormat::build(&self.blocker, &self.cosmetic_cache, &self.html_cache, self.list_fingerprint.as_ref(), false)
+            .with_allowlist(&self.allowlist)
+            .without_resources();
+
+        let serialized = serialize_format.serialize().map_err(|_e| {
+            BlockerError::SerializationError
+        })?;
+        crate::data_format::debug_check_round_trip(&self.blocker, &self.cosmetic_cache, &serialized);
+        Ok(serialized)
     }
 
     /// Serializes the `Engine` into a compressed binary format so that it can be quickly reloaded later.
@@ -90,46 +146,416 @@ impl Engine {
     pub fn serialize_compressed(&self) -> Result<Vec<u8>, BlockerError> {
         use crate::data_format::SerializeFormat;
 
-        let serialize_format = SerializeFormat::build(&self.blocker, &self.cosmetic_cache, true);
+        let serialize_format = SerializeFormat::build(&self.blocker, &self.cosmetic_cache, &self.html_cache, self.list_fingerprint.as_ref(), true);
 
         serialize_format.serialize().map_err(|_e| {
             BlockerError::SerializationError
         })
     }
 
+    /// Reads the header of data serialized by `serialize_raw` or `serialize_compressed` without
+    /// deserializing any of it, so that embedders can decide whether to rebuild the engine instead
+    /// of loading the data. Fails with `BlockerError::DeserializationError` if the data doesn't
+    /// start with a known header.
+    pub fn inspect_serialized(serialized: &[u8]) -> Result<FormatInfo, BlockerError> {
+        Ok(crate::data_format::inspect(serialized)?)
+    }
+
     /// Deserialize the `Engine` from the binary format generated by `Engine::serialize_compressed`
     /// or `Engine::serialize_raw`. The method will automatically select the correct
     /// deserialization implementation.
+    ///
+    /// The engine's current resources are replaced by the serialized ones, unless the data was
+    /// serialized by `serialize_raw_without_resources`, in which case they are kept. Domains that
+    /// were allowlisted when the data was serialized are added to the engine's allowlist.
     pub fn deserialize(&mut self, serialized: &[u8]) -> Result<(), BlockerError> {
         use crate::data_format::DeserializeFormat;
+        let mut deserialize_format = DeserializeFormat::deserialize(serialized)?;
+        self.list_fingerprint = deserialize_format.take_list_fingerprint();
+        self.allowlist.extend(deserialize_format.take_allowlist());
+        let resources_excluded = deserialize_format.resources_excluded();
+        let (mut blocker, mut cosmetic_cache, html_cache) = deserialize_format.build();
+        if resources_excluded {
+            blocker.resources = std::mem::take(&mut self.blocker.resources);
+            cosmetic_cache.scriptlets = std::mem::take(&mut self.cosmetic_cache.scriptlets);
+        }
+        self.use_deserialized(blocker, cosmetic_cache, html_cache);
+        Ok(())
+    }
+
+    /// Like `deserialize`, but only loads network filters. Cosmetic and HTML filtering data is
+    /// skipped, so cosmetic queries on the engine return no rules afterwards. This is useful for
+    /// embedders that only ever check network requests, since it saves the time and memory spent
+    /// on cosmetic filters.
+    pub fn deserialize_network_only(&mut self, serialized: &[u8]) -> Result<(), BlockerError> {
+        use crate::data_format::DeserializeFormat;
+        let (mut blocker, list_fingerprint, resources_excluded, allowlist) = DeserializeFormat::deserialize_network_only(serialized)?;
+        self.list_fingerprint = list_fingerprint;
+        self.allowlist.extend(allowlist);
+        if resources_excluded {
+            blocker.resources = std::mem::take(&mut self.blocker.resources);
+        }
+        self.use_deserialized(blocker, CosmeticFilterCache::new(), HtmlFilterCache::new());
+        Ok(())
+    }
+
+    /// Serializes only the engine's cosmetic filters, including scriptlet resources, so that
+    /// embedders running network and cosmetic filtering separately can store them on their own.
+    /// The data has a header of its own, and can only be loaded with `deserialize_cosmetic`.
+    pub fn serialize_cosmetic(&self) -> Result<Vec<u8>, BlockerError> {
+        self.cosmetic_cache.serialize().map_err(|_e| {
+            BlockerError::SerializationError
+        })
+    }
+
+    /// Replaces the engine's cosmetic filters with those serialized by `serialize_cosmetic`,
+    /// leaving its network and HTML filters as they are. Lists turned off with
+    /// `set_list_enabled` stay turned off.
+    pub fn deserialize_cosmetic(&mut self, serialized: &[u8]) -> Result<(), BlockerError> {
+        let mut cosmetic_cache = CosmeticFilterCache::deserialize(serialized)?;
+        cosmetic_cache.disabled_lists = self.cosmetic_cache.disabled_lists.clone();
+        self.cosmetic_cache = cosmetic_cache;
+        Ok(())
+    }
+
+    /// Builds an engine from two serialized engines, e.g. a base engine and a regional patch, by
+    /// combining their network filters, cosmetic and HTML filters, resources and allowlists
+    /// without parsing any rules again. `$badfilter` rules of either engine also cancel filters
+    /// of the other; see `Blocker::merge`. Resources of `patch` replace those of `base` with the
+    /// same name. The merged engine has no list fingerprint, since it was built from the lists of
+    /// both.
+    pub fn merge_serialized(base: &[u8], patch: &[u8]) -> Result<Self, BlockerError> {
+        let mut engine = Engine::default();
+        engine.deserialize(base)?;
+        let mut patch_engine = Engine::default();
+        patch_engine.deserialize(patch)?;
+
+        engine.blocker.merge(patch_engine.blocker);
+        engine.cosmetic_cache.merge(patch_engine.cosmetic_cache);
+        engine.html_cache.merge(patch_engine.html_cache);
+        engine.allowlist.extend(patch_engine.allowlist);
+        engine.list_fingerprint = None;
+        Ok(engine)
+    }
+
+    /// Records the fingerprint of the lists this engine was built from, to be stored with it by
+    /// `serialize_raw`. It is not stored by the legacy `serialize_compressed` format.
+    pub fn set_list_fingerprint(&mut self, list_fingerprint: Option<ListFingerprint>) {
+        self.list_fingerprint = list_fingerprint;
+    }
+
+    /// The fingerprint stored with `set_list_fingerprint`, or loaded by `deserialize`.
+    pub fn list_fingerprint(&self) -> Option<&ListFingerprint> {
+        self.list_fingerprint.as_ref()
+    }
+
+    /// Returns `true` if this engine has a stored fingerprint matching `current`, i.e. it was built
+    /// from the same lists. Engines without a fingerprint are always considered stale.
+    pub fn is_built_from(&self, current: &ListFingerprint) -> bool {
+        self.list_fingerprint.as_ref() == Some(current)
+    }
+
+    /// Replaces the engine's components with deserialized ones, keeping any settings that are not
+    /// serialized.
+    fn use_deserialized(&mut self, blocker: Blocker, cosmetic_cache: CosmeticFilterCache, html_cache: HtmlFilterCache) {
         let current_tags = self.blocker.tags_enabled();
-        let deserialize_format = DeserializeFormat::deserialize(serialized).map_err(|_e| {
-            BlockerError::DeserializationError
-        })?;
-        let (blocker, cosmetic_cache) = deserialize_format.build();
+        let slow_filters = self.blocker.slow_filters.take();
+        let bucket_stats = self.blocker.bucket_stats.take();
+        let redirect_fallback = self.blocker.redirect_fallback;
+        let unknown_resource_policy = self.blocker.unknown_resource_policy;
+        let preserve_redirect_query = self.blocker.preserve_redirect_query;
+        let disabled_lists = std::mem::take(&mut self.blocker.disabled_lists);
+        let stop_tokens = std::mem::take(&mut self.blocker.stop_tokens);
         self.blocker = blocker;
         self.blocker.use_tags(&current_tags.iter().map(|s| &**s).collect::<Vec<_>>());
+        self.blocker.slow_filters = slow_filters;
+        self.blocker.bucket_stats = bucket_stats;
+        self.blocker.redirect_fallback = redirect_fallback;
+        self.blocker.unknown_resource_policy = unknown_resource_policy;
+        self.blocker.preserve_redirect_query = preserve_redirect_query;
         self.cosmetic_cache = cosmetic_cache;
-        Ok(())
+        self.cosmetic_cache.disabled_lists = disabled_lists.clone();
+        self.blocker.disabled_lists = disabled_lists;
+        self.blocker.stop_tokens = stop_tokens;
+        self.html_cache = html_cache;
+        self.clear_result_cache();
+    }
+
+    fn check(&self, request: &Request, matched_rule: bool, force_check_exceptions: bool) -> BlockerResult {
+        let result = self.check_unlogged(request, matched_rule, force_check_exceptions);
+        self.log_request(request, &result);
+        result
+    }
+
+    /// Like `check`, but without recording the request in the request log or block stream, for
+    /// checks that are only part of deciding the result of another request.
+    fn check_unlogged(&self, request: &Request, matched_rule: bool, force_check_exceptions: bool) -> BlockerResult {
+        self.check_with_generic_block(&self.with_generic_block(request, &self.blocker.tags_enabled), matched_rule, force_check_exceptions)
+    }
+
+    /// Like `check_unlogged`, for a request whose `genericblock` flag is already resolved.
+    fn check_with_generic_block(&self, request: &Request, matched_rule: bool, force_check_exceptions: bool) -> BlockerResult {
+        // Only plain checks are cached, since the others depend on the caller's earlier results.
+        let cache = self.result_cache.as_ref().filter(|_| !matched_rule && !force_check_exceptions);
+        let key = cache.map(|_| ResultCacheKey::from(request));
+        if let (Some(cache), Some(key)) = (cache, &key) {
+            if let Some(result) = cache.get(key) {
+                return result;
+            }
+        }
+
+        let result = if self.is_allowlisted(request) {
+            BlockerResult {
+                request_type: Some(request.request_type.clone()),
+                ..Default::default()
+            }
+        } else {
+            let result = self.blocker.check_parameterised(request, matched_rule, force_check_exceptions);
+            self.apply_user_filters(request, result, matched_rule || force_check_exceptions)
+        };
+        if let (Some(cache), Some(key)) = (cache, key) {
+            cache.insert(key, result.clone());
+        }
+        result
+    }
+
+    /// Sets the user's own network filters, which take precedence over the engine's subscription
+    /// filters: a user exception allows a request blocked by a subscription filter, unless that
+    /// filter is `$important`, and a user blocking filter blocks a request even if a subscription
+    /// exception allows it. Replaces any previously set user filters.
+    ///
+    /// User filters are not serialized, and are kept across deserialization. Their cosmetic
+    /// filters are not used, and `$redirect` resources are not available to them.
+    pub fn set_user_filters(&mut self, filter_set: FilterSet) {
+        self.user_blocker = if filter_set.network_filters.is_empty() {
+            None
+        } else {
+            Some(Blocker::new(filter_set.network_filters, &BlockerOptions {
+                enable_optimizations: self.blocker.enable_optimizations,
+            }))
+        };
+        self.clear_result_cache();
+    }
+
+    /// Overrides the `result` of checking `request` against the subscription filters with the
+    /// user's filters, as described in `set_user_filters`. If `report_exceptions` is set, as for
+    /// checks with `matched_rule` or `force_check_exceptions`, a user exception is also reported
+    /// when nothing was blocked.
+    fn apply_user_filters(&self, request: &Request, result: BlockerResult, report_exceptions: bool) -> BlockerResult {
+        let user_blocker = match &self.user_blocker {
+            Some(user_blocker) => user_blocker,
+            None => return result,
+        };
+        let user_result = user_blocker.check_parameterised(request, false, true);
+        let report_user_exception = report_exceptions && result.exception.is_none() && user_result.exception.is_some();
+        if !result.matched && (user_result.matched || report_user_exception) {
+            user_result
+        } else if user_result.exception.is_some() && result.matched && !result.important {
+            BlockerResult {
+                matched: false,
+                exception: user_result.exception,
+                matched_exception: user_result.matched_exception,
+                filter: result.filter,
+                matched_filter: result.matched_filter,
+                request_type: result.request_type,
+                ..Default::default()
+            }
+        } else {
+            result
+        }
+    }
+
+    /// Creates a new `Engine` from the binary format generated by `Engine::serialize_raw` or
+    /// `Engine::serialize_compressed`, replacing any serialized redirect and scriptlet resources
+    /// with `resources`. This allows resources to be updated independently of the compiled filters.
+    pub fn from_serialized_with_resources(serialized: &[u8], resources: &[Resource]) -> Result<Self, BlockerError> {
+        let mut engine = Self::default();
+        engine.deserialize(serialized)?;
+        engine.use_resources(resources);
+        Ok(engine)
     }
 
     /// Check if a request for a network resource from `url`, of type `request_type`, initiated by
     /// `source_url`, should be blocked.
     pub fn check_network_urls(&self, url: &str, source_url: &str, request_type: &str) -> BlockerResult {
-        Request::from_urls(&url, &source_url, &request_type)
-        .map(|request| {
-            self.blocker.check(&request)
-        })
-        .unwrap_or_else(|_e| {
-            BlockerResult {
+        Request::from_urls(url, source_url, request_type)
+        .map(|request| self.check(&request, false, false))
+        .unwrap_or_else(|_e| request_error_result())
+    }
+
+    /// Returns `request` with `genericblock` set if the document making it has a `$genericblock`
+    /// exception, disabling generic blocking filters for it. Exceptions of the subscriptions are
+    /// checked with `tags_enabled`.
+    fn with_generic_block<'a>(&self, request: &'a Request, tags_enabled: &HashSet<String>) -> Cow<'a, Request> {
+        match &request.source_url {
+            Some(source_url) if !request.genericblock && self.document_generic_block(source_url, tags_enabled) => {
+                let mut request = request.clone();
+                request.genericblock = true;
+                Cow::Owned(request)
+            }
+            _ => Cow::Borrowed(request),
+        }
+    }
+
+    /// Returns the subscriptions' blocker paired with `tags_enabled`, followed by the blocker of
+    /// the user's filters, if any, paired with its own enabled tags.
+    fn tagged_blockers<'a>(&'a self, tags_enabled: &'a HashSet<String>) -> impl Iterator<Item = (&'a Blocker, &'a HashSet<String>)> {
+        std::iter::once((&self.blocker, tags_enabled))
+            .chain(self.user_blocker.as_ref().map(|blocker| (blocker, &blocker.tags_enabled)))
+    }
+
+    fn document_generic_block(&self, source_url: &str, tags_enabled: &HashSet<String>) -> bool {
+        let tagged_blockers = || self.tagged_blockers(tags_enabled);
+        if !tagged_blockers().any(|(blocker, _)| blocker.has_generic_block_exceptions()) {
+            return false;
+        }
+        Request::from_url(source_url)
+            .map(|request| tagged_blockers().any(|(blocker, tags)| blocker.generic_block_exception(&request, tags).is_some()))
+            .unwrap_or(false)
+    }
+
+    /// Like `check_network_urls`, but for an already constructed `Request`. This allows checking
+    /// requests with additional information attached, like the IP address used for `$ipaddress`
+    /// options.
+    pub fn check_network_request(&self, request: &Request) -> BlockerResult {
+        self.check(request, false, false)
+    }
+
+    /// Checks whether a popup to `url`, opened by the document at `opener_url`, should be
+    /// blocked. Only filters with a `$popup` option apply to popups.
+    ///
+    /// A blocked popup is allowed if a `$popup` exception matches the popup's own URL, like any
+    /// other request, or if one matches the opener document. For example, `@@||example.com^$popup`
+    /// allows every popup opened by pages on `example.com`, even those blocked by a broad `$popup`
+    /// filter.
+    pub fn check_popup(&self, url: &str, opener_url: &str) -> BlockerResult {
+        let request = match Request::from_urls(url, opener_url, "popup") {
+            Ok(request) => request,
+            Err(_e) => return request_error_result(),
+        };
+        let result = self.check_unlogged(&request, false, false);
+        // Like other exceptions, an exception for the opener doesn't override `$important`.
+        let opener_exception = Some(&result)
+            .filter(|result| result.matched && !result.important)
+            .and_then(|_| Request::from_urls(opener_url, opener_url, "popup").ok())
+            .map(|opener| self.check_unlogged(&opener, true, false))
+            .filter(|opener_result| opener_result.exception.is_some());
+        let result = match opener_exception {
+            Some(opener_result) => BlockerResult {
                 matched: false,
                 important: false,
                 redirect: None,
-                exception: None,
-                filter: None,
-                error: Some("Error parsing request".to_owned())
+                exception: opener_result.exception,
+                matched_exception: opener_result.matched_exception,
+                ..result
+            },
+            None => result,
+        };
+        self.log_request(&request, &result);
+        result
+    }
+
+    /// Like `check_network_request`, but also returns why the request was allowed, or `None` if it
+    /// was blocked. This helps with debugging why a request wasn't blocked, but is slower than
+    /// `check_network_request`, since determining the reason may require checking the request
+    /// again.
+    pub fn check_with_reason(&self, request: &Request) -> (BlockerResult, Option<AllowReason>) {
+        let resolved = self.with_generic_block(request, &self.blocker.tags_enabled);
+        let result = self.check_with_generic_block(&resolved, false, false);
+        self.log_request(request, &result);
+        let reason = if self.is_allowlisted(request) {
+            Some(AllowReason::Allowlisted)
+        } else {
+            self.blocker.allow_reason(&resolved, &result)
+        };
+        (result, reason)
+    }
+
+    /// Computes which exceptions apply to every request made by the document loaded from
+    /// `source_url`. This only needs to be done once per navigation; pass the result to
+    /// `check_network_urls_in_context` to check each of the document's subrequests.
+    pub fn document_context(&self, source_url: &str) -> DocumentContext {
+        self.document_context_with(source_url, &self.blocker.tags_enabled, |request| self.check_unlogged(request, true, false))
+    }
+
+    /// Returns which document-level exceptions apply to the document loaded from `source_url`,
+    /// without keeping the details of any matching `$document` exception. See `DocumentContext`
+    /// for checking subrequests with these exceptions applied.
+    pub fn document_flags(&self, source_url: &str) -> DocumentFlags {
+        DocumentFlags::from(&self.document_context(source_url))
+    }
+
+    /// `check_document` should check the document with `matched_rule` set, so that it only looks
+    /// for exceptions. Only exceptions with an explicit `$document` option allowlist the page.
+    /// `$generichide`, `$elemhide` and `$genericblock` exceptions of the subscriptions are checked
+    /// with `tags_enabled`.
+    fn document_context_with<F: FnOnce(&Request) -> BlockerResult>(&self, source_url: &str, tags_enabled: &HashSet<String>, check_document: F) -> DocumentContext {
+        let document_exception = Request::from_urls(source_url, source_url, "document").ok()
+            .map(|mut request| {
+                request.explicit_document_only = true;
+                check_document(&request)
+            })
+            .filter(|result| result.exception.is_some());
+        let hostname_request = Request::from_url(source_url).ok();
+        let allowlisted = hostname_request.as_ref()
+            .map(|request| self.hostname_allowlisted(&request.hostname))
+            .unwrap_or(false);
+        // Document-level exceptions can come from the subscriptions or the user's filters
+        let tagged_blockers = || self.tagged_blockers(tags_enabled);
+        let (generic_hide_filter, elem_hide_filter) = match &hostname_request {
+            Some(request) if !allowlisted => {
+                let generic_hide_filter = tagged_blockers().find_map(|(blocker, tags)| blocker.generic_hide_exception(request, tags));
+                let elem_hide_filter = generic_hide_filter.as_ref()
+                    .and_then(|_| tagged_blockers().find_map(|(blocker, tags)| blocker.elem_hide_exception(request, tags)));
+                (generic_hide_filter, elem_hide_filter)
             }
-        })
+            _ => (None, None),
+        };
+        let generic_block_filter = hostname_request.as_ref()
+            .and_then(|request| tagged_blockers().find_map(|(blocker, tags)| blocker.generic_block_exception(request, tags)));
+
+        DocumentContext {
+            source_url: source_url.to_owned(),
+            document_exception,
+            generic_hide: allowlisted || generic_hide_filter.is_some(),
+            elem_hide: allowlisted || elem_hide_filter.is_some(),
+            generic_block: generic_block_filter.is_some(),
+            generic_hide_filter: generic_hide_filter.map(|filter| filter.to_string()),
+            elem_hide_filter: elem_hide_filter.map(|filter| filter.to_string()),
+            generic_block_filter: generic_block_filter.map(|filter| filter.to_string()),
+            generic_hide_exception: generic_hide_filter.map(|filter| filter.filter_id()),
+            elem_hide_exception: elem_hide_filter.map(|filter| filter.filter_id()),
+            generic_block_exception: generic_block_filter.map(|filter| filter.filter_id()),
+        }
+    }
+
+    /// Like `check_network_urls`, but for a subrequest of the document described by `context`.
+    /// If the document is allowlisted by a `$document` exception, the request is allowed without
+    /// checking any filters.
+    pub fn check_network_urls_in_context(&self, url: &str, request_type: &str, context: &DocumentContext) -> BlockerResult {
+        self.check_in_context(url, request_type, context, |request| self.check(request, false, false))
+    }
+
+    fn check_in_context<F: FnOnce(&Request) -> BlockerResult>(&self, url: &str, request_type: &str, context: &DocumentContext, check: F) -> BlockerResult {
+        match Request::from_urls(url, &context.source_url, request_type) {
+            Ok(mut request) => match &context.document_exception {
+                Some(result) => {
+                    let result = BlockerResult {
+                        request_type: Some(request.request_type.clone()),
+                        ..result.clone()
+                    };
+                    self.log_request(&request, &result);
+                    result
+                }
+                None => {
+                    // Already looked up for the whole document.
+                    request.genericblock = context.generic_block;
+                    request.source_url = None;
+                    check(&request)
+                }
+            },
+            Err(_) => request_error_result(),
+        }
     }
 
     pub fn check_network_urls_with_hostnames(
@@ -141,7 +567,7 @@ impl Engine {
         third_party_request: Option<bool>
     ) -> BlockerResult {
         let request = Request::from_urls_with_hostname(url, hostname, source_hostname, request_type, third_party_request);
-        self.blocker.check(&request)
+        self.check(&request, false, false)
     }
 
     pub fn check_network_urls_with_hostnames_subset(
@@ -155,13 +581,15 @@ impl Engine {
         force_check_exceptions: bool,
     ) -> BlockerResult {
         let request = Request::from_urls_with_hostname(url, hostname, source_hostname, request_type, third_party_request);
-        self.blocker.check_parameterised(&request, previously_matched_rule, force_check_exceptions)
+        self.check(&request, previously_matched_rule, force_check_exceptions)
     }
 
     /// Returns a string containing any additional CSP directives that should be added to this
-    /// request's response. Only applies to document and subdocument requests.
+    /// request's response. Only applies to document and subdocument requests that aren't blocked.
     ///
     /// If multiple policies are present from different rules, they will be joined by commas.
+    /// `report-uri` and `report-to` directives are preserved, but each distinct reporting
+    /// directive will only appear once in the result.
     pub fn get_csp_directives(
         &self,
         url: &str,
@@ -170,8 +598,35 @@ impl Engine {
         request_type: &str,
         third_party_request: Option<bool>,
     ) -> Option<String> {
+        let policies = self.get_csp_policies(url, hostname, source_hostname, request_type, third_party_request);
+        if policies.is_empty() {
+            None
+        } else {
+            Some(policies.join(","))
+        }
+    }
+
+    /// Like `get_csp_directives`, but returns each policy from a different rule as a separate
+    /// entry rather than joining them with commas. This suits APIs that take one header per
+    /// policy. Exceptions are applied the same way, and the result is empty if there is nothing
+    /// to add.
+    pub fn get_csp_policies(
+        &self,
+        url: &str,
+        hostname: &str,
+        source_hostname: &str,
+        request_type: &str,
+        third_party_request: Option<bool>,
+    ) -> Vec<String> {
         let request = Request::from_urls_with_hostname(url, hostname, source_hostname, request_type, third_party_request);
-        self.blocker.get_csp_directives(&request)
+        if self.is_allowlisted(&request) {
+            return vec![];
+        }
+        // Whether the document is blocked is decided like for `check_network_request`, including
+        // user filters
+        self.blocker.get_csp_policies_unless_blocked(&request, &self.blocker.tags_enabled, |request| {
+            self.check_unlogged(request, false, false).matched
+        })
     }
 
     /// Check if a given filter has been previously added to this `Engine`.
@@ -196,6 +651,7 @@ impl Engine {
     /// option.
     pub fn use_tags(&mut self, tags: &[&str]) {
         self.blocker.use_tags(tags);
+        self.clear_result_cache();
     }
 
     /// Sets this engine's tags to additionally include the ones provided in `tags`.
@@ -204,6 +660,7 @@ impl Engine {
     /// option.
     pub fn enable_tags(&mut self, tags: &[&str]) {
         self.blocker.enable_tags(tags);
+        self.clear_result_cache();
     }
 
     /// Sets this engine's tags to no longer include the ones provided in `tags`.
@@ -212,6 +669,48 @@ impl Engine {
     /// option.
     pub fn disable_tags(&mut self, tags: &[&str]) {
         self.blocker.disable_tags(tags);
+        self.clear_result_cache();
+    }
+
+    /// Returns the sorted list of tags used by any rule in this engine, whether or not they are
+    /// currently enabled. Only network rules support tags.
+    pub fn available_tags(&self) -> Vec<String> {
+        self.blocker.available_tags()
+    }
+
+    /// Returns the sorted ids of all `$redirect` rules referencing the resource `name`, e.g. to
+    /// prune resources that no rule uses. See `Blocker::filters_using_resource`.
+    pub fn filters_using_resource(&self, name: &str) -> Vec<FilterId> {
+        self.blocker.filters_using_resource(name)
+    }
+
+    /// Parses a network filter rule, and reports its tokens along with the buckets it is stored
+    /// under in this engine. This helps to find out why a filter doesn't match a request: it is
+    /// only checked against requests containing the token of its bucket.
+    pub fn filter_tokenization(&self, rule: &str) -> Result<FilterTokenization, NetworkFilterError> {
+        let filter = NetworkFilter::parse(rule, true, ParseOptions::default())?;
+        Ok(self.blocker.filter_tokenization(&filter))
+    }
+
+    /// Parses a network filter rule and checks it against `request` on its own, without adding it
+    /// to this engine. This lets a rule be tried out before it is added; none of the engine's
+    /// filters, tags or allowlist are taken into account.
+    pub fn test_rule(&self, rule: &str, request: &Request) -> Result<TestResult, NetworkFilterError> {
+        let filter = NetworkFilter::parse(rule, true, ParseOptions::default())?;
+        if let Some(reason) = filter.unsupported_reason.filter(UnsupportedReason::disables_filter) {
+            return Ok(TestResult::Unsupported(reason));
+        }
+        if filter.matches(request) {
+            return Ok(if filter.is_exception() { TestResult::Exception } else { TestResult::Match });
+        }
+
+        let mut any_type = filter;
+        any_type.mask |= NetworkFilterMask::from(&request.request_type);
+        if any_type.matches(request) {
+            Ok(TestResult::TypeMismatch)
+        } else {
+            Ok(TestResult::NoMatch)
+        }
     }
 
     /// Checks if a given tag exists in this engine.
@@ -222,19 +721,153 @@ impl Engine {
         self.blocker.tags_enabled().contains(&tag.to_owned())
     }
 
+    /// Starts recording network filters whose evaluation takes longer than `budget` during
+    /// request checks. Telemetry is disabled by default and has no cost until enabled.
+    ///
+    /// This is intended to help list maintainers find pathological filters, such as expensive
+    /// regexes. Recorded filters are available from `slow_filters`.
+    pub fn enable_slow_filter_telemetry(&mut self, budget: Duration) {
+        self.blocker.enable_slow_filter_telemetry(budget);
+    }
+
+    /// Stops recording slow network filters, discarding any previously recorded ones.
+    pub fn disable_slow_filter_telemetry(&mut self) {
+        self.blocker.disable_slow_filter_telemetry();
+    }
+
+    /// Starts counting how many filters network request checks compare, versus how many are
+    /// skipped thanks to token bucketing. Counting is disabled by default and has no cost until
+    /// enabled. Checks answered from the result cache are not counted.
+    pub fn enable_bucket_stats(&mut self) {
+        self.blocker.enable_bucket_stats();
+    }
+
+    /// Stops counting filter comparisons, discarding any recorded counts.
+    pub fn disable_bucket_stats(&mut self) {
+        self.blocker.disable_bucket_stats();
+    }
+
+    /// Returns the counts recorded since `enable_bucket_stats` or the last `reset_bucket_stats`.
+    pub fn bucket_stats(&self) -> BucketStats {
+        self.blocker.bucket_stats()
+    }
+
+    /// Sets all recorded bucket stats back to zero.
+    pub fn reset_bucket_stats(&self) {
+        self.blocker.reset_bucket_stats();
+    }
+
+    /// Compiles the regexes of all network filters ahead of time, so that no request check has to
+    /// pay for compiling one. Returns how many regexes were compiled, along with any filters whose
+    /// regex could not be compiled.
+    pub fn warmup(&self) -> RegexWarmup {
+        self.blocker.warmup()
+    }
+
+    /// Serves a built-in no-op resource for `$redirect` filters naming a resource that has not been
+    /// loaded, when one exists for the request type: an empty script for scripts, a transparent
+    /// 1x1 GIF for images, and empty documents for subdocuments and XHRs. This can keep redirects
+    /// working with an incomplete resource pack, but may also hide a genuinely missing resource,
+    /// so it is disabled by default. Missing resources are reported either way.
+    pub fn set_redirect_fallback(&mut self, enabled: bool) {
+        self.blocker.set_redirect_fallback(enabled);
+        self.clear_result_cache();
+    }
+
+    /// Sets what to serve for `$redirect` filters naming a resource of unknown MIME type, since
+    /// it's impossible to tell whether such a resource suits the request. By default, a built-in
+    /// no-op resource is served instead, as when a resource doesn't suit the request type. This
+    /// setting is kept across deserialization.
+    pub fn set_unknown_resource_policy(&mut self, policy: UnknownResourcePolicy) {
+        self.blocker.set_unknown_resource_policy(policy);
+        self.clear_result_cache();
+    }
+
+    /// Keeps the query string of redirected requests, for resources that take parameters. The
+    /// query is reported in `BlockerResult::redirect_query`, and appended to the URL of
+    /// `Redirection::Url` redirects. Disabled by default, and kept across deserialization.
+    pub fn set_preserve_redirect_query(&mut self, enabled: bool) {
+        self.blocker.set_preserve_redirect_query(enabled);
+        self.clear_result_cache();
+    }
+
+    /// Turns all network and cosmetic filters parsed with the given `ParseOptions::list_id` off or
+    /// back on. This takes effect immediately and doesn't require recompiling the engine. All
+    /// lists are enabled by default, and this setting is kept across deserialization.
+    pub fn set_list_enabled(&mut self, list_id: ListId, enabled: bool) {
+        self.blocker.set_list_enabled(list_id, enabled);
+        self.clear_result_cache();
+        self.cosmetic_cache.set_list_enabled(list_id, enabled);
+    }
+
+    /// Writes out the engine's network filters as text rules, e.g. to back them up or to move
+    /// them to another blocker. Parsing the rules again produces an engine that blocks the same
+    /// requests. User filters from `set_user_filters` are not included. See
+    /// `Blocker::export_network_filters`.
+    pub fn export_network_filters(&self) -> Vec<String> {
+        self.blocker.export_network_filters()
+    }
+
+    /// Returns network filters that exceeded the budget configured with
+    /// `enable_slow_filter_telemetry`. Always empty if telemetry is not enabled.
+    pub fn slow_filters(&self) -> Vec<SlowFilter> {
+        self.blocker.slow_filters()
+    }
+
     /// Sets this engine's resources to be _only_ the ones provided in `resources`.
     pub fn use_resources(&mut self, resources: &[Resource]) {
         self.blocker.use_resources(resources);
+        self.clear_result_cache();
         self.cosmetic_cache.use_resources(resources);
     }
 
     /// Sets this engine's resources to additionally include `resource`.
     pub fn add_resource(&mut self, resource: Resource) -> Result<(), crate::resources::AddResourceError> {
+        self.clear_result_cache();
         self.blocker.add_resource(&resource)?;
         self.cosmetic_cache.add_resource(&resource)?;
         Ok(())
     }
 
+    /// Sets this engine's resources to additionally include a pack of `resources`, e.g. a user
+    /// supplied pack on top of the built-in one. Resources from later packs take precedence:
+    /// existing redirect resources and scriptlets with the same name or alias are replaced, and
+    /// within the pack, later resources replace earlier ones. Returns the sorted names and aliases
+    /// that were replaced either way, so that collisions can be reported. Resources that fail to
+    /// decode are skipped, as with `use_resources`.
+    pub fn add_resources(&mut self, resources: &[Resource]) -> Vec<String> {
+        self.clear_result_cache();
+        let mut collisions = self.blocker.resources.merge(RedirectResourceStorage::from_resources(resources));
+        let mut pack_names = HashSet::new();
+        collisions.extend(resources.iter()
+            .flat_map(|resource| std::iter::once(&resource.name).chain(resource.aliases.iter()))
+            .filter(|name| !pack_names.insert(name.as_str()))
+            .cloned());
+        for resource in resources {
+            if matches!(resource.kind, ResourceType::Template | ResourceType::Mime(MimeType::ApplicationJavascript)) {
+                collisions.extend(std::iter::once(&resource.name).chain(resource.aliases.iter())
+                    .filter(|name| self.cosmetic_cache.scriptlets.contains(name))
+                    .cloned());
+            }
+            let _result = self.cosmetic_cache.add_resource(resource);
+        }
+        collisions.sort();
+        collisions.dedup();
+        collisions
+    }
+
+    /// Sets this engine's redirect resources to be _only_ the ones described by `resources`, whose
+    /// bodies are fetched from `provider` when first used. See `RedirectResourceStorage::lazy`.
+    /// Scriptlets are unaffected. Lazily loaded resources are not included when serializing the
+    /// engine.
+    pub fn use_lazy_redirect_resources<F>(&mut self, resources: &[LazyResource], provider: F)
+    where
+        F: Fn(&str) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.clear_result_cache();
+        self.blocker.resources = RedirectResourceStorage::lazy(resources, provider);
+    }
+
     /// Gets a previously added resource from the engine.
     pub fn get_resource(&self, key: &str) -> Option<RedirectResource> {
         self.blocker.get_resource(key).cloned()
@@ -256,424 +889,3698 @@ impl Engine {
     /// been called, all CSS ids and classes on a page should be passed to
     /// `hidden_class_id_selectors` to obtain any stylesheets consisting of generic rules (if the
     /// returned `generichide` value is false).
+    ///
+    /// On documents with an `$elemhide` exception, no hiding, style, remove or procedural rules are
+    /// returned, and `generichide` is set. Scriptlets are still injected.
     pub fn url_cosmetic_resources(&self, url: &str) -> UrlSpecificResources {
+        self.url_cosmetic_resources_with_tags(url, &self.blocker.tags_enabled)
+    }
+
+    /// Like `url_cosmetic_resources`, but checks `$generichide` and `$elemhide` exceptions with
+    /// the provided enabled tags.
+    pub(crate) fn url_cosmetic_resources_with_tags(&self, url: &str, tags_enabled: &HashSet<String>) -> UrlSpecificResources {
         let request = Request::from_url(url);
         if request.is_err() {
             return UrlSpecificResources::empty();
         }
         let request = request.unwrap();
 
-        let generichide = self.blocker.check_generic_hide(&request);
-        self.cosmetic_cache.hostname_cosmetic_resources(&request.hostname, generichide)
+        if self.hostname_allowlisted(&request.hostname) {
+            return UrlSpecificResources {
+                generichide: true,
+                ..UrlSpecificResources::empty()
+            };
+        }
+
+        let generichide = self.blocker.generic_hide_exception(&request, tags_enabled).is_some();
+        let resources = self.cosmetic_cache.hostname_cosmetic_resources(&request.hostname, generichide);
+        if generichide && self.blocker.elem_hide_exception(&request, tags_enabled).is_some() {
+            // Scriptlets are not cosmetic rules, so they still apply
+            return UrlSpecificResources {
+                injected_script: resources.injected_script,
+                generichide: true,
+                ..UrlSpecificResources::empty()
+            };
+        }
+        resources
     }
-}
 
+    /// Builds a stylesheet hiding every element selected by the `hide_selectors` that
+    /// `url_cosmetic_resources` returns for pages on `host`, ready to be injected as is. Each
+    /// selector gets a `{display:none!important}` rule of its own, in alphabetical order, so that
+    /// one selector the browser doesn't support can't invalidate the others. Returns an empty
+    /// string if there is nothing to hide.
+    ///
+    /// Generic rules starting with a class or id still need to be queried with
+    /// `hidden_class_id_selectors`, and style, remove and procedural rules aren't included.
+    pub fn cosmetic_stylesheet_for(&self, host: &str) -> String {
+        self.cosmetic_stylesheet_for_with_tags(host, &self.blocker.tags_enabled)
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::resources::{ResourceType, MimeType};
-    use crate::blocker::Redirection;
-    use crate::lists::FilterFormat;
+    /// Like `cosmetic_stylesheet_for`, with `tags_enabled` as in `url_cosmetic_resources_with_tags`.
+    pub(crate) fn cosmetic_stylesheet_for_with_tags(&self, host: &str, tags_enabled: &HashSet<String>) -> String {
+        let resources = self.url_cosmetic_resources_with_tags(&format!("https://{}/", host), tags_enabled);
+        let mut selectors = resources.hide_selectors.into_iter().collect::<Vec<_>>();
+        selectors.sort();
+        selectors.iter().map(|selector| format!("{}{{display:none!important}}\n", selector)).collect()
+    }
 
-    #[test]
-    fn tags_enable_adds_tags() {
-        let filters = vec![
-            String::from("adv$tag=stuff"),
-            String::from("somelongpath/test$tag=stuff"),
-            String::from("||brianbondy.com/$tag=brian"),
-            String::from("||brave.com$tag=brian"),
-        ];
-        let url_results = vec![
-            ("http://example.com/advert.html", true),
-            ("http://example.com/somelongpath/test/2.html", true),
-            ("https://brianbondy.com/about", true),
-            ("https://brave.com/about", true),
-        ];
+    /// Cheaply checks whether `url_cosmetic_resources` could return any rules for pages on `host`,
+    /// so that content scripts can skip observing the DOM of pages that have none. This takes
+    /// `$generichide` exceptions and allowlisted hostnames into account, but not individual
+    /// cosmetic exceptions, so it may return `true` for hosts where every rule is excepted.
+    pub fn has_cosmetic_rules_for(&self, host: &str) -> bool {
+        self.has_cosmetic_rules_for_with_tags(host, &self.blocker.tags_enabled)
+    }
 
-        let mut engine = Engine::from_rules(&filters, Default::default());
-        engine.enable_tags(&["stuff"]);
-        engine.enable_tags(&["brian"]);
+    /// Like `has_cosmetic_rules_for`, with `tags_enabled` as in `url_cosmetic_resources_with_tags`.
+    pub(crate) fn has_cosmetic_rules_for_with_tags(&self, host: &str, tags_enabled: &HashSet<String>) -> bool {
+        let request = match Request::from_url(&format!("https://{}/", host)) {
+            Ok(request) => request,
+            Err(_) => return false,
+        };
+        if self.hostname_allowlisted(&request.hostname) {
+            return false;
+        }
 
-        url_results.into_iter().for_each(|(url, expected_result)| {
-            let matched_rule = engine.check_network_urls(&url, "", "");
-            if expected_result {
-                assert!(matched_rule.matched, "Expected match for {}", url);
-            } else {
-                assert!(!matched_rule.matched, "Expected no match for {}, matched with {:?}", url, matched_rule.filter);
-            }
-        });
+        let generichide = self.blocker.generic_hide_exception(&request, tags_enabled).is_some();
+        self.cosmetic_cache.has_rules_for(&request.hostname, generichide)
     }
 
-    #[test]
-    fn tags_disable_works() {
-        let filters = vec![
-            String::from("adv$tag=stuff"),
-            String::from("somelongpath/test$tag=stuff"),
-            String::from("||brianbondy.com/$tag=brian"),
-            String::from("||brave.com$tag=brian"),
-        ];
-        let url_results = vec![
-            ("http://example.com/advert.html", false),
-            ("http://example.com/somelongpath/test/2.html", false),
-            ("https://brianbondy.com/about", true),
-            ("https://brave.com/about", true),
-        ];
+    /// Compares the `hide_selectors` that `url_cosmetic_resources` returns for pages on `host_a`
+    /// and `host_b`, e.g. to check which rules differ between sites sharing the same
+    /// infrastructure. Generic rules that apply to both hosts end up in `shared`.
+    pub fn cosmetic_diff(&self, host_a: &str, host_b: &str) -> CosmeticDiff {
+        self.cosmetic_diff_with_tags(host_a, host_b, &self.blocker.tags_enabled)
+    }
 
-        let mut engine = Engine::from_rules(&filters, Default::default());
-        engine.enable_tags(&["brian", "stuff"]);
-        engine.disable_tags(&["stuff"]);
+    /// Like `cosmetic_diff`, with `tags_enabled` as in `url_cosmetic_resources_with_tags`.
+    pub(crate) fn cosmetic_diff_with_tags(&self, host_a: &str, host_b: &str, tags_enabled: &HashSet<String>) -> CosmeticDiff {
+        let selectors_a = self.url_cosmetic_resources_with_tags(&format!("https://{}/", host_a), tags_enabled).hide_selectors;
+        let selectors_b = self.url_cosmetic_resources_with_tags(&format!("https://{}/", host_b), tags_enabled).hide_selectors;
 
-        url_results.into_iter().for_each(|(url, expected_result)| {
-            let matched_rule = engine.check_network_urls(&url, "", "");
-            if expected_result {
-                assert!(matched_rule.matched, "Expected match for {}", url);
-            } else {
-                assert!(!matched_rule.matched, "Expected no match for {}, matched with {:?}", url, matched_rule.filter);
-            }
-        });
+        let sorted = |selectors: Vec<&String>| {
+            let mut selectors = selectors.into_iter().cloned().collect::<Vec<_>>();
+            selectors.sort();
+            selectors
+        };
+        CosmeticDiff {
+            only_a: sorted(selectors_a.difference(&selectors_b).collect()),
+            only_b: sorted(selectors_b.difference(&selectors_a).collect()),
+            shared: sorted(selectors_a.intersection(&selectors_b).collect()),
+        }
     }
 
-    #[test]
-    fn exception_tags_inactive_by_default() {
-        let filters = vec![
-            String::from("adv"),
-            String::from("||brianbondy.com/$tag=brian"),
-            String::from("@@||brianbondy.com/$tag=brian"),
-        ];
-        let url_results = vec![
-            ("http://example.com/advert.html", true),
-            ("https://brianbondy.com/about", false),
-            ("https://brianbondy.com/advert", true),
-        ];
+    /// Lists the hostnames and entities, like `example.*`, that have hostname-specific cosmetic
+    /// rules of any kind, including exceptions and scriptlets, in alphabetical order. Since only
+    /// hashes of hostnames are needed for matching, their names are lost on deserialization.
+    pub fn specific_cosmetic_hostnames(&self) -> Vec<String> {
+        self.cosmetic_cache.specific_hostnames()
+    }
 
-        let engine = Engine::from_rules(&filters, Default::default());
+    /// Lists the hostname-specific cosmetic rules that apply to `hostname`, including
+    /// exceptions and scriptlets, each with the `ParseOptions::list_id` of the list it came from,
+    /// so that a broken site can be reported to the right list. Rule text is only kept for
+    /// engines built from rules in debug mode, so this is empty for other engines, and after
+    /// deserialization.
+    pub fn cosmetic_rules_for_host(&self, hostname: &str) -> Vec<CosmeticRuleSource> {
+        self.cosmetic_cache.hostname_rule_sources(hostname)
+    }
 
-        url_results.into_iter().for_each(|(url, expected_result)| {
-            let matched_rule = engine.check_network_urls(&url, "", "");
-            if expected_result {
-                assert!(matched_rule.matched, "Expected match for {}", url);
-            } else {
-                assert!(!matched_rule.matched, "Expected no match for {}, matched with {:?}", url, matched_rule.filter);
-            }
+    /// Returns the HTML filtering rules (AdGuard's `$$` syntax) that should be applied to the
+    /// source of a document loaded from `url`, before it is parsed by the browser.
+    pub fn url_html_filters(&self, url: &str) -> Vec<HtmlFilter> {
+        match Request::from_url(url) {
+            Ok(request) if !self.hostname_allowlisted(&request.hostname) => self.html_cache.hostname_html_filters(&request.hostname),
+            _ => vec![],
+        }
+    }
+
+    /// Starts recording the most recent `capacity` request checks, which can be retrieved using
+    /// `request_log`. Once full, the oldest entry is dropped for each new one. Replaces any
+    /// previously recorded entries.
+    pub fn enable_request_log(&mut self, capacity: usize) {
+        self.request_log = Some(RequestLog::new(capacity));
+    }
+
+    /// Stops recording request checks, discarding any previously recorded ones.
+    pub fn disable_request_log(&mut self) {
+        self.request_log = None;
+    }
+
+    /// Returns the recorded request checks, from oldest to newest. Always empty if the request log
+    /// is not enabled.
+    pub fn request_log(&self) -> Vec<LoggedRequest> {
+        self.request_log.as_ref().map(|log| log.entries()).unwrap_or_default()
+    }
+
+    /// Discards all recorded request checks, without disabling the request log.
+    pub fn clear_request_log(&self) {
+        if let Some(log) = &self.request_log {
+            log.clear();
+        }
+    }
+
+    /// Starts caching the results of up to `capacity` network request checks, so that checking an
+    /// identical request again returns the cached result without matching any filters. Once full,
+    /// the least recently used result is dropped for each new one. Replaces any previously cached
+    /// results.
+    ///
+    /// Requests are identical if they have the same URL, type, third-partiness, source hostname,
+    /// and any other information attached to the `Request`. The cache is cleared by every method
+    /// that changes the engine's filters, tags, resources or settings; call `clear_result_cache`
+    /// after changing `blocker` directly. Requests checked by an `EngineView` with tags of its own
+    /// are never cached.
+    pub fn enable_result_cache(&mut self, capacity: usize) {
+        self.result_cache = Some(ResultCache::new(capacity));
+    }
+
+    /// Stops caching request check results, discarding any cached ones.
+    pub fn disable_result_cache(&mut self) {
+        self.result_cache = None;
+    }
+
+    /// Discards all cached request check results, without disabling the result cache.
+    pub fn clear_result_cache(&self) {
+        if let Some(cache) = &self.result_cache {
+            cache.clear();
+        }
+    }
+
+    /// Starts writing each blocked request to `writer` as it is checked, as a single line of JSON
+    /// with these fields:
+    /// - `url`: the request URL
+    /// - `request_type`: the lowercase name of its `RequestType`, e.g. `"script"`
+    /// - `filter`: the matched blocking filter as reported in `BlockerResult::filter`, or `null`
+    /// - `important`: whether an `$important` filter matched
+    /// - `redirect`: whether the request is replaced by a redirect rather than cancelled
+    ///
+    /// Unlike the request log, nothing is buffered by the engine, so `writer` should do its own
+    /// buffering if needed. Lines are formatted before the writer is locked, which is only held to
+    /// write a single line. Errors from `writer` are ignored. Replaces any previous writer.
+    pub fn set_block_stream<W: Write + Send + 'static>(&mut self, writer: W) {
+        self.block_stream = Some(Mutex::new(Box::new(writer)));
+    }
+
+    /// Stops writing blocked requests, returning the writer given to `set_block_stream` if any.
+    pub fn clear_block_stream(&mut self) -> Option<Box<dyn Write + Send>> {
+        self.block_stream.take().and_then(|writer| writer.into_inner().ok())
+    }
+
+    fn log_request(&self, request: &Request, result: &BlockerResult) {
+        if let Some(log) = &self.request_log {
+            log.push(LoggedRequest {
+                url: request.url.clone(),
+                request_type: request.request_type.clone(),
+                blocked: result.matched,
+                filter_id: result.matched_filter,
+                exception_id: result.matched_exception,
+            });
+        }
+        if let (Some(stream), true) = (&self.block_stream, result.matched) {
+            let line = format!(
+                "{{\"url\":{},\"request_type\":{},\"filter\":{},\"important\":{},\"redirect\":{}}}\n",
+                json_string(&request.url),
+                json_string(&format!("{:?}", request.request_type).to_ascii_lowercase()),
+                result.filter.as_deref().map(json_string).unwrap_or_else(|| "null".to_owned()),
+                result.important,
+                result.applied_redirect().is_some(),
+            );
+            if let Ok(mut writer) = stream.lock() {
+                let _ = writer.write_all(line.as_bytes());
+            }
+        }
+    }
+
+    /// Disables all blocking on `domain` and any of its subdomains. Requests made from documents on
+    /// an allowlisted domain are never blocked, and no cosmetic or HTML filtering rules are
+    /// returned for pages on it.
+    ///
+    /// The allowlist is stored by `serialize_raw`, but not by the legacy `serialize_compressed`
+    /// format.
+    pub fn add_allowlist_domain(&mut self, domain: &str) {
+        let domain = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+        let domain = if domain.is_ascii() {
+            domain
+        } else {
+            match idna::domain_to_ascii(&domain) {
+                Ok(domain) => domain,
+                Err(_) => return,
+            }
+        };
+        if !domain.is_empty() {
+            self.allowlist.insert(fast_hash(&domain));
+            self.clear_result_cache();
+        }
+    }
+
+    /// Checks whether `hostname`, or any domain it is a subdomain of, has been allowlisted.
+    fn hostname_allowlisted(&self, hostname: &str) -> bool {
+        if self.allowlist.is_empty() {
+            return false;
+        }
+        std::iter::once(hostname)
+            .chain(hostname.match_indices('.').map(|(i, _)| &hostname[i + 1..]))
+            .any(|suffix| self.allowlist.contains(&fast_hash(suffix)))
+    }
+
+    /// Checks whether the document making `request` is on an allowlisted domain. Top-level
+    /// document requests are checked against their own hostname.
+    fn is_allowlisted(&self, request: &Request) -> bool {
+        if self.allowlist.is_empty() {
+            return false;
+        }
+        let source_allowlisted = request.source_hostname_hashes.as_ref()
+            .map(|hashes| hashes.iter().any(|hash| self.allowlist.contains(hash)))
+            .unwrap_or(false);
+        source_allowlisted || (request.request_type == RequestType::Document && self.hostname_allowlisted(&request.hostname))
+    }
+
+    /// Converts this engine into an `EngineView` which can be cheaply cloned and shared between
+    /// threads.
+    pub fn into_view(self) -> EngineView {
+        EngineView::from(self)
+    }
+}
+
+/// The outcome of checking a single rule against a request, as returned by `Engine::test_rule`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestResult {
+    /// The rule is a blocking filter that matches the request.
+    Match,
+    /// The rule is an exception that matches the request.
+    Exception,
+    /// The rule would match the request, but its content type options don't include the type of
+    /// the request.
+    TypeMismatch,
+    /// The rule doesn't match the request.
+    NoMatch,
+    /// The rule never matches any request, because it uses an option that isn't supported.
+    Unsupported(UnsupportedReason),
+}
+
+/// The hiding selectors that differ between two hosts, as returned by `Engine::cosmetic_diff`.
+/// Each list is sorted alphabetically.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CosmeticDiff {
+    /// Selectors hidden on the first host only.
+    pub only_a: Vec<String>,
+    /// Selectors hidden on the second host only.
+    pub only_b: Vec<String>,
+    /// Selectors hidden on both hosts.
+    pub shared: Vec<String>,
+}
+
+/// Exceptions that apply to all requests made by a single document, as computed by
+/// `Engine::document_context` when the document is navigated to.
+#[derive(Debug, Clone)]
+pub struct DocumentContext {
+    /// The URL of the document, used as the source URL of each of its subrequests.
+    pub source_url: String,
+    /// The result of checking the document itself, if it is allowlisted by a `$document`
+    /// exception. All of the document's subrequests are allowed with this same result.
+    pub document_exception: Option<BlockerResult>,
+    /// Whether generic cosmetic rules are disabled for the document by a `$generichide`
+    /// exception.
+    pub generic_hide: bool,
+    /// Whether all cosmetic rules are disabled for the document by an `$elemhide` exception, or
+    /// because its hostname is allowlisted. This implies `generic_hide`.
+    pub elem_hide: bool,
+    /// Whether generic blocking filters are disabled for the document's subrequests by a
+    /// `$genericblock` exception.
+    pub generic_block: bool,
+    /// The `$generichide` or `$elemhide` exception that set `generic_hide`. This is `None` if it
+    /// was set because the hostname is allowlisted.
+    pub generic_hide_filter: Option<String>,
+    /// The `$elemhide` exception that set `elem_hide`. This is `None` if it was set because the
+    /// hostname is allowlisted.
+    pub elem_hide_filter: Option<String>,
+    /// The `$genericblock` exception that set `generic_block`.
+    pub generic_block_filter: Option<String>,
+    /// The id of `generic_hide_filter`.
+    pub generic_hide_exception: Option<FilterId>,
+    /// The id of `elem_hide_filter`.
+    pub elem_hide_exception: Option<FilterId>,
+    /// The id of `generic_block_filter`.
+    pub generic_block_exception: Option<FilterId>,
+}
+
+/// The exceptions that apply to a whole document, as returned by `Engine::document_flags`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DocumentFlags {
+    /// Whether generic cosmetic rules are disabled by a `$generichide` exception, or because the
+    /// document's hostname is allowlisted.
+    pub generic_hide: bool,
+    /// Whether all cosmetic rules are disabled by an `$elemhide` exception, or because the
+    /// document's hostname is allowlisted.
+    pub elem_hide: bool,
+    /// Whether generic blocking filters are disabled by a `$genericblock` exception.
+    pub generic_block: bool,
+    /// Whether the document and all of its subrequests are allowlisted by a `$document`
+    /// exception.
+    pub document_allowlisted: bool,
+    /// The id of the exception that set `generic_hide`. The filter itself is available as
+    /// `DocumentContext::generic_hide_filter`.
+    pub generic_hide_exception: Option<FilterId>,
+    /// The id of the exception that set `elem_hide`. See `DocumentContext::elem_hide_filter`.
+    pub elem_hide_exception: Option<FilterId>,
+    /// The id of the exception that set `generic_block`. See
+    /// `DocumentContext::generic_block_filter`.
+    pub generic_block_exception: Option<FilterId>,
+}
+
+impl From<&DocumentContext> for DocumentFlags {
+    fn from(context: &DocumentContext) -> Self {
+        Self {
+            generic_hide: context.generic_hide,
+            elem_hide: context.elem_hide,
+            generic_block: context.generic_block,
+            document_allowlisted: context.document_exception.is_some(),
+            generic_hide_exception: context.generic_hide_exception,
+            elem_hide_exception: context.elem_hide_exception,
+            generic_block_exception: context.generic_block_exception,
+        }
+    }
+}
+
+/// A single request check recorded by the request log. See `Engine::enable_request_log`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoggedRequest {
+    pub url: String,
+    pub request_type: RequestType,
+    /// Whether the request should be blocked, i.e. `BlockerResult::matched`.
+    pub blocked: bool,
+    /// The id of the matched blocking filter, as reported in `BlockerResult::matched_filter`.
+    pub filter_id: Option<FilterId>,
+    /// The id of the matched exception filter, as reported in `BlockerResult::matched_exception`.
+    pub exception_id: Option<FilterId>,
+}
+
+/// Bounded buffer of the most recent request checks. The lock is only held to push or copy out
+/// entries, never while a request is being checked.
+struct RequestLog {
+    capacity: usize,
+    entries: Mutex<VecDeque<LoggedRequest>>,
+}
+
+impl RequestLog {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn push(&self, entry: LoggedRequest) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.len() == self.capacity {
+                entries.pop_front();
+            }
+            entries.push_back(entry);
+        }
+    }
+
+    fn entries(&self) -> Vec<LoggedRequest> {
+        self.entries.lock()
+            .map(|entries| entries.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn clear(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.clear();
+        }
+    }
+}
+
+/// Quotes and escapes `value` as a JSON string.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Everything about a `Request` that can affect the result of checking it.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct ResultCacheKey {
+    url: String,
+    request_type: RequestType,
+    is_third_party: Option<bool>,
+    source_hostname_hashes: Option<Vec<Hash>>,
+    ip_address: Option<IpAddr>,
+    response_headers: Option<Vec<(String, String)>>,
+    bug: Option<u32>,
+    is_redirect_target: bool,
+    genericblock: bool,
+    is_navigation: bool,
+}
+
+impl From<&Request> for ResultCacheKey {
+    fn from(request: &Request) -> Self {
+        Self {
+            url: request.url_case_sensitive().to_owned(),
+            request_type: request.request_type.clone(),
+            is_third_party: request.is_third_party,
+            source_hostname_hashes: request.source_hostname_hashes.clone(),
+            ip_address: request.ip_address,
+            response_headers: request.response_headers.clone(),
+            bug: request.bug,
+            is_redirect_target: request.is_redirect_target,
+            genericblock: request.genericblock,
+            is_navigation: request.is_navigation,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ResultCacheEntries {
+    results: HashMap<ResultCacheKey, (BlockerResult, u64)>,
+    /// Keys of `results` by the time they were last used, oldest first.
+    recency: BTreeMap<u64, ResultCacheKey>,
+    clock: u64,
+}
+
+/// Least recently used cache of request check results. See `Engine::enable_result_cache`. Like
+/// `RequestLog`, the lock is never held while a request is being checked.
+struct ResultCache {
+    capacity: usize,
+    entries: Mutex<ResultCacheEntries>,
+}
+
+impl ResultCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(ResultCacheEntries::default()),
+        }
+    }
+
+    fn get(&self, key: &ResultCacheKey) -> Option<BlockerResult> {
+        let mut entries = self.entries.lock().ok()?;
+        let ResultCacheEntries { results, recency, clock } = &mut *entries;
+        let (result, last_used) = results.get_mut(key)?;
+        *clock += 1;
+        if let Some(key) = recency.remove(last_used) {
+            recency.insert(*clock, key);
+        }
+        *last_used = *clock;
+        Some(result.clone())
+    }
+
+    fn insert(&self, key: ResultCacheKey, result: BlockerResult) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Ok(mut entries) = self.entries.lock() {
+            let ResultCacheEntries { results, recency, clock } = &mut *entries;
+            *clock += 1;
+            if let Some((_, last_used)) = results.get(&key) {
+                recency.remove(last_used);
+            } else if results.len() == self.capacity {
+                if let Some((_, oldest)) = recency.pop_first() {
+                    results.remove(&oldest);
+                }
+            }
+            recency.insert(*clock, key.clone());
+            results.insert(key, (result, *clock));
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            *entries = ResultCacheEntries::default();
+        }
+    }
+}
+
+fn request_error_result() -> BlockerResult {
+    BlockerResult {
+        matched: false,
+        important: false,
+        redirect: None,
+        missing_redirect: None,
+        redirect_query: None,
+        exception: None,
+        matched_exception: None,
+        filter: None,
+        matched_filter: None,
+        error: Some("Error parsing request".to_owned()),
+        request_type: None,
+    }
+}
+
+/// Tag configuration belonging to a single `EngineView`, overriding the tags of the shared
+/// `Engine`.
+struct TagOverlay {
+    tags_enabled: HashSet<String>,
+    filters_tagged: NetworkFilterList,
+}
+
+/// A read-only view of an `Engine` that can be cheaply cloned and shared between threads.
+///
+/// All clones share the same rules and resources. Tags can still be changed on each clone without
+/// affecting any other; doing so only rebuilds that clone's set of tagged filters.
+#[derive(Clone)]
+pub struct EngineView {
+    engine: Arc<Engine>,
+    tags: Option<Arc<TagOverlay>>,
+}
+
+impl From<Engine> for EngineView {
+    fn from(engine: Engine) -> Self {
+        Self::from(Arc::new(engine))
+    }
+}
+
+impl From<Arc<Engine>> for EngineView {
+    fn from(engine: Arc<Engine>) -> Self {
+        Self {
+            engine,
+            tags: None,
+        }
+    }
+}
+
+impl EngineView {
+    /// Returns the shared `Engine` backing this view. Note that tags set on this view are not
+    /// reflected by the returned `Engine`.
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    fn check(&self, request: &Request, matched_rule: bool, force_check_exceptions: bool) -> BlockerResult {
+        let result = self.check_unlogged(request, matched_rule, force_check_exceptions);
+        self.engine.log_request(request, &result);
+        result
+    }
+
+    /// See `Engine::check_unlogged`.
+    fn check_unlogged(&self, request: &Request, matched_rule: bool, force_check_exceptions: bool) -> BlockerResult {
+        let request = &*self.engine.with_generic_block(request, self.tags_enabled());
+        let tags = match &self.tags {
+            Some(tags) => tags,
+            None => return self.engine.check_with_generic_block(request, matched_rule, force_check_exceptions),
+        };
+        if self.engine.is_allowlisted(request) {
+            BlockerResult {
+                request_type: Some(request.request_type.clone()),
+                ..Default::default()
+            }
+        } else {
+            let result = self.engine.blocker.check_parameterised_with_tags(request, matched_rule, force_check_exceptions, &tags.filters_tagged, &tags.tags_enabled);
+            self.engine.apply_user_filters(request, result, matched_rule || force_check_exceptions)
+        }
+    }
+
+    /// See `Engine::check_network_urls`.
+    pub fn check_network_urls(&self, url: &str, source_url: &str, request_type: &str) -> BlockerResult {
+        Request::from_urls(url, source_url, request_type)
+            .map(|request| self.check(&request, false, false))
+            .unwrap_or_else(|_e| request_error_result())
+    }
+
+    /// See `Engine::check_network_request`.
+    pub fn check_network_request(&self, request: &Request) -> BlockerResult {
+        self.check(request, false, false)
+    }
+
+    /// See `Engine::document_context`.
+    pub fn document_context(&self, source_url: &str) -> DocumentContext {
+        self.engine.document_context_with(source_url, self.tags_enabled(), |request| self.check_unlogged(request, true, false))
+    }
+
+    /// See `Engine::document_flags`.
+    pub fn document_flags(&self, source_url: &str) -> DocumentFlags {
+        DocumentFlags::from(&self.document_context(source_url))
+    }
+
+    /// See `Engine::check_network_urls_in_context`.
+    pub fn check_network_urls_in_context(&self, url: &str, request_type: &str, context: &DocumentContext) -> BlockerResult {
+        self.engine.check_in_context(url, request_type, context, |request| self.check(request, false, false))
+    }
+
+    /// See `Engine::check_network_urls_with_hostnames`.
+    pub fn check_network_urls_with_hostnames(
+        &self,
+        url: &str,
+        hostname: &str,
+        source_hostname: &str,
+        request_type: &str,
+        third_party_request: Option<bool>
+    ) -> BlockerResult {
+        let request = Request::from_urls_with_hostname(url, hostname, source_hostname, request_type, third_party_request);
+        self.check(&request, false, false)
+    }
+
+    /// See `Engine::check_network_urls_with_hostnames_subset`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn check_network_urls_with_hostnames_subset(
+        &self,
+        url: &str,
+        hostname: &str,
+        source_hostname: &str,
+        request_type: &str,
+        third_party_request: Option<bool>,
+        previously_matched_rule: bool,
+        force_check_exceptions: bool,
+    ) -> BlockerResult {
+        let request = Request::from_urls_with_hostname(url, hostname, source_hostname, request_type, third_party_request);
+        self.check(&request, previously_matched_rule, force_check_exceptions)
+    }
+
+    /// See `Engine::get_csp_directives`.
+    pub fn get_csp_directives(
+        &self,
+        url: &str,
+        hostname: &str,
+        source_hostname: &str,
+        request_type: &str,
+        third_party_request: Option<bool>,
+    ) -> Option<String> {
+        let policies = self.get_csp_policies(url, hostname, source_hostname, request_type, third_party_request);
+        if policies.is_empty() {
+            None
+        } else {
+            Some(policies.join(","))
+        }
+    }
+
+    /// See `Engine::get_csp_policies`.
+    pub fn get_csp_policies(
+        &self,
+        url: &str,
+        hostname: &str,
+        source_hostname: &str,
+        request_type: &str,
+        third_party_request: Option<bool>,
+    ) -> Vec<String> {
+        let request = Request::from_urls_with_hostname(url, hostname, source_hostname, request_type, third_party_request);
+        if self.engine.is_allowlisted(&request) {
+            return vec![];
+        }
+        let tags_enabled = match &self.tags {
+            Some(tags) => &tags.tags_enabled,
+            None => &self.engine.blocker.tags_enabled,
+        };
+        self.engine.blocker.get_csp_policies_unless_blocked(&request, tags_enabled, |request| {
+            self.check_unlogged(request, false, false).matched
+        })
+    }
+
+    /// See `Engine::filter_exists`.
+    pub fn filter_exists(&self, filter: &str) -> bool {
+        self.engine.filter_exists(filter)
+    }
+
+    fn tags_enabled(&self) -> &HashSet<String> {
+        match &self.tags {
+            Some(tags) => &tags.tags_enabled,
+            None => &self.engine.blocker.tags_enabled,
+        }
+    }
+
+    fn tags_with_set(&mut self, tags_enabled: HashSet<String>) {
+        let filters_tagged = self.engine.blocker.build_tagged_filters(&tags_enabled);
+        self.tags = Some(Arc::new(TagOverlay {
+            tags_enabled,
+            filters_tagged,
+        }));
+    }
+
+    /// Sets this view's tags to be _only_ the ones provided in `tags`. Other clones of this view
+    /// are unaffected.
+    pub fn use_tags(&mut self, tags: &[&str]) {
+        self.tags_with_set(tags.iter().map(|&t| String::from(t)).collect());
+    }
+
+    /// Sets this view's tags to additionally include the ones provided in `tags`. Other clones of
+    /// this view are unaffected.
+    pub fn enable_tags(&mut self, tags: &[&str]) {
+        let mut tag_set = self.tags_enabled().clone();
+        tag_set.extend(tags.iter().map(|&t| String::from(t)));
+        self.tags_with_set(tag_set);
+    }
+
+    /// Sets this view's tags to no longer include the ones provided in `tags`. Other clones of
+    /// this view are unaffected.
+    pub fn disable_tags(&mut self, tags: &[&str]) {
+        let mut tag_set = self.tags_enabled().clone();
+        for tag in tags {
+            tag_set.remove(*tag);
+        }
+        self.tags_with_set(tag_set);
+    }
+
+    /// See `Engine::available_tags`.
+    pub fn available_tags(&self) -> Vec<String> {
+        self.engine.available_tags()
+    }
+
+    /// See `Engine::filters_using_resource`.
+    pub fn filters_using_resource(&self, name: &str) -> Vec<FilterId> {
+        self.engine.filters_using_resource(name)
+    }
+
+    /// Checks if a given tag is enabled in this view.
+    pub fn tag_exists(&self, tag: &str) -> bool {
+        match &self.tags {
+            Some(tags) => tags.tags_enabled.contains(tag),
+            None => self.engine.tag_exists(tag),
+        }
+    }
+
+    /// See `Engine::get_resource`.
+    pub fn get_resource(&self, key: &str) -> Option<RedirectResource> {
+        self.engine.get_resource(key)
+    }
+
+    /// See `Engine::hidden_class_id_selectors`.
+    pub fn hidden_class_id_selectors(&self, classes: &[String], ids: &[String], exceptions: &HashSet<String>) -> Vec<String> {
+        self.engine.hidden_class_id_selectors(classes, ids, exceptions)
+    }
+
+    /// See `Engine::url_cosmetic_resources`.
+    pub fn url_cosmetic_resources(&self, url: &str) -> UrlSpecificResources {
+        self.engine.url_cosmetic_resources_with_tags(url, self.tags_enabled())
+    }
+
+    /// See `Engine::test_rule`.
+    pub fn test_rule(&self, rule: &str, request: &Request) -> Result<TestResult, NetworkFilterError> {
+        self.engine.test_rule(rule, request)
+    }
+
+    /// See `Engine::export_network_filters`.
+    pub fn export_network_filters(&self) -> Vec<String> {
+        self.engine.export_network_filters()
+    }
+
+    /// See `Engine::cosmetic_rules_for_host`.
+    pub fn cosmetic_rules_for_host(&self, hostname: &str) -> Vec<CosmeticRuleSource> {
+        self.engine.cosmetic_rules_for_host(hostname)
+    }
+
+    /// See `Engine::cosmetic_stylesheet_for`.
+    pub fn cosmetic_stylesheet_for(&self, host: &str) -> String {
+        self.engine.cosmetic_stylesheet_for_with_tags(host, self.tags_enabled())
+    }
+
+    /// See `Engine::has_cosmetic_rules_for`.
+    pub fn has_cosmetic_rules_for(&self, host: &str) -> bool {
+        self.engine.has_cosmetic_rules_for_with_tags(host, self.tags_enabled())
+    }
+
+    /// See `Engine::cosmetic_diff`.
+    pub fn cosmetic_diff(&self, host_a: &str, host_b: &str) -> CosmeticDiff {
+        self.engine.cosmetic_diff_with_tags(host_a, host_b, self.tags_enabled())
+    }
+
+    /// See `Engine::specific_cosmetic_hostnames`.
+    pub fn specific_cosmetic_hostnames(&self) -> Vec<String> {
+        self.engine.specific_cosmetic_hostnames()
+    }
+
+    /// See `Engine::url_html_filters`.
+    pub fn url_html_filters(&self, url: &str) -> Vec<HtmlFilter> {
+        self.engine.url_html_filters(url)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocker::{FilterToken, Redirection};
+    use crate::filters::network::NetworkFilter;
+    use crate::lists::FilterFormat;
+    use crate::request::RequestBuilder;
+    use std::collections::HashMap;
+
+    #[test]
+    fn tagged_rule_inactive_until_enabled() {
+        let filter = NetworkFilter::parse("||regional.example.com^$tag=regional", true, Default::default()).unwrap();
+        assert_eq!(filter.tag.as_deref(), Some("regional"));
+
+        let mut engine = Engine::from_rules(&[
+            String::from("||regional.example.com^$tag=regional"),
+            String::from("||ads.example.com^"),
+        ], Default::default());
+        assert_eq!(engine.blocker.tagged_filters_all.len(), 1);
+        assert_eq!(engine.blocker.tagged_filters_all[0].tag.as_deref(), Some("regional"));
+        assert!(engine.blocker.filters_tagged.filter_map.is_empty());
+        assert!(engine.filter_exists("||regional.example.com^$tag=regional"));
+
+        let check = |engine: &Engine| engine.check_network_urls("https://regional.example.com/ad.js", "https://example.com", "script").matched;
+        assert!(!check(&engine));
+        assert!(engine.check_network_urls("https://ads.example.com/ad.js", "https://example.com", "script").matched);
+
+        engine.enable_tags(&["regional"]);
+        assert!(check(&engine));
+        assert!(!engine.blocker.filters_tagged.filter_map.is_empty());
+
+        engine.disable_tags(&["regional"]);
+        assert!(!check(&engine));
+    }
+
+    #[test]
+    fn tags_enable_adds_tags() {
+        let filters = vec![
+            String::from("adv$tag=stuff"),
+            String::from("somelongpath/test$tag=stuff"),
+            String::from("||brianbondy.com/$tag=brian"),
+            String::from("||brave.com$tag=brian"),
+        ];
+        let url_results = vec![
+            ("http://example.com/advert.html", true),
+            ("http://example.com/somelongpath/test/2.html", true),
+            ("https://brianbondy.com/about", true),
+            ("https://brave.com/about", true),
+        ];
+
+        let mut engine = Engine::from_rules(&filters, Default::default());
+        engine.enable_tags(&["stuff"]);
+        engine.enable_tags(&["brian"]);
+
+        url_results.into_iter().for_each(|(url, expected_result)| {
+            let matched_rule = engine.check_network_urls(&url, "", "");
+            if expected_result {
+                assert!(matched_rule.matched, "Expected match for {}", url);
+            } else {
+                assert!(!matched_rule.matched, "Expected no match for {}, matched with {:?}", url, matched_rule.filter);
+            }
+        });
+    }
+
+    #[test]
+    fn tags_disable_works() {
+        let filters = vec![
+            String::from("adv$tag=stuff"),
+            String::from("somelongpath/test$tag=stuff"),
+            String::from("||brianbondy.com/$tag=brian"),
+            String::from("||brave.com$tag=brian"),
+        ];
+        let url_results = vec![
+            ("http://example.com/advert.html", false),
+            ("http://example.com/somelongpath/test/2.html", false),
+            ("https://brianbondy.com/about", true),
+            ("https://brave.com/about", true),
+        ];
+
+        let mut engine = Engine::from_rules(&filters, Default::default());
+        engine.enable_tags(&["brian", "stuff"]);
+        engine.disable_tags(&["stuff"]);
+
+        url_results.into_iter().for_each(|(url, expected_result)| {
+            let matched_rule = engine.check_network_urls(&url, "", "");
+            if expected_result {
+                assert!(matched_rule.matched, "Expected match for {}", url);
+            } else {
+                assert!(!matched_rule.matched, "Expected no match for {}, matched with {:?}", url, matched_rule.filter);
+            }
+        });
+    }
+
+    #[test]
+    fn available_tags() {
+        let filters = vec![
+            String::from("adv$tag=stuff"),
+            String::from("somelongpath/test$tag=stuff"),
+            String::from("||brianbondy.com/$tag=brian"),
+            String::from("@@||brave.com^$tag=allow"),
+            String::from("||example.com^"),
+            String::from("example.com##.ad"),
+        ];
+        let mut engine = Engine::from_rules(&filters, Default::default());
+        assert_eq!(engine.available_tags(), vec!["allow", "brian", "stuff"]);
+
+        engine.enable_tags(&["brian"]);
+        assert_eq!(engine.available_tags(), vec!["allow", "brian", "stuff"]);
+
+        let engine = Engine::from_rules(&[String::from("||example.com^")], Default::default());
+        assert!(engine.available_tags().is_empty());
+    }
+
+    #[test]
+    fn popup_exceptions_for_opener() {
+        let engine = Engine::from_rules(&[
+            "||ads.net^$popup".to_owned(),
+            "||tracker.net^$popup,important".to_owned(),
+            "@@||trusted.com^$popup".to_owned(),
+        ], Default::default());
+
+        let result = engine.check_popup("https://ads.net/landing", "https://news.com/article");
+        assert!(result.matched);
+        assert_eq!(result.request_type, Some(RequestType::Popup));
+
+        // Other requests are unaffected by `$popup` filters
+        assert!(!engine.check_network_urls("https://ads.net/landing", "https://news.com/article", "document").matched);
+        assert!(!engine.check_network_urls("https://ads.net/ad.js", "https://news.com/article", "script").matched);
+
+        let result = engine.check_popup("https://ads.net/landing", "https://www.trusted.com/article");
+        assert!(!result.matched);
+        assert!(result.exception.is_some());
+        assert!(result.filter.is_some());
+
+        // The exception also applies to popups to the excepted site itself
+        assert!(!engine.check_popup("https://trusted.com/login", "https://news.com/article").matched);
+
+        assert!(engine.check_popup("https://tracker.net/", "https://www.trusted.com/article").matched);
+        assert!(!engine.check_popup("https://example.com/", "https://news.com/article").matched);
+
+        // Each popup is logged once, with its final result
+        let mut engine = engine;
+        engine.enable_request_log(10);
+        assert!(!engine.check_popup("https://ads.net/landing", "https://www.trusted.com/article").matched);
+        let log = engine.request_log();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].url, "https://ads.net/landing");
+        assert_eq!(log[0].request_type, RequestType::Popup);
+        assert!(!log[0].blocked);
+        assert!(log[0].exception_id.is_some());
+    }
+
+    #[test]
+    fn filters_using_resource() {
+        let filters = vec![
+            String::from("||ads.example.com^$script,redirect=noop.js"),
+            String::from("/banner.js$redirect=noop.js,domain=example.org"),
+            String::from("||ads.example.com^$image,redirect=1x1.gif"),
+            String::from("||ads.example.com^$redirect-url=https://example.com/noop.js"),
+            String::from("||ads.example.com^"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+        let id = |filter: &str| NetworkFilter::parse(filter, false, Default::default()).unwrap().filter_id();
+
+        let mut expected = vec![id(&filters[0]), id(&filters[1])];
+        expected.sort_unstable();
+        assert_eq!(engine.filters_using_resource("noop.js"), expected);
+        assert_eq!(engine.filters_using_resource("1x1.gif"), vec![id(&filters[2])]);
+        assert!(engine.filters_using_resource("noopjs").is_empty());
+        assert!(engine.filters_using_resource("unused.js").is_empty());
+    }
+
+    #[test]
+    fn deserialized_domains_are_interned() {
+        let filters = vec![
+            String::from("||ads.example.com^$domain=site.com|other.com"),
+            String::from("||tracker.example.com^$domain=site.com|other.com"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+        let serialized = engine.serialize_raw().unwrap();
+
+        let mut deserialized = Engine::default();
+        deserialized.deserialize(&serialized).unwrap();
+
+        let report = deserialized.blocker.domain_memory_report();
+        assert_eq!(report, engine.blocker.domain_memory_report());
+        assert_eq!(report.entries, 4);
+        assert_eq!(report.strings, 2);
+        assert!(deserialized.check_network_urls("https://ads.example.com", "https://other.com", "script").matched);
+    }
+
+    #[test]
+    fn programmatic_tags() {
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filter_list_with_tag("||ads.example.com^\n||tracker.example.com^$tag=other\nexample.com##.ad", Default::default(), "annoyances");
+        filter_set.add_filter_with_tag("/banner/*/img", Default::default(), "annoyances").unwrap();
+        filter_set.add_filter("||example.net^", Default::default()).unwrap();
+        let mut engine = Engine::from_filter_set(filter_set, true);
+
+        assert!(!engine.check_network_urls("https://ads.example.com/script.js", "https://example.com", "script").matched);
+        assert!(!engine.check_network_urls("https://example.com/banner/top/img", "https://example.com", "image").matched);
+        assert!(engine.check_network_urls("https://example.net/", "https://example.com", "script").matched);
+        assert!(!engine.url_cosmetic_resources("https://example.com").hide_selectors.is_empty());
+
+        engine.enable_tags(&["annoyances"]);
+        assert!(engine.check_network_urls("https://ads.example.com/script.js", "https://example.com", "script").matched);
+        assert!(engine.check_network_urls("https://example.com/banner/top/img", "https://example.com", "image").matched);
+        // The rule's own `$tag=` option is kept
+        assert!(!engine.check_network_urls("https://tracker.example.com/", "https://example.com", "script").matched);
+
+        engine.disable_tags(&["annoyances"]);
+        assert!(!engine.check_network_urls("https://ads.example.com/script.js", "https://example.com", "script").matched);
+        assert!(!engine.check_network_urls("https://example.com/banner/top/img", "https://example.com", "image").matched);
+    }
+
+    #[test]
+    fn merge_serialized() {
+        let base = Engine::from_rules(&[
+            "||ads.example.com^".to_owned(),
+            "||tracker.example.com^$script".to_owned(),
+            "/banner/*/img$image".to_owned(),
+            "##.ad".to_owned(),
+            "example.com##.banner".to_owned(),
+        ], Default::default());
+        let patch = Engine::from_rules(&[
+            "||tracker.example.com^$script,badfilter".to_owned(),
+            "||regional.example.org^".to_owned(),
+            "@@||ads.example.com/allowed^".to_owned(),
+            "##.regional-ad".to_owned(),
+            "example.com##.promo".to_owned(),
+            "example.com#@#.banner".to_owned(),
+        ], Default::default());
+
+        let engine = Engine::merge_serialized(&base.serialize_raw().unwrap(), &patch.serialize_raw().unwrap()).unwrap();
+        let blocked = |url: &str, request_type: &str| engine.check_network_urls(url, "https://example.com", request_type).matched;
+
+        assert!(blocked("https://ads.example.com/ad.js", "script"));
+        assert!(blocked("https://example.com/banner/top/img", "image"));
+        // Added by the patch
+        assert!(blocked("https://regional.example.org/ad.js", "script"));
+        assert!(!blocked("https://ads.example.com/allowed/ad.js", "script"));
+        // Cancelled by the patch's `$badfilter`
+        assert!(!blocked("https://tracker.example.com/t.js", "script"));
+
+        let mut classes = engine.hidden_class_id_selectors(&["ad".to_owned(), "regional-ad".to_owned()], &[], &HashSet::new());
+        classes.sort();
+        assert_eq!(classes, vec![".ad", ".regional-ad"]);
+        let hidden = engine.url_cosmetic_resources("https://example.com").hide_selectors;
+        assert!(hidden.contains(".promo"));
+        assert!(!hidden.contains(".banner"));
+
+        // The badfilter also cancels filters of the base engine when merged the other way around
+        let engine = Engine::merge_serialized(&patch.serialize_raw().unwrap(), &base.serialize_raw().unwrap()).unwrap();
+        assert!(!engine.check_network_urls("https://tracker.example.com/t.js", "https://example.com", "script").matched);
+        assert!(engine.check_network_urls("https://regional.example.org/ad.js", "https://example.com", "script").matched);
+
+        // The merged engine can be serialized in turn
+        let serialized = engine.serialize_raw().unwrap();
+        let mut deserialized = Engine::default();
+        deserialized.deserialize(&serialized).unwrap();
+        assert!(!deserialized.check_network_urls("https://tracker.example.com/t.js", "https://example.com", "script").matched);
+        assert!(deserialized.check_network_urls("https://ads.example.com/ad.js", "https://example.com", "script").matched);
+    }
+
+    #[test]
+    fn merge_serialized_cancels_fused_filters() {
+        let base = Engine::from_rules_debug(&[
+            "/ads/banner$script".to_owned(),
+            "/ads/popup$script".to_owned(),
+            "/ads/track$script".to_owned(),
+        ], Default::default());
+        // The optimizer fuses the base's filters into one
+        let fused = base.check_network_urls("https://example.com/ads/popup.js", "https://example.com", "script");
+        assert!(fused.filter.unwrap().contains(" <+> "));
+        let patch = Engine::from_rules(&["/ads/popup$script,badfilter".to_owned()], Default::default());
+
+        for engine in [
+            Engine::merge_serialized(&base.serialize_raw().unwrap(), &patch.serialize_raw().unwrap()).unwrap(),
+            Engine::merge_serialized(&patch.serialize_raw().unwrap(), &base.serialize_raw().unwrap()).unwrap(),
+        ] {
+            let check = |url: &str| engine.check_network_urls(url, "https://example.com", "script");
+            assert!(!check("https://example.com/ads/popup.js").matched);
+            let result = check("https://example.com/ads/banner.js");
+            assert!(result.matched);
+            assert_eq!(result.filter.as_deref(), Some("/ads/banner$script <+> /ads/track$script"));
+            assert!(check("https://example.com/ads/track.js").matched);
+        }
+    }
+
+    #[test]
+    fn tags_survive_serialization() {
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filter_list_with_tag("||ads.example.com^\n||tracker.example.com^$tag=other", Default::default(), "annoyances");
+        filter_set.add_filter_with_tag("/banner/*/img", Default::default(), "annoyances").unwrap();
+        filter_set.add_filter("||example.net^$tag=regional", Default::default()).unwrap();
+        let mut engine = Engine::from_filter_set(filter_set, true);
+        engine.enable_tags(&["annoyances"]);
+
+        for serialized in [engine.serialize_raw().unwrap(), engine.serialize_compressed().unwrap()].iter() {
+            let mut deserialized = Engine::default();
+            deserialized.deserialize(serialized).unwrap();
+            assert_eq!(deserialized.available_tags(), vec!["annoyances", "other", "regional"]);
+
+            let check = |engine: &Engine| (
+                engine.check_network_urls("https://ads.example.com/script.js", "https://example.com", "script").matched,
+                engine.check_network_urls("https://example.com/banner/top/img", "https://example.com", "image").matched,
+                engine.check_network_urls("https://tracker.example.com/", "https://example.com", "script").matched,
+                engine.check_network_urls("https://example.net/", "https://example.com", "script").matched,
+            );
+
+            // Tags enabled when serializing aren't enabled on the deserialized engine
+            assert_eq!(check(&deserialized), (false, false, false, false));
+
+            deserialized.enable_tags(&["annoyances", "regional"]);
+            assert_eq!(check(&deserialized), (true, true, false, true));
+
+            deserialized.disable_tags(&["annoyances"]);
+            assert_eq!(check(&deserialized), (false, false, false, true));
+
+            deserialized.enable_tags(&["other"]);
+            assert_eq!(check(&deserialized), (false, false, true, true));
+        }
+    }
+
+    #[test]
+    fn exception_tags_inactive_by_default() {
+        let filters = vec![
+            String::from("adv"),
+            String::from("||brianbondy.com/$tag=brian"),
+            String::from("@@||brianbondy.com/$tag=brian"),
+        ];
+        let url_results = vec![
+            ("http://example.com/advert.html", true),
+            ("https://brianbondy.com/about", false),
+            ("https://brianbondy.com/advert", true),
+        ];
+
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        url_results.into_iter().for_each(|(url, expected_result)| {
+            let matched_rule = engine.check_network_urls(&url, "", "");
+            if expected_result {
+                assert!(matched_rule.matched, "Expected match for {}", url);
+            } else {
+                assert!(!matched_rule.matched, "Expected no match for {}, matched with {:?}", url, matched_rule.filter);
+            }
+        });
+    }
+
+    #[test]
+    fn exception_tags_works() {
+        let filters = vec![
+            String::from("adv"),
+            String::from("||brianbondy.com/$tag=brian"),
+            String::from("@@||brianbondy.com/$tag=brian"),
+        ];
+        let url_results = vec![
+            ("http://example.com/advert.html", true),
+            ("https://brianbondy.com/about", false),
+            ("https://brianbondy.com/advert", false),
+        ];
+
+        let mut engine = Engine::from_rules(&filters, Default::default());
+        engine.enable_tags(&["brian", "stuff"]);
+
+        url_results.into_iter().for_each(|(url, expected_result)| {
+            let matched_rule = engine.check_network_urls(&url, "", "");
+            if expected_result {
+                assert!(matched_rule.matched, "Expected match for {}", url);
+            } else {
+                assert!(!matched_rule.matched, "Expected no match for {}, matched with {:?}", url, matched_rule.filter);
+            }
+        });
+    }
+
+    #[test]
+    fn serialization_retains_tags() {
+        let filters = vec![
+            String::from("adv$tag=stuff"),
+            String::from("somelongpath/test$tag=stuff"),
+            String::from("||brianbondy.com/$tag=brian"),
+            String::from("||brave.com$tag=brian"),
+        ];
+        let url_results = vec![
+            ("http://example.com/advert.html", true),
+            ("http://example.com/somelongpath/test/2.html", true),
+            ("https://brianbondy.com/about", false),
+            ("https://brave.com/about", false),
+        ];
+
+        let mut engine = Engine::from_rules(&filters, Default::default());
+        engine.enable_tags(&["stuff"]);
+        engine.enable_tags(&["brian"]);
+        let serialized = engine.serialize_raw().unwrap();
+        let mut deserialized_engine = Engine::default();
+        deserialized_engine.enable_tags(&["stuff"]);
+        deserialized_engine.deserialize(&serialized).unwrap();
+
+        url_results.into_iter().for_each(|(url, expected_result)| {
+            let matched_rule = deserialized_engine.check_network_urls(&url, "", "");
+            if expected_result {
+                assert!(matched_rule.matched, "Expected match for {}", url);
+            } else {
+                assert!(!matched_rule.matched, "Expected no match for {}, matched with {:?}", url, matched_rule.filter);
+            }
+        });
+    }
+
+    #[test]
+    fn deserialization_backwards_compatible_plain() {
+        // deserialization_generate_simple();
+        // assert!(false);
+        let serialized: Vec<u8> = vec![31, 139, 8, 0, 0, 0, 0, 0, 0, 255, 1, 68, 0, 187, 255, 155, 145, 128, 145, 128,
+            145, 128, 145, 128, 145, 128, 145, 129, 207, 202, 167, 36, 217, 43, 56, 97, 176, 145, 158, 145, 206, 0, 3,
+            31, 255, 146, 1, 145, 169, 97, 100, 45, 98, 97, 110, 110, 101, 114, 192, 192, 192, 192, 192, 192, 192, 192,
+            207, 186, 136, 69, 13, 115, 187, 170, 226, 192, 192, 192, 144, 194, 195, 194, 195, 207, 77, 26, 78, 68, 0,
+            0, 0];
+
+        let mut deserialized_engine = Engine::default();
+        deserialized_engine.deserialize(&serialized).unwrap();
+
+        let url = "http://example.com/ad-banner.gif";
+        let matched_rule = deserialized_engine.check_network_urls(url, "", "");
+        assert!(matched_rule.matched, "Expected match for {}", url);
+    }
+
+    #[test]
+    fn deserialization_backwards_compatible_tags() {
+        // deserialization_generate_tags();
+        // assert!(false);
+        let serialized: Vec<u8> = vec![31, 139, 8, 0, 0, 0, 0, 0, 0, 255, 149, 139, 49, 14, 64, 48, 24, 70, 137, 131, 88,
+            108, 98, 148, 184, 135, 19, 252, 197, 218, 132, 3, 8, 139, 85, 126, 171, 132, 193, 32, 54, 71, 104, 218, 205,
+            160, 139, 197, 105, 218, 166, 233, 5, 250, 125, 219, 203, 123, 43, 14, 238, 163, 124, 206, 228, 79, 11, 184,
+            113, 195, 55, 136, 98, 181, 132, 120, 65, 157, 17, 160, 180, 233, 152, 221, 1, 164, 98, 178, 255, 242, 178,
+            221, 231, 201, 0, 19, 122, 216, 92, 112, 161, 1, 58, 213, 199, 143, 114, 0, 0, 0];
+        let mut deserialized_engine = Engine::default();
+
+        deserialized_engine.enable_tags(&[]);
+        deserialized_engine.deserialize(&serialized).unwrap();
+        let url = "http://example.com/ad-banner.gif";
+        let matched_rule = deserialized_engine.check_network_urls(url, "", "");
+        assert!(!matched_rule.matched, "Expected NO match for {}", url);
+
+        deserialized_engine.enable_tags(&["abc"]);
+        deserialized_engine.deserialize(&serialized).unwrap();
+
+        let url = "http://example.com/ad-banner.gif";
+        let matched_rule = deserialized_engine.check_network_urls(url, "", "");
+        assert!(matched_rule.matched, "Expected match for {}", url);
+    }
+
+    #[test]
+    fn deserialization_backwards_compatible_resources() {
+        // deserialization_generate_resources();
+        // assert!(false);
+        let serialized: Vec<u8> = vec![31, 139, 8, 0, 0, 0, 0, 0, 0, 255, 61, 139, 189, 10, 64, 80, 28, 197, 201, 46,
+            229, 1, 44, 54, 201, 234, 117, 174, 143, 65, 233, 18, 6, 35, 118, 229, 127, 103, 201, 230, 99, 146, 39,
+            184, 177, 25, 152, 61, 13, 238, 29, 156, 83, 167, 211, 175, 115, 90, 40, 184, 203, 235, 24, 244, 219, 176,
+            209, 2, 29, 156, 130, 164, 61, 68, 132, 9, 121, 166, 131, 48, 246, 19, 74, 71, 28, 69, 113, 230, 231, 25,
+            101, 186, 42, 121, 86, 73, 189, 42, 95, 103, 255, 102, 219, 183, 29, 170, 127, 68, 102, 150, 86, 28, 162,
+            0, 247, 3, 163, 110, 154, 146, 145, 195, 175, 245, 47, 101, 250, 113, 201, 119, 0, 0, 0];
+
+        let mut deserialized_engine = Engine::default();
+        deserialized_engine.deserialize(&serialized).unwrap();
+
+        let url = "http://example.com/ad-banner.gif";
+        let matched_rule = deserialized_engine.check_network_urls(url, "", "");
+        // This serialized DAT was generated prior to
+        // https://github.com/brave/adblock-rust/pull/185, so the `redirect` filter did not get
+        // duplicated into the list of blocking filters.
+        //
+        // TODO - The failure to match here is considered acceptable for now, as it's part of a
+        // breaking change (minor version bump). However, the test should be updated at some point.
+        //assert!(matched_rule.matched, "Expected match for {}", url);
+        assert_eq!(matched_rule.redirect, Some(Redirection::Resource("data:text/plain;base64,".to_owned())), "Expected redirect to contain resource");
+    }
+
+    #[test]
+    fn deserialization_generate_simple() {
+        let mut engine = Engine::from_rules(&[
+            "ad-banner".to_owned()
+        ], Default::default());
+        let serialized = engine.serialize_compressed().unwrap();
+        println!("Engine serialized: {:?}", serialized);
+        engine.deserialize(&serialized).unwrap();
+    }
+
+    #[test]
+    fn deserialization_generate_tags() {
+        let mut engine = Engine::from_rules(&[
+            "ad-banner$tag=abc".to_owned()
+        ], Default::default());
+        engine.use_tags(&["abc"]);
+        let serialized = engine.serialize_compressed().unwrap();
+        println!("Engine serialized: {:?}", serialized);
+        engine.deserialize(&serialized).unwrap();
+    }
+
+    #[test]
+    fn deserialization_generate_resources() {
+        let mut engine = Engine::from_rules(&[
+            "ad-banner$redirect=nooptext".to_owned()
+        ], Default::default());
+
+        let resources = vec![
+            Resource {
+                name: "nooptext".to_string(),
+                aliases: vec![],
+                kind: ResourceType::Mime(MimeType::TextPlain),
+                content: base64::encode(""),
+            },
+            Resource {
+                name: "noopcss".to_string(),
+                aliases: vec![],
+                kind: ResourceType::Mime(MimeType::TextPlain),
+                content: base64::encode(""),
+            },
+        ];
+        engine.use_resources(&resources);
+
+        let serialized = engine.serialize_compressed().unwrap();
+        println!("Engine serialized: {:?}", serialized);
+        engine.deserialize(&serialized).unwrap();
+    }
+
+    #[test]
+    fn redirect_resource_insertion_works() {
+        let mut engine = Engine::from_rules(&[
+            "ad-banner$redirect=nooptext".to_owned()
+        ], Default::default());
+
+        engine.add_resource(Resource {
+            name: "nooptext".to_owned(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::TextPlain),
+            content: "".to_owned(),
+        }).unwrap();
+
+        let url = "http://example.com/ad-banner.gif";
+        let matched_rule = engine.check_network_urls(url, "", "");
+        assert!(matched_rule.matched, "Expected match for {}", url);
+        assert_eq!(matched_rule.redirect, Some(Redirection::Resource("data:text/plain;base64,".to_owned())), "Expected redirect to contain resource");
+    }
+
+    #[test]
+    fn redirect_resource_lookup_works() {
+        let script = base64::encode(r#"
+(function() {
+	;
+})();
+
+        "#);
+
+        let mut engine = Engine::default();
+
+        engine.add_resource(Resource {
+            name: "noopjs".to_owned(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: script.to_owned(),
+        }).unwrap();
+        let inserted_resource = engine.get_resource("noopjs");
+        assert!(inserted_resource.is_some());
+        let resource = inserted_resource.unwrap();
+        assert_eq!(resource.content_type, "application/javascript");
+        assert_eq!(resource.data, script);
+    }
+
+    #[test]
+    fn redirects_to_lazy_resources() {
+        let mut engine = Engine::from_rules(&["||ads.example.com^$script,redirect=noop.js".to_owned()], Default::default());
+        engine.use_lazy_redirect_resources(&[LazyResource {
+            name: "noop.js".to_owned(),
+            aliases: vec![],
+            content_type: MimeType::ApplicationJavascript,
+        }], |_| Some(b"(function() {})()".to_vec()));
+
+        let result = engine.check_network_urls("https://ads.example.com/ad.js", "https://example.com", "script");
+        assert_eq!(result.redirect, Some(Redirection::Resource(format!(
+            "data:application/javascript;base64,{}",
+            base64::encode("(function() {})()"),
+        ))));
+    }
+
+    #[test]
+    fn from_serialized_with_resources() {
+        let resource = |content: &str| Resource {
+            name: "noopjs".to_owned(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: base64::encode(content),
+        };
+        let filters = vec![
+            String::from("||ads.com/x.js$redirect=noopjs"),
+        ];
+        let mut engine = Engine::from_rules(&filters, Default::default());
+        engine.use_resources(&[resource("(function() {})();")]);
+        let serialized = engine.serialize_raw().unwrap();
+
+        let engine = Engine::from_serialized_with_resources(&serialized, &[resource("(function() { /* v2 */ })();")]).unwrap();
+        let result = engine.check_network_urls("https://ads.com/x.js", "https://example.com", "script");
+        assert!(result.matched);
+        assert_eq!(result.redirect, Some(Redirection::Resource(format!(
+            "data:application/javascript;base64,{}",
+            base64::encode("(function() { /* v2 */ })();"),
+        ))));
+
+        let engine = Engine::from_serialized_with_resources(&serialized, &[]).unwrap();
+        let result = engine.check_network_urls("https://ads.com/x.js", "https://example.com", "script");
+        assert_eq!(result.redirect, None);
+        assert_eq!(result.missing_redirect, Some("noopjs".to_owned()));
+
+        assert!(Engine::from_serialized_with_resources(&[1, 2, 3], &[]).is_err());
+    }
+
+    #[test]
+    fn document() {
+        let filters = vec![
+            String::from("||example.com$document"),
+            String::from("@@||sub.example.com$document"),
+        ];
+
+        let engine = Engine::from_rules_debug(&filters, Default::default());
+
+        assert!(engine.check_network_urls("https://example.com", "https://example.com", "document").matched);
+        assert!(!engine.check_network_urls("https://example.com", "https://example.com", "script").matched);
+        assert!(engine.check_network_urls("https://sub.example.com", "https://sub.example.com", "document").exception.is_some());
+    }
+
+    #[test]
+    fn unimplemented_options_never_block() {
+        let engine = Engine::from_rules(&["||t.co^$urlskip=?u,document".to_owned()], Default::default());
+
+        assert!(!engine.check_network_urls("https://t.co/abc?u=https%3A%2F%2Fexample.net", "", "document").matched);
+        assert!(!engine.check_network_urls("https://t.co/abc", "https://example.com", "script").matched);
+    }
+
+    #[test]
+    fn unimplemented_options_are_not_optimized() {
+        let rules = ["/static/ad.$urlskip=?url".to_owned(), "/static/ad-".to_owned()];
+        for optimize in [false, true].iter() {
+            let mut filter_set = FilterSet::new(false);
+            filter_set.add_filters(&rules, Default::default());
+            let engine = Engine::from_filter_set(filter_set, *optimize);
+
+            assert!(!engine.check_network_urls("https://x.com/static/ad.js", "https://example.com", "script").matched, "optimize: {}", optimize);
+            assert!(engine.check_network_urls("https://x.com/static/ad-1.js", "https://example.com", "script").matched, "optimize: {}", optimize);
+        }
+    }
+
+    #[test]
+    fn untyped_rules_skip_document_navigations() {
+        let engine = Engine::from_rules(&["||ads.com^".to_owned()], Default::default());
+
+        assert!(engine.check_network_urls("https://ads.com/script.js", "https://example.com", "script").matched);
+        assert!(engine.check_network_urls("https://ads.com/frame.html", "https://example.com", "sub_frame").matched);
+        assert!(!engine.check_network_urls("https://ads.com/", "https://ads.com", "main_frame").matched);
+        assert!(!engine.check_network_urls("https://ads.com/", "", "document").matched);
+    }
+
+    #[test]
+    fn all_exception() {
+        let filters = vec![
+            String::from("||trusted.com^"),
+            String::from("/ads.js$all"),
+            String::from("/banner.png"),
+            String::from("@@||trusted.com^$all"),
+        ];
+
+        let engine = Engine::from_rules_debug(&filters, Default::default());
+
+        for (url, request_type) in &[
+            ("https://trusted.com/ads.js", "script"),
+            ("https://cdn.trusted.com/ads.js", "script"),
+            ("https://trusted.com/banner.png", "image"),
+            ("https://trusted.com/ads.js", "document"),
+        ] {
+            let result = engine.check_network_urls(url, "https://example.com", request_type);
+            assert!(!result.matched, "{} ({}) should be allowed", url, request_type);
+            assert_eq!(result.exception, Some("@@||trusted.com^$all".to_owned()));
+        }
+
+        // Untyped filters don't match the navigation, so there is nothing for the exception to
+        // override
+        let result = engine.check_network_urls("https://trusted.com", "https://example.com", "document");
+        assert!(!result.matched);
+        assert_eq!(result.exception, None);
+
+        // Other hosts are still blocked, including `$all` document matches
+        assert!(engine.check_network_urls("https://example.com/ads.js", "https://example.com", "script").matched);
+        assert!(engine.check_network_urls("https://example.com/ads.js", "https://example.com", "document").matched);
+        assert!(engine.check_network_urls("https://example.com/banner.png", "https://example.com", "image").matched);
+    }
+
+    #[test]
+    fn implicit_all() {
+        // Hostname filters don't implicitly block documents
+        {
+            let engine = Engine::from_rules_debug(&vec![String::from("||example.com^")], Default::default());
+            assert!(!engine.check_network_urls("https://example.com", "https://example.com", "document").matched);
+        }
+        {
+            let engine = Engine::from_rules_debug(&vec![String::from("||example.com^$first-party,match-case")], Default::default());
+            assert!(!engine.check_network_urls("https://example.com", "https://example.com", "document").matched);
+        }
+        {
+            let engine = Engine::from_rules_debug(&vec![String::from("||example.com^$script")], Default::default());
+            assert!(!engine.check_network_urls("https://example.com", "https://example.com", "document").matched);
+        }
+        {
+            let engine = Engine::from_rules_debug(&vec![String::from("||example.com^$~script")], Default::default());
+            assert!(!engine.check_network_urls("https://example.com", "https://example.com", "document").matched);
+        }
+        {
+            let engine = Engine::from_rules_debug(&vec![String::from("||example.com^$document"), String::from("@@||example.com^$generichide")], Default::default());
+            assert!(engine.check_network_urls("https://example.com", "https://example.com", "document").matched);
+        }
+        {
+            let engine = Engine::from_rules_debug(&vec![String::from("example.com")], ParseOptions { format: FilterFormat::Hosts, ..Default::default() });
+            assert!(engine.check_network_urls("https://example.com", "https://example.com", "document").matched);
+        }
+        {
+            let engine = Engine::from_rules_debug(&vec![String::from("||example.com/path")], Default::default());
+            assert!(!engine.check_network_urls("https://example.com/path", "https://example.com/path", "document").matched);
+        }
+        {
+            let engine = Engine::from_rules_debug(&vec![String::from("||example.com/path^")], Default::default());
+            assert!(!engine.check_network_urls("https://example.com/path", "https://example.com/path", "document").matched);
+        }
+    }
+
+    #[test]
+    fn generichide() {
+        let filters = vec![
+            String::from("##.donotblock"),
+            String::from("##a[href=\"generic.com\"]"),
+
+            String::from("@@||example.com$generichide"),
+            String::from("example.com##.block"),
+
+            String::from("@@||example2.com/test.html$generichide"),
+            String::from("example2.com##.block"),
+        ];
+        let url_results = vec![
+            ("https://example.com", vec![".block"], true),
+            ("https://example.com/test.html", vec![".block"], true),
+            ("https://example2.com", vec![".block", "a[href=\"generic.com\"]"], false),
+            ("https://example2.com/test.html", vec![".block"], true),
+        ];
+
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        url_results.into_iter().for_each(|(url, expected_result, expected_generichide)| {
+            let result = engine.url_cosmetic_resources(url);
+            assert_eq!(result.hide_selectors, expected_result.iter().map(|s| s.to_string()).collect::<HashSet<_>>());
+            assert_eq!(result.generichide, expected_generichide);
+        });
+    }
+
+    #[test]
+    fn important_redirect() {
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filters(&vec![
+            "||addthis.com^$important,3p,domain=~missingkids.com|~missingkids.org|~sainsburys.jobs|~sitecore.com|~amd.com".to_string(),
+            "||addthis.com/*/addthis_widget.js$script,redirect=addthis.com/addthis_widget.js".to_string(),
+        ], Default::default());
+        let mut engine = Engine::from_filter_set(filter_set, false);
+
+        engine.add_resource(Resource {
+            name: "addthis.com/addthis_widget.js".to_owned(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: base64::encode("window.addthis = undefined"),
+        }).unwrap();
+
+        let result = engine.check_network_urls("https://s7.addthis.com/js/250/addthis_widget.js?pub=resto", "https://www.rhmodern.com/catalog/product/product.jsp?productId=prod14970086&categoryId=cat7150028", "script");
+
+        assert!(result.redirect.is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "full-regex-handling")]
+    fn slow_filter_telemetry() {
+        let slow_filter = r"/^https?:\/\/(?:[a-z0-9-]+\.)*example\.com\/(?:[a-z0-9]+\/){1,32}.*(?:ad|banner)[0-9]{2,}\.js/$script";
+        let filters = vec![
+            String::from(slow_filter),
+            String::from("||fast.com^"),
+        ];
+        let url = format!("https://a.b.c.example.com/{}banner42.js", "path/".repeat(30));
+
+        let mut engine = Engine::from_rules_debug(&filters, Default::default());
+
+        // Telemetry is disabled by default
+        engine.check_network_urls(&url, "https://example.com", "script");
+        assert!(engine.slow_filters().is_empty());
+
+        // A generous budget should not flag anything
+        engine.enable_slow_filter_telemetry(Duration::from_secs(60));
+        engine.check_network_urls(&url, "https://example.com", "script");
+        assert!(engine.slow_filters().is_empty());
+
+        // Any regex evaluation will exceed a budget of 1ns
+        engine.enable_slow_filter_telemetry(Duration::from_nanos(1));
+        let result = engine.check_network_urls(&url, "https://example.com", "script");
+        assert!(result.matched);
+        let slow = engine.slow_filters();
+        assert!(slow.iter().any(|f| f.filter == slow_filter && f.count >= 1));
+
+        engine.disable_slow_filter_telemetry();
+        assert!(engine.slow_filters().is_empty());
+    }
+
+    #[test]
+    fn bucket_stats() {
+        let filters = vec![
+            String::from("||ads.example.com^"),
+            String::from("/banner/*/img^"),
+            String::from("||tracker.net^$third-party"),
+            String::from("@@||ads.example.com/allowed^"),
+        ];
+        let mut engine = Engine::from_rules(&filters, Default::default());
+
+        // Nothing is recorded by default
+        engine.check_network_urls("https://ads.example.com/script.js", "https://example.com", "script");
+        assert_eq!(engine.bucket_stats(), BucketStats { filters_indexed: 4, ..Default::default() });
+
+        engine.enable_bucket_stats();
+        assert!(engine.check_network_urls("https://ads.example.com/script.js", "https://example.com", "script").matched);
+        assert!(!engine.check_network_urls("https://example.org/index.html", "https://example.org", "document").matched);
+        assert!(!engine.check_network_urls("https://ads.example.com/allowed", "https://example.com", "xhr").matched);
+
+        let stats = engine.bucket_stats();
+        assert_eq!(stats.checks, 3);
+        assert_eq!(stats.filters_indexed, 4);
+        assert!(stats.buckets_visited >= 2);
+        // Both `||ads.example.com^` checks compare it, and the exception once
+        assert!(stats.filters_compared >= 3);
+        assert!(stats.filters_compared < stats.checks * stats.filters_indexed);
+
+        engine.reset_bucket_stats();
+        assert_eq!(engine.bucket_stats(), BucketStats { filters_indexed: 4, ..Default::default() });
+
+        engine.check_network_urls("https://example.org/img.png", "https://example.org", "image");
+        assert_eq!(engine.bucket_stats().checks, 1);
+
+        engine.disable_bucket_stats();
+        assert_eq!(engine.bucket_stats().checks, 0);
+    }
+
+    #[test]
+    fn user_filters_take_precedence() {
+        let mut engine = Engine::from_rules_debug(&[
+            "||ads.example.com^".to_owned(),
+            "||important.example.com^$important".to_owned(),
+            "@@||cdn.example.com^".to_owned(),
+        ], Default::default());
+        let check = |engine: &Engine, url: &str| engine.check_network_urls(url, "https://news.com", "script");
+        assert!(check(&engine, "https://ads.example.com/ad.js").matched);
+        assert!(!check(&engine, "https://cdn.example.com/tracker.js").matched);
+
+        let mut user_filters = FilterSet::new(true);
+        user_filters.add_filters(&[
+            "@@||ads.example.com/needed.js".to_owned(),
+            "@@||important.example.com^".to_owned(),
+            "||cdn.example.com/tracker.js".to_owned(),
+        ], Default::default());
+        engine.set_user_filters(user_filters);
+
+        // A user exception overrides a subscription block
+        let result = check(&engine, "https://ads.example.com/needed.js");
+        assert!(!result.matched);
+        assert_eq!(result.exception.as_deref(), Some("@@||ads.example.com/needed.js"));
+        assert_eq!(result.filter.as_deref(), Some("||ads.example.com^"));
+        assert!(check(&engine, "https://ads.example.com/ad.js").matched);
+
+        // ...but not an important one
+        assert!(check(&engine, "https://important.example.com/ad.js").matched);
+
+        // A user block overrides a subscription exception
+        let result = check(&engine, "https://cdn.example.com/tracker.js");
+        assert!(result.matched);
+        assert_eq!(result.filter.as_deref(), Some("||cdn.example.com/tracker.js"));
+        assert!(!check(&engine, "https://cdn.example.com/lib.js").matched);
+
+        engine.set_user_filters(FilterSet::new(true));
+        assert!(check(&engine, "https://ads.example.com/needed.js").matched);
+        assert!(!check(&engine, "https://cdn.example.com/tracker.js").matched);
+    }
+
+    #[test]
+    fn check_with_reason() {
+        let mut engine = Engine::from_rules(&[
+            "||ads.example.com^".to_owned(),
+            "@@||ads.example.com/allowed^".to_owned(),
+            "/generic-ad.".to_owned(),
+            "||tagged.example.com^$tag=annoyances".to_owned(),
+        ], Default::default());
+        engine.add_allowlist_domain("trusted.com");
+        let request = |url: &str, source_url: &str| Request::from_urls(url, source_url, "script").unwrap();
+        let exception = NetworkFilter::parse("@@||ads.example.com/allowed^", false, Default::default()).unwrap();
+
+        let (result, reason) = engine.check_with_reason(&request("https://ads.example.com/ad.js", "https://news.com"));
+        assert!(result.matched);
+        assert_eq!(reason, None);
+
+        let (_, reason) = engine.check_with_reason(&request("https://cdn.com/app.js", "https://news.com"));
+        assert_eq!(reason, Some(AllowReason::NoMatch));
+
+        let (_, reason) = engine.check_with_reason(&request("https://ads.example.com/allowed", "https://news.com"));
+        assert_eq!(reason, Some(AllowReason::Exception(exception.filter_id())));
+
+        let (_, reason) = engine.check_with_reason(&request("https://ads.example.com/ad.js", "https://trusted.com"));
+        assert_eq!(reason, Some(AllowReason::Allowlisted));
+
+        let mut generic = request("https://cdn.com/generic-ad.js", "https://news.com");
+        assert_eq!(engine.check_with_reason(&generic).1, None);
+        generic.genericblock = true;
+        assert_eq!(engine.check_with_reason(&generic).1, Some(AllowReason::GenericBlockSuppressed));
+
+        let tagged = request("https://tagged.example.com/popup.js", "https://news.com");
+        assert_eq!(engine.check_with_reason(&tagged).1, Some(AllowReason::TagDisabled));
+        engine.enable_tags(&["annoyances"]);
+        assert_eq!(engine.check_with_reason(&tagged).1, None);
+    }
+
+    #[test]
+    fn media_type() {
+        let engine = Engine::from_rules_debug(&[
+            "||cdn.com^$media".to_owned(),
+            "@@||cdn.com/allowed/$media".to_owned(),
+        ], Default::default());
+        let request = |url| Request::builder(url).source_url("https://example.com");
+        let check = |builder: RequestBuilder| engine.check_network_request(&builder.build().unwrap());
+
+        assert!(check(request("https://cdn.com/clip.mp4").infer_type()).matched);
+        assert!(check(request("https://cdn.com/stream").fetch_dest("audio")).matched);
+        assert!(check(request("https://cdn.com/stream").fetch_dest("video")).matched);
+        assert!(check(request("https://cdn.com/stream").request_type("media")).matched);
+        assert!(!check(request("https://cdn.com/app.js").request_type("script")).matched);
+        assert!(!check(request("https://cdn.com/app.js").fetch_dest("script")).matched);
+
+        let result = check(request("https://cdn.com/allowed/clip.mp4").infer_type());
+        assert!(!result.matched);
+        assert_eq!(result.exception.as_deref(), Some("@@||cdn.com/allowed/$media"));
+    }
+
+    #[test]
+    fn navigation_without_source() {
+        let engine = Engine::from_rules_debug(&[
+            "||tracker.net^$third-party,document".to_owned(),
+            "||ads.com^$document,domain=ads.com".to_owned(),
+            "||ads.com^$document,domain=news.com".to_owned(),
+            "||popup.net^$document,domain=~popup.net".to_owned(),
+        ], Default::default());
+        let navigate = |url: &str| engine.check_network_urls(url, "", "document");
+
+        assert!(!navigate("https://tracker.net/").matched);
+        assert!(engine.check_network_urls("https://tracker.net/", "https://news.com", "document").matched);
+
+        // `$domain` applies to the navigated page itself
+        assert_eq!(navigate("https://www.ads.com/").filter.as_deref(), Some("||ads.com^$document,domain=ads.com"));
+        assert!(!navigate("https://popup.net/").matched);
+    }
+
+    #[test]
+    fn image_type() {
+        let engine = Engine::from_rules_debug(&[
+            "||cdn.com^$image".to_owned(),
+            "||other.com^$~image".to_owned(),
+        ], Default::default());
+        let check = |builder: RequestBuilder| engine.check_network_request(&builder.build().unwrap());
+
+        for (host, image) in &[("https://cdn.com", true), ("https://other.com", false)] {
+            let (png, favicon, icon, script) = (format!("{}/logo.png", host), format!("{}/favicon.ico", host), format!("{}/icon", host), format!("{}/app.js", host));
+            let request = |url| Request::builder(url).source_url("https://example.com");
+            assert_eq!(check(request(&png).infer_type()).matched, *image);
+            assert_eq!(check(request(&favicon).infer_type()).matched, *image);
+            assert_eq!(check(request(&icon).request_type("favicon")).matched, *image);
+            assert_eq!(check(request(&icon).fetch_dest("image")).matched, *image);
+            assert_eq!(check(request(&script).request_type("script")).matched, !image);
+        }
+    }
+
+    #[test]
+    fn xhr_type() {
+        for option in &["xhr", "xmlhttprequest"] {
+            let engine = Engine::from_rules_debug(&[
+                format!("||api.com^${}", option),
+                format!("@@||api.com/allowed/${}", option),
+            ], Default::default());
+            let check = |url: &str, request_type: &str| engine.check_network_urls(url, "https://example.com", request_type);
+
+            for request_type in &["xhr", "xmlhttprequest"] {
+                assert!(check("https://api.com/collect", request_type).matched, "${} with {}", option, request_type);
+                let result = check("https://api.com/allowed/collect", request_type);
+                assert!(!result.matched);
+                assert_eq!(result.exception, Some(format!("@@||api.com/allowed/${}", option)));
+            }
+            assert!(!check("https://api.com/collect", "script").matched);
+            // Like in the upstream matching dataset, `fetch` is not an alias of `xmlhttprequest`
+            assert!(!check("https://api.com/collect", "fetch").matched);
+        }
+
+        let xhr = NetworkFilter::parse("||api.com^$xhr", false, Default::default()).unwrap();
+        let xmlhttprequest = NetworkFilter::parse("||api.com^$xmlhttprequest", false, Default::default()).unwrap();
+        assert_eq!(xhr.mask, xmlhttprequest.mask);
+        let negated = NetworkFilter::parse("||api.com^$~xhr", false, Default::default()).unwrap();
+        assert_eq!(negated.mask, NetworkFilter::parse("||api.com^$~xmlhttprequest", false, Default::default()).unwrap().mask);
+    }
+
+    #[test]
+    fn match_case() {
+        let engine = Engine::from_rules(&["/Banner$match-case".to_owned(), "/Tracker".to_owned()], Default::default());
+
+        assert!(engine.check_network_urls("https://example.com/Banner", "https://example.com", "image").matched);
+        assert!(!engine.check_network_urls("https://example.com/banner", "https://example.com", "image").matched);
+        assert!(engine.check_network_urls_with_hostnames("https://example.com/Banner", "example.com", "example.com", "image", None).matched);
+        assert!(!engine.check_network_urls_with_hostnames("https://example.com/banner", "example.com", "example.com", "image", None).matched);
+
+        assert!(engine.check_network_urls("https://example.com/Tracker", "https://example.com", "image").matched);
+        assert!(engine.check_network_urls("https://example.com/tracker", "https://example.com", "image").matched);
+    }
+
+    #[test]
+    fn request_log_keeps_most_recent() {
+        let filters = vec![
+            String::from("||ads.example.com^"),
+            String::from("@@||ads.example.com/ok.js"),
+        ];
+        let mut engine = Engine::from_rules(&filters, Default::default());
+        let id = |rule: &str| NetworkFilter::parse(rule, false, Default::default()).unwrap().filter_id();
+
+        engine.check_network_urls("https://ads.example.com/ad.js", "https://example.com", "script");
+        assert!(engine.request_log().is_empty());
+
+        engine.enable_request_log(3);
+        for i in 0..5 {
+            engine.check_network_urls(&format!("https://example.com/{}.png", i), "https://example.com", "image");
+        }
+        engine.check_network_urls("https://ads.example.com/ad.js", "https://example.com", "script");
+        engine.check_network_urls("https://ads.example.com/ok.js", "https://example.com", "script");
+
+        let log = engine.request_log();
+        assert_eq!(log, vec![
+            LoggedRequest {
+                url: "https://example.com/4.png".to_owned(),
+                request_type: RequestType::Image,
+                blocked: false,
+                filter_id: None,
+                exception_id: None,
+            },
+            LoggedRequest {
+                url: "https://ads.example.com/ad.js".to_owned(),
+                request_type: RequestType::Script,
+                blocked: true,
+                filter_id: Some(id("||ads.example.com^")),
+                exception_id: None,
+            },
+            LoggedRequest {
+                url: "https://ads.example.com/ok.js".to_owned(),
+                request_type: RequestType::Script,
+                blocked: false,
+                filter_id: Some(id("||ads.example.com^")),
+                exception_id: Some(id("@@||ads.example.com/ok.js")),
+            },
+        ]);
+
+        engine.clear_request_log();
+        assert!(engine.request_log().is_empty());
+        engine.check_network_urls("https://ads.example.com/ad.js", "https://example.com", "script");
+        assert_eq!(engine.request_log().len(), 1);
+
+        engine.disable_request_log();
+        assert!(engine.request_log().is_empty());
+    }
+
+    #[test]
+    fn allowlisted_domains() {
+        let filters = vec![
+            String::from("||ads.example.net^"),
+            String::from("||example.org^$document"),
+            String::from("##div.ad"),
+            String::from("$$iframe"),
+        ];
+        let mut engine = Engine::from_rules(&filters, ParseOptions { format: FilterFormat::Adguard, ..Default::default() });
+        engine.add_allowlist_domain("Example.COM");
+        engine.add_allowlist_domain("example.org");
+
+        assert!(!engine.check_network_urls("https://ads.example.net/ad.js", "https://example.com", "script").matched);
+        assert!(!engine.check_network_urls("https://ads.example.net/ad.js", "https://www.example.com/page", "script").matched);
+        assert!(!engine.check_network_urls_with_hostnames("https://ads.example.net/ad.js", "ads.example.net", "sub.example.com", "script", None).matched);
+        assert!(!engine.check_network_urls("https://example.org", "", "document").matched);
+        assert!(engine.check_network_urls("https://ads.example.net/ad.js", "https://notexample.com", "script").matched);
+        assert!(engine.check_network_urls("https://ads.example.net/ad.js", "https://example.co", "script").matched);
+
+        let resources = engine.url_cosmetic_resources("https://www.example.com");
+        assert!(resources.hide_selectors.is_empty());
+        assert!(resources.generichide);
+        assert!(engine.url_html_filters("https://example.com").is_empty());
+        assert!(engine.url_cosmetic_resources("https://example.net").hide_selectors.contains("div.ad"));
+        assert_eq!(engine.url_html_filters("https://example.net").len(), 1);
+
+        let view = engine.into_view();
+        assert!(!view.check_network_urls("https://ads.example.net/ad.js", "https://example.com", "script").matched);
+        assert!(view.check_network_urls("https://ads.example.net/ad.js", "https://example.net", "script").matched);
+    }
+
+    #[test]
+    fn html_filters() {
+        let filters = vec![
+            String::from(r#"example.com$$script[tag-content="adsbygoogle"][max-length="2000"]"#),
+            String::from("sub.example.com$@$script[tag-content=\"adsbygoogle\"][max-length=\"2000\"]"),
+            String::from("||ads.example.com^"),
+        ];
+        let engine = Engine::from_rules(&filters, ParseOptions { format: FilterFormat::Adguard, ..Default::default() });
+
+        let html_filters = engine.url_html_filters("https://www.example.com/index.html");
+        assert_eq!(html_filters.len(), 1);
+        assert_eq!(html_filters[0].tag_name, "script");
+        assert_eq!(html_filters[0].attribute("tag-content"), Some("adsbygoogle"));
+        assert_eq!(html_filters[0].attribute("max-length"), Some("2000"));
+
+        assert!(engine.url_html_filters("https://sub.example.com").is_empty());
+        assert!(engine.url_html_filters("https://example.org").is_empty());
+
+        let serialized = engine.serialize_raw().unwrap();
+        let mut deserialized = Engine::default();
+        deserialized.deserialize(&serialized).unwrap();
+        assert_eq!(deserialized.url_html_filters("https://www.example.com").len(), 1);
+        assert!(deserialized.check_network_urls("https://ads.example.com/ad.js", "https://example.com", "script").matched);
+
+        // Other formats don't have HTML filtering rules
+        let engine = Engine::from_rules(&filters, Default::default());
+        assert!(engine.url_html_filters("https://www.example.com").is_empty());
+        assert!(!engine.check_network_urls("https://www.example.com/", "https://www.example.com/", "document").matched);
+    }
+
+    #[test]
+    fn skip_cosmetic_filters() {
+        let rules = [
+            "||ads.example.com^",
+            "example.com##.ad",
+            "##.banner",
+            "example.com##+js(nowebrtc)",
+            r#"example.com$$script[tag-content="adsbygoogle"]"#,
+        ].iter().map(|rule| rule.to_string()).collect::<Vec<_>>();
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filters(&rules, ParseOptions { format: FilterFormat::Adguard, skip_cosmetic_filters: true, ..Default::default() });
+        assert_eq!(filter_set.network_filters.len(), 1);
+        assert!(filter_set.cosmetic_filters.is_empty());
+        assert!(filter_set.html_filters.is_empty());
+
+        let engine = Engine::from_filter_set(filter_set, true);
+        assert!(engine.check_network_urls("https://ads.example.com/ad.js", "https://example.com", "script").matched);
+        let resources = engine.url_cosmetic_resources("https://example.com");
+        assert!(resources.hide_selectors.is_empty());
+        assert!(resources.injected_script.is_empty());
+        assert!(engine.hidden_class_id_selectors(&["banner".to_string()], &[], &HashSet::new()).is_empty());
+        assert!(engine.url_html_filters("https://example.com").is_empty());
+    }
+
+    #[test]
+    fn engine_view_shared_between_threads() {
+        let filters = vec![
+            String::from("||ads.example.com^"),
+            String::from("||tracker.example.com^$tag=trackers"),
+        ];
+        let mut engine = Engine::from_rules(&filters, Default::default());
+        engine.enable_tags(&["trackers"]);
+        let view = engine.into_view();
+
+        let mut untagged = view.clone();
+        untagged.disable_tags(&["trackers"]);
+        assert!(!untagged.tag_exists("trackers"));
+        assert!(view.tag_exists("trackers"));
+
+        let handles: Vec<_> = (0..4).map(|i| {
+            let view = if i % 2 == 0 { view.clone() } else { untagged.clone() };
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    assert!(view.check_network_urls("https://ads.example.com/ad.js", "https://example.com", "script").matched);
+                    let tracked = view.check_network_urls("https://tracker.example.com/t.js", "https://example.com", "script").matched;
+                    assert_eq!(tracked, i % 2 == 0);
+                }
+            })
+        }).collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn engine_view_cosmetic_tags() {
+        let filters = vec![
+            String::from("@@||example.com^$generichide,tag=nogeneric"),
+            String::from("##.banner"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+        assert!(!engine.url_cosmetic_resources("https://example.com").generichide);
+        let view = engine.into_view();
+
+        let mut tagged = view.clone();
+        tagged.enable_tags(&["nogeneric"]);
+        assert!(tagged.url_cosmetic_resources("https://example.com").generichide);
+        assert!(tagged.document_flags("https://example.com").generic_hide);
+        assert!(tagged.cosmetic_stylesheet_for("example.com").is_empty());
+        assert!(!tagged.has_cosmetic_rules_for("example.com"));
+
+        assert!(!view.url_cosmetic_resources("https://example.com").generichide);
+        assert!(!view.document_flags("https://example.com").generic_hide);
+    }
+
+    #[test]
+    fn empty_redirect() {
+        let filters = vec![
+            String::from("||ads.com/x.js^$empty"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        let result = engine.check_network_urls("https://ads.com/x.js", "https://example.com", "script");
+        assert!(result.matched);
+        assert_eq!(result.redirect, Some(Redirection::Resource("data:application/javascript;base64,".to_owned())));
+
+        let result = engine.check_network_urls("https://ads.com/x.js", "https://example.com", "");
+        assert!(result.matched);
+        assert_eq!(result.redirect, Some(Redirection::Resource("data:text/plain;base64,".to_owned())));
+    }
+
+    #[test]
+    fn empty_redirect_prefers_loaded_resource() {
+        let filters = vec![
+            String::from("||ads.com/x.js^$empty"),
+        ];
+        let mut engine = Engine::from_rules(&filters, Default::default());
+        engine.add_resource(Resource {
+            name: "empty".to_owned(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::TextPlain),
+            content: base64::encode(""),
+        }).unwrap();
+
+        let result = engine.check_network_urls("https://ads.com/x.js", "https://example.com", "script");
+        assert_eq!(result.redirect, Some(Redirection::Resource("data:text/plain;base64,".to_owned())));
+    }
+
+    #[test]
+    fn procedural_rules_serialization() {
+        use crate::filters::cosmetic::{ProceduralOperator, TextMatcher};
+
+        let filters = vec![
+            String::from("example.com##div:has-text(/sponsored/i)"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        let mut expected = HashMap::new();
+        expected.insert("div".to_owned(), vec![vec![ProceduralOperator::HasText(TextMatcher::Regex {
+            pattern: "sponsored".to_owned(),
+            flags: "i".to_owned(),
+        })]]);
+        assert_eq!(engine.url_cosmetic_resources("https://example.com").procedural_selectors, expected);
+        assert!(engine.url_cosmetic_resources("https://example.com").hide_selectors.is_empty());
+
+        for serialized in &[engine.serialize_raw().unwrap(), engine.serialize_compressed().unwrap()] {
+            let mut deserialized_engine = Engine::default();
+            deserialized_engine.deserialize(serialized).unwrap();
+            assert_eq!(deserialized_engine.url_cosmetic_resources("https://example.com").procedural_selectors, expected);
+        }
+    }
+
+    #[test]
+    fn remove_rules_serialization() {
+        let filters = vec![
+            String::from("example.com##.ad:remove()"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        let mut expected = HashSet::new();
+        expected.insert(".ad".to_owned());
+        assert_eq!(engine.url_cosmetic_resources("https://example.com").remove_selectors, expected);
+        assert!(engine.url_cosmetic_resources("https://example.com").hide_selectors.is_empty());
+
+        for serialized in &[engine.serialize_raw().unwrap(), engine.serialize_compressed().unwrap()] {
+            let mut deserialized_engine = Engine::default();
+            deserialized_engine.deserialize(serialized).unwrap();
+            assert_eq!(deserialized_engine.url_cosmetic_resources("https://example.com").remove_selectors, expected);
+        }
+    }
+
+    #[test]
+    fn cosmetic_additions_serialization() {
+        let filters = vec![
+            String::from("example.com##.ad:style(margin: 0)"),
+            String::from("example.com##.banner:remove()"),
+            String::from("example.com##div:has-text(Sponsored)"),
+            String::from("example.com##.hidden"),
+            String::from("sub.example.com#@#.banner:remove()"),
+            String::from("sub.example.com#@#div:has-text(Sponsored)"),
+            String::from("example.com$$script[tag-content=\"ads\"]"),
+        ];
+        let engine = Engine::from_rules(&filters, ParseOptions { format: FilterFormat::Adguard, ..Default::default() });
+
+        let resources = |engine: &Engine, url: &str| {
+            let resources = engine.url_cosmetic_resources(url);
+            (resources.hide_selectors, resources.style_selectors, resources.remove_selectors, resources.procedural_selectors.len())
+        };
+        let expected = resources(&engine, "https://example.com");
+        assert_eq!(expected.0.len(), 1);
+        assert_eq!(expected.1.len(), 1);
+        assert_eq!(expected.2.len(), 1);
+        assert_eq!(expected.3, 1);
+        let expected_sub = resources(&engine, "https://sub.example.com");
+        assert!(expected_sub.2.is_empty());
+        assert_eq!(expected_sub.3, 0);
+
+        for serialized in &[engine.serialize_raw().unwrap(), engine.serialize_compressed().unwrap()] {
+            let mut deserialized_engine = Engine::default();
+            deserialized_engine.deserialize(serialized).unwrap();
+            assert_eq!(resources(&deserialized_engine, "https://example.com"), expected);
+            assert_eq!(resources(&deserialized_engine, "https://sub.example.com"), expected_sub);
+        }
+
+        // HTML filtering rules are only supported by the uncompressed format
+        let mut deserialized_engine = Engine::default();
+        deserialized_engine.deserialize(&engine.serialize_raw().unwrap()).unwrap();
+        assert_eq!(deserialized_engine.url_html_filters("https://example.com").len(), 1);
+    }
+
+    #[test]
+    fn cosmetic_stylesheet_for() {
+        let engine = Engine::from_rules(&[
+            String::from("example.com##.ad"),
+            String::from("example.com##div[id^=\"banner\"]"),
+            String::from("sub.example.com#@#.ad"),
+            String::from("##a[href*=\"tracker\"]"),
+            String::from("#@#.excepted-everywhere"),
+            String::from("example.com##.excepted-everywhere"),
+            String::from("example.com##.styled:style(color: red)"),
+            String::from("###generic-id"),
+            String::from("@@||nogeneric.com^$generichide"),
+        ], Default::default());
+
+        assert_eq!(engine.cosmetic_stylesheet_for("example.com"), concat!(
+            ".ad{display:none!important}\n",
+            ".excepted-everywhere{display:none!important}\n",
+            "a[href*=\"tracker\"]{display:none!important}\n",
+            "div[id^=\"banner\"]{display:none!important}\n",
+        ));
+        // The hostname-specific exception removes `.ad`
+        assert_eq!(engine.cosmetic_stylesheet_for("sub.example.com"), concat!(
+            ".excepted-everywhere{display:none!important}\n",
+            "a[href*=\"tracker\"]{display:none!important}\n",
+            "div[id^=\"banner\"]{display:none!important}\n",
+        ));
+        assert_eq!(engine.cosmetic_stylesheet_for("other.com"), "a[href*=\"tracker\"]{display:none!important}\n");
+        assert_eq!(engine.cosmetic_stylesheet_for("nogeneric.com"), "");
+    }
+
+    #[test]
+    fn serialize_cosmetic() {
+        let engine = Engine::from_rules(&[
+            String::from("||ads.example.com^"),
+            String::from("example.com##.ad"),
+            String::from("example.com##.banner:remove()"),
+            String::from("example.com##div:has-text(Sponsored)"),
+            String::from("sub.example.com#@#.ad"),
+            String::from("##.generic"),
+        ], Default::default());
+        let serialized = engine.serialize_cosmetic().unwrap();
+
+        let mut network_engine = Engine::from_rules(&[String::from("||tracker.net^")], Default::default());
+        network_engine.deserialize_cosmetic(&serialized).unwrap();
+        for url in &["https://example.com", "https://sub.example.com", "https://other.com"] {
+            assert_eq!(network_engine.url_cosmetic_resources(url), engine.url_cosmetic_resources(url));
+        }
+        assert_eq!(network_engine.hidden_class_id_selectors(&["generic".to_owned()], &[], &HashSet::new()), vec![".generic"]);
+        // Network filters are left alone
+        assert!(network_engine.check_network_urls("https://tracker.net/t.js", "https://example.com", "script").matched);
+        assert!(!network_engine.check_network_urls("https://ads.example.com/ad.js", "https://example.com", "script").matched);
+
+        // The formats can't be mixed up
+        assert!(network_engine.deserialize_cosmetic(&engine.serialize_raw().unwrap()).is_err());
+        assert!(network_engine.deserialize(&serialized).is_err());
+    }
+
+    #[test]
+    fn cosmetic_diff() {
+        let engine = Engine::from_rules(&[
+            String::from("a.example.com,b.example.com##.shared-ad"),
+            String::from("example.com##.parent-ad"),
+            String::from("a.example.com##.only-a"),
+            String::from("b.example.com##.only-b"),
+            String::from("b.example.com#@#.parent-ad"),
+            String::from("##a[href*=\"tracker\"]"),
+        ], Default::default());
+
+        assert_eq!(engine.cosmetic_diff("a.example.com", "b.example.com"), CosmeticDiff {
+            only_a: vec![".only-a".to_owned(), ".parent-ad".to_owned()],
+            only_b: vec![".only-b".to_owned()],
+            shared: vec![".shared-ad".to_owned(), "a[href*=\"tracker\"]".to_owned()],
+        });
+        let reversed = engine.cosmetic_diff("b.example.com", "a.example.com");
+        assert_eq!(reversed.only_a, vec![".only-b".to_owned()]);
+        assert_eq!(reversed.only_b, vec![".only-a".to_owned(), ".parent-ad".to_owned()]);
+
+        let same = engine.cosmetic_diff("a.example.com", "a.example.com");
+        assert!(same.only_a.is_empty() && same.only_b.is_empty());
+        assert_eq!(same.shared.len(), 4);
+    }
+
+    #[test]
+    fn has_cosmetic_rules_for() {
+        let engine = Engine::from_rules(&[
+            String::from("example.com##.ad"),
+            String::from("sub.example.com#@#.ad"),
+            String::from("other.com#@#.banner"),
+            String::from("||example.net^$script"),
+        ], Default::default());
+        assert!(engine.has_cosmetic_rules_for("example.com"));
+        assert!(engine.has_cosmetic_rules_for("sub.example.com"));
+        assert!(!engine.has_cosmetic_rules_for("other.com"));
+        assert!(!engine.has_cosmetic_rules_for("test.com"));
+
+        let engine = Engine::from_rules(&[
+            String::from("##.ad"),
+            String::from("example.com##+js(nowebrtc)"),
+            String::from("@@||test.com^$generichide"),
+        ], Default::default());
+        assert!(engine.has_cosmetic_rules_for("example.com"));
+        assert!(engine.has_cosmetic_rules_for("other.com"));
+        assert!(!engine.has_cosmetic_rules_for("test.com"));
+    }
+
+    #[test]
+    fn specific_cosmetic_hostnames() {
+        let rules = [
+            "example.com,sub.example.org##.ad",
+            "~other.example.com,example.com##.banner",
+            "news.*##.sponsored",
+            "test.com#@#.ad",
+            "scripts.net##+js(nowebrtc)",
+            "münchen.de##.ad",
+            "##.generic",
+            "||example.net^$script",
+        ].iter().map(|rule| rule.to_string()).collect::<Vec<_>>();
+        let engine = Engine::from_rules_debug(&rules, Default::default());
+        assert_eq!(engine.specific_cosmetic_hostnames(), vec![
+            "example.com",
+            "news.*",
+            "other.example.com",
+            "scripts.net",
+            "sub.example.org",
+            "test.com",
+            "xn--mnchen-3ya.de",
+        ]);
+
+        // Names are kept without debugging too, but not once serialized
+        let engine = Engine::from_rules(&rules, Default::default());
+        assert_eq!(engine.specific_cosmetic_hostnames(), Engine::from_rules_debug(&rules, Default::default()).specific_cosmetic_hostnames());
+        let mut deserialized = Engine::default();
+        deserialized.deserialize(&engine.serialize_raw().unwrap()).unwrap();
+        assert!(deserialized.specific_cosmetic_hostnames().is_empty());
+        assert!(deserialized.has_cosmetic_rules_for("example.com"));
+    }
+
+    #[test]
+    fn generic_cosmetic_exceptions() {
+        let filters = vec![
+            String::from("##.ad"),
+            String::from("#@#.ad"),
+            String::from("example.com##.ad"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        let hidden = |engine: &Engine, url: &str| {
+            let resources = engine.url_cosmetic_resources(url);
+            let mut hidden = engine.hidden_class_id_selectors(&["ad".into()], &[], &resources.exceptions);
+            hidden.extend(resources.hide_selectors);
+            hidden
+        };
+        assert!(hidden(&engine, "https://test.com").is_empty());
+        assert_eq!(hidden(&engine, "https://example.com"), vec![".ad".to_owned()]);
+
+        for serialized in &[engine.serialize_raw().unwrap(), engine.serialize_compressed().unwrap()] {
+            let mut deserialized_engine = Engine::default();
+            deserialized_engine.deserialize(serialized).unwrap();
+            assert!(hidden(&deserialized_engine, "https://test.com").is_empty());
+            assert_eq!(hidden(&deserialized_engine, "https://example.com"), vec![".ad".to_owned()]);
+        }
+    }
+
+    #[test]
+    fn check_network_request_with_ip_address() {
+        let filters = vec![
+            String::from("||example.com^$ipaddress=10.0.0.0/8"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        let request = Request::from_urls("https://example.com/ad.js", "https://example.org", "script").unwrap();
+        assert!(!engine.check_network_request(&request).matched);
+        assert!(engine.check_network_request(&request.clone().with_ip_address("10.1.2.3".parse().unwrap())).matched);
+        assert!(!engine.check_network_request(&request.with_ip_address("11.1.2.3".parse().unwrap())).matched);
+    }
+
+    #[test]
+    fn check_network_request_with_response_headers() {
+        let filters = vec![
+            String::from("||example.com^$header=x-ad-server"),
+            String::from("@@||example.com/allowed/$header=x-ad-server:trusted"),
+            String::from("@@*$header=x-acceptable-ads"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        let request = |url: &str, headers: &[(&str, &str)]| {
+            Request::from_urls(url, "https://example.org", "script").unwrap().with_response_headers(headers)
+        };
+
+        // `$header` rules only apply once the response headers are known
+        assert!(!engine.check_network_urls("https://example.com/ad.js", "https://example.org", "script").matched);
+        assert!(engine.check_network_request(&request("https://example.com/ad.js", &[("X-Ad-Server", "adnet")])).matched);
+        assert!(!engine.check_network_request(&request("https://example.com/ad.js", &[("Server", "nginx")])).matched);
+
+        // `@@$header` exceptions only apply when the response has their header
+        let result = engine.check_network_request(&request("https://example.com/allowed/ad.js", &[("X-Ad-Server", "trusted")]));
+        assert!(!result.matched);
+        assert!(result.exception.is_some());
+        assert!(engine.check_network_request(&request("https://example.com/allowed/ad.js", &[("X-Ad-Server", "adnet")])).matched);
+        assert!(!engine.check_network_request(&request("https://example.com/ad.js", &[("X-Ad-Server", "adnet"), ("X-Acceptable-Ads", "1")])).matched);
+    }
+
+    #[test]
+    fn important_blocks_are_flagged() {
+        let engine = Engine::from_rules_debug(&[
+            String::from("||tracker.com^$important"),
+            String::from("@@||tracker.com^"),
+            String::from("||ads.com^"),
+            String::from("@@||ads.com/allowed^"),
+        ], Default::default());
+
+        // Exceptions can't override an `$important` block, which is reported as such
+        let result = engine.check_network_urls("https://tracker.com/pixel.gif", "https://example.com", "image");
+        assert!(result.matched);
+        assert!(result.important);
+        assert_eq!(result.filter.as_deref(), Some("||tracker.com^$important"));
+        assert_eq!(result.exception, None);
+
+        let result = engine.check_network_urls("https://ads.com/banner.gif", "https://example.com", "image");
+        assert!(result.matched);
+        assert!(!result.important);
+
+        let result = engine.check_network_urls("https://ads.com/allowed/banner.gif", "https://example.com", "image");
+        assert!(!result.matched);
+        assert!(!result.important);
+        assert!(result.exception.is_some());
+    }
+
+    #[test]
+    fn ping_and_beacon_requests() {
+        let engine = Engine::from_rules(&[
+            String::from("||tracker.com^$ping"),
+            String::from("@@||tracker.com/allowed^$ping"),
+            String::from("||stats.com^$beacon"),
+        ], Default::default());
+
+        for request_type in ["ping", "beacon"] {
+            let result = engine.check_network_urls("https://tracker.com/collect", "https://example.com", request_type);
+            assert!(result.matched, "{} should be blocked", request_type);
+            assert_eq!(result.request_type, Some(RequestType::Ping));
+
+            let result = engine.check_network_urls("https://tracker.com/allowed/collect", "https://example.com", request_type);
+            assert!(!result.matched, "{} should be allowed", request_type);
+            assert!(result.exception.is_some());
+
+            assert!(engine.check_network_urls("https://stats.com/collect", "https://example.com", request_type).matched);
+        }
+
+        // Other request types aren't affected
+        assert!(!engine.check_network_urls("https://tracker.com/collect", "https://example.com", "script").matched);
+        assert!(!engine.check_network_urls("https://stats.com/collect", "https://example.com", "xhr").matched);
+    }
+
+    #[test]
+    fn csp_of_blocked_documents_with_user_filters() {
+        let mut engine = Engine::from_rules(&[
+            String::from("||blocked.com^$document"),
+            String::from("||blocked.com^$csp=script-src 'none'"),
+            String::from("||allowed.com^$csp=script-src 'none'"),
+        ], Default::default());
+        let csp = |engine: &Engine, url: &str| {
+            let request = Request::from_urls(url, url, "document").unwrap();
+            (
+                engine.get_csp_directives(&request.url, &request.hostname, &request.hostname, "document", Some(false)),
+                engine.get_csp_policies(&request.url, &request.hostname, &request.hostname, "document", Some(false)),
+            )
+        };
+        assert_eq!(csp(&engine, "https://blocked.com"), (None, vec![]));
+        assert_eq!(csp(&engine, "https://allowed.com").0, Some(String::from("script-src 'none'")));
+
+        let mut user_filters = FilterSet::new(false);
+        user_filters.add_filters(&[
+            "@@||blocked.com^$document".to_owned(),
+            "||allowed.com^$document".to_owned(),
+        ], Default::default());
+        engine.set_user_filters(user_filters);
+
+        // A document unblocked by a user exception is loaded, so it gets its policies
+        assert!(!engine.check_network_urls("https://blocked.com", "", "document").matched);
+        assert_eq!(csp(&engine, "https://blocked.com"), (Some(String::from("script-src 'none'")), vec![String::from("script-src 'none'")]));
+        // ...while one blocked by a user filter doesn't
+        assert!(engine.check_network_urls("https://allowed.com", "", "document").matched);
+        assert_eq!(csp(&engine, "https://allowed.com"), (None, vec![]));
+
+        let view = engine.into_view();
+        assert_eq!(view.get_csp_directives("https://blocked.com", "blocked.com", "blocked.com", "document", Some(false)), Some(String::from("script-src 'none'")));
+        assert_eq!(view.get_csp_directives("https://allowed.com", "allowed.com", "allowed.com", "document", Some(false)), None);
+    }
+
+    #[test]
+    fn csp_without_pattern() {
+        let engine = Engine::from_rules(&[
+            String::from("$csp=script-src 'none',domain=example.com|example.org"),
+            String::from("$csp=worker-src 'none',domain=example.org|~sub.example.org"),
+            String::from("||example.net^$csp=img-src 'self'"),
+        ], Default::default());
+        let csp = |url: &str, request_type: &str| {
+            let request = Request::from_urls(url, url, request_type).unwrap();
+            engine.get_csp_directives(&request.url, &request.hostname, &request.hostname, request_type, Some(false))
+        };
+
+        // Applies to any document on the listed domains, including their subdomains
+        assert_eq!(csp("https://example.com", "document"), Some(String::from("script-src 'none'")));
+        assert_eq!(csp("https://www.example.com/any/path?q=1", "document"), Some(String::from("script-src 'none'")));
+        assert_eq!(csp("https://example.com/frame.html", "subdocument"), Some(String::from("script-src 'none'")));
+        let policies = csp("https://example.org/index.html", "document").unwrap();
+        let mut policies = policies.split(',').collect::<Vec<_>>();
+        policies.sort();
+        assert_eq!(policies, vec!["script-src 'none'", "worker-src 'none'"]);
+        assert_eq!(csp("https://sub.example.org", "document"), Some(String::from("script-src 'none'")));
+
+        // Never to other domains or request types
+        assert_eq!(csp("https://example.com/script.js", "script"), None);
+        assert_eq!(csp("https://example.net", "document"), Some(String::from("img-src 'self'")));
+        assert_eq!(csp("https://other.com", "document"), None);
+    }
+
+    #[test]
+    fn document_flags() {
+        let engine = Engine::from_rules_debug(&[
+            String::from("@@||hide.example.com^$generichide"),
+            String::from("@@||block.example.com^$genericblock"),
+            String::from("@@||allowed.example.com^$document"),
+            String::from("||ads.example.com^"),
+        ], Default::default());
+        let id = |filter: &str| NetworkFilter::parse(filter, false, Default::default()).unwrap().filter_id();
+
+        assert_eq!(engine.document_flags("https://hide.example.com/page"), DocumentFlags {
+            generic_hide: true,
+            elem_hide: false,
+            generic_block: false,
+            document_allowlisted: false,
+            generic_hide_exception: Some(id("@@||hide.example.com^$generichide")),
+            elem_hide_exception: None,
+            generic_block_exception: None,
         });
+        assert_eq!(engine.document_flags("https://block.example.com/page"), DocumentFlags {
+            generic_hide: false,
+            elem_hide: false,
+            generic_block: true,
+            document_allowlisted: false,
+            generic_hide_exception: None,
+            elem_hide_exception: None,
+            generic_block_exception: Some(id("@@||block.example.com^$genericblock")),
+        });
+        assert_eq!(engine.document_flags("https://allowed.example.com/page"), DocumentFlags {
+            generic_hide: false,
+            elem_hide: false,
+            generic_block: false,
+            document_allowlisted: true,
+            ..Default::default()
+        });
+        assert_eq!(engine.document_flags("https://other.com"), DocumentFlags::default());
+        assert_eq!(engine.document_flags("not a url"), DocumentFlags::default());
+
+        let view = engine.into_view();
+        assert!(view.document_flags("https://hide.example.com/page").generic_hide);
     }
 
     #[test]
-    fn exception_tags_works() {
+    fn document_context() {
         let filters = vec![
-            String::from("adv"),
-            String::from("||brianbondy.com/$tag=brian"),
-            String::from("@@||brianbondy.com/$tag=brian"),
+            String::from("||ads.example.com^"),
+            String::from("@@||allowed.com^$document"),
+            String::from("@@||nohide.com^$generichide"),
         ];
-        let url_results = vec![
-            ("http://example.com/advert.html", true),
-            ("https://brianbondy.com/about", false),
-            ("https://brianbondy.com/advert", false),
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        let context = engine.document_context("https://www.allowed.com/article");
+        assert!(context.document_exception.is_some());
+        assert!(!context.generic_hide);
+        let result = engine.check_network_urls_in_context("https://ads.example.com/ad.js", "script", &context);
+        assert!(!result.matched);
+        assert_eq!(result.matched_exception, context.document_exception.as_ref().unwrap().matched_exception);
+        assert!(result.matched_exception.is_some());
+        // Checking without the context doesn't know about the document's exception
+        assert!(engine.check_network_urls("https://ads.example.com/ad.js", "https://www.allowed.com/article", "script").matched);
+
+        let context = engine.document_context("https://nohide.com");
+        assert!(context.document_exception.is_none());
+        assert!(context.generic_hide);
+        assert!(engine.check_network_urls_in_context("https://ads.example.com/ad.js", "script", &context).matched);
+        assert!(!engine.check_network_urls_in_context("https://cdn.example.com/lib.js", "script", &context).matched);
+
+        let view = engine.into_view();
+        let context = view.document_context("https://allowed.com");
+        assert!(!view.check_network_urls_in_context("https://ads.example.com/ad.js", "script", &context).matched);
+        let context = view.document_context("https://example.org");
+        assert!(context.document_exception.is_none());
+        assert!(!context.generic_hide);
+        assert!(view.check_network_urls_in_context("https://ads.example.com/ad.js", "script", &context).matched);
+    }
+
+    #[test]
+    fn document_context_with_user_filters() {
+        let mut engine = Engine::from_rules(&["||ads.example.com^".to_owned()], Default::default());
+        let mut user_filters = FilterSet::new(false);
+        user_filters.add_filters(&[
+            "@@||trusted.com^$document".to_owned(),
+            "@@||nohide.com^$generichide".to_owned(),
+        ], Default::default());
+        engine.set_user_filters(user_filters);
+
+        let context = engine.document_context("https://trusted.com/article");
+        assert!(context.document_exception.is_some());
+        assert!(!engine.check_network_urls_in_context("https://ads.example.com/ad.js", "script", &context).matched);
+        assert!(engine.document_flags("https://nohide.com").generic_hide);
+
+        let context = engine.document_context("https://news.com/article");
+        assert!(context.document_exception.is_none());
+        assert!(engine.check_network_urls_in_context("https://ads.example.com/ad.js", "script", &context).matched);
+
+        let view = engine.into_view();
+        assert!(view.document_context("https://trusted.com/article").document_exception.is_some());
+    }
+
+    #[test]
+    fn document_context_requires_document_exceptions() {
+        let filters = vec![
+            String::from("||ads.example.com^"),
+            String::from("@@||news.com/article^"),
+            String::from("@@||allowed.com^$document"),
         ];
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        let context = engine.document_context("https://news.com/article/x");
+        assert!(context.document_exception.is_none());
+        assert!(!engine.document_flags("https://news.com/article/x").document_allowlisted);
+        assert!(engine.check_network_urls_in_context("https://ads.example.com/ad.js", "script", &context).matched);
+
+        let context = engine.document_context("https://allowed.com/article/x");
+        assert!(context.document_exception.is_some());
+        assert!(!engine.check_network_urls_in_context("https://ads.example.com/ad.js", "script", &context).matched);
+    }
+
+    #[test]
+    fn list_fingerprint() {
+        let lists = ["||ads.example.com^\n", "example.com##.ad\n"];
+        let mut filter_set = FilterSet::new(false);
+        for list in lists.iter() {
+            filter_set.add_filter_list(list, Default::default());
+        }
+        let mut engine = Engine::from_filter_set(filter_set, true);
+        let fingerprint = ListFingerprint::from_lists(&lists);
+        assert_eq!(engine.list_fingerprint(), None);
+        assert!(!engine.is_built_from(&fingerprint));
+        engine.set_list_fingerprint(Some(fingerprint.clone()));
+
+        let mut deserialized = Engine::default();
+        deserialized.deserialize(&engine.serialize_raw().unwrap()).unwrap();
+        assert_eq!(deserialized.list_fingerprint(), Some(&fingerprint));
+        assert!(deserialized.is_built_from(&fingerprint));
+        assert!(deserialized.check_network_urls("https://ads.example.com/ad.js", "https://news.com", "script").matched);
+
+        let updated = ListFingerprint::from_lists(&["||ads.example.com^\n||tracker.com^\n", "example.com##.ad\n"]);
+        assert_ne!(updated, fingerprint);
+        assert!(!deserialized.is_built_from(&updated));
+        let reordered = ListFingerprint::from_lists(&[lists[1], lists[0]]);
+        assert!(!deserialized.is_built_from(&reordered));
 
+        let mut network_only = Engine::default();
+        network_only.deserialize_network_only(&engine.serialize_raw().unwrap()).unwrap();
+        assert!(network_only.is_built_from(&fingerprint));
+
+        // Not supported by the legacy format
+        deserialized.deserialize(&engine.serialize_compressed().unwrap()).unwrap();
+        assert_eq!(deserialized.list_fingerprint(), None);
+    }
+
+    #[test]
+    fn serialize_without_resources() {
+        let filters = vec![
+            String::from("||example.com/ads.js$script,redirect=noop.js"),
+            String::from("example.com##+js(noop.js)"),
+        ];
+        let resources = vec![Resource {
+            name: "noop.js".to_owned(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: base64::encode("(function() {})()"),
+        }];
         let mut engine = Engine::from_rules(&filters, Default::default());
-        engine.enable_tags(&["brian", "stuff"]);
+        engine.use_resources(&resources);
 
-        url_results.into_iter().for_each(|(url, expected_result)| {
-            let matched_rule = engine.check_network_urls(&url, "", "");
-            if expected_result {
-                assert!(matched_rule.matched, "Expected match for {}", url);
-            } else {
-                assert!(!matched_rule.matched, "Expected no match for {}, matched with {:?}", url, matched_rule.filter);
-            }
-        });
+        let with_resources = engine.serialize_raw().unwrap();
+        let serialized = engine.serialize_raw_without_resources().unwrap();
+        assert!(serialized.len() < with_resources.len());
+
+        let check = |engine: &Engine| {
+            let result = engine.check_network_urls("https://example.com/ads.js", "https://example.com", "script");
+            assert!(result.matched);
+            let scriptlets = engine.url_cosmetic_resources("https://example.com").injected_script;
+            (result.redirect.is_some(), !scriptlets.is_empty())
+        };
+
+        // Filters are loaded, but there are no resources until they are supplied
+        let mut deserialized = Engine::default();
+        deserialized.deserialize(&serialized).unwrap();
+        assert_eq!(check(&deserialized), (false, false));
+        deserialized.use_resources(&resources);
+        assert_eq!(check(&deserialized), (true, true));
+
+        // Resources already loaded into the engine are kept
+        let mut deserialized = Engine::default();
+        deserialized.use_resources(&resources);
+        deserialized.deserialize(&serialized).unwrap();
+        assert_eq!(check(&deserialized), (true, true));
+        let mut network_only = Engine::default();
+        network_only.use_resources(&resources);
+        network_only.deserialize_network_only(&serialized).unwrap();
+        assert!(network_only.check_network_urls("https://example.com/ads.js", "https://example.com", "script").redirect.is_some());
+
+        // Complete data still replaces them
+        let mut deserialized = Engine::default();
+        deserialized.deserialize(&with_resources).unwrap();
+        assert_eq!(check(&deserialized), (true, true));
+        deserialized.use_resources(&[]);
+        deserialized.deserialize(&engine.serialize_raw().unwrap()).unwrap();
+        assert_eq!(check(&deserialized), (true, true));
     }
 
     #[test]
-    fn serialization_retains_tags() {
+    fn deserialize_network_only() {
         let filters = vec![
-            String::from("adv$tag=stuff"),
-            String::from("somelongpath/test$tag=stuff"),
-            String::from("||brianbondy.com/$tag=brian"),
-            String::from("||brave.com$tag=brian"),
+            String::from("||ads.example.com^"),
+            String::from("@@||ads.example.com/allowed^"),
+            String::from("@@||example.com^$genericblock"),
+            String::from("||generic.net^"),
+            String::from("example.com##.ad"),
+            String::from("##.banner"),
+            String::from("example.com##+js(set, x, 1)"),
         ];
-        let url_results = vec![
-            ("http://example.com/advert.html", true),
-            ("http://example.com/somelongpath/test/2.html", true),
-            ("https://brianbondy.com/about", false),
-            ("https://brave.com/about", false),
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        for serialized in [engine.serialize_raw().unwrap(), engine.serialize_compressed().unwrap()] {
+            let mut deserialized = Engine::default();
+            deserialized.deserialize_network_only(&serialized).unwrap();
+
+            assert!(deserialized.check_network_urls("https://ads.example.com/ad.js", "https://news.com", "script").matched);
+            assert!(!deserialized.check_network_urls("https://ads.example.com/allowed/ad.js", "https://news.com", "script").matched);
+            assert!(!deserialized.check_network_urls("https://generic.net/ad.js", "https://example.com", "script").matched);
+
+            let resources = deserialized.url_cosmetic_resources("https://example.com");
+            assert!(resources.hide_selectors.is_empty());
+            assert!(resources.injected_script.is_empty());
+            assert!(deserialized.hidden_class_id_selectors(&["banner".into()], &[], &Default::default()).is_empty());
+        }
+
+        let mut deserialized = Engine::default();
+        assert!(deserialized.deserialize_network_only(&[0xd1, 0xd9, 0x3a, 0xaf, 0, 0x95]).is_err());
+    }
+
+    #[test]
+    fn inspect_serialized() {
+        let engine = Engine::from_rules(&[String::from("||ads.example.com^")], Default::default());
+        let info = Engine::inspect_serialized(&engine.serialize_raw().unwrap()).unwrap();
+        assert_eq!(info, FormatInfo { version: Some(0), compressed: false, loadable: true });
+        let info = Engine::inspect_serialized(&engine.serialize_compressed().unwrap()).unwrap();
+        assert_eq!(info, FormatInfo { version: None, compressed: true, loadable: true });
+        assert_eq!(Engine::inspect_serialized(b"[Adblock Plus 2.0]"), Err(BlockerError::DeserializationError));
+    }
+
+    #[test]
+    fn deserialize_truncated_data() {
+        let engine = Engine::from_rules(&[String::from("||ads.example.com^"), String::from("example.com##.ad")], Default::default());
+        let serialized = engine.serialize_raw().unwrap();
+
+        let mut deserialized = Engine::default();
+        match deserialized.deserialize(&serialized[..serialized.len() - 1]) {
+            Err(BlockerError::CorruptedData { offset, message }) => {
+                assert_eq!(offset, serialized.len() - 1);
+                assert!(!message.is_empty());
+            }
+            result => panic!("Expected a corrupted data error, got {:?}", result),
+        }
+        assert_eq!(deserialized.deserialize(&[1, 2, 3]), Err(BlockerError::DeserializationError));
+    }
+
+    #[test]
+    fn generic_block_exception_for_every_check() {
+        let mut engine = Engine::from_rules(&[
+            String::from("/generic-ad."),
+            String::from("||ads.example.net^$domain=example.com"),
+            String::from("@@||example.com^$genericblock"),
+        ], Default::default());
+        let check_all = |engine: &Engine, url: &str, hostname: &str| {
+            let request = Request::builder(url).source_url("https://example.com/page").request_type("script").build().unwrap();
+            [
+                engine.check_network_urls(url, "https://example.com/page", "script").matched,
+                engine.check_network_urls_with_hostnames(url, hostname, "example.com", "script", None).matched,
+                engine.check_network_urls_with_hostnames_subset(url, hostname, "example.com", "script", None, false, false).matched,
+                engine.check_network_request(&request).matched,
+                engine.check_with_reason(&request).0.matched,
+            ]
+        };
+
+        assert_eq!(check_all(&engine, "https://cdn.com/generic-ad.js", "cdn.com"), [false; 5]);
+        assert_eq!(check_all(&engine, "https://ads.example.net/ad.js", "ads.example.net"), [true; 5]);
+        assert!(engine.check_network_urls("https://cdn.com/generic-ad.js", "https://news.com", "script").matched);
+
+        let request = Request::from_urls("https://cdn.com/generic-ad.js", "https://example.com", "script").unwrap();
+        assert_eq!(engine.check_with_reason(&request).1, Some(AllowReason::GenericBlockSuppressed));
+
+        let mut user_filters = FilterSet::new(false);
+        user_filters.add_filters(&[String::from("@@||news.com^$genericblock")], Default::default());
+        engine.set_user_filters(user_filters);
+        assert!(!engine.check_network_urls("https://cdn.com/generic-ad.js", "https://news.com", "script").matched);
+    }
+
+    #[test]
+    fn tagged_generic_block_exception() {
+        let filters = [
+            String::from("/generic-ad."),
+            String::from("@@||site.com^$genericblock,tag=x"),
         ];
+        let blocked = |engine: &Engine| engine.check_network_urls("https://cdn.com/generic-ad.js", "https://site.com", "script").matched;
 
         let mut engine = Engine::from_rules(&filters, Default::default());
-        engine.enable_tags(&["stuff"]);
-        engine.enable_tags(&["brian"]);
+        assert!(blocked(&engine));
+        assert!(!engine.document_flags("https://site.com").generic_block);
+
+        engine.enable_tags(&["x"]);
+        assert!(!blocked(&engine));
+        assert!(engine.document_flags("https://site.com").generic_block);
+        assert!(engine.blocker.check_generic_block(&Request::from_url("https://site.com").unwrap()));
+
+        // Tags enabled on a view only apply to that view
+        let view = Engine::from_rules(&filters, Default::default()).into_view();
+        let mut tagged = view.clone();
+        tagged.enable_tags(&["x"]);
+        assert!(!tagged.check_network_urls("https://cdn.com/generic-ad.js", "https://site.com", "script").matched);
+        assert!(tagged.document_flags("https://site.com").generic_block);
+        assert!(view.check_network_urls("https://cdn.com/generic-ad.js", "https://site.com", "script").matched);
+        assert!(!view.document_flags("https://site.com").generic_block);
+    }
+
+    #[test]
+    fn stylesheet_type() {
+        let filters = vec![
+            String::from("||cdn.example.com^$css"),
+            String::from("@@||cdn.example.com/site.css$stylesheet"),
+        ];
+        let engine = Engine::from_rules_debug(&filters, Default::default());
+
+        assert!(engine.check_network_urls("https://cdn.example.com/ads.css", "https://example.com", "stylesheet").matched);
+        assert!(!engine.check_network_urls("https://cdn.example.com/ads.js", "https://example.com", "script").matched);
+        let request = Request::from_url("https://cdn.example.com/ads.css").unwrap();
+        assert_eq!(request.request_type, RequestType::Stylesheet);
+        assert!(engine.check_network_request(&request).matched);
+
+        let result = engine.check_network_urls("https://cdn.example.com/site.css", "https://example.com", "stylesheet");
+        assert!(!result.matched);
+        assert_eq!(result.exception, Some("@@||cdn.example.com/site.css$stylesheet".to_owned()));
+    }
+
+    #[test]
+    fn websocket_type_inferred_from_scheme() {
+        let filters = vec![
+            String::from("||socket.example.com^$websocket"),
+            String::from("||cdn.example.com^$script"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
+
+        assert!(engine.check_network_urls("wss://socket.example.com/live", "https://example.com", "").matched);
+        assert!(engine.check_network_urls("ws://socket.example.com/live", "https://example.com", "").matched);
+        assert!(!engine.check_network_urls("wss://cdn.example.com/live.js", "https://example.com", "").matched);
+        assert!(!engine.check_network_urls("https://socket.example.com/live", "https://example.com", "").matched);
+    }
+
+    #[test]
+    fn reports_inferred_request_type() {
+        let engine = Engine::from_rules(&[String::from("||ads.example.com^$script")], Default::default());
+
+        let result = engine.check_network_urls("ws://socket.example.com/live", "https://example.com", "");
+        assert!(!result.matched);
+        assert_eq!(result.request_type, Some(RequestType::Websocket));
+
+        let result = engine.check_network_urls("https://ads.example.com/ad.js", "https://example.com", "script");
+        assert!(result.matched);
+        assert_eq!(result.request_type, Some(RequestType::Script));
+
+        let result = engine.check_network_request(&Request::from_url("https://ads.example.com/ad.js").unwrap());
+        assert!(result.matched);
+        assert_eq!(result.request_type, Some(RequestType::Script));
+
+        assert_eq!(engine.check_network_urls("not a url", "https://example.com", "script").request_type, None);
+    }
+
+    #[test]
+    fn generic_block() {
+        let filters = vec![
+            String::from("@@||example.com^$genericblock"),
+            String::from("||tracker.com^"),
+            String::from("/adframe."),
+            String::from("-adframe-"),
+            String::from("||ads.com^$domain=example.com"),
+        ];
+        let engine = Engine::from_rules_parametrised(&filters, Default::default(), true, true);
+        // The generic `adframe` patterns are fused into a single optimized filter
+        assert!(engine.blocker.filters.filter_map.values().flatten()
+            .any(|filter| filter.raw_line.as_ref().map(|line| line.contains(" <+> ")).unwrap_or(false)));
+
+        assert!(!engine.check_network_urls("https://tracker.com/t.js", "https://www.example.com", "script").matched);
+        assert!(!engine.check_network_urls("https://cdn.net/adframe.js", "https://www.example.com", "script").matched);
+        assert!(!engine.check_network_urls("https://cdn.net/x-adframe-1.js", "https://www.example.com", "script").matched);
+        assert!(engine.check_network_urls("https://ads.com/ad.js", "https://www.example.com", "script").matched);
+        assert!(engine.check_network_urls("https://tracker.com/t.js", "https://other.com", "script").matched);
+        assert!(engine.check_network_urls("https://cdn.net/adframe.js", "https://other.com", "script").matched);
+
+        let context = engine.document_context("https://example.com");
+        assert!(context.generic_block);
+        assert!(!context.generic_hide);
+        assert!(!engine.check_network_urls_in_context("https://tracker.com/t.js", "script", &context).matched);
+        assert!(engine.check_network_urls_in_context("https://ads.com/ad.js", "script", &context).matched);
+
         let serialized = engine.serialize_raw().unwrap();
-        let mut deserialized_engine = Engine::default();
-        deserialized_engine.enable_tags(&["stuff"]);
-        deserialized_engine.deserialize(&serialized).unwrap();
+        let mut deserialized = Engine::default();
+        deserialized.deserialize(&serialized).unwrap();
+        assert!(!deserialized.check_network_urls("https://tracker.com/t.js", "https://example.com", "script").matched);
+        assert!(deserialized.check_network_urls("https://ads.com/ad.js", "https://example.com", "script").matched);
+    }
 
-        url_results.into_iter().for_each(|(url, expected_result)| {
-            let matched_rule = deserialized_engine.check_network_urls(&url, "", "");
-            if expected_result {
-                assert!(matched_rule.matched, "Expected match for {}", url);
-            } else {
-                assert!(!matched_rule.matched, "Expected no match for {}, matched with {:?}", url, matched_rule.filter);
-            }
-        });
+    #[test]
+    #[cfg(feature = "full-regex-handling")]
+    fn warmup() {
+        let filters = vec![
+            String::from("/banner/*/img^"),
+            String::from("/^https?:\\/\\/ads\\./$script"),
+            String::from("||ads.example.com^"),
+            String::from("@@/other/*/path^"),
+            String::from("/tagged/*/ad^$tag=extra"),
+        ];
+        let engine = Engine::from_rules_debug(&filters, Default::default());
+
+        // Compile one regex lazily before warming up
+        assert!(engine.check_network_urls("https://example.com/banner/top/img/", "https://example.com", "image").matched);
+
+        let warmup = engine.warmup();
+        assert_eq!(warmup.compiled, 3);
+        // Invalid regexes are already rejected when parsing
+        assert!(warmup.failed.is_empty());
+
+        // Everything has been compiled, including filters used by later checks
+        assert!(engine.check_network_urls("https://ads.example.org/x.js", "https://example.com", "script").matched);
+        assert_eq!(engine.warmup(), RegexWarmup::default());
     }
 
     #[test]
-    fn deserialization_backwards_compatible_plain() {
-        // deserialization_generate_simple();
-        // assert!(false);
-        let serialized: Vec<u8> = vec![31, 139, 8, 0, 0, 0, 0, 0, 0, 255, 1, 68, 0, 187, 255, 155, 145, 128, 145, 128,
-            145, 128, 145, 128, 145, 128, 145, 129, 207, 202, 167, 36, 217, 43, 56, 97, 176, 145, 158, 145, 206, 0, 3,
-            31, 255, 146, 1, 145, 169, 97, 100, 45, 98, 97, 110, 110, 101, 114, 192, 192, 192, 192, 192, 192, 192, 192,
-            207, 186, 136, 69, 13, 115, 187, 170, 226, 192, 192, 192, 144, 194, 195, 194, 195, 207, 77, 26, 78, 68, 0,
-            0, 0];
+    fn test_rule() {
+        let engine = Engine::from_rules(&["||ads.example.com^".to_owned()], Default::default());
+        let request = |url: &str, request_type: &str| Request::from_urls(url, "https://example.com", request_type).unwrap();
 
-        let mut deserialized_engine = Engine::default();
-        deserialized_engine.deserialize(&serialized).unwrap();
+        let script = request("https://ads.example.com/ad.js", "script");
+        let image = request("https://ads.example.com/ad.png", "image");
+        assert_eq!(engine.test_rule("||ads.example.com^$script", &script), Ok(TestResult::Match));
+        assert_eq!(engine.test_rule("||ads.example.com^$script", &image), Ok(TestResult::TypeMismatch));
+        assert_eq!(engine.test_rule("@@||ads.example.com/ad.js$script", &script), Ok(TestResult::Exception));
+        assert_eq!(engine.test_rule("||other.com^$script", &script), Ok(TestResult::NoMatch));
+        assert_eq!(engine.test_rule("||ads.example.com^$script,domain=other.com", &image), Ok(TestResult::NoMatch));
+        assert!(engine.test_rule("||ads.example.com^$unknown-option", &script).is_err());
+        assert_eq!(engine.test_rule("||ads.example.com^$network", &script), Ok(TestResult::Unsupported(UnsupportedReason::Platform)));
+        assert_eq!(engine.test_rule("||ads.example.com^$urlskip=?url", &script), Ok(TestResult::Unsupported(UnsupportedReason::Unimplemented)));
+        assert_eq!(engine.test_rule("||ads.example.com^$webbundle,script", &script), Ok(TestResult::Match));
 
-        let url = "http://example.com/ad-banner.gif";
-        let matched_rule = deserialized_engine.check_network_urls(url, "", "");
-        assert!(matched_rule.matched, "Expected match for {}", url);
+        // The rule isn't added to the engine
+        assert!(engine.test_rule("||ads.example.org^", &request("https://ads.example.org/ad.js", "script")).is_ok());
+        assert!(!engine.check_network_urls("https://ads.example.org/ad.js", "https://example.com", "script").matched);
     }
 
     #[test]
-    fn deserialization_backwards_compatible_tags() {
-        // deserialization_generate_tags();
-        // assert!(false);
-        let serialized: Vec<u8> = vec![31, 139, 8, 0, 0, 0, 0, 0, 0, 255, 149, 139, 49, 14, 64, 48, 24, 70, 137, 131, 88,
-            108, 98, 148, 184, 135, 19, 252, 197, 218, 132, 3, 8, 139, 85, 126, 171, 132, 193, 32, 54, 71, 104, 218, 205,
-            160, 139, 197, 105, 218, 166, 233, 5, 250, 125, 219, 203, 123, 43, 14, 238, 163, 124, 206, 228, 79, 11, 184,
-            113, 195, 55, 136, 98, 181, 132, 120, 65, 157, 17, 160, 180, 233, 152, 221, 1, 164, 98, 178, 255, 242, 178,
-            221, 231, 201, 0, 19, 122, 216, 92, 112, 161, 1, 58, 213, 199, 143, 114, 0, 0, 0];
-        let mut deserialized_engine = Engine::default();
+    fn filter_tokenization() {
+        let filters = vec![
+            String::from("/adserver/banner.js"),
+            String::from("/banner/ad-"),
+            String::from("*$domain=example.com"),
+            String::from("$image"),
+        ];
+        let engine = Engine::from_rules(&filters, Default::default());
 
-        deserialized_engine.enable_tags(&[]);
-        deserialized_engine.deserialize(&serialized).unwrap();
-        let url = "http://example.com/ad-banner.gif";
-        let matched_rule = deserialized_engine.check_network_urls(url, "", "");
-        assert!(!matched_rule.matched, "Expected NO match for {}", url);
+        let tokenization = engine.filter_tokenization("/adserver/banner.js").unwrap();
+        let names = tokenization.tokens[0].iter().map(|token| token.name.as_deref()).collect::<Vec<_>>();
+        assert_eq!(names, vec![Some("adserver"), Some("banner")]);
+        // `banner` is shared with another filter, so the distinctive `adserver` token is used
+        assert_eq!(tokenization.buckets, vec![FilterToken { hash: fast_hash("adserver"), name: Some("adserver".into()) }]);
+
+        let tokenization = engine.filter_tokenization("*$domain=example.com").unwrap();
+        assert_eq!(tokenization.buckets[0].name.as_deref(), Some("example.com"));
+
+        let tokenization = engine.filter_tokenization("$image").unwrap();
+        assert_eq!(tokenization.tokens, vec![vec![]]);
+        assert_eq!(tokenization.buckets, vec![FilterToken { hash: 0, name: None }]);
+
+        // Not in the engine
+        assert!(engine.filter_tokenization("||other.com^").unwrap().buckets.is_empty());
+        assert!(engine.filter_tokenization("||other.com^$unknownoption").is_err());
+    }
+
+    #[test]
+    fn preserve_redirect_query() {
+        let filters = vec![
+            String::from("||example.com/ads.js$script,redirect=noop.js"),
+            String::from("||example.com/pixel$image,redirect-url=https://cdn.com/pixel.gif?v=1"),
+            String::from("||example.com/frame$subdocument,redirect-url=https://cdn.com/frame.html"),
+        ];
+        let mut engine = Engine::from_rules(&filters, ParseOptions { include_redirect_urls: true, ..Default::default() });
+        engine.add_resource(Resource {
+            name: "noop.js".to_owned(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: base64::encode("(function() {})()"),
+        }).unwrap();
+        let noop = Some(Redirection::Resource(format!("data:application/javascript;base64,{}", base64::encode("(function() {})()"))));
+
+        // The query is dropped by default
+        let result = engine.check_network_urls("https://example.com/ads.js?Slot=Top#x", "https://example.com", "script");
+        assert_eq!(result.redirect, noop);
+        assert_eq!(result.redirect_query, None);
+        let result = engine.check_network_urls("https://example.com/pixel?id=1", "https://example.com", "image");
+        assert_eq!(result.redirect, Some(Redirection::Url("https://cdn.com/pixel.gif?v=1".to_owned())));
+
+        engine.set_preserve_redirect_query(true);
+
+        let result = engine.check_network_urls("https://example.com/ads.js?Slot=Top#x", "https://example.com", "script");
+        assert_eq!(result.redirect, noop);
+        assert_eq!(result.redirect_query.as_deref(), Some("Slot=Top"));
+        let result = engine.check_network_urls("https://example.com/pixel?id=1", "https://example.com", "image");
+        assert_eq!(result.redirect, Some(Redirection::Url("https://cdn.com/pixel.gif?v=1&id=1".to_owned())));
+        assert_eq!(result.redirect_query.as_deref(), Some("id=1"));
+        let result = engine.check_network_urls("https://example.com/frame?a=b", "https://example.com", "sub_frame");
+        assert_eq!(result.redirect, Some(Redirection::Url("https://cdn.com/frame.html?a=b".to_owned())));
+
+        // Requests without a query or a redirect have nothing to preserve
+        let result = engine.check_network_urls("https://example.com/ads.js", "https://example.com", "script");
+        assert_eq!(result.redirect, noop);
+        assert_eq!(result.redirect_query, None);
+        let result = engine.check_network_urls("https://example.com/other.js?a=b", "https://example.com", "script");
+        assert_eq!(result.redirect_query, None);
+
+        // The setting survives deserialization
+        let serialized = engine.serialize_raw().unwrap();
+        engine.deserialize(&serialized).unwrap();
+        let result = engine.check_network_urls("https://example.com/pixel?id=1", "https://example.com", "image");
+        assert_eq!(result.redirect_query.as_deref(), Some("id=1"));
+    }
+
+    #[test]
+    fn unknown_resource_policy() {
+        let filters = vec![
+            String::from("||example.com/ads.js$script,redirect=blob.bin"),
+            String::from("||example.com/data$xhr,redirect=blob.bin"),
+        ];
+        let mut engine = Engine::from_rules(&filters, Default::default());
+        engine.add_resource(Resource {
+            name: "blob.bin".to_owned(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::Unknown),
+            content: base64::encode("blob"),
+        }).unwrap();
+
+        let check = |engine: &Engine, url: &str, request_type: &str| {
+            let result = engine.check_network_urls(url, "https://example.com", request_type);
+            assert!(result.matched);
+            assert_eq!(result.missing_redirect, None);
+            result.redirect
+        };
+
+        // Served empty by default
+        assert_eq!(
+            check(&engine, "https://example.com/ads.js", "script"),
+            Some(Redirection::Resource(format!("data:application/javascript;base64,{}", base64::encode("(function() {})()")))),
+        );
+        assert_eq!(check(&engine, "https://example.com/data", "xhr"), Some(Redirection::Resource("data:text/plain;base64,".to_owned())));
+
+        engine.set_unknown_resource_policy(UnknownResourcePolicy::ServeAsText);
+        let as_text = Some(Redirection::Resource(format!("data:text/plain;base64,{}", base64::encode("blob"))));
+        assert_eq!(check(&engine, "https://example.com/ads.js", "script"), as_text);
+        assert_eq!(check(&engine, "https://example.com/data", "xhr"), as_text);
+
+        engine.set_unknown_resource_policy(UnknownResourcePolicy::Reject);
+        assert_eq!(check(&engine, "https://example.com/ads.js", "script"), None);
+        assert_eq!(check(&engine, "https://example.com/data", "xhr"), None);
+
+        engine.set_unknown_resource_policy(UnknownResourcePolicy::ServeEmpty);
+        assert_eq!(check(&engine, "https://example.com/data", "xhr"), Some(Redirection::Resource("data:text/plain;base64,".to_owned())));
+    }
+
+    #[test]
+    fn redirect_fallback() {
+        let filters = vec![
+            String::from("||example.com/ads.js$script,redirect=noop.js"),
+            String::from("||example.com/ad.gif$image,redirect=1x1.gif"),
+            String::from("||example.com/ad.mp4$media,redirect=noop-1s.mp4"),
+        ];
+        let mut engine = Engine::from_rules(&filters, Default::default());
+
+        // Missing resources are not replaced by default
+        let result = engine.check_network_urls("https://example.com/ads.js", "https://example.com", "script");
+        assert!(result.matched);
+        assert_eq!(result.redirect, None);
+        assert_eq!(result.missing_redirect, Some("noop.js".to_owned()));
+
+        engine.set_redirect_fallback(true);
+
+        let result = engine.check_network_urls("https://example.com/ads.js", "https://example.com", "script");
+        assert!(result.matched);
+        assert_eq!(result.redirect, Some(Redirection::Resource(format!("data:application/javascript;base64,{}", base64::encode("(function() {})()")))));
+        assert_eq!(result.missing_redirect, Some("noop.js".to_owned()));
+
+        let result = engine.check_network_urls("https://example.com/ad.gif", "https://example.com", "image");
+        assert!(result.matched);
+        let data_url = match result.redirect {
+            Some(Redirection::Resource(data_url)) => data_url,
+            other => panic!("Expected resource redirect, got {:?}", other),
+        };
+        let gif = base64::decode(data_url.strip_prefix("data:image/gif;base64,").unwrap()).unwrap();
+        assert!(gif.starts_with(b"GIF89a\x01\x00\x01\x00"));
+        assert_eq!(result.missing_redirect, Some("1x1.gif".to_owned()));
+
+        // There is no built-in stand-in for media
+        let result = engine.check_network_urls("https://example.com/ad.mp4", "https://example.com", "media");
+        assert!(result.matched);
+        assert_eq!(result.redirect, None);
+        assert_eq!(result.missing_redirect, Some("noop-1s.mp4".to_owned()));
+
+        // The setting survives deserialization
+        let serialized = engine.serialize_raw().unwrap();
+        engine.deserialize(&serialized).unwrap();
+        let result = engine.check_network_urls("https://example.com/ads.js", "https://example.com", "script");
+        assert!(result.redirect.is_some());
+
+        engine.set_redirect_fallback(false);
+        let result = engine.check_network_urls("https://example.com/ads.js", "https://example.com", "script");
+        assert_eq!(result.redirect, None);
+    }
+
+    #[test]
+    fn export_network_filters() {
+        let rules: Vec<String> = [
+            "||ads.example.com^",
+            "||tracker.example.com^$script,third-party",
+            "/banner/ad-",
+            "/banner/ad.",
+            "/banner/ad/*",
+            "/promo.js$domain=news.com|~sports.news.com",
+            "@@||ads.example.com/allowed/$xhr",
+            "||cdn.example.com/ads.js$important",
+            "||example.com/pixel.gif$image,redirect=nooptext",
+            "||example.com^$csp=script-src 'none'",
+            "@@||trusted.com^$generichide",
+            "||regional.example.org^$tag=regional",
+            "||example.net^$header=x-ad-server",
+            "/unwanted.js",
+            "/unwanted.js$badfilter",
+        ].iter().map(|rule| rule.to_string()).collect();
+        let requests = [
+            ("https://ads.example.com/ad.js", "https://news.com", "script"),
+            ("https://ads.example.com/allowed/data", "https://news.com", "xhr"),
+            ("https://tracker.example.com/t.js", "https://news.com", "script"),
+            ("https://tracker.example.com/t.js", "https://example.com", "script"),
+            ("https://static.com/banner/ad-top.png", "https://news.com", "image"),
+            ("https://static.com/banner/ad/top.png", "https://news.com", "image"),
+            ("https://static.com/promo.js", "https://news.com", "script"),
+            ("https://static.com/promo.js", "https://sports.news.com", "script"),
+            ("https://cdn.example.com/ads.js", "https://news.com", "script"),
+            ("https://example.com/pixel.gif", "https://news.com", "image"),
+            ("https://regional.example.org/ad.js", "https://news.com", "script"),
+            ("https://static.com/unwanted.js", "https://news.com", "script"),
+        ];
+        let setup = |mut engine: Engine| {
+            engine.use_tags(&["regional"]);
+            engine.add_resource(Resource {
+                name: "nooptext".to_owned(),
+                aliases: vec![],
+                kind: ResourceType::Mime(MimeType::TextPlain),
+                content: "".to_owned(),
+            }).unwrap();
+            engine
+        };
+        let results = |engine: &Engine| {
+            let mut results = requests.iter()
+                .map(|(url, source_url, request_type)| {
+                    let result = engine.check_network_urls(url, source_url, request_type);
+                    (result.matched, result.exception.is_some(), result.important, result.redirect)
+                })
+                .collect::<Vec<_>>();
+            let request = Request::from_urls("https://example.net/ad.js", "https://news.com", "script").unwrap()
+                .with_response_headers(&[("X-Ad-Server", "adnet")]);
+            results.push((engine.check_network_request(&request).matched, false, false, None));
+            (
+                results,
+                engine.get_csp_directives("https://example.com", "example.com", "", "document", None),
+                engine.document_flags("https://trusted.com").generic_hide,
+            )
+        };
+
+        for engine in [Engine::from_rules_debug(&rules, Default::default()), Engine::from_rules(&rules, Default::default())] {
+            let engine = setup(engine);
+            let exported = engine.export_network_filters();
+            assert!(exported.contains(&"/unwanted.js$badfilter".to_owned()));
+            assert!(!exported.contains(&"/unwanted.js".to_owned()));
+            assert!(exported.contains(&"||regional.example.org^$tag=regional".to_owned()));
+
+            let reparsed = setup(Engine::from_rules(&exported, Default::default()));
+            assert_eq!(results(&reparsed), results(&engine));
+
+            // Filters that were combined by the optimizer are exported separately
+            let reexported = reparsed.export_network_filters();
+            assert!(reexported.contains(&"/banner/ad-".to_owned()));
+            assert!(reexported.contains(&"/banner/ad/*".to_owned()));
+            assert_eq!(reexported.len(), exported.len());
+        }
+    }
 
-        deserialized_engine.enable_tags(&["abc"]);
-        deserialized_engine.deserialize(&serialized).unwrap();
+    #[test]
+    fn export_network_filters_with_changes_after_parsing() {
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filter_list_with_tag("||tagged.example.com^\n", Default::default(), "extra");
+        filter_set.add_filter_list("0.0.0.0 hosts.example.com\n", ParseOptions { format: FilterFormat::Hosts, ..Default::default() });
+        let mut engine = Engine::from_filter_set(filter_set, true);
+        engine.use_tags(&["extra"]);
 
-        let url = "http://example.com/ad-banner.gif";
-        let matched_rule = deserialized_engine.check_network_urls(url, "", "");
-        assert!(matched_rule.matched, "Expected match for {}", url);
+        let exported = engine.export_network_filters();
+        assert!(exported.contains(&"||tagged.example.com^$tag=extra".to_owned()), "{:?}", exported);
+        assert!(!exported.iter().any(|rule| rule.starts_with("0.0.0.0")), "{:?}", exported);
+
+        let mut reparsed = Engine::from_rules(&exported, Default::default());
+        reparsed.use_tags(&["extra"]);
+        for engine in [&engine, &reparsed] {
+            assert!(engine.check_network_urls("https://tagged.example.com/ad.js", "https://news.com", "script").matched);
+            assert!(engine.check_network_urls("https://hosts.example.com/ad.js", "https://news.com", "script").matched);
+            // Hosts file entries also block navigations
+            assert!(engine.check_network_urls("https://hosts.example.com/", "", "document").matched);
+        }
     }
 
     #[test]
-    fn deserialization_backwards_compatible_resources() {
-        // deserialization_generate_resources();
-        // assert!(false);
-        let serialized: Vec<u8> = vec![31, 139, 8, 0, 0, 0, 0, 0, 0, 255, 61, 139, 189, 10, 64, 80, 28, 197, 201, 46,
-            229, 1, 44, 54, 201, 234, 117, 174, 143, 65, 233, 18, 6, 35, 118, 229, 127, 103, 201, 230, 99, 146, 39,
-            184, 177, 25, 152, 61, 13, 238, 29, 156, 83, 167, 211, 175, 115, 90, 40, 184, 203, 235, 24, 244, 219, 176,
-            209, 2, 29, 156, 130, 164, 61, 68, 132, 9, 121, 166, 131, 48, 246, 19, 74, 71, 28, 69, 113, 230, 231, 25,
-            101, 186, 42, 121, 86, 73, 189, 42, 95, 103, 255, 102, 219, 183, 29, 170, 127, 68, 102, 150, 86, 28, 162,
-            0, 247, 3, 163, 110, 154, 146, 145, 195, 175, 245, 47, 101, 250, 113, 201, 119, 0, 0, 0];
+    fn cosmetic_rules_for_host() {
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filter_list("example.com##.banner\nexample.com,~shop.example.com##.promo\n##.ad", ParseOptions { list_id: Some(1), ..Default::default() });
+        filter_set.add_filter_list("news.example.com#@#.banner\nexample.*##+js(acis, open)\n~example.com##.sidebar", ParseOptions { list_id: Some(2), ..Default::default() });
+        filter_set.add_filter("news.example.com##.popup", Default::default()).unwrap();
+        let mut engine = Engine::from_filter_set(filter_set, true);
 
-        let mut deserialized_engine = Engine::default();
-        deserialized_engine.deserialize(&serialized).unwrap();
+        let sorted = |engine: &Engine, hostname: &str| {
+            let mut rules = engine.cosmetic_rules_for_host(hostname).into_iter()
+                .map(|source| (source.rule, source.list_id))
+                .collect::<Vec<_>>();
+            rules.sort();
+            rules
+        };
 
-        let url = "http://example.com/ad-banner.gif";
-        let matched_rule = deserialized_engine.check_network_urls(url, "", "");
-        // This serialized DAT was generated prior to
-        // https://github.com/brave/adblock-rust/pull/185, so the `redirect` filter did not get
-        // duplicated into the list of blocking filters.
-        //
-        // TODO - The failure to match here is considered acceptable for now, as it's part of a
-        // breaking change (minor version bump). However, the test should be updated at some point.
-        //assert!(matched_rule.matched, "Expected match for {}", url);
-        assert_eq!(matched_rule.redirect, Some(Redirection::Resource("data:text/plain;base64,".to_owned())), "Expected redirect to contain resource");
+        assert_eq!(sorted(&engine, "news.example.com"), vec![
+            ("example.*##+js(acis, open)".to_owned(), Some(2)),
+            ("example.com##.banner".to_owned(), Some(1)),
+            ("example.com,~shop.example.com##.promo".to_owned(), Some(1)),
+            ("news.example.com##.popup".to_owned(), None),
+            ("news.example.com#@#.banner".to_owned(), Some(2)),
+        ]);
+        assert_eq!(sorted(&engine, "shop.example.com"), vec![
+            ("example.*##+js(acis, open)".to_owned(), Some(2)),
+            ("example.com##.banner".to_owned(), Some(1)),
+        ]);
+        assert!(sorted(&engine, "other.org").is_empty());
+
+        engine.set_list_enabled(1, false);
+        assert_eq!(sorted(&engine, "shop.example.com"), vec![
+            ("example.*##+js(acis, open)".to_owned(), Some(2)),
+        ]);
+
+        // Rule text isn't kept without debug mode
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filter_list("example.com##.banner", ParseOptions { list_id: Some(1), ..Default::default() });
+        assert!(Engine::from_filter_set(filter_set, true).cosmetic_rules_for_host("example.com").is_empty());
     }
 
     #[test]
-    fn deserialization_generate_simple() {
-        let mut engine = Engine::from_rules(&[
-            "ad-banner".to_owned()
-        ], Default::default());
-        let serialized = engine.serialize_compressed().unwrap();
-        println!("Engine serialized: {:?}", serialized);
+    fn list_enable_disable() {
+        let mut filter_set = FilterSet::new(false);
+        filter_set.add_filter_list("||ads.example.com^\n@@||tracker.com/allowed^\n##.ad\n##.shared\nexample.com##.banner", ParseOptions { list_id: Some(1), ..Default::default() });
+        filter_set.add_filter_list("||tracker.com^\n##.sponsored\n##.shared\nexample.com##.promo", ParseOptions { list_id: Some(2), ..Default::default() });
+        let mut engine = Engine::from_filter_set(filter_set, true);
+
+        let classes = ["ad".to_owned(), "sponsored".to_owned(), "shared".to_owned()];
+        let check = |engine: &Engine| {
+            let ads = engine.check_network_urls("https://ads.example.com/ad.js", "https://example.com", "script").matched;
+            let tracker = engine.check_network_urls("https://tracker.com/script.js", "https://example.com", "script").matched;
+            let allowed = engine.check_network_urls("https://tracker.com/allowed/script.js", "https://example.com", "script").matched;
+            let mut generic = engine.hidden_class_id_selectors(&classes, &[], &HashSet::new());
+            generic.sort();
+            let mut specific = engine.url_cosmetic_resources("https://example.com").hide_selectors.into_iter().collect::<Vec<_>>();
+            specific.sort();
+            (ads, tracker, allowed, generic, specific)
+        };
+
+        assert_eq!(check(&engine), (true, true, false, vec![".ad".to_owned(), ".shared".to_owned(), ".sponsored".to_owned()], vec![".banner".to_owned(), ".promo".to_owned()]));
+
+        engine.set_list_enabled(1, false);
+        assert_eq!(check(&engine), (false, true, true, vec![".shared".to_owned(), ".sponsored".to_owned()], vec![".promo".to_owned()]));
+
+        // The setting survives deserialization
+        let serialized = engine.serialize_raw().unwrap();
         engine.deserialize(&serialized).unwrap();
+        assert_eq!(check(&engine), (false, true, true, vec![".shared".to_owned(), ".sponsored".to_owned()], vec![".promo".to_owned()]));
+
+        engine.set_list_enabled(2, false);
+        assert_eq!(check(&engine), (false, false, false, vec![], vec![]));
+
+        engine.set_list_enabled(1, true);
+        engine.set_list_enabled(2, true);
+        assert_eq!(check(&engine), (true, true, false, vec![".ad".to_owned(), ".shared".to_owned(), ".sponsored".to_owned()], vec![".banner".to_owned(), ".promo".to_owned()]));
     }
 
     #[test]
-    fn deserialization_generate_tags() {
-        let mut engine = Engine::from_rules(&[
-            "ad-banner$tag=abc".to_owned()
-        ], Default::default());
-        engine.use_tags(&["abc"]);
-        let serialized = engine.serialize_compressed().unwrap();
-        println!("Engine serialized: {:?}", serialized);
-        engine.deserialize(&serialized).unwrap();
+    fn list_disable_keeps_rules_shared_with_other_lists() {
+        let lists = "||shared.example.com^\n@@||shared.example.com/allowed^\nexample.com##.shared\n";
+        let requests = [
+            ("https://shared.example.com/ad.js", true),
+            ("https://shared.example.com/allowed/ad.js", false),
+            ("https://added.example.com/ad.js", true),
+        ];
+        for optimize in [true, false] {
+            let mut filter_set = FilterSet::new(false);
+            filter_set.add_filter_list(lists, ParseOptions { list_id: Some(1), ..Default::default() });
+            filter_set.add_filter_list(lists, ParseOptions { list_id: Some(2), ..Default::default() });
+            let mut engine = Engine::from_filter_set(filter_set, optimize);
+            for list in [1, 2] {
+                let filter = crate::filters::network::NetworkFilter::parse("||added.example.com^", false, ParseOptions { list_id: Some(list), ..Default::default() }).unwrap();
+                engine.blocker.add_filter(filter).unwrap();
+            }
+
+            for list in [1, 2] {
+                engine.set_list_enabled(list, false);
+                for (url, blocked) in requests.iter() {
+                    assert_eq!(engine.check_network_urls(url, "https://example.com", "script").matched, *blocked, "{} without list {}", url, list);
+                }
+                assert!(engine.url_cosmetic_resources("https://example.com").hide_selectors.contains(".shared"));
+                engine.set_list_enabled(list, true);
+            }
+        }
     }
 
     #[test]
-    fn deserialization_generate_resources() {
-        let mut engine = Engine::from_rules(&[
-            "ad-banner$redirect=nooptext".to_owned()
-        ], Default::default());
+    fn redirect_resolves_loaded_resource() {
+        let filters = vec![
+            String::from("||example.com/ads.js$script,redirect=noop.js"),
+        ];
+        let mut engine = Engine::from_rules(&filters, Default::default());
 
-        let resources = vec![
+        // The resource has not been loaded yet
+        let result = engine.check_network_urls("https://example.com/ads.js", "https://example.com", "script");
+        assert!(result.matched);
+        assert_eq!(result.redirect, None);
+        assert_eq!(result.missing_redirect, Some("noop.js".to_owned()));
+
+        engine.add_resource(Resource {
+            name: "noop.js".to_owned(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: base64::encode("(function() {})()"),
+        }).unwrap();
+
+        let result = engine.check_network_urls("https://example.com/ads.js", "https://example.com", "script");
+        assert!(result.matched);
+        assert_eq!(result.missing_redirect, None);
+        let data_url = match result.redirect {
+            Some(Redirection::Resource(data_url)) => data_url,
+            other => panic!("Expected resource redirect, got {:?}", other),
+        };
+        let body = data_url.strip_prefix("data:application/javascript;base64,").unwrap();
+        assert_eq!(base64::decode(body).unwrap(), b"(function() {})()");
+    }
+
+    #[test]
+    fn redirect_resource_type_mismatch() {
+        let filters = vec![
+            String::from("||example.com/ads.js$script,redirect=noop.js"),
+            String::from("||example.com/pixel.gif$image,redirect=1x1.gif"),
+            String::from("||example.com/ad.png$image,redirect=noop.js"),
+            String::from("||example.com/ad.mp4$media,redirect=noop.js"),
+            String::from("||example.com/data.json$xmlhttprequest,redirect=noop.txt"),
+        ];
+        let mut engine = Engine::from_rules(&filters, Default::default());
+        engine.use_resources(&[
             Resource {
-                name: "nooptext".to_string(),
+                name: "noop.js".to_owned(),
                 aliases: vec![],
-                kind: ResourceType::Mime(MimeType::TextPlain),
-                content: base64::encode(""),
+                kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+                content: base64::encode("(function() {})()"),
             },
             Resource {
-                name: "noopcss".to_string(),
+                name: "1x1.gif".to_owned(),
+                aliases: vec![],
+                kind: ResourceType::Mime(MimeType::ImageGif),
+                content: "R0lGODlhAQABAIABAAAAAP///yH5BAEAAAEALAAAAAABAAEAAAICTAEAOw==".to_owned(),
+            },
+            Resource {
+                name: "noop.txt".to_owned(),
                 aliases: vec![],
                 kind: ResourceType::Mime(MimeType::TextPlain),
-                content: base64::encode(""),
+                content: "".to_owned(),
             },
-        ];
-        engine.use_resources(&resources);
+        ]);
 
-        let serialized = engine.serialize_compressed().unwrap();
-        println!("Engine serialized: {:?}", serialized);
-        engine.deserialize(&serialized).unwrap();
+        let redirect = |url: &str, request_type: &str| {
+            let result = engine.check_network_urls(url, "https://example.com", request_type);
+            assert!(result.matched, "{}", url);
+            assert_eq!(result.missing_redirect, None);
+            result.redirect
+        };
+
+        // Resources of a suitable type are served as-is
+        assert_eq!(redirect("https://example.com/ads.js", "script"), Some(Redirection::Resource(format!("data:application/javascript;base64,{}", base64::encode("(function() {})()")))));
+        assert_eq!(redirect("https://example.com/pixel.gif", "image"), Some(Redirection::Resource("data:image/gif;base64,R0lGODlhAQABAIABAAAAAP///yH5BAEAAAEALAAAAAABAAEAAAICTAEAOw==".to_owned())));
+        assert_eq!(redirect("https://example.com/data.json", "xmlhttprequest"), Some(Redirection::Resource("data:text/plain;base64,".to_owned())));
+
+        // A script is never served in place of an image, but an empty image can be
+        assert_eq!(redirect("https://example.com/ad.png", "image"), Some(Redirection::Resource("data:image/gif;base64,R0lGODlhAQABAIAAAAAAAP///yH5BAEAAAAALAAAAAABAAEAAAIBRAA7".to_owned())));
+
+        // There is no empty media resource to fall back to, so the request is just blocked
+        assert_eq!(redirect("https://example.com/ad.mp4", "media"), None);
     }
 
     #[test]
-    fn redirect_resource_insertion_works() {
-        let mut engine = Engine::from_rules(&[
-            "ad-banner$redirect=nooptext".to_owned()
-        ], Default::default());
+    fn matched_exception() {
+        let rules = vec![
+            String::from("||ads.example.com^"),
+            String::from("@@||ads.example.com/allowed^"),
+        ];
+        let exception = NetworkFilter::parse("@@||ads.example.com/allowed^", false, Default::default()).unwrap();
+        for engine in [Engine::from_rules(&rules, Default::default()), Engine::from_rules_debug(&rules, Default::default())].iter() {
+            let result = engine.check_network_urls("https://ads.example.com/allowed/ad.js", "https://example.com", "script");
+            assert!(!result.matched);
+            assert_eq!(result.matched_exception, Some(exception.filter_id()));
+
+            let result = engine.check_network_urls("https://ads.example.com/ad.js", "https://example.com", "script");
+            assert!(result.matched);
+            assert_eq!(result.matched_exception, None);
+
+            let result = engine.check_network_urls("https://example.com/content.js", "https://example.com", "script");
+            assert!(!result.matched);
+            assert_eq!(result.matched_exception, None);
+        }
+    }
 
+    #[test]
+    fn blocked_and_applied_redirect() {
+        let filters = vec![
+            String::from("||example.com/ads.js$script"),
+            String::from("||example.com/noop.js$script,redirect=noop.js"),
+            String::from("||example.com/allowed.js$script,redirect=noop.js"),
+            String::from("@@||example.com/allowed.js$script"),
+        ];
+        let mut engine = Engine::from_rules(&filters, Default::default());
         engine.add_resource(Resource {
-            name: "nooptext".to_owned(),
+            name: "noop.js".to_owned(),
             aliases: vec![],
-            kind: ResourceType::Mime(MimeType::TextPlain),
-            content: "".to_owned(),
+            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: base64::encode("(function() {})()"),
         }).unwrap();
+        let check = |url: &str| engine.check_network_urls(url, "https://example.com", "script");
 
-        let url = "http://example.com/ad-banner.gif";
-        let matched_rule = engine.check_network_urls(url, "", "");
-        assert!(matched_rule.matched, "Expected match for {}", url);
-        assert_eq!(matched_rule.redirect, Some(Redirection::Resource("data:text/plain;base64,".to_owned())), "Expected redirect to contain resource");
+        let blocked = check("https://example.com/ads.js");
+        assert!(blocked.blocked());
+        assert_eq!(blocked.applied_redirect(), None);
+
+        let redirected = check("https://example.com/noop.js");
+        assert!(redirected.blocked());
+        assert!(matches!(redirected.applied_redirect(), Some(Redirection::Resource(_))));
+
+        let allowed = check("https://example.com/other.js");
+        assert!(!allowed.blocked());
+        assert_eq!(allowed.applied_redirect(), None);
+
+        // The redirect of an excepted request is still reported, but does not apply
+        let excepted = check("https://example.com/allowed.js");
+        assert!(!excepted.blocked());
+        assert!(excepted.redirect.is_some());
+        assert_eq!(excepted.applied_redirect(), None);
     }
 
     #[test]
-    fn redirect_resource_lookup_works() {
-        let script = base64::encode(r#"
-(function() {
-	;
-})();
+    fn font_requests() {
+        let engine = Engine::from_rules(&[
+            "||fonts.example.com^$font".to_owned(),
+            "@@||fonts.example.com/allowed/$font".to_owned(),
+        ], Default::default());
+        let check = |url: &str| engine.check_network_request(&Request::builder(url).source_url("https://page.com").infer_type().build().unwrap());
 
-        "#);
+        assert!(check("https://fonts.example.com/sans.woff2").matched);
+        assert!(check("https://fonts.example.com/sans.ttf").matched);
+        assert!(!check("https://fonts.example.com/loader.js").matched);
 
-        let mut engine = Engine::default();
+        let dest = Request::builder("https://fonts.example.com/css2?family=Sans")
+            .source_url("https://page.com")
+            .fetch_dest("font")
+            .build()
+            .unwrap();
+        assert!(engine.check_network_request(&dest).matched);
+
+        let allowed = check("https://fonts.example.com/allowed/sans.woff2");
+        assert!(!allowed.matched);
+        assert!(allowed.exception.is_some());
+    }
+
+    #[test]
+    fn result_cache() {
+        let mut engine = Engine::from_rules(&[
+            "||ads.example.com^".to_owned(),
+            "||tracker.com^$tag=trackers".to_owned(),
+            "||widgets.com^$third-party".to_owned(),
+        ], Default::default());
+        engine.enable_result_cache(2);
+        let check = |engine: &Engine, url: &str| engine.check_network_urls(url, "https://page.com", "script");
+
+        let first = check(&engine, "https://ads.example.com/ad.js");
+        assert!(first.matched);
+        let cached = check(&engine, "https://ads.example.com/ad.js");
+        assert_eq!(format!("{:?}", cached), format!("{:?}", first));
+
+        // Changing the blocker directly bypasses invalidation, so the cached result is returned
+        assert!(!check(&engine, "https://tracker.com/t.js").matched);
+        engine.blocker.enable_tags(&["trackers"]);
+        assert!(!check(&engine, "https://tracker.com/t.js").matched);
+        engine.clear_result_cache();
+        assert!(check(&engine, "https://tracker.com/t.js").matched);
+
+        // Changing tags through the engine clears the cache
+        engine.disable_tags(&["trackers"]);
+        assert!(!check(&engine, "https://tracker.com/t.js").matched);
+        engine.enable_tags(&["trackers"]);
+        assert!(check(&engine, "https://tracker.com/t.js").matched);
+
+        // Requests differing in their source are cached separately
+        assert!(check(&engine, "https://widgets.com/w.js").matched);
+        assert!(!engine.check_network_urls("https://widgets.com/w.js", "https://widgets.com", "script").matched);
+
+        // Only the most recently used results are kept
+        let entries = engine.result_cache.as_ref().unwrap().entries.lock().unwrap();
+        assert_eq!(entries.results.len(), 2);
+        assert_eq!(entries.recency.len(), 2);
+    }
 
+    #[test]
+    fn elemhide() {
+        let mut engine = Engine::from_rules_debug(&[
+            String::from("##.generic-ad"),
+            String::from("example.com##.specific-ad"),
+            String::from("example.com##+js(noop.js)"),
+            String::from("other.com##.specific-ad"),
+            String::from("@@||example.com^$elemhide"),
+        ], Default::default());
         engine.add_resource(Resource {
-            name: "noopjs".to_owned(),
+            name: "noop.js".to_owned(),
             aliases: vec![],
-            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
-            content: script.to_owned(),
+            kind: ResourceType::Template,
+            content: base64::encode("(function() {})()"),
         }).unwrap();
-        let inserted_resource = engine.get_resource("noopjs");
-        assert!(inserted_resource.is_some());
-        let resource = inserted_resource.unwrap();
-        assert_eq!(resource.content_type, "application/javascript");
-        assert_eq!(resource.data, script);
-    }
 
-    #[test]
-    fn document() {
-        let filters = vec![
-            String::from("||example.com$document"),
-            String::from("@@||sub.example.com$document"),
-        ];
+        let resources = engine.url_cosmetic_resources("https://example.com/page");
+        assert!(resources.hide_selectors.is_empty());
+        assert!(resources.generichide);
+        assert!(resources.injected_script.contains("(function() {})()"));
+        assert!(engine.cosmetic_stylesheet_for("example.com").is_empty());
 
-        let engine = Engine::from_rules_debug(&filters, Default::default());
+        let resources = engine.url_cosmetic_resources("https://other.com/page");
+        assert!(resources.hide_selectors.contains(".specific-ad"));
+        assert!(!resources.generichide);
 
-        assert!(engine.check_network_urls("https://example.com", "https://example.com", "document").matched);
-        assert!(!engine.check_network_urls("https://example.com", "https://example.com", "script").matched);
-        assert!(engine.check_network_urls("https://sub.example.com", "https://sub.example.com", "document").exception.is_some());
+        let exception = NetworkFilter::parse("@@||example.com^$elemhide", false, Default::default()).unwrap().filter_id();
+        assert_eq!(engine.document_flags("https://example.com/page"), DocumentFlags {
+            generic_hide: true,
+            elem_hide: true,
+            generic_hide_exception: Some(exception),
+            elem_hide_exception: Some(exception),
+            ..Default::default()
+        });
+        assert_eq!(engine.document_flags("https://other.com/page"), DocumentFlags::default());
     }
 
     #[test]
-    fn implicit_all() {
-        {
-            let engine = Engine::from_rules_debug(&vec![String::from("||example.com^")], Default::default());
-            assert!(engine.check_network_urls("https://example.com", "https://example.com", "document").matched);
-        }
-        {
-            let engine = Engine::from_rules_debug(&vec![String::from("||example.com^$first-party,match-case")], Default::default());
-            assert!(engine.check_network_urls("https://example.com", "https://example.com", "document").matched);
-        }
-        {
-            let engine = Engine::from_rules_debug(&vec![String::from("||example.com^$script")], Default::default());
-            assert!(!engine.check_network_urls("https://example.com", "https://example.com", "document").matched);
-        }
-        {
-            let engine = Engine::from_rules_debug(&vec![String::from("||example.com^$~script")], Default::default());
-            assert!(!engine.check_network_urls("https://example.com", "https://example.com", "document").matched);
-        }
-        {
-            let engine = Engine::from_rules_debug(&vec![String::from("||example.com^$document"), String::from("@@||example.com^$generichide")], Default::default());
-            assert!(engine.check_network_urls("https://example.com", "https://example.com", "document").matched);
-        }
-        {
-            let engine = Engine::from_rules_debug(&vec![String::from("example.com")], ParseOptions { format: FilterFormat::Hosts, ..Default::default() });
-            assert!(engine.check_network_urls("https://example.com", "https://example.com", "document").matched);
-        }
-        {
-            let engine = Engine::from_rules_debug(&vec![String::from("||example.com/path")], Default::default());
-            assert!(!engine.check_network_urls("https://example.com/path", "https://example.com/path", "document").matched);
-        }
-        {
-            let engine = Engine::from_rules_debug(&vec![String::from("||example.com/path^")], Default::default());
-            assert!(!engine.check_network_urls("https://example.com/path", "https://example.com/path", "document").matched);
+    fn block_stream() {
+        #[derive(Clone, Default)]
+        struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for SharedWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
         }
+
+        let mut engine = Engine::from_rules_debug(&[
+            String::from("||ads.example.com^"),
+            String::from("||tracker.com/\"quoted\"^$important"),
+            String::from("@@||ads.example.com/allowed^"),
+        ], Default::default());
+        let writer = SharedWriter::default();
+        engine.set_block_stream(writer.clone());
+
+        engine.check_network_urls("https://ads.example.com/banner.png", "https://page.com", "image");
+        engine.check_network_urls("https://ads.example.com/allowed/banner.png", "https://page.com", "image");
+        engine.check_network_urls("https://page.com/app.js", "https://page.com", "script");
+        engine.check_network_urls("https://tracker.com/\"quoted\"/t.js", "https://page.com", "script");
+
+        let output = String::from_utf8(writer.0.lock().unwrap().clone()).unwrap();
+        let lines = output.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![
+            serde_json::json!({
+                "url": "https://ads.example.com/banner.png",
+                "request_type": "image",
+                "filter": "||ads.example.com^",
+                "important": false,
+                "redirect": false,
+            }),
+            serde_json::json!({
+                "url": "https://tracker.com/\"quoted\"/t.js",
+                "request_type": "script",
+                "filter": "||tracker.com/\"quoted\"^$important",
+                "important": true,
+                "redirect": false,
+            }),
+        ]);
+
+        assert!(engine.clear_block_stream().is_some());
+        engine.check_network_urls("https://ads.example.com/banner.png", "https://page.com", "image");
+        assert_eq!(writer.0.lock().unwrap().len(), output.len());
     }
 
     #[test]
-    fn generichide() {
-        let filters = vec![
-            String::from("##.donotblock"),
-            String::from("##a[href=\"generic.com\"]"),
+    fn add_resources() {
+        let resource = |name: &str, kind: ResourceType, content: &str| Resource {
+            name: name.to_owned(),
+            aliases: vec![],
+            kind,
+            content: base64::encode(content),
+        };
+        let mut engine = Engine::from_rules(&[
+            String::from("||example.com/ads.js$script,redirect=noop.js"),
+            String::from("example.com##+js(log)"),
+        ], Default::default());
+        engine.use_resources(&[
+            resource("noop.js", ResourceType::Mime(MimeType::ApplicationJavascript), "built-in"),
+            resource("log", ResourceType::Template, "console.log('built-in')"),
+        ]);
 
-            String::from("@@||example.com$generichide"),
-            String::from("example.com##.block"),
+        let collisions = engine.add_resources(&[
+            resource("noop.js", ResourceType::Mime(MimeType::ApplicationJavascript), "user"),
+            resource("log", ResourceType::Template, "console.log('user')"),
+            resource("pixel.gif", ResourceType::Mime(MimeType::ImageGif), "user"),
+        ]);
+        assert_eq!(collisions, ["log", "noop.js"]);
 
-            String::from("@@||example2.com/test.html$generichide"),
-            String::from("example2.com##.block"),
-        ];
-        let url_results = vec![
-            ("https://example.com", vec![".block"], true),
-            ("https://example.com/test.html", vec![".block"], true),
-            ("https://example2.com", vec![".block", "a[href=\"generic.com\"]"], false),
-            ("https://example2.com/test.html", vec![".block"], true),
+        let result = engine.check_network_urls("https://example.com/ads.js", "https://example.com", "script");
+        assert_eq!(result.redirect, Some(Redirection::Resource(format!("data:application/javascript;base64,{}", base64::encode("user")))));
+        let script = engine.url_cosmetic_resources("https://example.com").injected_script;
+        assert!(script.contains("console.log('user')"));
+        assert!(!script.contains("built-in"));
+        assert!(engine.get_resource("pixel.gif").is_some());
+
+        assert!(engine.add_resources(&[resource("other.js", ResourceType::Mime(MimeType::ApplicationJavascript), "")]).is_empty());
+
+        // Duplicates within the same pack are reported too, and the last one is used
+        let collisions = engine.add_resources(&[
+            resource("pack.js", ResourceType::Mime(MimeType::ApplicationJavascript), "first"),
+            resource("pack.js", ResourceType::Mime(MimeType::ApplicationJavascript), "second"),
+        ]);
+        assert_eq!(collisions, ["pack.js"]);
+        assert_eq!(engine.get_resource("pack.js").map(|resource| resource.data), Some(base64::encode("second")));
+    }
+
+    #[test]
+    fn serialized_allowlist() {
+        let filters = vec![
+            String::from("||ads.example.net^"),
+            String::from("##div.ad"),
         ];
+        let mut engine = Engine::from_rules(&filters, Default::default());
+        engine.add_allowlist_domain("example.com");
+        let serialized = engine.serialize_raw().unwrap();
 
-        let engine = Engine::from_rules(&filters, Default::default());
+        let mut restored = Engine::new(true);
+        restored.deserialize(&serialized).unwrap();
+        assert!(!restored.check_network_urls("https://ads.example.net/ad.js", "https://www.example.com", "script").matched);
+        assert!(restored.check_network_urls("https://ads.example.net/ad.js", "https://example.org", "script").matched);
+        assert!(restored.url_cosmetic_resources("https://example.com").hide_selectors.is_empty());
+        assert!(restored.url_cosmetic_resources("https://example.org").hide_selectors.contains("div.ad"));
 
-        url_results.into_iter().for_each(|(url, expected_result, expected_generichide)| {
-            let result = engine.url_cosmetic_resources(url);
-            assert_eq!(result.hide_selectors, expected_result.iter().map(|s| s.to_string()).collect::<HashSet<_>>());
-            assert_eq!(result.generichide, expected_generichide);
-        });
+        let mut network_only = Engine::new(true);
+        network_only.deserialize_network_only(&engine.serialize_raw_without_resources().unwrap()).unwrap();
+        assert!(!network_only.check_network_urls("https://ads.example.net/ad.js", "https://example.com", "script").matched);
+
+        // Data without an allowlist leaves the current one in place.
+        let mut existing = Engine::new(true);
+        existing.add_allowlist_domain("example.org");
+        existing.deserialize(&Engine::from_rules(&filters, Default::default()).serialize_raw().unwrap()).unwrap();
+        assert!(!existing.check_network_urls("https://ads.example.net/ad.js", "https://example.org", "script").matched);
+        assert!(existing.check_network_urls("https://ads.example.net/ad.js", "https://example.com", "script").matched);
     }
 
     #[test]
-    fn important_redirect() {
-        let mut filter_set = FilterSet::new(true);
-        filter_set.add_filters(&vec![
-            "||addthis.com^$important,3p,domain=~missingkids.com|~missingkids.org|~sainsburys.jobs|~sitecore.com|~amd.com".to_string(),
-            "||addthis.com/*/addthis_widget.js$script,redirect=addthis.com/addthis_widget.js".to_string(),
+    fn document_flags_report_cosmetic_exceptions() {
+        let mut engine = Engine::from_rules_debug(&[
+            String::from("##.ad"),
+            String::from("@@||example.com^$generichide"),
+            String::from("@@||example.com/app^$elemhide"),
         ], Default::default());
-        let mut engine = Engine::from_filter_set(filter_set, false);
+        engine.add_allowlist_domain("trusted.com");
+        let id = |filter: &str| NetworkFilter::parse(filter, false, Default::default()).unwrap().filter_id();
 
-        engine.add_resource(Resource {
-            name: "addthis.com/addthis_widget.js".to_owned(),
-            aliases: vec![],
-            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
-            content: base64::encode("window.addthis = undefined"),
-        }).unwrap();
+        let flags = engine.document_flags("https://www.example.com/page");
+        assert!(flags.generic_hide);
+        assert!(!flags.elem_hide);
+        assert_eq!(flags.generic_hide_exception, Some(id("@@||example.com^$generichide")));
+        assert_eq!(flags.elem_hide_exception, None);
 
-        let result = engine.check_network_urls("https://s7.addthis.com/js/250/addthis_widget.js?pub=resto", "https://www.rhmodern.com/catalog/product/product.jsp?productId=prod14970086&categoryId=cat7150028", "script");
+        let flags = engine.document_flags("https://example.com/app/page");
+        assert!(flags.elem_hide);
+        assert_eq!(flags.elem_hide_exception, Some(id("@@||example.com/app^$elemhide")));
 
-        assert!(result.redirect.is_some());
+        // Allowlisted hostnames don't have an exception filter
+        let flags = engine.document_flags("https://trusted.com");
+        assert!(flags.generic_hide && flags.elem_hide);
+        assert_eq!(flags.generic_hide_exception, None);
+        assert_eq!(flags.elem_hide_exception, None);
+
+        let context = engine.document_context("https://www.example.com/page");
+        assert_eq!(context.generic_hide_filter.as_deref(), Some("@@||example.com^$generichide"));
+        assert_eq!(DocumentFlags::from(&context).generic_hide_exception, Some(id("@@||example.com^$generichide")));
     }
 }
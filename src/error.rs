@@ -0,0 +1,136 @@
+//! A single error type for everything that can fail in this crate, for embedders that would rather
+//! handle one type with `?` than each module's own errors.
+
+use crate::blocker::BlockerError;
+use crate::filters::cosmetic::CosmeticFilterError;
+use crate::filters::html::HtmlFilterError;
+use crate::filters::network::NetworkFilterError;
+use crate::lists::FilterParseError;
+use crate::request::RequestError;
+use crate::resources::{AddResourceError, ScriptletResourceError};
+
+pub use crate::data_format::{DeserializationError, SerializationError};
+
+#[derive(Debug)]
+pub enum AdblockError {
+    FilterParse(FilterParseError),
+    Request(RequestError),
+    Blocker(BlockerError),
+    Resource(AddResourceError),
+    Scriptlet(ScriptletResourceError),
+    Serialization(SerializationError),
+    Deserialization(DeserializationError),
+}
+
+impl std::fmt::Display for AdblockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::FilterParse(e) => write!(f, "{}", e),
+            Self::Request(e) => write!(f, "invalid request: {}", e),
+            Self::Blocker(e) => write!(f, "{}", e),
+            Self::Resource(e) => write!(f, "invalid resource: {}", e),
+            Self::Scriptlet(e) => write!(f, "invalid scriptlet: {}", e),
+            Self::Serialization(e) => write!(f, "{}", e),
+            Self::Deserialization(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for AdblockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::FilterParse(e) => Some(e),
+            Self::Request(e) => Some(e),
+            Self::Blocker(e) => Some(e),
+            Self::Resource(e) => Some(e),
+            Self::Scriptlet(e) => Some(e),
+            Self::Serialization(e) => Some(e),
+            Self::Deserialization(e) => Some(e),
+        }
+    }
+}
+
+impl From<FilterParseError> for AdblockError {
+    fn from(e: FilterParseError) -> Self { Self::FilterParse(e) }
+}
+
+impl From<NetworkFilterError> for AdblockError {
+    fn from(e: NetworkFilterError) -> Self { Self::FilterParse(e.into()) }
+}
+
+impl From<CosmeticFilterError> for AdblockError {
+    fn from(e: CosmeticFilterError) -> Self { Self::FilterParse(e.into()) }
+}
+
+impl From<HtmlFilterError> for AdblockError {
+    fn from(e: HtmlFilterError) -> Self { Self::FilterParse(e.into()) }
+}
+
+impl From<RequestError> for AdblockError {
+    fn from(e: RequestError) -> Self { Self::Request(e) }
+}
+
+impl From<BlockerError> for AdblockError {
+    fn from(e: BlockerError) -> Self { Self::Blocker(e) }
+}
+
+impl From<AddResourceError> for AdblockError {
+    fn from(e: AddResourceError) -> Self { Self::Resource(e) }
+}
+
+impl From<ScriptletResourceError> for AdblockError {
+    fn from(e: ScriptletResourceError) -> Self { Self::Scriptlet(e) }
+}
+
+impl From<SerializationError> for AdblockError {
+    fn from(e: SerializationError) -> Self { Self::Serialization(e) }
+}
+
+impl From<DeserializationError> for AdblockError {
+    fn from(e: DeserializationError) -> Self { Self::Deserialization(e) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    fn message<E: Into<AdblockError>>(e: E) -> String {
+        e.into().to_string()
+    }
+
+    #[test]
+    fn converts_sub_errors() {
+        assert_eq!(message(NetworkFilterError::NegatedImportant), "invalid network filter: `$important` cannot be negated");
+        assert_eq!(message(CosmeticFilterError::MissingSharp), "invalid cosmetic filter: cosmetic filter has no `##` separator");
+        assert_eq!(message(HtmlFilterError::EmptyRule), "invalid HTML filter: HTML filter has no tag name");
+        assert_eq!(message(FilterParseError::Empty), "empty filter");
+        assert_eq!(message(RequestError::HostnameParseError), "invalid request: request URL has no valid hostname");
+        assert_eq!(message(BlockerError::FilterExists), "filter already exists");
+        assert_eq!(message(AddResourceError::InvalidBase64Content), "invalid resource: resource content is not valid base64");
+        assert_eq!(message(ScriptletResourceError::NoMatchingScriptlet), "invalid scriptlet: no scriptlet resource with that name");
+        assert_eq!(message(SerializationError::GzError(std::io::Error::other("disk full"))), "failed to compress serialized data: disk full");
+        assert_eq!(message(DeserializationError::UnsupportedFormatVersion(7)), "unsupported serialized data format version 7");
+
+        let error = AdblockError::from(NetworkFilterError::UnrecognisedOption);
+        assert!(matches!(error, AdblockError::FilterParse(FilterParseError::Network(NetworkFilterError::UnrecognisedOption))));
+        assert_eq!(error.source().map(|e| e.to_string()), Some("invalid network filter: unrecognised filter option".to_owned()));
+        assert_eq!(error.source().and_then(Error::source).map(|e| e.to_string()), Some("unrecognised filter option".to_owned()));
+    }
+
+    #[test]
+    fn question_mark_conversion() {
+        fn parse_and_check(filter: &str, url: &str) -> Result<bool, AdblockError> {
+            let filter = crate::filters::network::NetworkFilter::parse(filter, false, Default::default())?;
+            let mut blocker = crate::blocker::Blocker::new(vec![], &crate::blocker::BlockerOptions { enable_optimizations: false });
+            blocker.add_filter(filter)?;
+            let request = crate::request::Request::from_urls(url, "", "script")?;
+            Ok(blocker.check(&request).matched)
+        }
+
+        assert!(parse_and_check("||example.com^", "https://example.com/ad.js").unwrap());
+        assert!(matches!(parse_and_check("||example.com^$~important", "https://example.com"), Err(AdblockError::FilterParse(_))));
+        assert!(matches!(parse_and_check("||example.com^", "not a url"), Err(AdblockError::Request(RequestError::HostnameParseError))));
+        assert!(matches!(parse_and_check("||example.com^$badfilter", "https://example.com"), Err(AdblockError::Blocker(BlockerError::BadFilterAddUnsupported))));
+    }
+}
@@ -0,0 +1,271 @@
+//! Flat, C-compatible representations of the engine's results, for embedders calling into
+//! `adblock-rust` over a C ABI, e.g. from C++, Swift or Kotlin bindings.
+//!
+//! Strings are passed as owned, nul-terminated C strings, with a null pointer standing in for
+//! `None`. Results are created with `adblock_engine_check_network_urls`, and must be released
+//! with `adblock_blocker_result_free` once the caller is done with them.
+
+#![allow(unsafe_code)]
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::ptr;
+
+use crate::blocker::{BlockerResult, Redirection};
+use crate::engine::Engine;
+use crate::filters::network::FilterId;
+use crate::request::RequestType;
+
+/// C-compatible equivalent of `BlockerResult`. See the documentation of its fields there.
+#[repr(C)]
+#[derive(Debug)]
+pub struct FfiBlockerResult {
+    /// `BlockerResult::matched`, i.e. whether the request should be blocked.
+    pub blocked: bool,
+    pub important: bool,
+    /// Whether an exception matched the request, in which case its text is in `exception_filter`.
+    pub exception: bool,
+    /// The data URL of a `Redirection::Resource`, or the URL of a `Redirection::Url` if
+    /// `redirect_is_url` is set.
+    pub redirect: *mut c_char,
+    pub redirect_is_url: bool,
+    pub redirect_query: *mut c_char,
+    pub missing_redirect: *mut c_char,
+    pub exception_filter: *mut c_char,
+    /// Only meaningful if `has_matched_exception` is set.
+    pub matched_exception: FilterId,
+    pub has_matched_exception: bool,
+    pub filter: *mut c_char,
+    /// Only meaningful if `has_matched_filter` is set.
+    pub matched_filter: FilterId,
+    pub has_matched_filter: bool,
+    pub error: *mut c_char,
+    /// Only meaningful if `has_request_type` is set.
+    pub request_type: RequestType,
+    pub has_request_type: bool,
+}
+
+/// Strings containing a nul byte can't be represented, and are passed as null instead.
+fn to_c_string(value: Option<&str>) -> *mut c_char {
+    value.and_then(|value| CString::new(value).ok())
+        .map(CString::into_raw)
+        .unwrap_or(ptr::null_mut())
+}
+
+/// # Safety
+///
+/// `value` must be null or a pointer returned by `to_c_string` that hasn't been freed yet.
+unsafe fn from_c_string(value: *mut c_char) -> Option<String> {
+    if value.is_null() {
+        None
+    } else {
+        Some(CString::from_raw(value).into_string().unwrap_or_default())
+    }
+}
+
+/// # Safety
+///
+/// `value` must be null or a valid nul-terminated string that outlives the returned reference.
+unsafe fn borrow_c_string<'a>(value: *const c_char) -> Option<&'a str> {
+    if value.is_null() {
+        None
+    } else {
+        CStr::from_ptr(value).to_str().ok()
+    }
+}
+
+impl From<&BlockerResult> for FfiBlockerResult {
+    fn from(result: &BlockerResult) -> Self {
+        let (redirect, redirect_is_url) = match &result.redirect {
+            Some(Redirection::Resource(resource)) => (Some(resource.as_str()), false),
+            Some(Redirection::Url(url)) => (Some(url.as_str()), true),
+            None => (None, false),
+        };
+
+        Self {
+            blocked: result.matched,
+            important: result.important,
+            exception: result.exception.is_some(),
+            redirect: to_c_string(redirect),
+            redirect_is_url,
+            redirect_query: to_c_string(result.redirect_query.as_deref()),
+            missing_redirect: to_c_string(result.missing_redirect.as_deref()),
+            exception_filter: to_c_string(result.exception.as_deref()),
+            matched_exception: result.matched_exception.unwrap_or_default(),
+            has_matched_exception: result.matched_exception.is_some(),
+            filter: to_c_string(result.filter.as_deref()),
+            matched_filter: result.matched_filter.unwrap_or_default(),
+            has_matched_filter: result.matched_filter.is_some(),
+            error: to_c_string(result.error.as_deref()),
+            request_type: result.request_type.clone().unwrap_or(RequestType::Other),
+            has_request_type: result.request_type.is_some(),
+        }
+    }
+}
+
+impl FfiBlockerResult {
+    /// Converts the result back into a `BlockerResult`, freeing its strings.
+    ///
+    /// # Safety
+    ///
+    /// The result must have been created by `FfiBlockerResult::from` or
+    /// `adblock_engine_check_network_urls`, and its strings must not have been freed or replaced
+    /// since.
+    pub unsafe fn into_blocker_result(self) -> BlockerResult {
+        let redirect = from_c_string(self.redirect).map(|redirect| if self.redirect_is_url {
+            Redirection::Url(redirect)
+        } else {
+            Redirection::Resource(redirect)
+        });
+        let exception_filter = from_c_string(self.exception_filter);
+
+        BlockerResult {
+            matched: self.blocked,
+            important: self.important,
+            redirect,
+            missing_redirect: from_c_string(self.missing_redirect),
+            redirect_query: from_c_string(self.redirect_query),
+            exception: if self.exception { Some(exception_filter.unwrap_or_default()) } else { None },
+            matched_exception: if self.has_matched_exception { Some(self.matched_exception) } else { None },
+            filter: from_c_string(self.filter),
+            matched_filter: if self.has_matched_filter { Some(self.matched_filter) } else { None },
+            error: from_c_string(self.error),
+            request_type: if self.has_request_type { Some(self.request_type) } else { None },
+        }
+    }
+
+    /// Returns the redirect as a borrowed string, without taking ownership of it.
+    ///
+    /// # Safety
+    ///
+    /// Same as `into_blocker_result`.
+    pub unsafe fn redirect_str(&self) -> Option<&str> {
+        borrow_c_string(self.redirect)
+    }
+}
+
+/// Checks a request against `engine`, like `Engine::check_network_urls`. A null engine, or a null
+/// or non UTF-8 string, is reported through the result's `error`, as for unparsable requests.
+/// The result must be released with `adblock_blocker_result_free`.
+///
+/// # Safety
+///
+/// `engine` must be null or point to a valid `Engine`, and each string must be null or a valid
+/// nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn adblock_engine_check_network_urls(
+    engine: *const Engine,
+    url: *const c_char,
+    source_url: *const c_char,
+    request_type: *const c_char,
+) -> FfiBlockerResult {
+    let result = match (engine.as_ref(), borrow_c_string(url), borrow_c_string(source_url), borrow_c_string(request_type)) {
+        (Some(engine), Some(url), Some(source_url), Some(request_type)) => engine.check_network_urls(url, source_url, request_type),
+        _ => BlockerResult {
+            error: Some("Error parsing request".to_owned()),
+            ..Default::default()
+        },
+    };
+    FfiBlockerResult::from(&result)
+}
+
+/// Frees the strings of a result returned to a C caller.
+///
+/// # Safety
+///
+/// Same as `FfiBlockerResult::into_blocker_result`. The strings must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn adblock_blocker_result_free(result: FfiBlockerResult) {
+    result.into_blocker_result();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_results_with_all_fields() {
+        let result = BlockerResult {
+            matched: true,
+            important: true,
+            redirect: Some(Redirection::Url("https://example.com/stub.js".to_owned())),
+            missing_redirect: Some("noop.js".to_owned()),
+            redirect_query: Some("v=1".to_owned()),
+            exception: Some("@@||example.com^".to_owned()),
+            matched_exception: Some(42),
+            filter: Some("||ads.example.com^$important".to_owned()),
+            matched_filter: Some(7),
+            error: Some("problem".to_owned()),
+            request_type: Some(RequestType::Script),
+        };
+
+        let ffi_result = FfiBlockerResult::from(&result);
+        assert!(ffi_result.blocked);
+        assert!(ffi_result.exception);
+        assert!(ffi_result.redirect_is_url);
+        assert!(ffi_result.has_request_type);
+        assert_eq!(unsafe { ffi_result.redirect_str() }, Some("https://example.com/stub.js"));
+
+        let converted = unsafe { ffi_result.into_blocker_result() };
+        assert_eq!(format!("{:?}", converted), format!("{:?}", result));
+    }
+
+    #[test]
+    fn converts_empty_results() {
+        let result = BlockerResult::default();
+
+        let ffi_result = FfiBlockerResult::from(&result);
+        assert!(!ffi_result.blocked);
+        assert!(!ffi_result.exception);
+        assert!(!ffi_result.has_matched_exception);
+        assert!(!ffi_result.has_matched_filter);
+        assert!(!ffi_result.has_request_type);
+        assert!(ffi_result.redirect.is_null());
+        assert!(ffi_result.redirect_query.is_null());
+        assert!(ffi_result.missing_redirect.is_null());
+        assert!(ffi_result.exception_filter.is_null());
+        assert!(ffi_result.filter.is_null());
+        assert!(ffi_result.error.is_null());
+        assert_eq!(unsafe { ffi_result.redirect_str() }, None);
+
+        let converted = unsafe { ffi_result.into_blocker_result() };
+        assert_eq!(format!("{:?}", converted), format!("{:?}", result));
+
+        let resource = BlockerResult {
+            redirect: Some(Redirection::Resource("data:text/plain;base64,".to_owned())),
+            ..Default::default()
+        };
+        let ffi_result = FfiBlockerResult::from(&resource);
+        assert!(!ffi_result.redirect_is_url);
+        unsafe { adblock_blocker_result_free(ffi_result) };
+    }
+
+    #[test]
+    fn checks_requests_through_the_c_abi() {
+        let engine = Engine::from_rules(&["||ads.example.com^$script".to_owned()], Default::default());
+        let url = CString::new("https://ads.example.com/ad.js").unwrap();
+        let source_url = CString::new("https://example.com").unwrap();
+        let script = CString::new("script").unwrap();
+        let image = CString::new("image").unwrap();
+
+        let result = unsafe { adblock_engine_check_network_urls(&engine, url.as_ptr(), source_url.as_ptr(), script.as_ptr()) };
+        assert!(result.blocked);
+        assert!(result.error.is_null());
+        unsafe { adblock_blocker_result_free(result) };
+
+        let result = unsafe { adblock_engine_check_network_urls(&engine, url.as_ptr(), source_url.as_ptr(), image.as_ptr()) };
+        assert!(!result.blocked);
+        assert!(result.error.is_null());
+        unsafe { adblock_blocker_result_free(result) };
+
+        let result = unsafe { adblock_engine_check_network_urls(&engine, ptr::null(), source_url.as_ptr(), script.as_ptr()) };
+        assert!(!result.blocked);
+        assert!(!result.error.is_null());
+        unsafe { adblock_blocker_result_free(result) };
+
+        let result = unsafe { adblock_engine_check_network_urls(ptr::null(), url.as_ptr(), source_url.as_ptr(), script.as_ptr()) };
+        assert!(!result.blocked);
+        assert!(!result.error.is_null());
+        unsafe { adblock_blocker_result_free(result) };
+    }
+}
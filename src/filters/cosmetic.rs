@@ -5,6 +5,7 @@ use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use crate::lists::ListId;
 use crate::utils::Hash;
 
 use css_validation::{is_valid_css_selector, is_valid_css_style};
@@ -17,13 +18,44 @@ pub enum CosmeticFilterError {
     MissingSharp,
     InvalidCssStyle,
     InvalidCssSelector,
+    /// No longer produced, since generic cosmetic exceptions like `#@#.ad` are now supported.
+    #[deprecated(note = "generic cosmetic exceptions are supported, so this error is never returned")]
     GenericUnhide,
     GenericScriptInject,
     GenericStyle,
+    GenericRemove,
     DoubleNegation,
     EmptyRule,
+    GenericProcedural,
+    InvalidProceduralRegex,
+    ProceduralRemove,
 }
 
+impl std::fmt::Display for CosmeticFilterError {
+    #[allow(deprecated)]
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::PunycodeError => write!(f, "hostname could not be converted to punycode"),
+            Self::InvalidStyleSpecifier => write!(f, "malformed `:style()` specifier"),
+            Self::UnsupportedSyntax => write!(f, "unsupported cosmetic filter syntax"),
+            Self::MissingSharp => write!(f, "cosmetic filter has no `##` separator"),
+            Self::InvalidCssStyle => write!(f, "invalid CSS style"),
+            Self::InvalidCssSelector => write!(f, "invalid CSS selector"),
+            Self::GenericUnhide => write!(f, "generic cosmetic exception rejected"),
+            Self::GenericScriptInject => write!(f, "scriptlet injections must be restricted to specific hostnames"),
+            Self::GenericStyle => write!(f, "style rules must be restricted to specific hostnames"),
+            Self::GenericRemove => write!(f, "`:remove()` rules must be restricted to specific hostnames"),
+            Self::DoubleNegation => write!(f, "hostname is negated more than once"),
+            Self::EmptyRule => write!(f, "cosmetic filter has no selector"),
+            Self::GenericProcedural => write!(f, "procedural filters must be restricted to specific hostnames"),
+            Self::InvalidProceduralRegex => write!(f, "invalid regex in procedural operator"),
+            Self::ProceduralRemove => write!(f, "`:remove()` can't be combined with procedural operators"),
+        }
+    }
+}
+
+impl std::error::Error for CosmeticFilterError {}
+
 bitflags::bitflags! {
     /// Boolean flags for cosmetic filter rules.
     #[derive(Serialize, Deserialize)]
@@ -34,6 +66,11 @@ bitflags::bitflags! {
         const IS_CLASS_SELECTOR = 1 << 3;
         const IS_ID_SELECTOR = 1 << 4;
         const IS_SIMPLE = 1 << 5;
+        /// Matched elements should be removed from the page rather than hidden, via `:remove()`.
+        const REMOVE = 1 << 6;
+        /// The rule used a procedural operator that isn't supported, and only hides the elements
+        /// matched by the CSS selector before it. See `ParseOptions::procedural_fallback`.
+        const PROCEDURAL_FALLBACK = 1 << 7;
 
         // Careful with checking for NONE - will always match
         const NONE = 0;
@@ -52,6 +89,141 @@ pub struct CosmeticFilter {
     pub selector: String,
     pub key: Option<String>,
     pub style: Option<String>,
+    /// Procedural operators that further filter the elements matched by `selector`, in the order
+    /// they should be applied. These must be evaluated by a content script rather than injected as
+    /// CSS.
+    pub procedural: Option<Vec<ProceduralOperator>>,
+    /// The list this filter was parsed from, if any. See `ParseOptions::list_id`.
+    #[serde(default)]
+    pub list_id: Option<ListId>,
+    /// The hostnames and entities preceding the `##` separator, as written, regardless of
+    /// `debug`. These are only kept to name the hostnames in `Engine::specific_cosmetic_hostnames`,
+    /// and are not serialized.
+    #[serde(skip)]
+    pub locations: Option<String>,
+}
+
+/// A single procedural operator from a cosmetic filter rule.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ProceduralOperator {
+    /// `:has-text(...)` matches elements whose text content matches the argument.
+    HasText(TextMatcher),
+}
+
+/// The argument of a text-matching procedural operator like `:has-text(...)`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TextMatcher {
+    /// Matches text that contains the string, e.g. `:has-text(Sponsored)`.
+    Literal(String),
+    /// Matches text against a regex, e.g. `:has-text(/^sponsored$/i)`. `pattern` is the regex
+    /// source between the slashes, and `flags` any JavaScript flags following the closing slash.
+    /// The pattern has been checked to compile with the given flags.
+    Regex { pattern: String, flags: String },
+}
+
+impl TextMatcher {
+    fn parse(argument: &str) -> Result<Self, CosmeticFilterError> {
+        if argument.is_empty() {
+            return Err(CosmeticFilterError::UnsupportedSyntax);
+        }
+        if argument.len() > 1 && argument.starts_with('/') {
+            if let Some(end) = argument.rfind('/').filter(|end| *end > 0) {
+                let flags = &argument[end + 1..];
+                if flags.chars().all(|c| c.is_ascii_alphabetic()) {
+                    let pattern = &argument[1..end];
+                    Self::compile_regex(pattern, flags)?;
+                    return Ok(TextMatcher::Regex { pattern: pattern.to_string(), flags: flags.to_string() });
+                }
+            }
+        }
+        Ok(TextMatcher::Literal(argument.to_string()))
+    }
+
+    /// Compiles `pattern` with the equivalent of the given JavaScript regex flags.
+    fn compile_regex(pattern: &str, flags: &str) -> Result<(), CosmeticFilterError> {
+        if pattern.is_empty() {
+            return Err(CosmeticFilterError::InvalidProceduralRegex);
+        }
+        let mut builder = regex::RegexBuilder::new(pattern);
+        for flag in flags.chars() {
+            match flag {
+                'i' => builder.case_insensitive(true),
+                'm' => builder.multi_line(true),
+                's' => builder.dot_matches_new_line(true),
+                'u' => builder.unicode(true),
+                _ => return Err(CosmeticFilterError::InvalidProceduralRegex),
+            };
+        }
+        builder.build().map(|_| ()).map_err(|_| CosmeticFilterError::InvalidProceduralRegex)
+    }
+}
+
+/// Functional pseudo-classes and pseudo-elements that are part of CSS. Any other `:name(...)` in a
+/// selector is a procedural operator that isn't supported.
+const CSS_FUNCTIONAL_PSEUDO_CLASSES: &[&str] = &[
+    "not", "is", "where", "matches", "any", "-webkit-any", "-moz-any",
+    "nth-child", "nth-last-child", "nth-of-type", "nth-last-of-type", "nth-col", "nth-last-col",
+    "lang", "dir", "host", "host-context", "state", "part", "slotted", "cue", "cue-region", "highlight",
+];
+
+/// If `selector` starts with a call like `:upward(2)`, i.e. a colon followed by a name and an
+/// opening parenthesis, returns the name.
+fn pseudo_call_name(selector: &str) -> Option<&str> {
+    selector.strip_prefix(':')
+        .and_then(|rest| rest.find('(').map(|paren| &rest[..paren]))
+        .filter(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+}
+
+/// Whether the end of `selector` is within an attribute selector like `[href="javascript:void(0)"]`.
+fn within_attribute_selector(selector: &str) -> bool {
+    selector.matches('[').count() > selector.matches(']').count()
+}
+
+/// Returns the index of the `)` closing an argument that begins at the start of `argument`,
+/// accounting for nested and backslash-escaped parentheses. Regex arguments may contain unbalanced
+/// parentheses, so they are closed by the first `/flags)` that ends the rule or is followed by
+/// another operator.
+fn closing_paren_index(argument: &str) -> Option<usize> {
+    if argument.starts_with('/') {
+        let regex_end = argument.match_indices(')').map(|(i, _)| i).find(|i| {
+            let rest = &argument[i + 1..];
+            let closed_regex = argument[..*i].rfind('/')
+                .filter(|slash| *slash > 0)
+                .map(|slash| argument[slash + 1..*i].chars().all(|c| c.is_ascii_alphabetic()))
+                .unwrap_or(false);
+            closed_regex && (rest.is_empty() || rest.starts_with(':'))
+        });
+        if regex_end.is_some() {
+            return regex_end;
+        }
+    }
+
+    let mut depth = 0;
+    let mut escaped = false;
+    for (i, c) in argument.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(i),
+            ')' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Parses a chain of procedural operators making up the end of a rule, e.g.
+/// `:has-text(ad):has-text(/^sponsored/i)`. Only `:has-text(...)` is currently supported.
+fn parse_procedural_operators(mut operators: &str) -> Result<Vec<ProceduralOperator>, CosmeticFilterError> {
+    let mut parsed = vec![];
+    while !operators.is_empty() {
+        let argument = operators.strip_prefix(":has-text(").ok_or(CosmeticFilterError::UnsupportedSyntax)?;
+        let end = closing_paren_index(argument).ok_or(CosmeticFilterError::UnsupportedSyntax)?;
+        parsed.push(ProceduralOperator::HasText(TextMatcher::parse(&argument[..end])?));
+        operators = &argument[end + 1..];
+    }
+    Ok(parsed)
 }
 
 pub enum CosmeticFilterLocationType {
@@ -173,7 +345,8 @@ impl CosmeticFilter {
         line: &'a str,
         suffix_start_index: usize,
         selector: &mut &'a str,
-        style: &mut Option<String>
+        style: &mut Option<String>,
+        procedural_fallback: bool,
     ) -> Result<(), CosmeticFilterError> {
         let mut index_after_colon = suffix_start_index;
         while let Some(colon_index) = line[index_after_colon..].find(':') {
@@ -203,23 +376,81 @@ impl CosmeticFilter {
             || content_after_colon.starts_with("remove")
             {
                 return Err(CosmeticFilterError::UnsupportedSyntax);
+            } else if let Some(name) = pseudo_call_name(&line[colon_index..]).filter(|_| procedural_fallback) {
+                // Other unknown operators are only recognized to find the part of the selector to
+                // fall back to.
+                if !CSS_FUNCTIONAL_PSEUDO_CLASSES.contains(&name) && !within_attribute_selector(&line[suffix_start_index..colon_index]) {
+                    return Err(CosmeticFilterError::UnsupportedSyntax);
+                }
             }
         }
         Ok(())
     }
 
+    /// Like `parse`, but if `procedural_fallback` is set, a hostname-specific rule that can't be
+    /// parsed because of an unsupported procedural operator is parsed as a plain hide rule for the
+    /// longest part of its selector that is valid CSS. See `ParseOptions::procedural_fallback`.
+    pub fn parse_with_fallback(line: &str, debug: bool, procedural_fallback: bool) -> Result<CosmeticFilter, CosmeticFilterError> {
+        let error = match CosmeticFilter::parse_with_options(line, debug, procedural_fallback) {
+            Ok(filter) => return Ok(filter),
+            Err(error @ CosmeticFilterError::UnsupportedSyntax) | Err(error @ CosmeticFilterError::InvalidCssSelector) if procedural_fallback => error,
+            Err(error) => return Err(error),
+        };
+
+        let sharp_index = match line.find('#') {
+            // Only hostname-specific rules fall back, since generic ones would hide far more
+            // than intended.
+            Some(0) | None => return Err(error),
+            Some(sharp_index) => sharp_index,
+        };
+        let suffix_start_index = sharp_index + if line[sharp_index + 1..].starts_with('@') { 3 } else { 2 };
+        let suffix = match line.get(suffix_start_index..) {
+            Some(suffix) => suffix,
+            None => return Err(error),
+        };
+
+        // Try the longest prefix of the selector that ends right before a `:operator(` call.
+        let operator_indices = suffix.match_indices(':')
+            .map(|(index, _)| suffix_start_index + index)
+            .filter(|index| pseudo_call_name(&line[*index..]).is_some() && !within_attribute_selector(&line[suffix_start_index..*index]))
+            .collect::<Vec<_>>();
+        for index in operator_indices.into_iter().rev() {
+            if line[suffix_start_index..index].trim().is_empty() {
+                continue;
+            }
+            match CosmeticFilter::parse_with_options(&line[..index], debug, true) {
+                Ok(mut filter) if filter.procedural.is_none()
+                    && filter.style.is_none()
+                    && !filter.mask.intersects(CosmeticFilterMask::SCRIPT_INJECT | CosmeticFilterMask::REMOVE) => {
+                    filter.mask |= CosmeticFilterMask::PROCEDURAL_FALLBACK;
+                    if debug {
+                        filter.raw_line = Some(String::from(line));
+                    }
+                    return Ok(filter);
+                }
+                // Styles and other actions can't be turned into a plain hide rule.
+                Ok(_) => break,
+                Err(_) => (),
+            }
+        }
+        Err(error)
+    }
+
     /// Parse the rule in `line` into a `CosmeticFilter`. If `debug` is true, the original rule
     /// will be reported in the resulting `CosmeticFilter` struct as well.
     pub fn parse(line: &str, debug: bool) -> Result<CosmeticFilter, CosmeticFilterError> {
+        CosmeticFilter::parse_with_options(line, debug, false)
+    }
+
+    /// With `procedural_fallback` set, selectors using any unknown `:name(...)` call are rejected,
+    /// not just those using known procedural operators.
+    fn parse_with_options(line: &str, debug: bool, procedural_fallback: bool) -> Result<CosmeticFilter, CosmeticFilterError> {
         let mut mask = CosmeticFilterMask::NONE;
         if let Some(sharp_index) = line.find('#') {
             let after_sharp_index = sharp_index + 1;
             let mut suffix_start_index = after_sharp_index + 1;
 
             if line[after_sharp_index..].starts_with('@') {
-                if sharp_index == 0 {
-                    return Err(CosmeticFilterError::GenericUnhide);
-                }
                 mask |= CosmeticFilterMask::UNHIDE;
                 suffix_start_index += 1;
             }
@@ -246,6 +477,7 @@ impl CosmeticFilter {
                 return Err(CosmeticFilterError::EmptyRule);
             }
             let mut style = None;
+            let mut procedural = None;
             if line.len() - suffix_start_index > 4 && line[suffix_start_index..].starts_with("+js(") && line.ends_with(')') {
                 if sharp_index == 0 {
                     return Err(CosmeticFilterError::GenericScriptInject);
@@ -253,7 +485,57 @@ impl CosmeticFilter {
                 mask |= CosmeticFilterMask::SCRIPT_INJECT;
                 selector = &line[suffix_start_index + 4..line.len() - 1];
             } else {
-                CosmeticFilter::parse_after_sharp_nonscript(line, suffix_start_index, &mut selector, &mut style)?;
+                // `:remove()` is a terminal action, so it can only appear at the very end of a rule.
+                let line = if let Some(without_remove) = line.strip_suffix(":remove()") {
+                    if sharp_index == 0 {
+                        return Err(CosmeticFilterError::GenericRemove);
+                    }
+                    mask |= CosmeticFilterMask::REMOVE;
+                    selector = &without_remove[suffix_start_index..];
+                    if selector.trim().is_empty() {
+                        return Err(CosmeticFilterError::EmptyRule);
+                    }
+                    without_remove
+                } else {
+                    line
+                };
+                // Procedural operators follow the CSS selector they apply to.
+                let line = if let Some(procedural_index) = line[suffix_start_index..].find(":has-text(") {
+                    if sharp_index == 0 {
+                        return Err(CosmeticFilterError::GenericProcedural);
+                    }
+                    // In uBO's `##^` HTML filtering syntax, `:has-text()` matches the source of the
+                    // element rather than its rendered text, so it can't be evaluated like a
+                    // procedural operator.
+                    if line[suffix_start_index..].starts_with('^') {
+                        return Err(CosmeticFilterError::UnsupportedSyntax);
+                    }
+                    let procedural_index = suffix_start_index + procedural_index;
+                    procedural = Some(parse_procedural_operators(&line[procedural_index..])?);
+                    selector = &line[suffix_start_index..procedural_index];
+                    if selector.trim().is_empty() {
+                        return Err(CosmeticFilterError::EmptyRule);
+                    }
+                    &line[..procedural_index]
+                } else {
+                    line
+                };
+                // Removing the elements matched by a procedural selector isn't supported. This is
+                // reported separately so that such rules don't fall back to a plain hide rule.
+                CosmeticFilter::parse_after_sharp_nonscript(line, suffix_start_index, &mut selector, &mut style, procedural_fallback)
+                    .map_err(|error| match error {
+                        CosmeticFilterError::UnsupportedSyntax if mask.contains(CosmeticFilterMask::REMOVE) => CosmeticFilterError::ProceduralRemove,
+                        error => error,
+                    })?;
+                if procedural.is_some() && mask.contains(CosmeticFilterMask::REMOVE) {
+                    return Err(CosmeticFilterError::ProceduralRemove);
+                }
+                if procedural.is_some() && style.is_some() {
+                    return Err(CosmeticFilterError::UnsupportedSyntax);
+                }
+                if style.is_some() && mask.contains(CosmeticFilterMask::REMOVE) {
+                    return Err(CosmeticFilterError::UnsupportedSyntax);
+                }
             }
 
             if !mask.contains(CosmeticFilterMask::SCRIPT_INJECT) && !is_valid_css_selector(selector) {
@@ -310,6 +592,13 @@ impl CosmeticFilter {
                 selector: String::from(selector),
                 key,
                 style,
+                procedural,
+                list_id: None,
+                locations: if sharp_index > 0 {
+                    Some(String::from(&line[..sharp_index]))
+                } else {
+                    None
+                },
             })
         } else {
             Err(CosmeticFilterError::MissingSharp)
@@ -334,12 +623,13 @@ impl CosmeticFilter {
     /// To account for this inconsistency, this method will generate and return the corresponding
     /// 'hidden' generic rule if one applies.
     ///
-    /// Note that this behavior is not applied to script injections or custom style rules.
+    /// Note that this behavior is not applied to script injections, custom style rules,
+    /// procedural rules, or `:remove()` rules.
     pub fn hidden_generic_rule(&self) -> Option<CosmeticFilter> {
         if self.hostnames.is_some() || self.entities.is_some() {
             None
         } else if (self.not_hostnames.is_some() || self.not_entities.is_some()) &&
-            (self.style.is_none() && !self.mask.contains(CosmeticFilterMask::SCRIPT_INJECT))
+            (self.style.is_none() && self.procedural.is_none() && !self.mask.intersects(CosmeticFilterMask::SCRIPT_INJECT | CosmeticFilterMask::REMOVE))
         {
             let mut generic_rule = self.clone();
             generic_rule.not_hostnames = None;
@@ -638,12 +928,14 @@ mod parse_tests {
         selector: String,
         key: Option<String>,
         style: Option<String>,
+        procedural: Option<Vec<ProceduralOperator>>,
 
         unhide: bool,
         script_inject: bool,
         is_unicode: bool,
         is_class_selector: bool,
         is_id_selector: bool,
+        remove: bool,
     }
 
     impl From<&CosmeticFilter> for CosmeticFilterBreakdown {
@@ -656,12 +948,14 @@ mod parse_tests {
                 selector: filter.selector.clone(),
                 key: filter.key.as_ref().cloned(),
                 style: filter.style.as_ref().cloned(),
+                procedural: filter.procedural.as_ref().cloned(),
 
                 unhide: filter.mask.contains(CosmeticFilterMask::UNHIDE),
                 script_inject: filter.mask.contains(CosmeticFilterMask::SCRIPT_INJECT),
                 is_unicode: filter.mask.contains(CosmeticFilterMask::IS_UNICODE),
                 is_class_selector: filter.mask.contains(CosmeticFilterMask::IS_CLASS_SELECTOR),
                 is_id_selector: filter.mask.contains(CosmeticFilterMask::IS_ID_SELECTOR),
+                remove: filter.mask.contains(CosmeticFilterMask::REMOVE),
             }
         }
     }
@@ -682,12 +976,14 @@ mod parse_tests {
                 selector: "".to_string(),
                 key: None,
                 style: None,
+                procedural: None,
 
                 unhide: false,
                 script_inject: false,
                 is_unicode: false,
                 is_class_selector: false,
                 is_id_selector: false,
+                remove: false,
             }
         }
     }
@@ -1236,6 +1532,136 @@ mod parse_tests {
         );
     }
 
+    #[test]
+    fn remove_action() {
+        check_parse_result(
+            "example.com##.ad:remove()",
+            CosmeticFilterBreakdown {
+                selector: ".ad".to_string(),
+                hostnames: sort_hash_domains(vec!["example.com"]),
+                key: Some("ad".to_string()),
+                is_class_selector: true,
+                remove: true,
+                ..Default::default()
+            }
+        );
+        check_parse_result(
+            "example.com#@#div[id^=\"banner\"]:remove()",
+            CosmeticFilterBreakdown {
+                selector: "div[id^=\"banner\"]".to_string(),
+                hostnames: sort_hash_domains(vec!["example.com"]),
+                unhide: true,
+                remove: true,
+                ..Default::default()
+            }
+        );
+        // generic rules are not supported
+        assert_eq!(CosmeticFilter::parse("##.ad:remove()", false).err(), Some(CosmeticFilterError::GenericRemove));
+        // `:remove()` must be terminal and take no arguments
+        assert!(CosmeticFilter::parse("example.com##.ad:remove() > div", false).is_err());
+        assert!(CosmeticFilter::parse("example.com##.ad:remove(x)", false).is_err());
+        // empty selectors and conflicting styles are rejected
+        assert!(CosmeticFilter::parse("example.com##:remove()", false).is_err());
+        assert!(CosmeticFilter::parse("example.com##.ad:style(color: red):remove()", false).is_err());
+        // procedural selectors can't be removed, and don't fall back to hiding their base selector
+        assert_eq!(CosmeticFilter::parse("example.com##div:has-text(ad):remove()", false).err(), Some(CosmeticFilterError::ProceduralRemove));
+        assert_eq!(CosmeticFilter::parse("example.com##.ad:-abp-has(.sponsored):remove()", false).err(), Some(CosmeticFilterError::ProceduralRemove));
+        assert_eq!(CosmeticFilter::parse_with_fallback("example.com##.ad:upward(2):remove()", false, true).err(), Some(CosmeticFilterError::ProceduralRemove));
+        assert_eq!(CosmeticFilter::parse_with_fallback("example.com##div:some-unknown-op(x):remove()", false, true).err(), Some(CosmeticFilterError::ProceduralRemove));
+        // negated-only rules do not produce a hidden generic rule
+        let rule = CosmeticFilter::parse("~example.com##.ad:remove()", false).unwrap();
+        assert!(rule.hidden_generic_rule().is_none());
+    }
+
+    #[test]
+    fn procedural_fallback() {
+        let rule = "example.com##div:some-unknown-op(x)";
+        // Without the fallback, unknown operators are left to CSS validation
+        assert_eq!(CosmeticFilter::parse(rule, false).is_ok(), !cfg!(feature = "css-validation"));
+        assert_eq!(CosmeticFilter::parse_with_fallback(rule, false, false).is_ok(), !cfg!(feature = "css-validation"));
+
+        let filter = CosmeticFilter::parse_with_fallback(rule, true, true).unwrap();
+        assert_eq!(filter.selector, "div");
+        assert_eq!(filter.hostnames, sort_hash_domains(vec!["example.com"]));
+        assert!(filter.mask.contains(CosmeticFilterMask::PROCEDURAL_FALLBACK));
+        assert_eq!(filter.raw_line.as_deref(), Some(rule));
+
+        // The longest valid selector is kept, including supported pseudo-classes
+        let filter = CosmeticFilter::parse_with_fallback("example.com##.ad:not(.keep) > span:upward(2)", false, true).unwrap();
+        assert_eq!(filter.selector, ".ad:not(.keep) > span");
+        let filter = CosmeticFilter::parse_with_fallback("example.com#@#.ad:-abp-has(.sponsored)", false, true).unwrap();
+        assert_eq!(filter.selector, ".ad");
+        assert!(filter.mask.contains(CosmeticFilterMask::UNHIDE | CosmeticFilterMask::PROCEDURAL_FALLBACK));
+
+        // Rules that parse normally aren't marked
+        let filter = CosmeticFilter::parse_with_fallback("example.com##div:has-text(ad)", false, true).unwrap();
+        assert!(!filter.mask.contains(CosmeticFilterMask::PROCEDURAL_FALLBACK));
+        assert!(filter.procedural.is_some());
+
+        // Calls within attribute values and CSS functions aren't procedural operators
+        assert_eq!(CosmeticFilter::parse("example.com##a[href^=\"javascript:void(0)\"]", false).unwrap().selector, "a[href^=\"javascript:void(0)\"]");
+        assert_eq!(CosmeticFilter::parse("example.com##li:nth-child(2):not(.keep)", false).unwrap().selector, "li:nth-child(2):not(.keep)");
+
+        // Generic rules and rules without a base selector are still dropped
+        assert!(CosmeticFilter::parse_with_fallback("##div:some-unknown-op(x)", false, true).is_err());
+        assert!(CosmeticFilter::parse_with_fallback("example.com##:some-unknown-op(x)", false, true).is_err());
+        assert!(CosmeticFilter::parse_with_fallback("example.com##div:style(color: red):upward(1)", false, true).is_err());
+    }
+
+    #[test]
+    fn has_text() {
+        check_parse_result(
+            "example.com##div.post:has-text(Sponsored)",
+            CosmeticFilterBreakdown {
+                selector: "div.post".to_string(),
+                hostnames: sort_hash_domains(vec!["example.com"]),
+                procedural: Some(vec![ProceduralOperator::HasText(TextMatcher::Literal("Sponsored".to_string()))]),
+                ..Default::default()
+            }
+        );
+        check_parse_result(
+            "example.com#@#article:has-text(/^(Promoted|Gesponsert)\\)$/i):has-text(a (b) c)",
+            CosmeticFilterBreakdown {
+                selector: "article".to_string(),
+                hostnames: sort_hash_domains(vec!["example.com"]),
+                unhide: true,
+                procedural: Some(vec![
+                    ProceduralOperator::HasText(TextMatcher::Regex {
+                        pattern: "^(Promoted|Gesponsert)\\)$".to_string(),
+                        flags: "i".to_string(),
+                    }),
+                    ProceduralOperator::HasText(TextMatcher::Literal("a (b) c".to_string())),
+                ]),
+                ..Default::default()
+            }
+        );
+        // a lone slash is not a regex
+        check_parse_result(
+            "example.com##span:has-text(/)",
+            CosmeticFilterBreakdown {
+                selector: "span".to_string(),
+                hostnames: sort_hash_domains(vec!["example.com"]),
+                procedural: Some(vec![ProceduralOperator::HasText(TextMatcher::Literal("/".to_string()))]),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(CosmeticFilter::parse("example.com##div:has-text(/(unclosed/)", false).err(), Some(CosmeticFilterError::InvalidProceduralRegex));
+        assert_eq!(CosmeticFilter::parse("example.com##div:has-text(/ad/x)", false).err(), Some(CosmeticFilterError::InvalidProceduralRegex));
+        assert_eq!(CosmeticFilter::parse("example.com##div:has-text(//)", false).err(), Some(CosmeticFilterError::InvalidProceduralRegex));
+        assert_eq!(CosmeticFilter::parse("##div:has-text(ad)", false).err(), Some(CosmeticFilterError::GenericProcedural));
+        assert_eq!(CosmeticFilter::parse("example.com##:has-text(ad)", false).err(), Some(CosmeticFilterError::EmptyRule));
+        assert!(CosmeticFilter::parse("example.com##div:has-text()", false).is_err());
+        assert!(CosmeticFilter::parse("example.com##div:has-text(ad", false).is_err());
+        assert!(CosmeticFilter::parse("example.com##div:has-text(ad) > span", false).is_err());
+        assert!(CosmeticFilter::parse("example.com##div:has-text(ad):style(color: red)", false).is_err());
+        assert!(CosmeticFilter::parse("example.com##div:has(span):has-text(ad)", false).is_err());
+
+        // procedural rules with only negated hostnames do not apply generically
+        let rule = CosmeticFilter::parse("~example.com##div:has-text(ad)", false).unwrap();
+        assert!(rule.hidden_generic_rule().is_none());
+    }
+
     #[test]
     fn unsupported() {
         assert!(CosmeticFilter::parse("yandex.*##.serp-item:if(:scope > div.organic div.organic__subtitle:matches-css-after(content: /[Рр]еклама/))", false).is_err());
@@ -0,0 +1,227 @@
+//! HTML filtering rules, written in AdGuard's `$$` syntax. These remove matching elements from a
+//! document's HTML source before it is parsed by the browser, e.g.
+//! `example.com$$script[tag-content="ads"]`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::filters::cosmetic::{CosmeticFilter, CosmeticFilterLocationType};
+use crate::utils::Hash;
+
+#[derive(Debug, PartialEq)]
+pub enum HtmlFilterError {
+    PunycodeError,
+    MissingSeparator,
+    GenericException,
+    EntityNotSupported,
+    InvalidTagName,
+    InvalidAttribute,
+    DoubleNegation,
+    EmptyRule,
+}
+
+impl std::fmt::Display for HtmlFilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::PunycodeError => write!(f, "hostname could not be converted to punycode"),
+            Self::MissingSeparator => write!(f, "HTML filter has no `$$` separator"),
+            Self::GenericException => write!(f, "generic HTML filter exceptions are not supported"),
+            Self::EntityNotSupported => write!(f, "HTML filters cannot be restricted to entities"),
+            Self::InvalidTagName => write!(f, "invalid tag name"),
+            Self::InvalidAttribute => write!(f, "invalid attribute condition"),
+            Self::DoubleNegation => write!(f, "hostname is negated more than once"),
+            Self::EmptyRule => write!(f, "HTML filter has no tag name"),
+        }
+    }
+}
+
+impl std::error::Error for HtmlFilterError {}
+
+/// A single `[name="value"]` condition of an HTML filtering rule.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HtmlFilterAttribute {
+    pub name: String,
+    pub value: String,
+}
+
+/// Struct representing a parsed HTML filtering rule.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HtmlFilter {
+    pub hostnames: Option<Vec<Hash>>,
+    pub not_hostnames: Option<Vec<Hash>>,
+    /// `true` for `$@$` rules, which disable an HTML filtering rule with the same selector.
+    pub exception: bool,
+    /// Everything following the `$$` or `$@$` separator.
+    pub selector: String,
+    pub tag_name: String,
+    /// Conditions on the element, in the order they were written. Besides regular attributes,
+    /// these can include AdGuard's special conditions like `tag-content` or `max-length`.
+    pub attributes: Vec<HtmlFilterAttribute>,
+    pub raw_line: Option<String>,
+}
+
+impl HtmlFilter {
+    /// Parse the rule in `line` into an `HtmlFilter`. If `debug` is true, the original rule will
+    /// be reported in the resulting `HtmlFilter` struct as well.
+    pub fn parse(line: &str, debug: bool) -> Result<HtmlFilter, HtmlFilterError> {
+        let (separator_index, separator_len, exception) = if let Some(i) = line.find("$@$") {
+            (i, 3, true)
+        } else if let Some(i) = line.find("$$") {
+            (i, 2, false)
+        } else {
+            return Err(HtmlFilterError::MissingSeparator);
+        };
+
+        if exception && separator_index == 0 {
+            return Err(HtmlFilterError::GenericException);
+        }
+
+        let mut hostnames = vec![];
+        let mut not_hostnames = vec![];
+        for (location_type, location) in CosmeticFilter::locations_before_sharp(line, separator_index) {
+            let hostname = if location.is_ascii() {
+                location.to_owned()
+            } else {
+                idna::domain_to_ascii(location).map_err(|_| HtmlFilterError::PunycodeError)?
+            };
+            let hash = crate::utils::fast_hash(&hostname);
+            match location_type {
+                CosmeticFilterLocationType::Hostname => hostnames.push(hash),
+                CosmeticFilterLocationType::NotHostname => not_hostnames.push(hash),
+                CosmeticFilterLocationType::Entity | CosmeticFilterLocationType::NotEntity => {
+                    return Err(HtmlFilterError::EntityNotSupported)
+                }
+            }
+        }
+        hostnames.sort_unstable();
+        not_hostnames.sort_unstable();
+
+        if exception && !not_hostnames.is_empty() {
+            return Err(HtmlFilterError::DoubleNegation);
+        }
+
+        let selector = line[separator_index + separator_len..].trim();
+        if selector.is_empty() {
+            return Err(HtmlFilterError::EmptyRule);
+        }
+        let (tag_name, attributes) = parse_selector(selector)?;
+
+        Ok(HtmlFilter {
+            hostnames: if hostnames.is_empty() { None } else { Some(hostnames) },
+            not_hostnames: if not_hostnames.is_empty() { None } else { Some(not_hostnames) },
+            exception,
+            selector: String::from(selector),
+            tag_name,
+            attributes,
+            raw_line: if debug {
+                Some(String::from(line))
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Returns the value of the condition called `name`, if the rule has one.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter()
+            .find(|attribute| attribute.name == name)
+            .map(|attribute| attribute.value.as_str())
+    }
+}
+
+/// Splits a selector of the form `tag[name="value"][name2="value2"]` into its tag name and
+/// attribute conditions. Double quotes within a value are escaped by doubling them (`""`).
+fn parse_selector(selector: &str) -> Result<(String, Vec<HtmlFilterAttribute>), HtmlFilterError> {
+    let tag_end = selector.find('[').unwrap_or(selector.len());
+    let tag_name = &selector[..tag_end];
+    if tag_name.is_empty() || !tag_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(HtmlFilterError::InvalidTagName);
+    }
+
+    let mut attributes = vec![];
+    let mut rest = &selector[tag_end..];
+    while !rest.is_empty() {
+        let inner = rest.strip_prefix('[').ok_or(HtmlFilterError::InvalidAttribute)?;
+        let equals = inner.find("=\"").ok_or(HtmlFilterError::InvalidAttribute)?;
+        let name = &inner[..equals];
+        if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == ']') {
+            return Err(HtmlFilterError::InvalidAttribute);
+        }
+
+        let mut value = String::new();
+        let mut chars = inner[equals + 2..].char_indices();
+        let value_end = loop {
+            match chars.next() {
+                Some((i, '"')) => {
+                    if inner[equals + 2 + i + 1..].starts_with('"') {
+                        value.push('"');
+                        chars.next();
+                    } else {
+                        break equals + 2 + i + 1;
+                    }
+                }
+                Some((_, c)) => value.push(c),
+                None => return Err(HtmlFilterError::InvalidAttribute),
+            }
+        };
+
+        rest = inner[value_end..].strip_prefix(']').ok_or(HtmlFilterError::InvalidAttribute)?;
+        attributes.push(HtmlFilterAttribute { name: String::from(name), value });
+    }
+
+    Ok((String::from(tag_name), attributes))
+}
+
+#[cfg(test)]
+mod parse_tests {
+    use super::*;
+
+    fn attributes(filter: &HtmlFilter) -> Vec<(&str, &str)> {
+        filter.attributes.iter().map(|a| (a.name.as_str(), a.value.as_str())).collect()
+    }
+
+    #[test]
+    fn parses_attribute_conditions() {
+        let filter = HtmlFilter::parse(r#"example.com,~sub.example.com$$script[tag-content="ads"][max-length="500"]"#, true).unwrap();
+        assert!(!filter.exception);
+        assert_eq!(filter.hostnames, Some(vec![crate::utils::fast_hash("example.com")]));
+        assert_eq!(filter.not_hostnames, Some(vec![crate::utils::fast_hash("sub.example.com")]));
+        assert_eq!(filter.tag_name, "script");
+        assert_eq!(filter.selector, r#"script[tag-content="ads"][max-length="500"]"#);
+        assert_eq!(attributes(&filter), vec![("tag-content", "ads"), ("max-length", "500")]);
+        assert_eq!(filter.attribute("tag-content"), Some("ads"));
+        assert_eq!(filter.attribute("min-length"), None);
+    }
+
+    #[test]
+    fn parses_escaped_quotes() {
+        let filter = HtmlFilter::parse(r#"example.com$$div[tag-content="say ""hi"""][id="a]b"]"#, false).unwrap();
+        assert_eq!(attributes(&filter), vec![("tag-content", r#"say "hi""#), ("id", "a]b")]);
+        assert_eq!(filter.raw_line, None);
+    }
+
+    #[test]
+    fn parses_generic_and_exceptions() {
+        let filter = HtmlFilter::parse("$$iframe", false).unwrap();
+        assert_eq!(filter.hostnames, None);
+        assert_eq!(filter.tag_name, "iframe");
+        assert!(filter.attributes.is_empty());
+
+        let filter = HtmlFilter::parse(r#"example.com$@$script[tag-content="ads"]"#, false).unwrap();
+        assert!(filter.exception);
+        assert_eq!(filter.selector, r#"script[tag-content="ads"]"#);
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert_eq!(HtmlFilter::parse("example.com##div", false).err(), Some(HtmlFilterError::MissingSeparator));
+        assert_eq!(HtmlFilter::parse("$@$script", false).err(), Some(HtmlFilterError::GenericException));
+        assert_eq!(HtmlFilter::parse("example.*$$script", false).err(), Some(HtmlFilterError::EntityNotSupported));
+        assert_eq!(HtmlFilter::parse("~example.com$@$script", false).err(), Some(HtmlFilterError::DoubleNegation));
+        assert_eq!(HtmlFilter::parse("example.com$$", false).err(), Some(HtmlFilterError::EmptyRule));
+        assert_eq!(HtmlFilter::parse("example.com$$[id=\"a\"]", false).err(), Some(HtmlFilterError::InvalidTagName));
+        assert_eq!(HtmlFilter::parse("example.com$$div.ad", false).err(), Some(HtmlFilterError::InvalidTagName));
+        assert_eq!(HtmlFilter::parse("example.com$$div[id]", false).err(), Some(HtmlFilterError::InvalidAttribute));
+        assert_eq!(HtmlFilter::parse("example.com$$div[id=\"a\"", false).err(), Some(HtmlFilterError::InvalidAttribute));
+        assert_eq!(HtmlFilter::parse("example.com$$div[id=\"a\"]x", false).err(), Some(HtmlFilterError::InvalidAttribute));
+    }
+}
@@ -1,15 +1,19 @@
-use regex::{Regex, RegexSet};
+use regex::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
 use serde::{Deserialize, Serialize};
 use once_cell::sync::Lazy;
 use crate::url_parser::parse_url;
 
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::fmt;
+use std::net::IpAddr;
 use std::sync::{Arc, RwLock};
 
 use crate::request;
 use crate::utils;
 use crate::utils::Hash;
-use crate::lists::ParseOptions;
+use crate::lists::{ListId, ParseOptions};
 
 pub const TOKENS_BUFFER_SIZE: usize = 200;
 
@@ -24,22 +28,81 @@ pub enum NetworkFilterError {
     NegatedRedirection,
     NegatedTag,
     NegatedGenericHide,
+    NegatedElemHide,
+    NegatedGenericBlock,
     NegatedDocument,
+    NegatedAll,
+    NegatedPopup,
+    NegatedInlineOption,
     GenericHideWithoutException,
+    GenericBlockWithoutException,
     EmptyRedirection,
     RedirectionUrlInvalid,
     MultipleRedirections,
+    NegatedIpAddress,
+    NegatedTo,
+    IpAddressInvalid,
+    NegatedHeader,
+    HeaderInvalid,
     UnrecognisedOption,
     NoRegex,
     FullRegexUnsupported,
     RegexParsingError(regex::Error),
+    /// The filter's regex is too complex to be matched safely. The parameter describes the limit
+    /// that was exceeded.
+    BadRegex(String),
     PunycodeError,
     CspWithContentType,
+    /// A domain option has more domains than allowed by `ParseOptions::max_domains`, which is
+    /// the parameter.
+    TooManyDomains(usize),
 }
 
+impl std::fmt::Display for NetworkFilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::FilterParseError => write!(f, "invalid network filter"),
+            Self::BugValueNotNumeric => write!(f, "`$bug` value is not a number"),
+            Self::NegatedBadFilter => write!(f, "`$badfilter` cannot be negated"),
+            Self::NegatedImportant => write!(f, "`$important` cannot be negated"),
+            Self::NegatedOptionMatchCase => write!(f, "`$match-case` cannot be negated"),
+            Self::NegatedExplicitCancel => write!(f, "`$explicitcancel` cannot be negated"),
+            Self::NegatedRedirection => write!(f, "redirect options cannot be negated"),
+            Self::NegatedTag => write!(f, "`$tag` cannot be negated"),
+            Self::NegatedGenericHide => write!(f, "`$generichide` cannot be negated"),
+            Self::NegatedElemHide => write!(f, "`$elemhide` cannot be negated"),
+            Self::NegatedGenericBlock => write!(f, "`$genericblock` cannot be negated"),
+            Self::NegatedDocument => write!(f, "`$document` can only be negated along with `$all`"),
+            Self::NegatedAll => write!(f, "`$all` cannot be negated"),
+            Self::NegatedPopup => write!(f, "`$popup` cannot be negated"),
+            Self::NegatedInlineOption => write!(f, "`$inline-script` and `$inline-font` cannot be negated"),
+            Self::GenericHideWithoutException => write!(f, "`$generichide` can only be used in exceptions"),
+            Self::GenericBlockWithoutException => write!(f, "`$genericblock` can only be used in exceptions"),
+            Self::EmptyRedirection => write!(f, "redirect option has no resource"),
+            Self::RedirectionUrlInvalid => write!(f, "`$redirect-url` value is not a valid URL"),
+            Self::MultipleRedirections => write!(f, "filter has more than one redirect option"),
+            Self::NegatedIpAddress => write!(f, "`$ipaddress` cannot be negated"),
+            Self::NegatedTo => write!(f, "`$to` cannot be negated"),
+            Self::IpAddressInvalid => write!(f, "`$ipaddress` value is not a valid address or range"),
+            Self::NegatedHeader => write!(f, "`$header` cannot be negated"),
+            Self::HeaderInvalid => write!(f, "`$header` value has no header name, or an invalid regex"),
+            Self::UnrecognisedOption => write!(f, "unrecognised filter option"),
+            Self::NoRegex => write!(f, "filter has no regex"),
+            Self::FullRegexUnsupported => write!(f, "regex filters require the `full-regex-handling` feature"),
+            Self::RegexParsingError(e) => write!(f, "invalid regex: {}", e),
+            Self::BadRegex(limit) => write!(f, "regex is too complex: {}", limit),
+            Self::PunycodeError => write!(f, "hostname could not be converted to punycode"),
+            Self::CspWithContentType => write!(f, "`$csp` cannot be combined with content type options"),
+            Self::TooManyDomains(limit) => write!(f, "domain option has more than {} domains", limit),
+        }
+    }
+}
+
+impl std::error::Error for NetworkFilterError {}
+
 bitflags::bitflags! {
     #[derive(Serialize, Deserialize)]
-    pub struct NetworkFilterMask: u32 {
+    pub struct NetworkFilterMask: u64 {
         const FROM_IMAGE = 1; // 1 << 0;
         const FROM_MEDIA = 1 << 1;
         const FROM_OBJECT = 1 << 2;
@@ -61,9 +124,19 @@ bitflags::bitflags! {
         const _EXPLICIT_CANCEL = 1 << 26;   // Unused
         const BAD_FILTER = 1 << 27;
         const GENERIC_HIDE = 1 << 30;
+        const GENERIC_BLOCK = 1 << 31;
+        // Always set along with `GENERIC_HIDE`.
+        const ELEM_HIDE = 1 << 32;
 
         // Full document rules are not implied by negated types.
         const FROM_DOCUMENT = 1 << 29;
+        // Popups are only matched by filters with an explicit `$popup` option, so they are not
+        // part of `FROM_ALL_TYPES` either.
+        const FROM_POPUP = 1 << 33;
+
+        // Set if any `$domain` entry is an entity like `example.*`, which needs the entity hashes
+        // of the request's source hostname to be matched.
+        const HAS_ENTITY_DOMAINS = 1 << 34;
 
         // Kind of pattern
         const IS_REGEX = 1 << 18;
@@ -78,7 +151,9 @@ bitflags::bitflags! {
         // "Other" network request types
         const UNMATCHED = 1 << 25;
 
-        // Includes all request types that are implied by any negated types.
+        // Includes all request types that are implied by any negated types. These are also the
+        // types matched by filters without any type options. Popups and CSP reports aren't part
+        // of it, and are only matched by `$popup` and `$csp` filters respectively.
         const FROM_NETWORK_TYPES = Self::FROM_FONT.bits |
             Self::FROM_IMAGE.bits |
             Self::FROM_MEDIA.bits |
@@ -91,8 +166,9 @@ bitflags::bitflags! {
             Self::FROM_WEBSOCKET.bits |
             Self::FROM_XMLHTTPREQUEST.bits;
 
-        // Includes all remaining types, not implied by any negated types.
-        // TODO Could also include popup, inline-font, inline-script
+        // Includes all remaining types, not implied by any negated types. This is the mask of
+        // every request type that a network filter can match, as enabled by `$all`.
+        // TODO Could also include inline-font, inline-script
         const FROM_ALL_TYPES = Self::FROM_NETWORK_TYPES.bits |
             Self::FROM_DOCUMENT.bits;
 
@@ -127,6 +203,7 @@ impl From<&request::RequestType> for NetworkFilterMask {
             request::RequestType::Media => NetworkFilterMask::FROM_MEDIA,
             request::RequestType::Object => NetworkFilterMask::FROM_OBJECT,
             request::RequestType::Other => NetworkFilterMask::FROM_OTHER,
+            request::RequestType::Popup => NetworkFilterMask::FROM_POPUP,
             request::RequestType::Ping => NetworkFilterMask::FROM_PING,
             request::RequestType::Script => NetworkFilterMask::FROM_SCRIPT,
             request::RequestType::Stylesheet => NetworkFilterMask::FROM_STYLESHEET,
@@ -229,7 +306,11 @@ enum NetworkFilterOption {
     RedirectUrl(String),
     Csp(Option<String>),
     Generichide,
-    Document,
+    Elemhide,
+    Genericblock,
+    Document(bool),
+    All,
+    Popup,
     Image(bool),
     Media(bool),
     Object(bool),
@@ -241,12 +322,20 @@ enum NetworkFilterOption {
     XmlHttpRequest(bool),
     Websocket(bool),
     Font(bool),
+    IpAddress(Vec<IpRange>),
+    Header(HeaderCondition),
+    To(Vec<(bool, String)>),
+    Unsupported(String, UnsupportedReason),
+    /// A resource type from `UNSUPPORTED_TYPES`, and whether it is enabled rather than negated.
+    UnsupportedType(String, bool),
 }
 
 impl NetworkFilterOption {
     pub fn is_content_type(&self) -> bool {
         match self {
-            Self::Document
+            Self::Document(..)
+            | Self::All
+            | Self::Popup
             | Self::Image(..)
             | Self::Media(..)
             | Self::Object(..)
@@ -257,7 +346,8 @@ impl NetworkFilterOption {
             | Self::Subdocument(..)
             | Self::XmlHttpRequest(..)
             | Self::Websocket(..)
-            | Self::Font(..) => true,
+            | Self::Font(..)
+            | Self::UnsupportedType(..) => true,
             _ => false,
         }
 
@@ -335,10 +425,128 @@ impl AbstractNetworkFilter {
     }
 }
 
+/// Why the options in `NetworkFilter::unsupported_options` aren't supported. Variants are ordered
+/// by how much of the filter is affected, and a filter records the most severe one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum UnsupportedReason {
+    /// A resource type from `UNSUPPORTED_TYPES`. The filter still applies to its other types.
+    UnsupportedType,
+    /// An option from `UNSUPPORTED_OPTIONS`, which isn't implemented yet. The filter never
+    /// matches.
+    Unimplemented,
+    /// An option that only DNS-level or system-wide blockers can support, such as AdGuard's
+    /// `$network` or `$dnsrewrite`. The filter never matches.
+    Platform,
+}
+
+impl UnsupportedReason {
+    /// Whether a filter with an option unsupported for this reason can never match.
+    pub fn disables_filter(&self) -> bool {
+        *self != Self::UnsupportedType
+    }
+}
+
+impl fmt::Display for UnsupportedReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnsupportedType => write!(f, "resource type can't be detected"),
+            Self::Unimplemented => write!(f, "option is not implemented"),
+            Self::Platform => write!(f, "option is only supported by DNS-level or system-wide blockers"),
+        }
+    }
+}
+
+/// Options that are recognized, but whose behavior isn't implemented. Filters using them are still
+/// accepted, with the unsupported options recorded in `NetworkFilter::unsupported_options`, but
+/// never match: without the unsupported option, the rest of the filter would usually block far
+/// more than intended.
+const UNSUPPORTED_OPTIONS: &[&str] = &[
+    // uBO's `$urlskip` extracts the destination of a redirect hop from the URL, to navigate there
+    // directly.
+    "urlskip",
+];
+
+/// Resource types that are recognized, but that requests can't be classified as yet. They are
+/// recorded in `NetworkFilter::unsupported_options` like `UNSUPPORTED_OPTIONS`. A filter that only
+/// applies to such types never matches, while negating them has no effect.
+const UNSUPPORTED_TYPES: &[&str] = &[
+    // Subresources loaded from a Web Bundle, as supported by uBO in Chromium.
+    "webbundle",
+];
+
+/// Converts an internationalized domain to punycode, so that it matches the hostnames of requests.
+/// Entity domains like `例子.*` keep their wildcard suffix.
+fn normalize_domain(domain: &str) -> Result<String, NetworkFilterError> {
+    if domain.is_ascii() {
+        return Ok(domain.to_string());
+    }
+    let to_ascii = |domain: &str| idna::domain_to_ascii(&domain.to_lowercase()).map_err(|_| NetworkFilterError::PunycodeError);
+    match domain.strip_suffix(".*") {
+        Some(entity) => Ok(format!("{}.*", to_ascii(entity)?)),
+        None => to_ascii(domain),
+    }
+}
+
+/// Parses a `|`-separated list of domains, where each domain may be negated with a leading `~`.
+/// Fails if there are more than `max_domains` of them.
+fn parse_domain_list(value: &str, max_domains: Option<usize>) -> Result<Vec<(bool, String)>, NetworkFilterError> {
+    let domains = value.split('|').filter(|domain| !domain.is_empty());
+    if let Some(max_domains) = max_domains {
+        if domains.clone().count() > max_domains {
+            return Err(NetworkFilterError::TooManyDomains(max_domains));
+        }
+    }
+    domains.map(|domain| {
+        if let Some(negated_domain) = domain.strip_prefix('~') {
+            Ok((false, normalize_domain(negated_domain)?))
+        } else {
+            Ok((true, normalize_domain(domain)?))
+        }
+    }).collect()
+}
+
+/// Whether `raw_option` could be the start of an option, rather than a continuation of a `$csp`
+/// value, e.g. `third-party` or `domain=example.com`, but not ` script-src` or `'self'`.
+fn looks_like_option(raw_option: &str) -> bool {
+    let name = raw_option.trim_start_matches('~').split('=').next().unwrap_or_default();
+    name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+/// Splits `raw_options` on commas. Commas within a `$csp` value are kept if they are escaped as
+/// `\,`, or if the text following them doesn't look like another option, as in
+/// `$csp=default-src 'self', script-src a.com`.
+fn split_options(raw_options: &str) -> Vec<Cow<'_, str>> {
+    let mut result = vec![];
+    let mut parts = raw_options.split(',').peekable();
+
+    while let Some(part) = parts.next() {
+        if !part.trim_start_matches('~').starts_with("csp=") {
+            result.push(Cow::Borrowed(part));
+            continue;
+        }
+
+        let mut csp = String::from(part);
+        while let Some(next) = parts.peek() {
+            if csp.ends_with('\\') {
+                csp.pop();
+            } else if looks_like_option(next) {
+                break;
+            }
+            csp.push(',');
+            csp.push_str(next);
+            parts.next();
+        }
+        result.push(Cow::Owned(csp));
+    }
+
+    result
+}
+
 fn parse_filter_options(raw_options: &str, opts: ParseOptions) -> Result<Vec<NetworkFilterOption>, NetworkFilterError> {
     let mut result = vec![];
 
-    for raw_option in raw_options.split(',') {
+    for raw_option in split_options(raw_options) {
+        let raw_option = raw_option.as_ref();
         // Check for negation: ~option
         let negation = raw_option.starts_with('~');
         let maybe_negated_option = raw_option.trim_start_matches('~');
@@ -351,16 +559,10 @@ fn parse_filter_options(raw_options: &str, opts: ParseOptions) -> Result<Vec<Net
         );
 
         result.push(match (option, negation) {
-            ("domain", _) => {
-                let domains: Vec<(bool, String)> = value.split('|').map(|domain| {
-                    if let Some(negated_domain) = domain.strip_prefix('~') {
-                        (false, negated_domain.to_string())
-                    } else {
-                        (true, domain.to_string())
-                    }
-                }).collect();
-                NetworkFilterOption::Domain(domains)
-            }
+            // uBO's `$from` is an alias of `$domain`
+            ("domain", _) | ("from", _) => NetworkFilterOption::Domain(parse_domain_list(value, opts.max_domains)?),
+            ("to", true) => return Err(NetworkFilterError::NegatedTo),
+            ("to", false) => NetworkFilterOption::To(parse_domain_list(value, opts.max_domains)?),
             ("badfilter", true) => return Err(NetworkFilterError::NegatedBadFilter),
             ("badfilter", false) => NetworkFilterOption::Badfilter,
             ("important", true) => return Err(NetworkFilterError::NegatedImportant),
@@ -382,6 +584,8 @@ fn parse_filter_options(raw_options: &str, opts: ParseOptions) -> Result<Vec<Net
 
                 NetworkFilterOption::Redirect(String::from(value))
             }
+            ("empty", true) => return Err(NetworkFilterError::NegatedRedirection),
+            ("empty", false) => NetworkFilterOption::Redirect(String::from(crate::resources::EMPTY_REDIRECT_RESOURCE)),
             ("redirect-url", true) => return Err(NetworkFilterError::NegatedRedirection),
             ("redirect-url", false) => {
                 // Only parse filter option if parse options allow it
@@ -399,15 +603,33 @@ fn parse_filter_options(raw_options: &str, opts: ParseOptions) -> Result<Vec<Net
                 }
                 NetworkFilterOption::RedirectUrl(String::from(value))
             }
+            ("ipaddress", true) => return Err(NetworkFilterError::NegatedIpAddress),
+            ("ipaddress", false) => NetworkFilterOption::IpAddress(value.split('|')
+                .map(IpRange::parse)
+                .collect::<Option<Vec<_>>>()
+                .ok_or(NetworkFilterError::IpAddressInvalid)?),
+            ("header", true) => return Err(NetworkFilterError::NegatedHeader),
+            ("header", false) => NetworkFilterOption::Header(HeaderCondition::parse(value).ok_or(NetworkFilterError::HeaderInvalid)?),
             ("csp", _) => NetworkFilterOption::Csp(if !value.is_empty() {
                 Some(String::from(value))
             } else {
                 None
             }),
+            // uBO shorthands for `$csp` rules that block inline resources
+            ("inline-script", true) | ("inline-font", true) => return Err(NetworkFilterError::NegatedInlineOption),
+            ("inline-script", false) => NetworkFilterOption::Csp(Some(String::from(INLINE_SCRIPT_CSP))),
+            ("inline-font", false) => NetworkFilterOption::Csp(Some(String::from(INLINE_FONT_CSP))),
             ("generichide", true) | ("ghide", true) => return Err(NetworkFilterError::NegatedGenericHide),
             ("generichide", false) | ("ghide", false) => NetworkFilterOption::Generichide,
-            ("document", true) => return Err(NetworkFilterError::NegatedDocument),
-            ("document", false) => NetworkFilterOption::Document,
+            ("elemhide", true) | ("ehide", true) => return Err(NetworkFilterError::NegatedElemHide),
+            ("elemhide", false) | ("ehide", false) => NetworkFilterOption::Elemhide,
+            ("genericblock", true) => return Err(NetworkFilterError::NegatedGenericBlock),
+            ("genericblock", false) => NetworkFilterOption::Genericblock,
+            ("document", negated) => NetworkFilterOption::Document(!negated),
+            ("all", true) => return Err(NetworkFilterError::NegatedAll),
+            ("all", false) => NetworkFilterOption::All,
+            ("popup", true) => return Err(NetworkFilterError::NegatedPopup),
+            ("popup", false) => NetworkFilterOption::Popup,
             ("image", negated) => NetworkFilterOption::Image(!negated),
             ("media", negated) => NetworkFilterOption::Media(!negated),
             ("object", negated) | ("object-subrequest", negated) => NetworkFilterOption::Object(!negated),
@@ -419,12 +641,235 @@ fn parse_filter_options(raw_options: &str, opts: ParseOptions) -> Result<Vec<Net
             ("xmlhttprequest", negated) | ("xhr", negated) => NetworkFilterOption::XmlHttpRequest(!negated),
             ("websocket", negated) => NetworkFilterOption::Websocket(!negated),
             ("font", negated) => NetworkFilterOption::Font(!negated),
+            // AdGuard modifiers which only apply to DNS-level or system-wide blockers
+            ("network", _)
+            | ("app", _)
+            | ("client", _)
+            | ("ctag", _)
+            | ("dnstype", _)
+            | ("dnsrewrite", _)
+            | ("extension", _)
+            | ("stealth", _) => NetworkFilterOption::Unsupported(String::from(raw_option), UnsupportedReason::Platform),
+            (option, _) if UNSUPPORTED_OPTIONS.contains(&option) => NetworkFilterOption::Unsupported(String::from(raw_option), UnsupportedReason::Unimplemented),
+            (option, negated) if UNSUPPORTED_TYPES.contains(&option) => NetworkFilterOption::UnsupportedType(String::from(raw_option), !negated),
             (_, _) => return Err(NetworkFilterError::UnrecognisedOption),
         });
     }
     Ok(result)
 }
 
+/// A range of destination IP addresses from an `$ipaddress` option, written as either a single
+/// address or a network in CIDR notation, e.g. `192.168.0.0/16`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IpRange {
+    pub address: IpAddr,
+    /// The number of leading bits of `address` that must match.
+    pub prefix_len: u8,
+}
+
+impl IpRange {
+    fn parse(value: &str) -> Option<Self> {
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value, None),
+        };
+        let address: IpAddr = address.parse().ok()?;
+        let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().ok().filter(|len| *len <= max_prefix_len)?,
+            None => max_prefix_len,
+        };
+        Some(Self { address, prefix_len })
+    }
+
+    /// Checks whether `ip` is within this range. IPv4 addresses are never within an IPv6 range,
+    /// and vice versa.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(range) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(range) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let max_prefix_len = if self.address.is_ipv4() { 32 } else { 128 };
+        if self.prefix_len == max_prefix_len {
+            write!(f, "{}", self.address)
+        } else {
+            write!(f, "{}/{}", self.address, self.prefix_len)
+        }
+    }
+}
+
+/// A condition on the response headers of a request from a `$header` option, written as `name`,
+/// `name:value` or `name:/regex/`. Without a value, the header only needs to be present.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeaderCondition {
+    /// The header name, in lowercase.
+    pub name: String,
+    /// The expected value as written. A value enclosed in slashes is a regex, which must match
+    /// part of the header's value. Any other value must be equal to the whole header value,
+    /// ignoring case.
+    pub value: Option<String>,
+    // Compiled lazily like `NetworkFilter::regex`, and shared between clones.
+    #[serde(skip)]
+    regex: Arc<RwLock<Option<Arc<CompiledRegex>>>>,
+}
+
+impl PartialEq for HeaderCondition {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.value == other.value
+    }
+}
+
+impl HeaderCondition {
+    fn new(name: &str, value: Option<&str>) -> Self {
+        Self { name: name.to_ascii_lowercase(), value: value.map(String::from), regex: Default::default() }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let (name, value) = match value.split_once(':') {
+            Some((name, value)) => (name, Some(value)),
+            None => (value, None),
+        };
+        if name.is_empty() {
+            return None;
+        }
+        // Only check the syntax here, the regex is compiled once a filter needs it.
+        if let Some(pattern) = value.and_then(Self::regex_pattern) {
+            regex_syntax::Parser::new().parse(pattern).ok()?;
+        }
+        Some(Self::new(name, value))
+    }
+
+    fn regex_pattern(value: &str) -> Option<&str> {
+        if value.len() > 1 && value.starts_with('/') && value.ends_with('/') {
+            Some(&value[1..value.len() - 1])
+        } else {
+            None
+        }
+    }
+
+    fn get_regex(&self, pattern: &str) -> Arc<CompiledRegex> {
+        if let Some(regex) = self.regex.read().unwrap().as_ref() {
+            return regex.clone();
+        }
+        let regex = Arc::new(match Regex::new(pattern) {
+            Ok(regex) => CompiledRegex::Compiled(regex),
+            Err(e) => CompiledRegex::RegexParsingError(e),
+        });
+        *self.regex.write().unwrap() = Some(regex.clone());
+        regex
+    }
+
+    /// Checks whether any of `headers`, given as name and value pairs, satisfies this condition.
+    /// Header names are compared ignoring case.
+    pub fn matches(&self, headers: &[(String, String)]) -> bool {
+        let regex = self.value.as_deref().and_then(Self::regex_pattern).map(|pattern| self.get_regex(pattern));
+        headers.iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(&self.name))
+            .any(|(_, value)| match (&regex, &self.value) {
+                (Some(regex), _) => regex.is_match(value),
+                (None, Some(expected)) => value.trim().eq_ignore_ascii_case(expected),
+                (None, None) => true,
+            })
+    }
+}
+
+impl fmt::Display for HeaderCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}:{}", self.name, value),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// The destination domains of a `$to` option. Requests are matched against the hashes of its
+/// domains, the same ids used for `$domain` options, so no strings are compared at match time.
+/// Like in `$domain` options, entities such as `example.*` match that domain under any public
+/// suffix. Only the domains as written are serialized; their hashes are recomputed when
+/// deserializing.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "Vec<Arc<str>>")]
+pub struct ToDomains {
+    // Sorted, with excluded domains prefixed by `~`. Shared like `NetworkFilter::opt_domains_text`.
+    domains: Vec<Arc<str>>,
+    // Sorted hashes of the included and excluded domains, without the `~` prefix.
+    included: Vec<Hash>,
+    excluded: Vec<Hash>,
+    // Whether any domain is an entity, so that entity hashes of the hostname need to be checked.
+    has_entities: bool,
+}
+
+impl From<Vec<Arc<str>>> for ToDomains {
+    fn from(mut domains: Vec<Arc<str>>) -> Self {
+        domains.sort_unstable();
+        domains.dedup();
+        DomainInterner::intern_deserialized(&mut domains);
+        let hashes = |excluded: bool| {
+            let mut hashes = domains.iter()
+                .filter(|domain| domain.starts_with('~') == excluded)
+                .map(|domain| utils::fast_hash(domain.trim_start_matches('~')))
+                .collect::<Vec<_>>();
+            hashes.sort_unstable();
+            hashes
+        };
+        let has_entities = domains.iter().any(|domain| domain.ends_with(".*"));
+        Self { included: hashes(false), excluded: hashes(true), has_entities, domains }
+    }
+}
+
+impl Serialize for ToDomains {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.domains.serialize(serializer)
+    }
+}
+
+impl ToDomains {
+    /// The domains as written, sorted, with excluded domains prefixed by `~`.
+    pub fn domains(&self) -> &[Arc<str>] {
+        &self.domains
+    }
+
+    /// Checks whether `hostname` is within one of the included domains, if there are any, and
+    /// not within any of the excluded ones.
+    pub fn matches(&self, hostname: &str) -> bool {
+        let suffixes = std::iter::once(hostname).chain(hostname.match_indices('.')
+            .map(|(i, _)| &hostname[i + 1..])
+            .filter(|suffix| !suffix.is_empty()));
+        let entities = if self.has_entities { crate::request::entity_hashes(hostname) } else { Vec::new() };
+
+        let mut included = self.included.is_empty();
+        for hash in suffixes.map(utils::fast_hash).chain(entities) {
+            if self.excluded.binary_search(&hash).is_ok() {
+                return false;
+            }
+            included = included || self.included.binary_search(&hash).is_ok();
+        }
+        included
+    }
+}
+
+/// Identifies a network filter, as returned by `NetworkFilter::filter_id`.
+pub type FilterId = Hash;
+
+/// CSP directive injected by `$inline-script` filters, which allows every script source except
+/// inline scripts.
+pub const INLINE_SCRIPT_CSP: &str = "script-src 'unsafe-eval' * blob: data:";
+/// CSP directive injected by `$inline-font` filters, which allows every font source except inline
+/// fonts.
+pub const INLINE_FONT_CSP: &str = "font-src *";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkFilter {
     pub mask: NetworkFilterMask,
@@ -445,6 +890,48 @@ pub struct NetworkFilter {
     pub opt_domains_union: Option<Hash>,
     pub opt_not_domains_union: Option<Hash>,
 
+    /// `$domain` entries as written, with negated entries prefixed by `~`. Only used to write the
+    /// filter back out as text. Entries are shared between filters of the same `Blocker`, see
+    /// `DomainInterner`.
+    #[serde(default, deserialize_with = "deserialize_domains_text")]
+    pub opt_domains_text: Option<Vec<Arc<str>>>,
+
+    /// The list this filter was parsed from, if any. Filters from a disabled list are skipped at
+    /// match time.
+    #[serde(default)]
+    pub list_id: Option<ListId>,
+
+    /// Options of this filter that are recognized but not supported, as written.
+    #[serde(default)]
+    pub unsupported_options: Option<Vec<String>>,
+
+    /// Why `unsupported_options` aren't supported, and so how the filter is affected. This is set
+    /// if and only if there are unsupported options.
+    #[serde(default)]
+    pub unsupported_reason: Option<UnsupportedReason>,
+
+    /// Ranges from an `$ipaddress` option. The filter only matches requests whose destination IP
+    /// address is known and falls within one of them.
+    #[serde(default)]
+    pub opt_ip_ranges: Option<Vec<IpRange>>,
+
+    /// Destination domains from a `$to` option. The filter only matches requests to one of the
+    /// included domains or their subdomains, and never matches requests to an excluded domain or
+    /// its subdomains.
+    #[serde(default)]
+    pub opt_to_domains: Option<ToDomains>,
+
+    /// The response header condition of a `$header` option. The filter only matches requests
+    /// whose response headers are known and satisfy it, see `Request::with_response_headers`.
+    #[serde(default)]
+    pub opt_header: Option<HeaderCondition>,
+
+    /// For a filter fused from several others by the optimizer, the `filter_id` of each of them,
+    /// in the order of their patterns. This allows removing a single one of them again, see
+    /// `without_parts`.
+    #[serde(default)]
+    pub fused_ids: Option<Vec<FilterId>>,
+
     // Regex compild lazily, using "Interior Mutability"
     // Arc (Atomic Reference Counter) allows for cloned NetworkFilters
     // to point to the same RwLock and what is inside.
@@ -459,26 +946,165 @@ pub struct NetworkFilter {
 // TODO - restrict the API so that this is always true - i.e. lazy-calculate IDs from actual data,
 // prevent field access, and don't load the ID from the serialized format.
 /// The ID of a filter is assumed to be correctly calculated for the purposes of this
-/// implementation.
+/// implementation. The same rule from different lists is a different filter, so that it stays in
+/// effect while only one of the lists is disabled.
 impl PartialEq for NetworkFilter {
     fn eq(&self, other: &Self) -> bool {
-        self.id == other.id
+        self.id == other.id && self.list_id == other.list_id
     }
 }
 
 /// Filters are sorted by ID to preserve a stable ordering of data in the serialized format.
 impl PartialOrd for NetworkFilter {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.id.partial_cmp(&other.id)
+        (self.id, self.list_id).partial_cmp(&(other.id, other.list_id))
     }
 }
 
+/// Shares the text of `$domain` and `$to` entries between filters. The same domains tend to be
+/// repeated across many filters of a list, so keeping a single copy of each saves memory for
+/// lists with many domain-scoped filters. Matching is unaffected, since both options are matched
+/// by the hashes of their domains.
+#[derive(Debug, Default)]
+pub(crate) struct DomainInterner {
+    domains: HashSet<Arc<str>>,
+}
+
+thread_local! {
+    // Set by `DomainInterner::deserializing` while filters are being deserialized.
+    static DESERIALIZING_INTERNER: RefCell<Option<DomainInterner>> = const { RefCell::new(None) };
+}
+
+impl DomainInterner {
+    fn intern(&mut self, domain: &mut Arc<str>) {
+        match self.domains.get(&**domain) {
+            Some(shared) => *domain = Arc::clone(shared),
+            None => {
+                self.domains.insert(Arc::clone(domain));
+            }
+        }
+    }
+
+    /// Replaces the `$domain` and `$to` entries of `filter` with shared copies.
+    pub(crate) fn intern_filter(&mut self, filter: &mut NetworkFilter) {
+        for domain in filter.opt_domains_text.iter_mut().flatten() {
+            self.intern(domain);
+        }
+        for domain in filter.opt_to_domains.iter_mut().flat_map(|to_domains| to_domains.domains.iter_mut()) {
+            self.intern(domain);
+        }
+    }
+
+    /// Forgets domains that are no longer used by any filter, so that removing filters frees
+    /// their domains.
+    pub(crate) fn shrink(&mut self) {
+        self.domains.retain(|domain| Arc::strong_count(domain) > 1);
+    }
+
+    /// Runs `deserialize`, sharing the entries of all filters deserialized by it as they are read,
+    /// and returns its result along with the interner holding the shared entries.
+    pub(crate) fn deserializing<T>(deserialize: impl FnOnce() -> T) -> (T, DomainInterner) {
+        DESERIALIZING_INTERNER.with(|interner| *interner.borrow_mut() = Some(DomainInterner::default()));
+        let result = deserialize();
+        let interner = DESERIALIZING_INTERNER.with(|interner| interner.borrow_mut().take());
+        (result, interner.unwrap_or_default())
+    }
+
+    fn intern_deserialized(domains: &mut [Arc<str>]) {
+        DESERIALIZING_INTERNER.with(|interner| {
+            if let Some(interner) = interner.borrow_mut().as_mut() {
+                for domain in domains {
+                    interner.intern(domain);
+                }
+            }
+        });
+    }
+}
+
+fn deserialize_domains_text<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<Arc<str>>>, D::Error> {
+    let mut domains = Option::<Vec<Arc<str>>>::deserialize(deserializer)?;
+    if let Some(domains) = domains.as_mut() {
+        DomainInterner::intern_deserialized(domains);
+    }
+    Ok(domains)
+}
+
+/// The decoded options of a `NetworkFilter`, as returned by `NetworkFilter::options`. This is
+/// meant for displaying a filter, e.g. in a filter editor, and doesn't depend on how options are
+/// represented internally.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FilterOptions {
+    /// Whether this is an exception (`@@`) filter.
+    pub exception: bool,
+    /// The request types the filter applies to, by their option names, e.g. `script`. Aliases are
+    /// replaced by their full names, and types implied by negated options are listed explicitly.
+    pub types: Vec<&'static str>,
+    /// Domains the filter is restricted to by a `$domain` option. Empty if there is no such
+    /// option, or if domain names were not kept when the filter was deserialized.
+    pub domains: Vec<String>,
+    /// Domains excluded by a `$domain` option, without their leading `~`.
+    pub excluded_domains: Vec<String>,
+    /// Destination domains from a `$to` option, with excluded ones prefixed by `~`.
+    pub to_domains: Vec<String>,
+    /// Whether the filter applies to first-party requests.
+    pub first_party: bool,
+    /// Whether the filter applies to third-party requests.
+    pub third_party: bool,
+    pub important: bool,
+    pub match_case: bool,
+    /// The resource name of a `$redirect` option, or the URL of a `$redirect-url` option.
+    pub redirect: Option<String>,
+    /// Whether `redirect` is a URL from `$redirect-url`.
+    pub redirect_is_url: bool,
+    /// Whether the filter has a `$csp` option, or one of its shorthands like `$inline-script`.
+    pub is_csp: bool,
+    /// The policy of a `$csp` option. `None` for `$csp` exceptions without a policy.
+    pub csp: Option<String>,
+    pub tag: Option<String>,
+    pub bug: Option<u32>,
+    pub ip_ranges: Vec<IpRange>,
+    /// The response header condition of a `$header` option.
+    pub header: Option<HeaderCondition>,
+    pub generic_hide: bool,
+    pub elem_hide: bool,
+    pub generic_block: bool,
+    pub badfilter: bool,
+    /// Options that are recognized but have no effect, as written.
+    pub unsupported: Vec<String>,
+    /// Why the `unsupported` options have no effect, see `NetworkFilter::unsupported_reason`.
+    pub unsupported_reason: Option<UnsupportedReason>,
+}
+
+/// Content type options, in the order they are written by `NetworkFilter::to_canonical_string`.
+const CONTENT_TYPE_OPTIONS: &[(NetworkFilterMask, &str)] = &[
+    (NetworkFilterMask::FROM_IMAGE, "image"),
+    (NetworkFilterMask::FROM_MEDIA, "media"),
+    (NetworkFilterMask::FROM_OBJECT, "object"),
+    (NetworkFilterMask::FROM_OTHER, "other"),
+    (NetworkFilterMask::FROM_PING, "ping"),
+    (NetworkFilterMask::FROM_SCRIPT, "script"),
+    (NetworkFilterMask::FROM_STYLESHEET, "stylesheet"),
+    (NetworkFilterMask::FROM_SUBDOCUMENT, "subdocument"),
+    (NetworkFilterMask::FROM_WEBSOCKET, "websocket"),
+    (NetworkFilterMask::FROM_XMLHTTPREQUEST, "xmlhttprequest"),
+    (NetworkFilterMask::FROM_FONT, "font"),
+    (NetworkFilterMask::FROM_DOCUMENT, "document"),
+    (NetworkFilterMask::FROM_POPUP, "popup"),
+];
+
 /// Ensure that no invalid option combinations were provided for a filter.
 fn validate_options(options: &[NetworkFilterOption]) -> Result<(), NetworkFilterError> {
     let mut has_csp = false;
     let mut has_content_type = false;
     let mut has_redirect = false;
+    let mut has_all = false;
+    let mut has_negated_document = false;
     for option in options {
+        if matches!(option, NetworkFilterOption::All) {
+            has_all = true;
+        } else if matches!(option, NetworkFilterOption::Document(false)) {
+            has_negated_document = true;
+        }
         if matches!(option, NetworkFilterOption::Csp(..)) {
             has_csp = true;
         } else if option.is_content_type() {
@@ -493,6 +1119,10 @@ fn validate_options(options: &[NetworkFilterOption]) -> Result<(), NetworkFilter
     if has_csp && has_content_type {
         return Err(NetworkFilterError::CspWithContentType);
     }
+    // `~document` only makes sense as an exclusion from the types expanded by `$all`.
+    if has_negated_document && !has_all {
+        return Err(NetworkFilterError::NegatedDocument);
+    }
 
     Ok(())
 }
@@ -518,11 +1148,18 @@ impl NetworkFilter {
         let mut opt_not_domains: Option<Vec<Hash>> = None;
         let mut opt_domains_union: Option<Hash> = None;
         let mut opt_not_domains_union: Option<Hash> = None;
+        let mut opt_domains_text: Option<Vec<Arc<str>>> = None;
 
         let mut redirect: Option<String> = None;
         let mut csp: Option<String> = None;
         let mut bug: Option<u32> = None;
         let mut tag: Option<String> = None;
+        let mut unsupported_options: Option<Vec<String>> = None;
+        let mut unsupported_reason: Option<UnsupportedReason> = None;
+        let mut has_unsupported_type = false;
+        let mut opt_ip_ranges: Option<Vec<IpRange>> = None;
+        let mut to_domains: Option<Vec<Arc<str>>> = None;
+        let mut opt_header: Option<HeaderCondition> = None;
 
         if parsed.exception {
             mask.set(NetworkFilterMask::IS_EXCEPTION, true);
@@ -550,15 +1187,22 @@ impl NetworkFilter {
                         domains.dedup();
                         let mut opt_domains_array: Vec<Hash> = vec![];
                         let mut opt_not_domains_array: Vec<Hash> = vec![];
+                        let mut domains_text: Vec<Arc<str>> = Vec::with_capacity(domains.len());
 
                         for (enabled, domain) in domains {
+                            if domain.ends_with(".*") {
+                                mask.set(NetworkFilterMask::HAS_ENTITY_DOMAINS, true);
+                            }
                             let domain_hash = utils::fast_hash(&domain);
                             if !enabled {
                                 opt_not_domains_array.push(domain_hash);
+                                domains_text.push(format!("~{}", domain).into());
                             } else {
                                 opt_domains_array.push(domain_hash);
+                                domains_text.push(domain.into());
                             }
                         }
+                        opt_domains_text = Some(domains_text);
 
                         if !opt_domains_array.is_empty() {
                             opt_domains_array.sort_unstable();
@@ -593,7 +1237,15 @@ impl NetworkFilter {
                         csp = value;
                     }
                     NetworkFilterOption::Generichide => mask.set(NetworkFilterMask::GENERIC_HIDE, true),
-                    NetworkFilterOption::Document => cpt_mask_positive.set(NetworkFilterMask::FROM_DOCUMENT, true),
+                    // `$elemhide` exceptions are a stronger form of `$generichide`, and are stored
+                    // with them.
+                    NetworkFilterOption::Elemhide => mask.set(NetworkFilterMask::GENERIC_HIDE | NetworkFilterMask::ELEM_HIDE, true),
+                    NetworkFilterOption::Genericblock => mask.set(NetworkFilterMask::GENERIC_BLOCK, true),
+                    NetworkFilterOption::Document(enabled) => apply_content_type!(FROM_DOCUMENT, enabled),
+                    // `$all` is equivalent to listing every supported type, including `document`.
+                    // Negated types are subtracted from it afterwards, e.g. `$all,~image`.
+                    NetworkFilterOption::All => cpt_mask_positive |= NetworkFilterMask::FROM_ALL_TYPES,
+                    NetworkFilterOption::Popup => cpt_mask_positive.set(NetworkFilterMask::FROM_POPUP, true),
                     NetworkFilterOption::Image(enabled) => apply_content_type!(FROM_IMAGE, enabled),
                     NetworkFilterOption::Media(enabled) => apply_content_type!(FROM_MEDIA, enabled),
                     NetworkFilterOption::Object(enabled) => apply_content_type!(FROM_OBJECT, enabled),
@@ -605,20 +1257,42 @@ impl NetworkFilter {
                     NetworkFilterOption::XmlHttpRequest(enabled) => apply_content_type!(FROM_XMLHTTPREQUEST, enabled),
                     NetworkFilterOption::Websocket(enabled) => apply_content_type!(FROM_WEBSOCKET, enabled),
                     NetworkFilterOption::Font(enabled) => apply_content_type!(FROM_FONT, enabled),
+                    NetworkFilterOption::IpAddress(ranges) => opt_ip_ranges.get_or_insert_with(Vec::new).extend(ranges),
+                    NetworkFilterOption::Header(condition) => opt_header = Some(condition),
+                    NetworkFilterOption::To(domains) => {
+                        to_domains.get_or_insert_with(Vec::new).extend(domains.into_iter().map(|(enabled, domain)| {
+                            if enabled { domain.into() } else { format!("~{}", domain).into() }
+                        }));
+                    }
+                    NetworkFilterOption::Unsupported(option, reason) => {
+                        unsupported_reason = unsupported_reason.max(Some(reason));
+                        unsupported_options.get_or_insert_with(Vec::new).push(option);
+                    }
+                    NetworkFilterOption::UnsupportedType(option, enabled) => {
+                        has_unsupported_type |= enabled;
+                        unsupported_reason = unsupported_reason.max(Some(UnsupportedReason::UnsupportedType));
+                        unsupported_options.get_or_insert_with(Vec::new).push(option);
+                    }
                 }
             });
         }
 
         mask |= cpt_mask_positive;
-
-        // If any negated "network" types were set, then implicitly enable all network types.
-        // The negated types will be applied later.
-        if (cpt_mask_negative & NetworkFilterMask::FROM_NETWORK_TYPES) != NetworkFilterMask::NONE {
-            mask |= NetworkFilterMask::FROM_NETWORK_TYPES;
-        }
-        // If no positive types were set, then the filter should apply to all network types.
-        if (cpt_mask_positive & NetworkFilterMask::FROM_ALL_TYPES).is_empty() {
-            mask |= NetworkFilterMask::FROM_NETWORK_TYPES;
+        let has_positive_types = !(cpt_mask_positive & (NetworkFilterMask::FROM_ALL_TYPES | NetworkFilterMask::FROM_POPUP)).is_empty();
+
+        // A filter that only applies to unsupported types is left without any types, so that it
+        // never matches.
+        let only_unsupported_types = has_unsupported_type && !has_positive_types;
+        if !only_unsupported_types {
+            // If any negated "network" types were set, then implicitly enable all network types.
+            // The negated types will be applied later.
+            if (cpt_mask_negative & NetworkFilterMask::FROM_NETWORK_TYPES) != NetworkFilterMask::NONE {
+                mask |= NetworkFilterMask::FROM_NETWORK_TYPES;
+            }
+            // If no positive types were set, then the filter should apply to all network types.
+            if !has_positive_types {
+                mask |= NetworkFilterMask::FROM_NETWORK_TYPES;
+            }
         }
 
         match parsed.pattern.left_anchor {
@@ -627,11 +1301,8 @@ impl NetworkFilter {
             None => (),
         }
 
-        // TODO these need to actually be handled differently than trailing `^`.
-        let mut end_url_anchor = false;
         if let Some(NetworkFilterRightAnchor::SinglePipe) = parsed.pattern.right_anchor {
             mask.set(NetworkFilterMask::IS_RIGHT_ANCHOR, true);
-            end_url_anchor = true;
         }
 
         let pattern = &parsed.pattern.pattern;
@@ -642,6 +1313,7 @@ impl NetworkFilter {
         if pattern.starts_with('/') && pattern.ends_with('/') {
             #[cfg(feature = "full-regex-handling")]
             {
+                check_complete_regex(pattern)?;
                 mask.set(NetworkFilterMask::IS_COMPLETE_REGEX, true);
             }
 
@@ -762,7 +1434,12 @@ impl NetworkFilter {
                 NetworkFilterMask::IS_REGEX,
                 check_is_regex(&pattern[filter_index_start..filter_index_end]),
             );
-            Some(String::from(&pattern[filter_index_start..filter_index_end]).to_ascii_lowercase())
+            let filter = &pattern[filter_index_start..filter_index_end];
+            if mask.contains(NetworkFilterMask::MATCH_CASE) {
+                Some(String::from(filter))
+            } else {
+                Some(filter.to_ascii_lowercase())
+            }
         } else {
             None
         };
@@ -788,24 +1465,14 @@ impl NetworkFilter {
         if mask.contains(NetworkFilterMask::GENERIC_HIDE) && !parsed.exception {
             return Err(NetworkFilterError::GenericHideWithoutException);
         }
-
-        // uBlock Origin would block main document `https://example.com` requests with all of the
-        // following filters:
-        // - ||example.com
-        // - ||example.com/
-        // - example.com
-        // - https://example.com
-        // However, it relies on checking the URL post-match against information from the matched
-        // filter, which isn't saved in Brave unless running with filter lists compiled in "debug"
-        // mode. Instead, we apply the implicit document matching more strictly, only for hostname
-        // filters of the form `||example.com^`.
-        if (cpt_mask_positive & NetworkFilterMask::FROM_ALL_TYPES).is_empty() &&
-                (cpt_mask_negative & NetworkFilterMask::FROM_ALL_TYPES).is_empty() &&
-                mask.contains(NetworkFilterMask::IS_HOSTNAME_ANCHOR) &&
-                mask.contains(NetworkFilterMask::IS_RIGHT_ANCHOR) &&
-                !end_url_anchor {
-            mask |= NetworkFilterMask::FROM_ALL_TYPES;
+        if mask.contains(NetworkFilterMask::GENERIC_BLOCK) && !parsed.exception {
+            return Err(NetworkFilterError::GenericBlockWithoutException);
         }
+
+        // Unlike uBlock Origin, hostname filters like `||example.com^` don't implicitly match
+        // top-level documents. Untyped filters only match `FROM_NETWORK_TYPES`, so blocking a
+        // navigation always takes an explicit `$document` or `$all`.
+
         // Finally, apply any explicitly negated request types
         mask &= !cpt_mask_negative;
 
@@ -831,6 +1498,14 @@ impl NetworkFilter {
             id: utils::fast_hash(&line),
             opt_domains_union,
             opt_not_domains_union,
+            opt_domains_text,
+            list_id: opts.list_id,
+            unsupported_options,
+            unsupported_reason,
+            opt_ip_ranges,
+            opt_to_domains: to_domains.map(ToDomains::from),
+            opt_header,
+            fused_ids: None,
             regex: Arc::new(RwLock::new(None))
         })
     }
@@ -861,30 +1536,299 @@ impl NetworkFilter {
         }
         hostname.push('^');
 
-        NetworkFilter::parse(&hostname, debug, Default::default())
+        // Hosts files block a hostname outright, including navigations to it.
+        let mut filter = NetworkFilter::parse(&hostname, debug, Default::default())?;
+        filter.mask |= NetworkFilterMask::FROM_ALL_TYPES;
+        Ok(filter)
     }
 
     pub fn get_id_without_badfilter(&self) -> Hash {
         let mut mask = self.mask;
         mask.set(NetworkFilterMask::BAD_FILTER, false);
-        compute_filter_id(
-            self.csp.as_deref(),
-            mask,
-            self.filter.string_view().as_deref(),
-            self.hostname.as_deref(),
-            self.opt_domains.as_ref(),
-            self.opt_not_domains.as_ref(),
-        )
+        self.compute_id(mask)
     }
 
     pub fn get_id(&self) -> Hash {
+        self.filter_id()
+    }
+
+    /// Returns this filter without the parts whose `filter_id` is `cancelled`, or `None` if
+    /// nothing is left of it. Filters fused by the optimizer consist of one part per fused filter;
+    /// any other filter is a single part with its own id.
+    pub(crate) fn without_parts<F: Fn(FilterId) -> bool>(mut self, cancelled: F) -> Option<Self> {
+        if cancelled(self.filter_id()) {
+            return None;
+        }
+        let keep: Vec<bool> = match (&self.filter, &self.fused_ids) {
+            (FilterPart::AnyOf(patterns), Some(ids)) if patterns.len() == ids.len() => {
+                ids.iter().map(|id| !cancelled(*id)).collect()
+            }
+            _ => return Some(self),
+        };
+        if keep.iter().all(|keep| *keep) {
+            return Some(self);
+        }
+        if !keep.iter().any(|keep| *keep) {
+            return None;
+        }
+
+        fn retain_parts<T>(parts: Vec<T>, keep: &[bool]) -> Vec<T> {
+            parts.into_iter().zip(keep).filter(|(_, keep)| **keep).map(|(part, _)| part).collect()
+        }
+        if let FilterPart::AnyOf(patterns) = &mut self.filter {
+            *patterns = retain_parts(std::mem::take(patterns), &keep);
+        }
+        self.fused_ids = self.fused_ids.take().map(|ids| retain_parts(ids, &keep));
+        self.raw_line = self.raw_line.take().map(|raw_line| {
+            let raw_lines: Vec<_> = raw_line.split(" <+> ").collect();
+            if raw_lines.len() == keep.len() {
+                retain_parts(raw_lines, &keep).join(" <+> ")
+            } else {
+                raw_line
+            }
+        });
+        // The compiled regex still includes the removed patterns
+        self.regex = Arc::new(RwLock::new(None));
+        Some(self)
+    }
+
+    /// Returns a canonical identifier for this filter, computed from its normalized pattern and
+    /// options. Filters that only differ in the order of their options or `$domain` entries will
+    /// have the same id. The id does not depend on any random state, so it is stable across runs.
+    ///
+    /// This is the identity used for `$badfilter`, as well as for checking whether a filter
+    /// already exists in a `Blocker`.
+    pub fn filter_id(&self) -> FilterId {
+        self.compute_id(self.mask)
+    }
+
+    /// Reconstructs a canonical text form of this filter from its parsed representation. Options
+    /// are written in a fixed order, and aliases are replaced by their full names. Parsing the
+    /// result produces a filter with the same `filter_id`.
+    ///
+    /// Returns `None` if the filter cannot be written as a single rule. This is the case for
+    /// filters that were combined by the optimizer, and for filters with `$domain` options that
+    /// were deserialized from a format which did not keep domain names.
+    pub fn to_canonical_string(&self) -> Option<String> {
+        let filter = match &self.filter {
+            FilterPart::Empty => None,
+            FilterPart::Simple(s) => Some(s.as_str()),
+            FilterPart::AnyOf(_) => return None,
+        };
+        if (self.opt_domains.is_some() || self.opt_not_domains.is_some()) && self.opt_domains_text.is_none() {
+            return None;
+        }
+
+        let mut rule = String::new();
+        if self.is_exception() {
+            rule.push_str("@@");
+        }
+
+        let cpt_mask = self.get_cpt_mask();
+
+        // Request types that will be matched if no content type options are written
+        let default_cpt_mask = NetworkFilterMask::FROM_NETWORK_TYPES;
+
+        if self.is_hostname_anchor() {
+            rule.push_str("||");
+            rule.push_str(self.hostname.as_deref().unwrap_or_default());
+            match filter {
+                Some(filter) => {
+                    // A leading wildcard between the hostname and the rest of the pattern is
+                    // removed during parsing.
+                    if !self.is_left_anchor() {
+                        rule.push('*');
+                    }
+                    rule.push_str(filter);
+                    if self.needs_trailing_wildcard(filter) {
+                        rule.push('*');
+                    }
+                    if self.is_right_anchor() {
+                        rule.push('|');
+                    }
+                }
+                None if self.is_left_anchor() => rule.push('*'),
+                None if self.is_right_anchor() => rule.push('^'),
+                None => (),
+            }
+        } else if !self.for_http() || !self.for_https() {
+            rule.push_str(match (self.for_http(), self.for_https()) {
+                (true, _) => "|http://",
+                (_, true) => "|https://",
+                _ => "|ws://",
+            });
+        } else {
+            if self.is_left_anchor() {
+                rule.push('|');
+            }
+            match filter {
+                Some(filter) => {
+                    // Restore wildcards that were removed during parsing, if the pattern would
+                    // otherwise be read back differently.
+                    if filter.starts_with('*') || filter.starts_with('|') || filter.starts_with("@@") {
+                        rule.push('*');
+                    }
+                    rule.push_str(filter);
+                    if self.needs_trailing_wildcard(filter)
+                        || (!self.is_complete_regex() && filter.len() > 1 && filter.starts_with('/') && filter.ends_with('/')) {
+                        rule.push('*');
+                    }
+                }
+                None if self.is_regex() => rule.push('*'),
+                None => (),
+            }
+            if self.is_right_anchor() {
+                rule.push('|');
+            }
+        }
+
+        let mut options: Vec<Cow<str>> = vec![];
+
+        if self.is_important() {
+            options.push("important".into());
+        }
+        if self.match_case() {
+            options.push("match-case".into());
+        }
+        if !self.first_party() {
+            options.push("third-party".into());
+        }
+        if !self.third_party() {
+            options.push("first-party".into());
+        }
+
+        // Filters that only apply to unsupported types are written with just those types, which
+        // are part of the unsupported options.
+        let only_unsupported_types = cpt_mask.is_empty() && self.unsupported_options.iter().flatten()
+            .any(|option| UNSUPPORTED_TYPES.contains(&option.as_str()));
+
+        if !self.is_csp() && cpt_mask == NetworkFilterMask::FROM_ALL_TYPES && cpt_mask != default_cpt_mask {
+            options.push("all".into());
+        } else if !self.is_csp() && !only_unsupported_types && cpt_mask != default_cpt_mask {
+            let excluded = NetworkFilterMask::FROM_NETWORK_TYPES & !cpt_mask;
+            // Use whichever of the included or excluded types is shorter to write. A filter
+            // with no types at all can only be written by excluding every type.
+            let negate = cpt_mask.is_empty() || (NetworkFilterMask::FROM_NETWORK_TYPES.contains(cpt_mask)
+                && excluded.bits().count_ones() < cpt_mask.bits().count_ones());
+            for (content_type, name) in CONTENT_TYPE_OPTIONS {
+                if negate && excluded.contains(*content_type) {
+                    options.push(format!("~{}", name).into());
+                } else if !negate && cpt_mask.contains(*content_type) {
+                    options.push((*name).into());
+                }
+            }
+        }
+
+        if let Some(domains) = &self.opt_domains_text {
+            options.push(format!("domain={}", domains.join("|")).into());
+        }
+        if let Some(to_domains) = &self.opt_to_domains {
+            options.push(format!("to={}", to_domains.domains().join("|")).into());
+        }
+        if let Some(ip_ranges) = &self.opt_ip_ranges {
+            options.push(format!("ipaddress={}", ip_ranges.iter().map(|range| range.to_string()).collect::<Vec<_>>().join("|")).into());
+        }
+        if let Some(header) = &self.opt_header {
+            options.push(format!("header={}", header).into());
+        }
+
+        if self.is_csp() {
+            options.push(match &self.csp {
+                Some(csp) => format!("csp={}", csp.replace(',', "\\,")).into(),
+                None => "csp".into(),
+            });
+        }
+        if let Some(redirect) = &self.redirect {
+            if self.is_redirect_url() {
+                options.push(format!("redirect-url={}", redirect).into());
+            } else {
+                options.push(format!("redirect={}", redirect).into());
+            }
+        }
+        if self.is_elem_hide() {
+            options.push("elemhide".into());
+        } else if self.is_generic_hide() {
+            options.push("generichide".into());
+        }
+        if self.is_generic_block() {
+            options.push("genericblock".into());
+        }
+        if let Some(tag) = &self.tag {
+            options.push(format!("tag={}", tag).into());
+        }
+        if let Some(bug) = self.bug {
+            options.push(format!("bug={}", bug).into());
+        }
+        if let Some(unsupported_options) = &self.unsupported_options {
+            options.extend(unsupported_options.iter().map(|option| option.as_str().into()));
+        }
+        if self.is_badfilter() {
+            options.push("badfilter".into());
+        }
+
+        if !options.is_empty() {
+            rule.push('$');
+            rule.push_str(&options.join(","));
+        }
+
+        Some(rule)
+    }
+
+    /// Returns the decoded options of this filter. See `FilterOptions`.
+    pub fn options(&self) -> FilterOptions {
+        let cpt_mask = self.get_cpt_mask();
+        let domains_text = self.opt_domains_text.as_deref().unwrap_or_default();
+
+        FilterOptions {
+            exception: self.is_exception(),
+            types: CONTENT_TYPE_OPTIONS.iter()
+                .filter(|(content_type, _)| cpt_mask.contains(*content_type))
+                .map(|(_, name)| *name)
+                .collect(),
+            domains: domains_text.iter().filter(|domain| !domain.starts_with('~')).map(|domain| domain.to_string()).collect(),
+            excluded_domains: domains_text.iter().filter_map(|domain| domain.strip_prefix('~')).map(str::to_owned).collect(),
+            to_domains: self.opt_to_domains.iter().flat_map(ToDomains::domains).map(|domain| domain.to_string()).collect(),
+            first_party: self.first_party(),
+            third_party: self.third_party(),
+            important: self.is_important(),
+            match_case: self.match_case(),
+            redirect: self.redirect.clone(),
+            redirect_is_url: self.is_redirect_url(),
+            is_csp: self.is_csp(),
+            csp: self.csp.clone(),
+            tag: self.tag.clone(),
+            bug: self.bug,
+            ip_ranges: self.opt_ip_ranges.clone().unwrap_or_default(),
+            header: self.opt_header.clone(),
+            generic_hide: self.is_generic_hide(),
+            elem_hide: self.is_elem_hide(),
+            generic_block: self.is_generic_block(),
+            badfilter: self.is_badfilter(),
+            unsupported: self.unsupported_options.clone().unwrap_or_default(),
+            unsupported_reason: self.unsupported_reason,
+        }
+    }
+
+    /// Checks whether a trailing wildcard that was removed during parsing must be written back
+    /// after `filter` for it to be read the same way.
+    fn needs_trailing_wildcard(&self, filter: &str) -> bool {
+        filter.ends_with('*') || (filter.ends_with('|') && !self.is_right_anchor())
+    }
+
+    fn compute_id(&self, mask: NetworkFilterMask) -> Hash {
         compute_filter_id(
             self.csp.as_deref(),
-            self.mask,
+            mask,
             self.filter.string_view().as_deref(),
             self.hostname.as_deref(),
             self.opt_domains.as_ref(),
             self.opt_not_domains.as_ref(),
+            self.redirect.as_deref(),
+            self.tag.as_deref(),
+            self.bug,
+            self.opt_ip_ranges.as_ref(),
+            self.opt_to_domains.as_ref().map(ToDomains::domains),
+            self.opt_header.as_ref(),
         )
     }
 
@@ -892,9 +1836,10 @@ impl NetworkFilter {
         let mut tokens: Vec<Hash> = Vec::with_capacity(TOKENS_BUFFER_SIZE);
 
         // If there is only one domain and no domain negation, we also use this
-        // domain as a token.
+        // domain as a token. Requests aren't tokenized by entity hashes, so entities are left out.
         if self.opt_domains.is_some()
             && self.opt_not_domains.is_none()
+            && !self.mask.contains(NetworkFilterMask::HAS_ENTITY_DOMAINS)
             && self.opt_domains.as_ref().map(|d| d.len()) == Some(1)
         {
             if let Some(domains) = self.opt_domains.as_ref() {
@@ -912,8 +1857,12 @@ impl NetworkFilter {
                         (self.is_plain() || self.is_regex()) && !self.is_right_anchor();
                     let skip_first_token = self.is_right_anchor();
 
-                    let mut filter_tokens =
-                        utils::tokenize_filter(&f, skip_first_token, skip_last_token);
+                    // Request tokens are always taken from the lowercase URL
+                    let mut filter_tokens = if self.match_case() {
+                        utils::tokenize_filter(&f.to_ascii_lowercase(), skip_first_token, skip_last_token)
+                    } else {
+                        utils::tokenize_filter(&f, skip_first_token, skip_last_token)
+                    };
 
                     tokens.append(&mut filter_tokens);
                 }
@@ -952,8 +1901,14 @@ impl NetworkFilter {
     }
 
 
+    /// Checks whether this filter has an unsupported option that keeps it from ever matching.
+    /// Unsupported types are handled through the filter's types instead.
+    pub(crate) fn has_unimplemented_option(&self) -> bool {
+        self.unsupported_reason.as_ref().map(UnsupportedReason::disables_filter).unwrap_or(false)
+    }
+
     fn get_cpt_mask(&self) -> NetworkFilterMask {
-        self.mask & NetworkFilterMask::FROM_ALL_TYPES
+        self.mask & (NetworkFilterMask::FROM_ALL_TYPES | NetworkFilterMask::FROM_POPUP)
     }
 
     pub fn is_exception(&self) -> bool {
@@ -996,6 +1951,23 @@ impl NetworkFilter {
         self.mask.contains(NetworkFilterMask::GENERIC_HIDE)
     }
 
+    /// `$elemhide` exceptions disable all cosmetic filtering on matching documents, not just
+    /// generic rules. These are also `is_generic_hide`.
+    pub fn is_elem_hide(&self) -> bool {
+        self.mask.contains(NetworkFilterMask::ELEM_HIDE)
+    }
+
+    pub fn is_generic_block(&self) -> bool {
+        self.mask.contains(NetworkFilterMask::GENERIC_BLOCK)
+    }
+
+    /// Generic filters apply regardless of the document making the request, i.e. they have no
+    /// `$domain` option listing the documents they apply to. `$genericblock` exceptions disable
+    /// generic blocking filters, including hostname filters like `||tracker.com^`.
+    pub fn is_generic(&self) -> bool {
+        self.opt_domains.is_none()
+    }
+
     pub fn is_regex(&self) -> bool {
         self.mask.contains(NetworkFilterMask::IS_REGEX)
     }
@@ -1008,6 +1980,12 @@ impl NetworkFilter {
         !self.is_regex()
     }
 
+    /// Returns `true` if this filter's regex has already been compiled and cached. Always `false`
+    /// for filters that don't need one.
+    pub(crate) fn is_regex_compiled(&self) -> bool {
+        self.regex.read().map(|cache| cache.is_some()).unwrap_or(false)
+    }
+
     pub fn is_csp(&self) -> bool {
         self.mask.contains(NetworkFilterMask::IS_CSP)
     }
@@ -1082,6 +2060,7 @@ impl NetworkMatchable for NetworkFilter {
 // Filter parsing
 // ---------------------------------------------------------------------------
 
+#[allow(clippy::too_many_arguments)]
 fn compute_filter_id(
     csp: Option<&str>,
     mask: NetworkFilterMask,
@@ -1089,6 +2068,12 @@ fn compute_filter_id(
     hostname: Option<&str>,
     opt_domains: Option<&Vec<Hash>>,
     opt_not_domains: Option<&Vec<Hash>>,
+    redirect: Option<&str>,
+    tag: Option<&str>,
+    bug: Option<u32>,
+    ip_ranges: Option<&Vec<IpRange>>,
+    to_domains: Option<&[Arc<str>]>,
+    header: Option<&HeaderCondition>,
 ) -> Hash {
     let mut hash: Hash = (5408 * 33) ^ Hash::from(mask.bits);
 
@@ -1106,6 +2091,8 @@ fn compute_filter_id(
     };
 
     if let Some(domains) = opt_not_domains {
+        // Distinguish negated domains from included ones
+        hash = hash.wrapping_mul(33) ^ ('~' as Hash);
         for d in domains {
             hash = hash.wrapping_mul(33) ^ d;
         }
@@ -1118,14 +2105,123 @@ fn compute_filter_id(
         }
     }
 
-    if let Some(s) = hostname {
-        let chars = s.chars();
-        for c in chars {
-            hash = hash.wrapping_mul(33) ^ (c as Hash);
+    if let Some(s) = hostname {
+        let chars = s.chars();
+        for c in chars {
+            hash = hash.wrapping_mul(33) ^ (c as Hash);
+        }
+    }
+
+    if let Some(s) = redirect {
+        let chars = s.chars();
+        for c in chars {
+            hash = hash.wrapping_mul(33) ^ (c as Hash);
+        }
+    }
+
+    if let Some(s) = tag {
+        let chars = s.chars();
+        for c in chars {
+            hash = hash.wrapping_mul(33) ^ (c as Hash);
+        }
+    }
+
+    if let Some(b) = bug {
+        hash = hash.wrapping_mul(33) ^ Hash::from(b);
+    }
+
+    if let Some(ranges) = ip_ranges {
+        for c in ranges.iter().map(|range| range.to_string()).collect::<Vec<_>>().join("|").chars() {
+            hash = hash.wrapping_mul(33) ^ (c as Hash);
+        }
+    }
+
+    if let Some(to_domains) = to_domains {
+        for c in to_domains.join("|").chars() {
+            hash = hash.wrapping_mul(33) ^ (c as Hash);
+        }
+    }
+
+    if let Some(header) = header {
+        for c in header.to_string().chars() {
+            hash = hash.wrapping_mul(33) ^ (c as Hash);
+        }
+    }
+
+    hash
+}
+
+/// The maximum size in bytes of a filter's compiled regex. Larger regexes are costly to build and
+/// to match against, and are usually a sign of a malicious or broken filter.
+pub const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Removes the surrounding slashes and unnecessary escaping from a complete regex filter, e.g.
+/// `/ads\/[0-9]+/`.
+fn unescape_complete_regex(filter_str: &str) -> String {
+    filter_str[1..filter_str.len() - 1]
+        .replace("\\/", "/")
+        .replace("\\:", ":")
+}
+
+/// Rejects complete regex filters whose regex is invalid, or would exceed `REGEX_SIZE_LIMIT` once
+/// compiled. The regex is only parsed here; it is compiled lazily when first needed for matching.
+#[cfg(feature = "full-regex-handling")]
+fn check_complete_regex(pattern: &str) -> Result<(), NetworkFilterError> {
+    let hir = regex_syntax::Parser::new().parse(&unescape_complete_regex(pattern))
+        .map_err(|e| NetworkFilterError::BadRegex(e.to_string()))?;
+    if estimated_regex_size(&hir).saturating_mul(ESTIMATED_INSTRUCTION_SIZE) > REGEX_SIZE_LIMIT {
+        return Err(NetworkFilterError::BadRegex(format!("compiled regex exceeds the size limit of {} bytes", REGEX_SIZE_LIMIT)));
+    }
+    Ok(())
+}
+
+/// Roughly the size in bytes of a compiled regex instruction.
+#[cfg(feature = "full-regex-handling")]
+const ESTIMATED_INSTRUCTION_SIZE: usize = 16;
+
+/// Estimates the number of instructions a parsed regex compiles to. Counted repetitions are
+/// compiled by repeating their contents, which is what makes nested ones like `(a{100}){100}` so
+/// costly.
+#[cfg(feature = "full-regex-handling")]
+fn estimated_regex_size(hir: &regex_syntax::hir::Hir) -> usize {
+    use regex_syntax::hir::{Class, HirKind, RepetitionKind, RepetitionRange};
+
+    match hir.kind() {
+        HirKind::Empty | HirKind::Literal(_) | HirKind::Anchor(_) | HirKind::WordBoundary(_) => 1,
+        HirKind::Class(Class::Unicode(class)) => class.ranges().len(),
+        HirKind::Class(Class::Bytes(class)) => class.ranges().len(),
+        HirKind::Repetition(repetition) => {
+            let count = match repetition.kind {
+                RepetitionKind::ZeroOrOne | RepetitionKind::ZeroOrMore | RepetitionKind::OneOrMore => 1,
+                RepetitionKind::Range(RepetitionRange::Exactly(n))
+                | RepetitionKind::Range(RepetitionRange::AtLeast(n))
+                | RepetitionKind::Range(RepetitionRange::Bounded(_, n)) => n.max(1) as usize,
+            };
+            estimated_regex_size(&repetition.hir).saturating_mul(count)
+        }
+        HirKind::Group(group) => estimated_regex_size(&group.hir),
+        HirKind::Concat(hirs) | HirKind::Alternation(hirs) => {
+            hirs.iter().map(estimated_regex_size).fold(0, usize::saturating_add)
         }
     }
+}
 
-    hash
+/// The characters other than ASCII letters and digits that are not separators. `-` comes last,
+/// so that it stands for itself in `SEPARATOR_CLASS`.
+const NON_SEPARATOR_SYMBOLS: [char; 4] = ['%', '.', '_', '-'];
+
+/// The characters matched by `^` in a filter pattern, as a regex character class. See
+/// `is_separator`.
+pub(crate) static SEPARATOR_CLASS: Lazy<String> = Lazy::new(|| {
+    format!("[^0-9A-Za-z{}]", NON_SEPARATOR_SYMBOLS.iter().collect::<String>())
+});
+
+/// Whether `ch` is a separator, i.e. a character that `^` matches in a filter pattern. Separators
+/// are all characters other than ASCII letters and digits and the four characters `%`, `.`, `_`
+/// and `-`. This includes the URL delimiters `/`, `:`, `?`, `#`, `&` and `=`, and any non-ASCII
+/// character. `^` also matches the end of the URL.
+pub fn is_separator(ch: char) -> bool {
+    !(ch.is_ascii_alphanumeric() || NON_SEPARATOR_SYMBOLS.contains(&ch))
 }
 
 /// Compiles a filter pattern to a regex. This is only performed *lazily* for
@@ -1146,6 +2242,8 @@ pub fn compile_regex(
     static ANCHOR_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\^(.)").unwrap());
     // ^ can match any separator or the end of the pattern
     static ANCHOR_RE_EOL: Lazy<Regex> = Lazy::new(|| Regex::new(r"\^$").unwrap());
+    static ANCHOR_REPLACEMENT: Lazy<String> = Lazy::new(|| format!("(?:{})$1", *SEPARATOR_CLASS));
+    static ANCHOR_REPLACEMENT_EOL: Lazy<String> = Lazy::new(|| format!("(?:{}|$)", *SEPARATOR_CLASS));
 
     let filters: Vec<String> = match filter {
         FilterPart::Empty => vec![],
@@ -1161,18 +2259,13 @@ pub fn compile_regex(
         }
         if is_complete_regex {
             // unescape unrecognised escaping sequences, otherwise a normal regex
-            let unescaped = filter_str[1..filter_str.len() - 1]
-                .replace("\\/", "/")
-                .replace("\\:", ":");
-
-            escaped_patterns.push(unescaped);
+            escaped_patterns.push(unescape_complete_regex(&filter_str));
         } else {
             let repl = SPECIAL_RE.replace_all(&filter_str, "\\$1");
             let repl = WILDCARD_RE.replace_all(&repl, ".*");
-            // in adblock rules, '^' is a separator.
-            // The separator character is anything but a letter, a digit, or one of the following: _ - . %
-            let repl = ANCHOR_RE.replace_all(&repl, "(?:[^\\w\\d\\._%-])$1");
-            let repl = ANCHOR_RE_EOL.replace_all(&repl, "(?:[^\\w\\d\\._%-]|$)");
+            // in adblock rules, '^' is a separator. See `is_separator`.
+            let repl = ANCHOR_RE.replace_all(&repl, ANCHOR_REPLACEMENT.as_str());
+            let repl = ANCHOR_RE_EOL.replace_all(&repl, ANCHOR_REPLACEMENT_EOL.as_str());
 
             // Should match start or end of url
             let left_anchor = if is_left_anchor { "^" } else { "" };
@@ -1187,7 +2280,7 @@ pub fn compile_regex(
         CompiledRegex::MatchAll
     } else if escaped_patterns.len() == 1 {
         let pattern = &escaped_patterns[0];
-        match Regex::new(&pattern) {
+        match RegexBuilder::new(&pattern).size_limit(REGEX_SIZE_LIMIT).build() {
             Ok(compiled) => CompiledRegex::Compiled(compiled),
             Err(e) => {
                 // println!("Regex parsing failed ({:?})", e);
@@ -1195,7 +2288,7 @@ pub fn compile_regex(
             }
         }
     } else {
-        match RegexSet::new(escaped_patterns) {
+        match RegexSetBuilder::new(escaped_patterns).size_limit(REGEX_SIZE_LIMIT).build() {
             Ok(compiled) => CompiledRegex::CompiledSet(compiled),
             Err(e) => CompiledRegex::RegexParsingError(e),
         }
@@ -1239,6 +2332,9 @@ fn is_anchored_by_hostname(filter_hostname: &str, hostname: &str, wildcard_filte
             //   * (foo, foo.com)
             //   * (sub.foo, sub.foo.com)
             wildcard_filter_hostname || filter_hostname.ends_with('.') || hostname[filter_hostname_len..].starts_with('.')
+        } else if crate::utils::is_ip_literal(hostname) {
+            // IP addresses have no subdomains, so they can only be matched from the start.
+            false
         } else if match_index == hostname_len - filter_hostname_len {
             // `filter_hostname` is a suffix of `hostname`.
             //
@@ -1268,13 +2364,13 @@ fn get_url_after_hostname<'a>(url: &'a str, hostname: &str) -> &'a str {
 // ---------------------------------------------------------------------------
 
 // pattern
-fn check_pattern_plain_filter_filter(filter: &NetworkFilter, request: &request::Request) -> bool {
+fn check_pattern_plain_filter_filter(filter: &NetworkFilter, url: &str) -> bool {
     match &filter.filter {
         FilterPart::Empty => true,
-        FilterPart::Simple(f) => twoway::find_str(&request.url, f).is_some(),
+        FilterPart::Simple(f) => twoway::find_str(url, f).is_some(),
         FilterPart::AnyOf(filters) => {
             for f in filters {
-                if twoway::find_str(&request.url, f).is_some() {
+                if twoway::find_str(url, f).is_some() {
                     return true;
                 }
             }
@@ -1284,13 +2380,13 @@ fn check_pattern_plain_filter_filter(filter: &NetworkFilter, request: &request::
 }
 
 // pattern|
-fn check_pattern_right_anchor_filter(filter: &NetworkFilter, request: &request::Request) -> bool {
+fn check_pattern_right_anchor_filter(filter: &NetworkFilter, url: &str) -> bool {
     match &filter.filter {
         FilterPart::Empty => true,
-        FilterPart::Simple(f) => request.url.ends_with(f),
+        FilterPart::Simple(f) => url.ends_with(f),
         FilterPart::AnyOf(filters) => {
             for f in filters {
-                if request.url.ends_with(f) {
+                if url.ends_with(f) {
                     return true;
                 }
             }
@@ -1300,13 +2396,13 @@ fn check_pattern_right_anchor_filter(filter: &NetworkFilter, request: &request::
 }
 
 // |pattern
-fn check_pattern_left_anchor_filter(filter: &NetworkFilter, request: &request::Request) -> bool {
+fn check_pattern_left_anchor_filter(filter: &NetworkFilter, url: &str) -> bool {
     match &filter.filter {
         FilterPart::Empty => true,
-        FilterPart::Simple(f) => request.url.starts_with(f),
+        FilterPart::Simple(f) => url.starts_with(f),
         FilterPart::AnyOf(filters) => {
             for f in filters {
-                if request.url.starts_with(f) {
+                if url.starts_with(f) {
                     return true;
                 }
             }
@@ -1318,14 +2414,14 @@ fn check_pattern_left_anchor_filter(filter: &NetworkFilter, request: &request::R
 // |pattern|
 fn check_pattern_left_right_anchor_filter(
     filter: &NetworkFilter,
-    request: &request::Request,
+    url: &str,
 ) -> bool {
     match &filter.filter {
         FilterPart::Empty => true,
-        FilterPart::Simple(f) => &request.url == f,
+        FilterPart::Simple(f) => url == f,
         FilterPart::AnyOf(filters) => {
             for f in filters {
-                if &request.url == f {
+                if url == f {
                     return true;
                 }
             }
@@ -1337,21 +2433,22 @@ fn check_pattern_left_right_anchor_filter(
 // pattern*^
 fn check_pattern_regex_filter_at(
     filter: &NetworkFilter,
-    request: &request::Request,
+    url: &str,
     start_from: usize,
 ) -> bool {
     let regex = filter.get_regex();
-    regex.is_match(&request.url[start_from..])
+    regex.is_match(&url[start_from..])
 }
 
-fn check_pattern_regex_filter(filter: &NetworkFilter, request: &request::Request) -> bool {
-    check_pattern_regex_filter_at(filter, request, 0)
+fn check_pattern_regex_filter(filter: &NetworkFilter, url: &str) -> bool {
+    check_pattern_regex_filter_at(filter, url, 0)
 }
 
 // ||pattern*^
 fn check_pattern_hostname_anchor_regex_filter(
     filter: &NetworkFilter,
     request: &request::Request,
+    url: &str,
 ) -> bool {
     filter
         .hostname
@@ -1360,8 +2457,8 @@ fn check_pattern_hostname_anchor_regex_filter(
             if is_anchored_by_hostname(hostname, &request.hostname, filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)) {
                 check_pattern_regex_filter_at(
                     filter,
-                    request,
-                    request.url.find(hostname).unwrap_or_default() + hostname.len(),
+                    url,
+                    url.find(hostname).unwrap_or_default() + hostname.len(),
                 )
             } else {
                 false
@@ -1374,6 +2471,7 @@ fn check_pattern_hostname_anchor_regex_filter(
 fn check_pattern_hostname_right_anchor_filter(
     filter: &NetworkFilter,
     request: &request::Request,
+    url: &str,
 ) -> bool {
     filter
         .hostname
@@ -1389,7 +2487,7 @@ fn check_pattern_hostname_right_anchor_filter(
                         request.hostname.len() == hostname.len()        // if lengths are equal, hostname equality is implied by anchoring check
                             || request.hostname.ends_with(hostname)
                     }
-                    _ => check_pattern_right_anchor_filter(&filter, request),
+                    _ => check_pattern_right_anchor_filter(&filter, url),
                 }
             } else {
                 false
@@ -1402,6 +2500,7 @@ fn check_pattern_hostname_right_anchor_filter(
 fn check_pattern_hostname_left_right_anchor_filter(
     filter: &NetworkFilter,
     request: &request::Request,
+    url: &str,
 ) -> bool {
     // Since this is not a regex, the filter pattern must follow the hostname
     // with nothing in between. So we extract the part of the URL following
@@ -1418,9 +2517,9 @@ fn check_pattern_hostname_left_right_anchor_filter(
                     // Since it must follow immediatly after the hostname and be a suffix of
                     // the URL, we conclude that filter must be equal to the part of the
                     // url following the hostname.
-                    FilterPart::Simple(f) => get_url_after_hostname(&request.url, hostname) == f,
+                    FilterPart::Simple(f) => get_url_after_hostname(url, hostname) == f,
                     FilterPart::AnyOf(filters) => {
-                        let url_after_hostname = get_url_after_hostname(&request.url, hostname);
+                        let url_after_hostname = get_url_after_hostname(url, hostname);
                         for f in filters {
                             if url_after_hostname == f {
                                 return true;
@@ -1441,6 +2540,7 @@ fn check_pattern_hostname_left_right_anchor_filter(
 fn check_pattern_hostname_left_anchor_filter(
     filter: &NetworkFilter,
     request: &request::Request,
+    url: &str,
 ) -> bool {
     filter
         .hostname
@@ -1454,10 +2554,10 @@ fn check_pattern_hostname_left_anchor_filter(
                     // with nothing in between. So we extract the part of the URL following
                     // after hostname and will perform the matching on it.
                     FilterPart::Simple(f) => {
-                        get_url_after_hostname(&request.url, hostname).starts_with(f)
+                        get_url_after_hostname(url, hostname).starts_with(f)
                     }
                     FilterPart::AnyOf(filters) => {
-                        let url_after_hostname = get_url_after_hostname(&request.url, hostname);
+                        let url_after_hostname = get_url_after_hostname(url, hostname);
                         for f in filters {
                             if url_after_hostname.starts_with(f) {
                                 return true;
@@ -1477,6 +2577,7 @@ fn check_pattern_hostname_left_anchor_filter(
 fn check_pattern_hostname_anchor_filter(
     filter: &NetworkFilter,
     request: &request::Request,
+    url: &str,
 ) -> bool {
     filter
         .hostname
@@ -1487,10 +2588,10 @@ fn check_pattern_hostname_anchor_filter(
                     // if no filter, we have a match
                     FilterPart::Empty => true,
                     // Filter hostname does not necessarily have to be a full, proper hostname, part of it can be lumped together with the URL
-                    FilterPart::Simple(f) => get_url_after_hostname(&request.url, hostname)
+                    FilterPart::Simple(f) => get_url_after_hostname(url, hostname)
                         .contains(f),
                     FilterPart::AnyOf(filters) => {
-                        let url_after_hostname = get_url_after_hostname(&request.url, hostname);
+                        let url_after_hostname = get_url_after_hostname(url, hostname);
                         for f in filters {
                             if url_after_hostname.contains(f) {
                                 return true;
@@ -1509,28 +2610,36 @@ fn check_pattern_hostname_anchor_filter(
 /// Efficiently checks if a certain network filter matches against a network
 /// request.
 fn check_pattern(filter: &NetworkFilter, request: &request::Request) -> bool {
+    // Only the part of the URL following the hostname is case-sensitive for `$match-case`, and
+    // hostnames are always stored in lowercase.
+    let url = if filter.match_case() {
+        request.url_case_sensitive()
+    } else {
+        &request.url
+    };
+
     if filter.is_hostname_anchor() {
         if filter.is_regex() {
-            check_pattern_hostname_anchor_regex_filter(filter, request)
+            check_pattern_hostname_anchor_regex_filter(filter, request, url)
         } else if filter.is_right_anchor() && filter.is_left_anchor() {
-            check_pattern_hostname_left_right_anchor_filter(filter, request)
+            check_pattern_hostname_left_right_anchor_filter(filter, request, url)
         } else if filter.is_right_anchor() {
-            check_pattern_hostname_right_anchor_filter(filter, request)
+            check_pattern_hostname_right_anchor_filter(filter, request, url)
         } else if filter.is_left_anchor() {
-            check_pattern_hostname_left_anchor_filter(filter, request)
+            check_pattern_hostname_left_anchor_filter(filter, request, url)
         } else {
-            check_pattern_hostname_anchor_filter(filter, request)
+            check_pattern_hostname_anchor_filter(filter, request, url)
         }
     } else if filter.is_regex() || filter.is_complete_regex() {
-        check_pattern_regex_filter(filter, request)
+        check_pattern_regex_filter(filter, url)
     } else if filter.is_left_anchor() && filter.is_right_anchor() {
-        check_pattern_left_right_anchor_filter(filter, request)
+        check_pattern_left_right_anchor_filter(filter, url)
     } else if filter.is_left_anchor() {
-        check_pattern_left_anchor_filter(filter, request)
+        check_pattern_left_anchor_filter(filter, url)
     } else if filter.is_right_anchor() {
-        check_pattern_right_anchor_filter(filter, request)
+        check_pattern_right_anchor_filter(filter, url)
     } else {
-        check_pattern_plain_filter_filter(filter, request)
+        check_pattern_plain_filter_filter(filter, url)
     }
 }
 
@@ -1539,7 +2648,9 @@ pub fn check_cpt_allowed(filter: &NetworkFilter, cpt: &request::RequestType) ->
         // TODO this is not ideal, but required to allow regexed exception rules without an
         // explicit `$document` option to apply uBO-style.
         // See also: https://github.com/uBlockOrigin/uBlock-issues/issues/1501
-        NetworkFilterMask::FROM_DOCUMENT => filter.mask.contains(NetworkFilterMask::FROM_DOCUMENT) || filter.is_exception(),
+        // Exceptions restricted to other types, like `$subdocument`, still don't apply.
+        NetworkFilterMask::FROM_DOCUMENT => filter.mask.contains(NetworkFilterMask::FROM_DOCUMENT)
+            || (filter.is_exception() && filter.mask.contains(NetworkFilterMask::FROM_NETWORK_TYPES)),
         mask => filter.mask.contains(mask),
     }
 }
@@ -1549,6 +2660,22 @@ fn check_options(filter: &NetworkFilter, request: &request::Request) -> bool {
     if filter.is_badfilter() {
         return false;
     }
+    // Neither do filters relying on an option that isn't implemented
+    if filter.has_unimplemented_option() {
+        return false;
+    }
+    // Generic blocking filters are disabled for documents with a `$genericblock` exception
+    if request.genericblock && filter.is_generic() && !filter.is_exception() && !filter.is_csp() {
+        return false;
+    }
+    // Only navigations can be blocked as documents
+    if request.request_type == request::RequestType::Document && !request.is_navigation && !filter.is_exception() && !filter.is_csp() {
+        return false;
+    }
+    // Only `$document` filters apply when checking whether a page is allowlisted
+    if request.explicit_document_only && !filter.mask.contains(NetworkFilterMask::FROM_DOCUMENT) {
+        return false;
+    }
     // We first discard requests based on type, protocol and party. This is really
     // cheap and should be done first.
     if !check_cpt_allowed(&filter, &request.request_type)
@@ -1566,17 +2693,48 @@ fn check_options(filter: &NetworkFilter, request: &request::Request) -> bool {
         return false;
     }
 
+    // The destination must be within one of the included `$to` domains, and none of the excluded
+    if let Some(to_domains) = filter.opt_to_domains.as_ref() {
+        if !to_domains.matches(&request.hostname) {
+            return false;
+        }
+    }
+
+    // The destination address must be known and within range to match `$ipaddress`
+    if let Some(ip_ranges) = filter.opt_ip_ranges.as_ref() {
+        match &request.ip_address {
+            Some(ip) if ip_ranges.iter().any(|range| range.contains(ip)) => (),
+            _ => return false,
+        }
+    }
+
+    // The response headers must be known and satisfy `$header`
+    if let Some(header) = filter.opt_header.as_ref() {
+        match &request.response_headers {
+            Some(headers) if header.matches(headers) => (),
+            _ => return false,
+        }
+    }
+
+    // Entity hashes of the source are only looked up for filters with entity domains
+    let entity_hashes = || if filter.mask.contains(NetworkFilterMask::HAS_ENTITY_DOMAINS) {
+        request.source_entity_hashes()
+    } else {
+        &[]
+    };
+
     // Source URL must be among these domains to match
     if let Some(included_domains) = filter.opt_domains.as_ref() {
         if let Some(source_hashes) = request.source_hostname_hashes.as_ref() {
+            let source_hashes = || source_hashes.iter().chain(entity_hashes());
             // If the union of included domains is recorded
             if let Some(included_domains_union) = filter.opt_domains_union {
                 // If there isn't any source hash that matches the union, there's no match at all
-                if source_hashes.iter().all(|h| h & included_domains_union != *h) {
+                if source_hashes().all(|h| h & included_domains_union != *h) {
                     return false
                 }
             }
-            if source_hashes.iter().all(|h| !utils::bin_lookup(&included_domains, *h)) {
+            if source_hashes().all(|h| !utils::bin_lookup(&included_domains, *h)) {
                 return false
             }
         }
@@ -1584,13 +2742,14 @@ fn check_options(filter: &NetworkFilter, request: &request::Request) -> bool {
 
     if let Some(excluded_domains) = filter.opt_not_domains.as_ref() {
         if let Some(source_hashes) = request.source_hostname_hashes.as_ref() {
+            let source_hashes = || source_hashes.iter().chain(entity_hashes());
             // If the union of excluded domains is recorded
             if let Some(excluded_domains_union) = filter.opt_not_domains_union {
                 // If there's any source hash that matches the union, check the actual values
-                if source_hashes.iter().any(|h| (h & excluded_domains_union == *h) && utils::bin_lookup(&excluded_domains, *h)) {
+                if source_hashes().any(|h| (h & excluded_domains_union == *h) && utils::bin_lookup(&excluded_domains, *h)) {
                     return false
                 }
-            } else if source_hashes.iter().any(|h| utils::bin_lookup(&excluded_domains, *h)) {
+            } else if source_hashes().any(|h| utils::bin_lookup(&excluded_domains, *h)) {
                 return false
             }
         }
@@ -2166,6 +3325,182 @@ mod parse_tests {
         }
     }
 
+    #[test]
+    fn filter_id_is_canonical() {
+        let id = |rule: &str| NetworkFilter::parse(rule, true, Default::default()).unwrap().filter_id();
+
+        // Option order and domain order should not matter
+        assert_eq!(
+            id("||foo.com^$script,third-party,domain=b.com|a.com|~c.com"),
+            id("||foo.com^$domain=~c.com|a.com|b.com,third-party,script"),
+        );
+        assert_eq!(id("/ads/*$image,script"), id("/ads/*$script,image"));
+        assert_eq!(id("||foo.com^$redirect=noop.js,script"), id("||foo.com^$script,redirect=noop.js"));
+
+        // Genuinely different rules should have different ids
+        assert_ne!(id("||foo.com^$script"), id("||foo.com^$image"));
+        assert_ne!(id("||foo.com^$script"), id("||bar.com^$script"));
+        assert_ne!(id("||foo.com^$script"), id("@@||foo.com^$script"));
+        assert_ne!(id("||foo.com^$domain=a.com"), id("||foo.com^$domain=~a.com"));
+        assert_ne!(id("||foo.com^$redirect=noop.js"), id("||foo.com^$redirect=noop.txt"));
+        assert_ne!(id("||foo.com^$tag=a"), id("||foo.com^$tag=b"));
+        assert_ne!(id("||foo.com^$csp=script-src 'none'"), id("||foo.com^$csp=worker-src 'none'"));
+
+        // The id is also what `$badfilter` uses to match filters
+        let filter = NetworkFilter::parse("||foo.com^$script,image", true, Default::default()).unwrap();
+        let badfilter = NetworkFilter::parse("||foo.com^$image,script,badfilter", true, Default::default()).unwrap();
+        assert_eq!(filter.filter_id(), badfilter.get_id_without_badfilter());
+    }
+
+    fn assert_canonical_roundtrip(rule: &str) {
+        let opts = ParseOptions { include_redirect_urls: true, ..Default::default() };
+        let filter = NetworkFilter::parse(rule, false, opts).unwrap();
+        let canonical = filter.to_canonical_string().unwrap();
+        let reparsed = NetworkFilter::parse(&canonical, false, opts)
+            .unwrap_or_else(|e| panic!("Canonical form {} of {} failed to parse: {:?}", canonical, rule, e));
+        assert_eq!(filter.filter_id(), reparsed.filter_id(), "{} was written as {}", rule, canonical);
+        assert_eq!(reparsed.to_canonical_string().unwrap(), canonical);
+    }
+
+    #[test]
+    fn decodes_options() {
+        let opts = ParseOptions { include_redirect_urls: true, ..Default::default() };
+        let filter = NetworkFilter::parse(
+            "||ads.example.com^$~xhr,~websocket,~other,~ping,~object,~media,~font,~subdocument,third-party,important,match-case,domain=a.com|~b.a.com|c.org,to=cdn.com,ipaddress=10.0.0.0/8,tag=regional,redirect=noop.js",
+            true,
+            opts,
+        ).unwrap();
+        assert_eq!(filter.options(), FilterOptions {
+            exception: false,
+            types: vec!["image", "script", "stylesheet"],
+            domains: vec!["a.com".to_owned(), "c.org".to_owned()],
+            excluded_domains: vec!["b.a.com".to_owned()],
+            to_domains: vec!["cdn.com".to_owned()],
+            first_party: false,
+            third_party: true,
+            important: true,
+            match_case: true,
+            redirect: Some("noop.js".to_owned()),
+            redirect_is_url: false,
+            is_csp: false,
+            csp: None,
+            tag: Some("regional".to_owned()),
+            bug: None,
+            ip_ranges: vec![IpRange::parse("10.0.0.0/8").unwrap()],
+            header: None,
+            generic_hide: false,
+            elem_hide: false,
+            generic_block: false,
+            badfilter: false,
+            unsupported: vec![],
+            unsupported_reason: None,
+        });
+
+        let options = NetworkFilter::parse("@@||example.com^$1p,csp=worker-src 'none',urlskip=?url", true, opts).unwrap().options();
+        assert!(options.exception);
+        assert!(options.first_party && !options.third_party);
+        assert!(options.is_csp);
+        assert_eq!(options.csp.as_deref(), Some("worker-src 'none'"));
+        assert_eq!(options.unsupported, vec!["urlskip=?url".to_owned()]);
+
+        let options = NetworkFilter::parse("||example.com^$image,redirect-url=https://cdn.com/pixel.gif", true, opts).unwrap().options();
+        assert_eq!(options.redirect.as_deref(), Some("https://cdn.com/pixel.gif"));
+        assert!(options.redirect_is_url);
+
+        let options = NetworkFilter::parse("@@||example.com^$elemhide,badfilter", true, opts).unwrap().options();
+        assert!(options.elem_hide && options.generic_hide && options.badfilter);
+    }
+
+    #[test]
+    fn canonical_string() {
+        let canonical = |rule: &str| NetworkFilter::parse(rule, false, Default::default()).unwrap().to_canonical_string().unwrap();
+
+        assert_eq!(canonical("||foo.com^"), "||foo.com^");
+        assert_eq!(canonical("||www.foo.com|"), "||foo.com^");
+        assert_eq!(canonical("||foo.com^$script,3p"), "||foo.com^$third-party,script");
+        assert_eq!(canonical("@@/ads/*$domain=b.com|~c.com|a.com,xhr,css"), "@@/ads/*$stylesheet,xmlhttprequest,domain=~c.com|a.com|b.com");
+        assert_eq!(canonical("|https://$~image,~script"), "|https://$~image,~script");
+        assert_eq!(canonical("@@*$ghide,important"), "@@*$important,generichide");
+        assert_eq!(canonical("/ads/*"), "/ads/*");
+        assert_eq!(canonical("/ads/*$all"), "/ads/*$all");
+        assert_eq!(canonical("||foo.com^$all"), "||foo.com^$all");
+    }
+
+    #[test]
+    fn canonical_string_roundtrip() {
+        let rules = [
+            "||foo.com",
+            "||foo.com^",
+            "||foo.com|",
+            "||foo.com^|",
+            "||foo.com*",
+            "||foo.com/ads",
+            "||foo.com^ads^",
+            "||ads*.foo.com/banner",
+            "||*.foo.com",
+            "|http://",
+            "|https://$image",
+            "|ws://$third-party",
+            "|http*://ads.",
+            "*",
+            "|",
+            "/banner/*/img^",
+            "|https://ads.example.com/ad.js|",
+            "@@||foo.com^$document",
+            "@@||foo.com^$all",
+            "/ads/*$all,~image",
+            "||foo.com^$popup",
+            "@@||foo.com^$generichide",
+            "||foo.com^$csp=script-src 'none'",
+            "@@||foo.com^$csp",
+            "||foo.com^$~third-party,~image,~script,~font",
+            "||foo.com^$1p,3p",
+            "||foo.com^$important,match-case,bug=123,tag=abc",
+            "||foo.com^$domain=a.com|~b.a.com,redirect=noop.js",
+            "||foo.com^$empty",
+            "||foo.com^$redirect-url=https://example.com/",
+            "||foo.com^$image,media,object,other,ping,script,stylesheet,subdocument,websocket,xmlhttprequest,font",
+            "||foo.com^$~image,~media,~object,~other,~ping,~script,~stylesheet,~subdocument,~websocket,~xmlhttprequest,~font",
+            "||foo.com^$script,badfilter",
+        ];
+        for rule in rules.iter() {
+            assert_canonical_roundtrip(rule);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "full-regex-handling")]
+    fn canonical_string_roundtrip_regex() {
+        assert_canonical_roundtrip("/^https?:\\/\\/[a-z]+\\.com\\/ads/$script");
+        assert_canonical_roundtrip("/ads/$all,~image");
+    }
+
+    #[test]
+    fn canonical_string_roundtrip_easylist() {
+        let opts = ParseOptions { include_redirect_urls: true, ..Default::default() };
+        let list = std::fs::read_to_string("data/easylist.to/easylist/easylist.txt").unwrap();
+        let mut checked = 0;
+        for line in list.lines() {
+            if let Ok(crate::lists::ParsedFilter::Network(_)) = crate::lists::parse_filter(line, false, opts) {
+                assert_canonical_roundtrip(line);
+                checked += 1;
+            }
+        }
+        assert!(checked > 10000);
+    }
+
+    #[test]
+    fn canonical_string_unavailable() {
+        // Domain names are not kept in the legacy serialization format
+        let mut filter = NetworkFilter::parse("||foo.com^$domain=a.com", false, Default::default()).unwrap();
+        filter.opt_domains_text = None;
+        assert_eq!(filter.to_canonical_string(), None);
+
+        let mut filter = NetworkFilter::parse("/ads/*", false, Default::default()).unwrap();
+        filter.filter = FilterPart::AnyOf(vec!["/ads/".to_owned(), "/banner/".to_owned()]);
+        assert_eq!(filter.to_canonical_string(), None);
+    }
+
     #[test]
     fn parses_csp() {
         {
@@ -2178,6 +3513,17 @@ mod parse_tests {
             assert_eq!(filter.is_csp(), true);
             assert_eq!(filter.csp, Some(String::from(r#"self bar """#)));
         }
+        {
+            // keeps commas within the CSP value
+            let filter = NetworkFilter::parse("||foo.com$csp=script-src 'self' a.com, b.com,third-party", true, Default::default()).unwrap();
+            assert_eq!(filter.csp.as_deref(), Some("script-src 'self' a.com, b.com"));
+            assert!(filter.third_party());
+            let filter = NetworkFilter::parse(r"||foo.com$csp=script-src a.com\,b.com,domain=bar.com", true, Default::default()).unwrap();
+            assert_eq!(filter.csp.as_deref(), Some("script-src a.com,b.com"));
+            assert_eq!(filter.opt_domains_text.as_deref(), Some(&[Arc::from("bar.com")][..]));
+            let canonical = NetworkFilter::parse(&filter.to_canonical_string().unwrap(), true, Default::default()).unwrap();
+            assert_eq!(canonical.csp, filter.csp);
+        }
         {
             // parses empty CSP
             let filter = NetworkFilter::parse("||foo.com$csp", true, Default::default()).unwrap();
@@ -2332,6 +3678,16 @@ mod parse_tests {
             let filter = NetworkFilter::parse("||foo.com$~redirect", true, Default::default());
             assert_eq!(filter.err(), Some(NetworkFilterError::NegatedRedirection));
         }
+        // parses empty as a shorthand for the built-in empty resource
+        {
+            let filter = NetworkFilter::parse("||foo.com$empty", true, Default::default()).unwrap();
+            assert_eq!(filter.redirect, Some(String::from("empty")));
+            assert!(filter.is_redirect());
+        }
+        {
+            let filter = NetworkFilter::parse("||foo.com$~empty", true, Default::default());
+            assert_eq!(filter.err(), Some(NetworkFilterError::NegatedRedirection));
+        }
         // parses redirect without a value
         {
             // Not valid
@@ -2534,6 +3890,48 @@ mod parse_tests {
         }
     }
 
+    #[test]
+    fn parses_elem_hide() {
+        assert!(matches!(
+            NetworkFilter::parse("||foo.com$elemhide", true, Default::default()),
+            Err(NetworkFilterError::GenericHideWithoutException)
+        ));
+        assert!(matches!(
+            NetworkFilter::parse("@@||foo.com$~ehide", true, Default::default()),
+            Err(NetworkFilterError::NegatedElemHide)
+        ));
+
+        let filter = NetworkFilter::parse("@@||foo.com^$elemhide", true, Default::default()).unwrap();
+        assert!(filter.is_elem_hide());
+        assert!(filter.is_generic_hide());
+        assert_eq!(filter.to_canonical_string().as_deref(), Some("@@||foo.com^$elemhide"));
+
+        let generichide = NetworkFilter::parse("@@||foo.com^$generichide", true, Default::default()).unwrap();
+        assert!(!generichide.is_elem_hide());
+        assert_ne!(filter.id, generichide.id);
+    }
+
+    #[test]
+    fn parses_generic_block() {
+        assert!(matches!(
+            NetworkFilter::parse("||foo.com$genericblock", true, Default::default()),
+            Err(NetworkFilterError::GenericBlockWithoutException)
+        ));
+        assert!(matches!(
+            NetworkFilter::parse("@@||foo.com$~genericblock", true, Default::default()),
+            Err(NetworkFilterError::NegatedGenericBlock)
+        ));
+
+        let filter = NetworkFilter::parse("@@||foo.com^$genericblock", true, Default::default()).unwrap();
+        assert!(filter.is_exception());
+        assert!(filter.is_generic_block());
+        assert!(!filter.is_generic_hide());
+
+        assert!(NetworkFilter::parse("||foo.com^", true, Default::default()).unwrap().is_generic());
+        assert!(!NetworkFilter::parse("||foo.com^$domain=bar.com", true, Default::default()).unwrap().is_generic());
+        assert!(NetworkFilter::parse("||foo.com^$domain=~bar.com", true, Default::default()).unwrap().is_generic());
+    }
+
     #[test]
     fn parses_hosts_style() {
         {
@@ -2571,13 +3969,116 @@ mod parse_tests {
         }
     }
 
+    #[test]
+    fn parses_all() {
+        let filter = NetworkFilter::parse("@@||trusted.com^$all", true, Default::default()).unwrap();
+        let mut defaults = default_network_filter_breakdown();
+        defaults.hostname = Some("trusted.com".to_string());
+        defaults.is_exception = true;
+        defaults.is_plain = true;
+        defaults.is_hostname_anchor = true;
+        defaults.is_right_anchor = true;
+        defaults.from_document = true;
+        assert_eq!(defaults, NetworkFilterBreakdown::from(&filter));
+
+        // `$all` includes `document` even for filters that would not match documents implicitly
+        let filter = NetworkFilter::parse("/ads/*$all", true, Default::default()).unwrap();
+        assert!(filter.mask.contains(NetworkFilterMask::FROM_ALL_TYPES));
+
+        assert!(NetworkFilter::parse("||foo.com^$~all", true, Default::default()).is_err());
+        assert!(NetworkFilter::parse("||foo.com^$all,csp=script-src 'none'", true, Default::default()).is_err());
+    }
+
+    #[test]
+    fn parses_popup() {
+        let filter = NetworkFilter::parse("||ads.net^$popup,third-party", true, Default::default()).unwrap();
+        assert_eq!(filter.get_cpt_mask(), NetworkFilterMask::FROM_POPUP);
+        assert_eq!(filter.to_canonical_string(), Some("||ads.net^$third-party,popup".to_owned()));
+
+        let request = |request_type: &str| request::Request::from_urls("https://ads.net/", "https://example.com", request_type).unwrap();
+        assert!(filter.matches(&request("popup")));
+        assert!(!filter.matches(&request("document")));
+        assert!(!filter.matches(&request("script")));
+
+        // Neither implicit nor `$all` types include popups
+        assert!(!NetworkFilter::parse("||ads.net^", true, Default::default()).unwrap().matches(&request("popup")));
+        assert!(!NetworkFilter::parse("||ads.net^$all", true, Default::default()).unwrap().matches(&request("popup")));
+
+        let filter = NetworkFilter::parse("/ads/*$script,popup", true, Default::default()).unwrap();
+        assert_eq!(filter.get_cpt_mask(), NetworkFilterMask::FROM_SCRIPT | NetworkFilterMask::FROM_POPUP);
+
+        assert_eq!(
+            NetworkFilter::parse("||ads.net^$~popup", true, Default::default()).err(),
+            Some(NetworkFilterError::NegatedPopup),
+        );
+    }
+
+    #[test]
+    fn subtracts_negated_types_from_all() {
+        let matches = |filter: &NetworkFilter, url: &str, request_type: &str| {
+            let request = request::Request::from_urls(url, "https://example.org", request_type).unwrap();
+            filter.matches(&request)
+        };
+
+        let filter = NetworkFilter::parse("/ads/*$all,~image", true, Default::default()).unwrap();
+        assert_eq!(filter.get_cpt_mask(), NetworkFilterMask::FROM_ALL_TYPES - NetworkFilterMask::FROM_IMAGE);
+        assert!(matches(&filter, "https://example.com/ads/script.js", "script"));
+        assert!(matches(&filter, "https://example.com/ads/", "document"));
+        assert!(!matches(&filter, "https://example.com/ads/banner.png", "image"));
+
+        // The order of the options doesn't matter
+        let filter = NetworkFilter::parse("/ads/*$~image,all", true, Default::default()).unwrap();
+        assert_eq!(filter.get_cpt_mask(), NetworkFilterMask::FROM_ALL_TYPES - NetworkFilterMask::FROM_IMAGE);
+
+        let filter = NetworkFilter::parse("||example.com^$all,~document", true, Default::default()).unwrap();
+        assert_eq!(filter.get_cpt_mask(), NetworkFilterMask::FROM_NETWORK_TYPES);
+        assert!(matches(&filter, "https://example.com/app.js", "script"));
+        assert!(!matches(&filter, "https://example.com/", "document"));
+        assert_eq!(filter.to_canonical_string(), Some("||example.com^".to_owned()));
+
+        assert_eq!(
+            NetworkFilter::parse("||example.com^$~document", true, Default::default()).err(),
+            Some(NetworkFilterError::NegatedDocument),
+        );
+    }
+
+    #[test]
+    fn type_masks_share_all_types() {
+        let types = |filter: &str| NetworkFilter::parse(filter, true, Default::default()).unwrap().get_cpt_mask();
+
+        assert_eq!(types("/ads/*$all"), NetworkFilterMask::FROM_ALL_TYPES);
+        assert_eq!(types("/ads/*$~script"), NetworkFilterMask::FROM_NETWORK_TYPES - NetworkFilterMask::FROM_SCRIPT);
+        assert_eq!(types("/ads/*"), NetworkFilterMask::FROM_NETWORK_TYPES);
+        assert_eq!(types("||foo.com^"), NetworkFilterMask::FROM_NETWORK_TYPES);
+        assert_eq!(types("||foo.com^$~script"), NetworkFilterMask::FROM_NETWORK_TYPES - NetworkFilterMask::FROM_SCRIPT);
+    }
+
+    #[test]
+    fn parses_inline_options() {
+        let filter = NetworkFilter::parse("||example.com^$inline-script", true, Default::default()).unwrap();
+        assert!(filter.is_csp());
+        assert_eq!(filter.csp.as_deref(), Some(INLINE_SCRIPT_CSP));
+        assert!(filter.mask.contains(NetworkFilterMask::FROM_DOCUMENT));
+
+        let filter = NetworkFilter::parse("@@||example.com^$inline-font", true, Default::default()).unwrap();
+        assert!(filter.is_csp());
+        assert!(filter.is_exception());
+        assert_eq!(filter.csp.as_deref(), Some(INLINE_FONT_CSP));
+
+        assert_eq!(
+            NetworkFilter::parse("||example.com^$~inline-script", true, Default::default()).err(),
+            Some(NetworkFilterError::NegatedInlineOption),
+        );
+        assert_eq!(
+            NetworkFilter::parse("||example.com^$inline-script,script", true, Default::default()).err(),
+            Some(NetworkFilterError::CspWithContentType),
+        );
+    }
+
     #[test]
     fn handles_unsupported_options() {
         let options = vec![
-            "genericblock",
-            "inline-script",
             "popunder",
-            "popup",
             "woot",
         ];
 
@@ -2587,6 +4088,206 @@ mod parse_tests {
         }
     }
 
+    #[test]
+    fn handles_platform_unsupported_options() {
+        let options = vec![
+            "network",
+            "app=org.example.app",
+            "client=127.0.0.1",
+            "ctag=device_pc",
+            "dnstype=AAAA",
+            "dnsrewrite=1.2.3.4",
+            "extension",
+            "~extension",
+            "stealth",
+        ];
+
+        for option in options {
+            let filter = NetworkFilter::parse(&format!("||foo.com${}", option), true, Default::default()).unwrap();
+            assert_eq!(filter.unsupported_options, Some(vec![option.to_owned()]));
+            assert_eq!(filter.unsupported_reason, Some(UnsupportedReason::Platform), "{}", option);
+            let request = request::Request::from_urls("https://foo.com/ad.js", "https://example.org", "script").unwrap();
+            assert!(!filter.matches(&request), "{}", option);
+        }
+
+        // The most severe reason is recorded
+        let filter = NetworkFilter::parse("||foo.com$webbundle,urlskip=?url,network", true, Default::default()).unwrap();
+        assert_eq!(filter.unsupported_reason, Some(UnsupportedReason::Platform));
+        assert_eq!(filter.options().unsupported_reason, Some(UnsupportedReason::Platform));
+        let filter = NetworkFilter::parse("||foo.com$webbundle", true, Default::default()).unwrap();
+        assert_eq!(filter.unsupported_reason, Some(UnsupportedReason::UnsupportedType));
+        let filter = NetworkFilter::parse("||foo.com$script", true, Default::default()).unwrap();
+        assert_eq!(filter.unsupported_reason, None);
+    }
+
+    #[test]
+    fn records_known_unsupported_options() {
+        let filter = NetworkFilter::parse("||example.com/out$urlskip=?url,script", true, Default::default()).unwrap();
+        assert_eq!(filter.unsupported_options, Some(vec!["urlskip=?url".to_owned()]));
+        assert_eq!(filter.to_canonical_string(), Some("||example.com/out$script,urlskip=?url".to_owned()));
+
+        // The filter is kept, but never matches
+        let matches = |filter: &NetworkFilter, url: &str, request_type: &str| {
+            let request = request::Request::from_urls(url, "https://example.org", request_type).unwrap();
+            filter.matches(&request)
+        };
+        assert!(!matches(&filter, "https://example.com/out?url=https%3A%2F%2Fexample.net", "script"));
+        assert!(!matches(&filter, "https://example.com/out?url=https%3A%2F%2Fexample.net", "image"));
+
+        let filter = NetworkFilter::parse("||t.co^$urlskip=?u,document", true, Default::default()).unwrap();
+        assert!(!matches(&filter, "https://t.co/abc?u=https%3A%2F%2Fexample.net", "document"));
+        assert!(!matches(&filter, "https://t.co/abc", "script"));
+
+        let filter = NetworkFilter::parse("||example.com^", true, Default::default()).unwrap();
+        assert_eq!(filter.unsupported_options, None);
+    }
+
+    #[test]
+    fn beacon_is_an_alias_of_ping() {
+        for (beacon, ping) in [("||example.com^$beacon", "||example.com^$ping"), ("||example.com^$~beacon,third-party", "||example.com^$~ping,third-party")].iter() {
+            let beacon = NetworkFilter::parse(beacon, true, Default::default()).unwrap();
+            let ping = NetworkFilter::parse(ping, true, Default::default()).unwrap();
+            assert_eq!(beacon.mask, ping.mask);
+            assert_eq!(beacon.to_canonical_string(), ping.to_canonical_string());
+
+            for request_type in ["beacon", "ping", "image"].iter() {
+                let request = request::Request::from_urls("https://example.com/collect", "https://example.org", request_type).unwrap();
+                assert_eq!(beacon.matches(&request), ping.matches(&request), "{}", request_type);
+            }
+        }
+    }
+
+    #[test]
+    fn records_unsupported_types() {
+        let matches = |filter: &NetworkFilter, url: &str, request_type: &str| {
+            let request = request::Request::from_urls(url, "https://example.org", request_type).unwrap();
+            filter.matches(&request)
+        };
+
+        let filter = NetworkFilter::parse("||example.com^$script,webbundle,domain=example.org", true, Default::default()).unwrap();
+        assert_eq!(filter.unsupported_options, Some(vec!["webbundle".to_owned()]));
+        assert!(matches(&filter, "https://example.com/app.js", "script"));
+        assert!(!matches(&filter, "https://example.com/logo.png", "image"));
+
+        let filter = NetworkFilter::parse("||example.com^$~webbundle", true, Default::default()).unwrap();
+        assert_eq!(filter.unsupported_options, Some(vec!["~webbundle".to_owned()]));
+        assert!(matches(&filter, "https://example.com/app.js", "script"));
+        assert!(matches(&filter, "https://example.com/logo.png", "image"));
+
+        // Requests can't be identified as coming from a Web Bundle, so this never matches
+        let filter = NetworkFilter::parse("||example.com^$webbundle,third-party", true, Default::default()).unwrap();
+        assert_eq!(filter.unsupported_options, Some(vec!["webbundle".to_owned()]));
+        assert_eq!(filter.to_canonical_string(), Some("||example.com^$third-party,webbundle".to_owned()));
+        for request_type in ["script", "image", "xhr", "other", "document"].iter() {
+            assert!(!matches(&filter, "https://example.com/app.js", request_type), "{}", request_type);
+        }
+
+        assert_eq!(NetworkFilter::parse("||example.com^$webbundle,csp=script-src 'none'", true, Default::default()).err(), Some(NetworkFilterError::CspWithContentType));
+    }
+
+    #[test]
+    #[cfg(feature = "full-regex-handling")]
+    fn rejects_overly_complex_regexes() {
+        let filter = NetworkFilter::parse("/((a{100}){100}){100}/", true, Default::default());
+        assert!(matches!(filter, Err(NetworkFilterError::BadRegex(_))), "{:?}", filter);
+        let filter = NetworkFilter::parse("/\\w{1000}\\d{1000}/$script", true, Default::default());
+        assert!(matches!(filter, Err(NetworkFilterError::BadRegex(_))), "{:?}", filter);
+
+        let filter = NetworkFilter::parse("/^https?:\\/\\/ads\\.[a-z]{2,10}\\.com\\//$script", true, Default::default()).unwrap();
+        let request = request::Request::from_urls("https://ads.example.com/banner.js", "https://example.org", "script").unwrap();
+        assert!(filter.matches(&request));
+    }
+
+    #[test]
+    #[cfg(feature = "full-regex-handling")]
+    fn parses_complete_regexes() {
+        let filter = NetworkFilter::parse("/ads?banner/", true, Default::default()).unwrap();
+        assert!(filter.is_complete_regex());
+        let matches = |filter: &NetworkFilter, url: &str| {
+            let request = request::Request::from_urls(url, "https://example.org", "image").unwrap();
+            filter.matches(&request)
+        };
+        assert!(matches(&filter, "https://example.com/adsbanner.png"));
+        assert!(matches(&filter, "https://example.com/img/adbanner.png"));
+        // The regex is matched against the full URL
+        assert!(matches(&filter, "https://adbanner.example.com/"));
+        assert!(!matches(&filter, "https://example.com/ad-banner.png"));
+        assert!(!matches(&filter, "https://example.com/adssbanner.png"));
+
+        // Options apply as usual
+        let filter = NetworkFilter::parse("/^https:\\/\\/[a-z]+\\.example\\.com\\/ad/$script", true, Default::default()).unwrap();
+        assert!(filter.is_complete_regex());
+        assert!(!matches(&filter, "https://cdn.example.com/ad.png"));
+        let request = request::Request::from_urls("https://cdn.example.com/ad.js", "https://example.org", "script").unwrap();
+        assert!(filter.matches(&request));
+
+        // Regexes that can't be compiled are rejected
+        for rule in ["/ad{2,1}s/", "/ads(banner/", "/[z-a]ds/$image"] {
+            let filter = NetworkFilter::parse(rule, true, Default::default());
+            assert!(matches!(filter, Err(NetworkFilterError::BadRegex(_))), "{}: {:?}", rule, filter);
+        }
+    }
+
+    #[test]
+    fn parses_ipaddress_option() {
+        let filter = NetworkFilter::parse("||example.com^$ipaddress=93.184.216.34|10.0.0.0/8|2001:db8::/32", true, Default::default()).unwrap();
+        assert_eq!(filter.opt_ip_ranges.as_ref().map(|ranges| ranges.iter().map(|range| range.to_string()).collect::<Vec<_>>()),
+            Some(vec!["93.184.216.34".to_owned(), "10.0.0.0/8".to_owned(), "2001:db8::/32".to_owned()]));
+        assert_eq!(filter.to_canonical_string(), Some("||example.com^$ipaddress=93.184.216.34|10.0.0.0/8|2001:db8::/32".to_owned()));
+        assert_ne!(filter.filter_id(), NetworkFilter::parse("||example.com^", true, Default::default()).unwrap().filter_id());
+
+        assert_eq!(NetworkFilter::parse("||example.com^$~ipaddress=10.0.0.1", true, Default::default()).err(), Some(NetworkFilterError::NegatedIpAddress));
+        assert_eq!(NetworkFilter::parse("||example.com^$ipaddress=10.0.0", true, Default::default()).err(), Some(NetworkFilterError::IpAddressInvalid));
+        assert_eq!(NetworkFilter::parse("||example.com^$ipaddress=10.0.0.0/33", true, Default::default()).err(), Some(NetworkFilterError::IpAddressInvalid));
+        assert_eq!(NetworkFilter::parse("||example.com^$ipaddress=", true, Default::default()).err(), Some(NetworkFilterError::IpAddressInvalid));
+    }
+
+    #[test]
+    fn parses_header_option() {
+        let filter = NetworkFilter::parse("||example.com^$header=Via:1.1 google", true, Default::default()).unwrap();
+        assert_eq!(filter.opt_header, Some(HeaderCondition::new("via", Some("1.1 google"))));
+        assert_eq!(filter.to_canonical_string(), Some("||example.com^$header=via:1.1 google".to_owned()));
+        assert_ne!(filter.filter_id(), NetworkFilter::parse("||example.com^", true, Default::default()).unwrap().filter_id());
+        assert_ne!(filter.filter_id(), NetworkFilter::parse("||example.com^$header=via", true, Default::default()).unwrap().filter_id());
+
+        let filter = NetworkFilter::parse("*$script,header=server:/^cloud/", true, Default::default()).unwrap();
+        assert_eq!(filter.options().header, Some(HeaderCondition::new("server", Some("/^cloud/"))));
+
+        assert_eq!(NetworkFilter::parse("||example.com^$~header=via", true, Default::default()).err(), Some(NetworkFilterError::NegatedHeader));
+        assert_eq!(NetworkFilter::parse("||example.com^$header=", true, Default::default()).err(), Some(NetworkFilterError::HeaderInvalid));
+        assert_eq!(NetworkFilter::parse("||example.com^$header=:value", true, Default::default()).err(), Some(NetworkFilterError::HeaderInvalid));
+        assert_eq!(NetworkFilter::parse("||example.com^$header=server:/(/", true, Default::default()).err(), Some(NetworkFilterError::HeaderInvalid));
+    }
+
+    #[test]
+    fn parses_to_and_from_options() {
+        let filter = NetworkFilter::parse("/ads/*$from=example.com|~sub.example.com,to=~cdn.tracker.com|tracker.com", true, Default::default()).unwrap();
+        assert_eq!(filter.opt_to_domains.as_ref().map(ToDomains::domains), Some(&[Arc::from("tracker.com"), Arc::from("~cdn.tracker.com")][..]));
+        assert_eq!(filter.opt_domains, Some(vec![utils::fast_hash("example.com")]));
+        assert_eq!(filter.opt_not_domains, Some(vec![utils::fast_hash("sub.example.com")]));
+        assert_eq!(filter.to_canonical_string(), Some("/ads/*$domain=~sub.example.com|example.com,to=tracker.com|~cdn.tracker.com".to_owned()));
+        assert_eq!(filter.filter_id(), NetworkFilter::parse("/ads/*$to=tracker.com|~cdn.tracker.com,domain=example.com|~sub.example.com", true, Default::default()).unwrap().filter_id());
+        assert_ne!(filter.filter_id(), NetworkFilter::parse("/ads/*$domain=example.com|~sub.example.com", true, Default::default()).unwrap().filter_id());
+
+        assert_eq!(NetworkFilter::parse("/ads/*$~to=tracker.com", true, Default::default()).err(), Some(NetworkFilterError::NegatedTo));
+    }
+
+    #[test]
+    fn domain_interner_shrinks() {
+        let mut interner = DomainInterner::default();
+        let mut filter = NetworkFilter::parse("/ads/*$domain=site.com,to=tracker.com", true, Default::default()).unwrap();
+        let mut other = NetworkFilter::parse("/ads/*$domain=site.com|other.com", true, Default::default()).unwrap();
+        interner.intern_filter(&mut filter);
+        interner.intern_filter(&mut other);
+        assert_eq!(interner.domains.len(), 3);
+
+        // Only the domains used by the remaining filter are kept
+        drop(filter);
+        interner.shrink();
+        assert_eq!(interner.domains.len(), 2);
+        assert!(!interner.domains.contains("tracker.com"));
+    }
+
     #[test]
     fn handles_content_type_options() {
         let options = vec![
@@ -2931,6 +4632,78 @@ mod match_tests {
         filter_match_url("||s.foo.com", "https://substrings.foo.com", false);
     }
 
+    #[test]
+    // ^ matches a single separator character, or the end of the URL. Letters, digits and `_-.%`
+    // are not separators.
+    fn check_pattern_separator_works() {
+        // After a hostname
+        filter_match_url("||example.com^", "https://example.com/", true);
+        filter_match_url("||example.com^", "https://example.com", true);
+        filter_match_url("||example.com^", "https://example.com:8080/", true);
+        filter_match_url("||example.com^", "https://example.com/path?q=1", true);
+        filter_match_url("||example.com^", "https://example.com?q=1", true);
+        filter_match_url("||example.com^", "https://sub.example.com/", true);
+        filter_match_url("||example.com^", "https://example.com.evil.com/", false);
+        filter_match_url("||example.com^", "https://example.community/", false);
+        filter_match_url("||example.com^", "https://notexample.com/", false);
+        filter_match_url("||example.com^", "https://evil.com/example.com/", false);
+
+        // After a path
+        filter_match_url("||example.com/ads^", "https://example.com/ads", true);
+        filter_match_url("||example.com/ads^", "https://example.com/ads/", true);
+        filter_match_url("||example.com/ads^", "https://example.com/ads?x=1", true);
+        filter_match_url("||example.com/ads^", "https://example.com/ads&x=1", true);
+        filter_match_url("||example.com/ads^", "https://example.com/ads.js", false);
+        filter_match_url("||example.com/ads^", "https://example.com/ads-banner", false);
+        filter_match_url("||example.com/ads^", "https://example.com/ads_banner", false);
+        filter_match_url("||example.com/ads^", "https://example.com/adsbanner", false);
+        filter_match_url("||example.com/ads^", "https://example.com/ads%20", false);
+
+        // Without an anchor
+        filter_match_url("/ads^", "https://example.com/ads", true);
+        filter_match_url("/ads^", "https://example.com/ads/banner", true);
+        filter_match_url("/ads^", "https://example.com/adsbanner", false);
+        filter_match_url("^ads^", "https://example.com/x/ads/y", true);
+        filter_match_url("^ads^", "https://example.com/x/ads", true);
+        filter_match_url("^ads^", "https://example.com/xads/y", false);
+
+        // Only a single character is consumed
+        filter_match_url("||example.com^^", "https://example.com//", true);
+        filter_match_url("||example.com^ads", "https://example.com/ads", true);
+        filter_match_url("||example.com^ads", "https://example.com//ads", false);
+    }
+
+    #[test]
+    fn separator_characters() {
+        for ch in "/:?#&=;,+!$'()*@[]^|{}<>~\"\\` \u{7f}\u{0}\u{e9}\u{20ac}".chars() {
+            assert!(is_separator(ch), "{:?} should be a separator", ch);
+        }
+        for ch in "azAZ09%._-".chars() {
+            assert!(!is_separator(ch), "{:?} should not be a separator", ch);
+        }
+
+        // `^` matches exactly the characters accepted by `is_separator`
+        let separated = compile_regex(&FilterPart::Simple("/ads^x".to_owned()), false, false, false);
+        let trailing = compile_regex(&FilterPart::Simple("/ads^".to_owned()), false, false, false);
+        for ch in (0u8..=0x7f).map(char::from).chain(['\u{e9}', '\u{20ac}'].iter().copied()) {
+            let url = format!("/ads{}x", ch);
+            assert_eq!(separated.is_match(&url), is_separator(ch), "Unexpected match of /ads^x on {:?}", url);
+            assert_eq!(trailing.is_match(&url), is_separator(ch), "Unexpected match of /ads^ on {:?}", url);
+        }
+        assert!(trailing.is_match("/ads"));
+        assert!(!separated.is_match("/ads"));
+
+        // URL delimiters are treated consistently
+        for url in &["https://example.com/ads?x", "https://example.com/ads#x", "https://example.com/ads&x", "https://example.com/ads=x", "https://example.com/ads/x", "https://example.com/ads:x", "https://example.com/ads;x"] {
+            filter_match_url("||example.com/ads^", url, true);
+            filter_match_url("/ads^x", url, true);
+        }
+        for url in &["https://example.com/ads_x", "https://example.com/ads-x", "https://example.com/ads.x", "https://example.com/ads%20x", "https://example.com/adsx"] {
+            filter_match_url("||example.com/ads^", url, false);
+            filter_match_url("/ads^x", url, false);
+        }
+    }
+
     #[test]
     fn check_hosts_style_works() {
         hosts_filter_match_url("foo.com", "https://foo.com/bar", true);
@@ -2978,6 +4751,27 @@ mod match_tests {
         filter_match_url("https", "http://foo.com", false);
     }
 
+    #[test]
+    fn check_pattern_match_case_works() {
+        filter_match_url("/Banner$match-case", "https://foo.com/Banner", true);
+        filter_match_url("/Banner$match-case", "https://foo.com/banner", false);
+        filter_match_url("/Banner", "https://foo.com/Banner", true);
+        filter_match_url("/Banner", "https://foo.com/banner", true);
+
+        // Hostnames are never case-sensitive
+        filter_match_url("||foo.com/Banner$match-case", "https://foo.com/BANNER", false);
+        filter_match_url("||Foo.com/Banner$match-case", "https://foo.com/Banner", true);
+
+        // Wildcards and separators follow the same rules
+        filter_match_url("/Ads/*/Banner^$match-case", "https://foo.com/Ads/x/Banner?", true);
+        filter_match_url("/Ads/*/Banner^$match-case", "https://foo.com/ads/x/Banner?", false);
+        #[cfg(feature = "full-regex-handling")]
+        {
+            filter_match_url("/\\/AdS[0-9]\\//$match-case", "https://foo.com/AdS1/", true);
+            filter_match_url("/\\/AdS[0-9]\\//$match-case", "https://foo.com/ads1/", false);
+        }
+    }
+
     #[test]
     // |pattern|
     fn check_pattern_left_right_anchor_filter_works() {
@@ -3099,6 +4893,108 @@ mod match_tests {
         assert!(!network_filter.matches(&request::Request::from_urls("https://example.com", "https://4shared.com", "websocket").unwrap()));
     }
 
+    #[test]
+    fn check_pattern_ip_hosts_works() {
+        filter_match_url("||192.0.2.1^", "http://192.0.2.1/ads.js", true);
+        filter_match_url("||192.0.2.1^", "http://192.0.2.1:8080/ads.js", true);
+        filter_match_url("||192.0.2.1^", "http://192.0.2.10/ads.js", false);
+        filter_match_url("||192.0.2.1/ads.js", "http://192.0.2.1/ads.js", true);
+        filter_match_url("||192.0.", "http://192.0.2.1/", true);
+        // IP addresses are not split into labels like hostnames
+        filter_match_url("||0.2.1^", "http://192.0.2.1/", false);
+        filter_match_url("||2.1/ads.js", "http://192.0.2.1/ads.js", false);
+
+        filter_match_url("||[::1]^", "http://[::1]/ads.js", true);
+        filter_match_url("||[::1]^", "http://[::1]:8080/", true);
+        filter_match_url("||[2001:db8::1]^", "http://[2001:db8::1]:8080/x", true);
+        filter_match_url("||[2001:db8::1]^", "http://[2001:db8::10]/x", false);
+        filter_match_url("||db8::1]^", "http://[2001:db8::1]/x", false);
+    }
+
+    #[test]
+    fn check_to_and_from_options() {
+        let filter = NetworkFilter::parse("/ads/*$from=news.com,to=tracker.com|~cdn.tracker.com", true, Default::default()).unwrap();
+        let matches = |url: &str, source: &str| {
+            filter.matches(&request::Request::from_urls(url, source, "script").unwrap())
+        };
+
+        // Both `$from` and `$to` satisfied
+        assert!(matches("https://tracker.com/ads/1.js", "https://news.com"));
+        assert!(matches("https://a.tracker.com/ads/1.js", "https://www.news.com"));
+        // Only `$from` satisfied
+        assert!(!matches("https://other.com/ads/1.js", "https://news.com"));
+        assert!(!matches("https://cdn.tracker.com/ads/1.js", "https://news.com"));
+        assert!(!matches("https://nottracker.com/ads/1.js", "https://news.com"));
+        // Only `$to` satisfied
+        assert!(!matches("https://tracker.com/ads/1.js", "https://blog.com"));
+        // Neither satisfied
+        assert!(!matches("https://other.com/ads/1.js", "https://blog.com"));
+
+        let filter = NetworkFilter::parse("/ads/*$to=~tracker.com", true, Default::default()).unwrap();
+        assert!(filter.matches(&request::Request::from_urls("https://other.com/ads/1.js", "https://blog.com", "script").unwrap()));
+        assert!(!filter.matches(&request::Request::from_urls("https://a.tracker.com/ads/1.js", "https://blog.com", "script").unwrap()));
+
+        // Entities match under any public suffix, like in `$domain`
+        let filter = NetworkFilter::parse("/ads/*$to=tracker.*|~cdn.tracker.*", true, Default::default()).unwrap();
+        assert!(filter.matches(&request::Request::from_urls("https://tracker.co.uk/ads/1.js", "https://blog.com", "script").unwrap()));
+        assert!(filter.matches(&request::Request::from_urls("https://a.tracker.com/ads/1.js", "https://blog.com", "script").unwrap()));
+        assert!(!filter.matches(&request::Request::from_urls("https://cdn.tracker.de/ads/1.js", "https://blog.com", "script").unwrap()));
+        assert!(!filter.matches(&request::Request::from_urls("https://nottracker.com/ads/1.js", "https://blog.com", "script").unwrap()));
+    }
+
+    #[test]
+    fn check_ipaddress_option() {
+        let request = |ip: Option<&str>| {
+            let request = request::Request::from_urls("https://example.com/ad.js", "https://example.org", "script").unwrap();
+            match ip {
+                Some(ip) => request.with_ip_address(ip.parse().unwrap()),
+                None => request,
+            }
+        };
+
+        let filter = NetworkFilter::parse("||example.com^$ipaddress=93.184.216.34", true, Default::default()).unwrap();
+        assert!(filter.matches(&request(Some("93.184.216.34"))));
+        assert!(!filter.matches(&request(Some("93.184.216.35"))));
+        assert!(!filter.matches(&request(None)));
+
+        let filter = NetworkFilter::parse("||example.com^$ipaddress=192.168.0.0/16|::1", true, Default::default()).unwrap();
+        assert!(filter.matches(&request(Some("192.168.0.1"))));
+        assert!(filter.matches(&request(Some("192.168.255.255"))));
+        assert!(!filter.matches(&request(Some("192.169.0.1"))));
+        assert!(filter.matches(&request(Some("::1"))));
+        assert!(!filter.matches(&request(Some("::2"))));
+        assert!(!filter.matches(&request(None)));
+
+        let filter = NetworkFilter::parse("$ipaddress=0.0.0.0/0", true, Default::default()).unwrap();
+        assert!(filter.matches(&request(Some("8.8.8.8"))));
+        assert!(!filter.matches(&request(Some("2001:db8::1"))));
+    }
+
+    #[test]
+    fn check_header_option() {
+        let request = |headers: Option<&[(&str, &str)]>| {
+            let request = request::Request::from_urls("https://example.com/ad.js", "https://example.org", "script").unwrap();
+            match headers {
+                Some(headers) => request.with_response_headers(headers),
+                None => request,
+            }
+        };
+
+        let filter = NetworkFilter::parse("||example.com^$header=via", true, Default::default()).unwrap();
+        assert!(filter.matches(&request(Some(&[("Via", "1.1 google")]))));
+        assert!(!filter.matches(&request(Some(&[("Server", "nginx")]))));
+        assert!(!filter.matches(&request(None)));
+
+        let filter = NetworkFilter::parse("||example.com^$header=via:1.1 google", true, Default::default()).unwrap();
+        assert!(filter.matches(&request(Some(&[("Server", "nginx"), ("VIA", "1.1 Google")]))));
+        assert!(!filter.matches(&request(Some(&[("Via", "1.1 google, 1.1 proxy")]))));
+
+        let filter = NetworkFilter::parse("||example.com^$header=server:/^cloud/", true, Default::default()).unwrap();
+        assert!(filter.matches(&request(Some(&[("server", "cloudflare")]))));
+        assert!(!filter.matches(&request(Some(&[("server", "not-cloudflare")]))));
+        assert!(!filter.matches(&request(Some(&[]))));
+    }
+
     #[test]
     // options
     fn check_options_works() {
@@ -3180,6 +5076,110 @@ mod match_tests {
         }
     }
 
+    #[test]
+    fn check_mixed_domain_options() {
+        let options_match = |filter: &str, source: &str| {
+            let network_filter = NetworkFilter::parse(filter, true, Default::default()).unwrap();
+            let request = request::Request::from_urls("https://ads.net/ad.js", source, "script").unwrap();
+            check_options(&network_filter, &request)
+        };
+
+        let filter = "||ads.net^$domain=a.com|~b.a.com|c.*";
+        // Included domains and their subdomains
+        assert!(options_match(filter, "https://a.com"));
+        assert!(options_match(filter, "https://www.a.com"));
+        // Excluded subdomains of an included domain
+        assert!(!options_match(filter, "https://b.a.com"));
+        assert!(!options_match(filter, "https://x.b.a.com"));
+        // Included entity, under any public suffix
+        assert!(options_match(filter, "https://c.com"));
+        assert!(options_match(filter, "https://c.co.uk"));
+        assert!(options_match(filter, "https://www.c.de"));
+        // Domains outside of the include set
+        assert!(!options_match(filter, "https://d.com"));
+        assert!(!options_match(filter, "https://cc.com"));
+        assert!(!options_match(filter, "https://c.d.com"));
+
+        // Without any includes, everything but the excludes matches
+        let filter = "||ads.net^$domain=~b.com|~c.*";
+        assert!(options_match(filter, "https://a.com"));
+        assert!(!options_match(filter, "https://www.b.com"));
+        assert!(!options_match(filter, "https://c.org"));
+
+        // Empty entries are ignored
+        let network_filter = NetworkFilter::parse("||ads.net^$domain=a.com||~b.a.com|", true, Default::default()).unwrap();
+        assert_eq!(network_filter.opt_domains, Some(vec![utils::fast_hash("a.com")]));
+        assert_eq!(network_filter.opt_not_domains, Some(vec![utils::fast_hash("b.a.com")]));
+    }
+
+    #[test]
+    fn check_third_party_with_domain_options() {
+        let options_match = |filter: &str, url: &str, source: &str| {
+            let network_filter = NetworkFilter::parse(filter, true, Default::default()).unwrap();
+            let request = request::Request::from_urls(url, source, "script").unwrap();
+            check_options(&network_filter, &request)
+        };
+
+        // The party is that of the request relative to the document, while `$domain` only looks
+        // at the document, so both must hold independently
+        let filter = "$third-party,domain=a.com";
+        assert!(options_match(filter, "https://tracker.net/t.js", "https://a.com"));
+        assert!(options_match(filter, "https://tracker.net/t.js", "https://www.a.com"));
+        assert!(!options_match(filter, "https://a.com/t.js", "https://a.com"));
+        assert!(!options_match(filter, "https://cdn.a.com/t.js", "https://www.a.com"));
+        assert!(!options_match(filter, "https://tracker.net/t.js", "https://b.com"));
+        // A request to `a.com` is third-party on `b.com`, but `$domain` doesn't apply there
+        assert!(!options_match(filter, "https://a.com/t.js", "https://b.com"));
+
+        let filter = "$~third-party,domain=a.com";
+        assert!(options_match(filter, "https://cdn.a.com/t.js", "https://a.com"));
+        assert!(!options_match(filter, "https://tracker.net/t.js", "https://a.com"));
+        assert!(!options_match(filter, "https://b.com/t.js", "https://b.com"));
+    }
+
+    #[test]
+    fn check_document_and_subdocument_are_distinct() {
+        let options_match = |filter: &str, request_type: &str| {
+            let network_filter = NetworkFilter::parse(filter, true, Default::default()).unwrap();
+            let request = request::Request::from_urls("https://foo.com/bar", "https://example.com", request_type).unwrap();
+            check_options(&network_filter, &request)
+        };
+
+        assert!(options_match("||foo.com$subdocument", "sub_frame"));
+        assert!(!options_match("||foo.com$subdocument", "main_frame"));
+        assert!(options_match("||foo.com$document", "main_frame"));
+        assert!(!options_match("||foo.com$document", "sub_frame"));
+        assert!(options_match("||foo.com$~subdocument", "script"));
+        assert!(!options_match("||foo.com$~subdocument", "sub_frame"));
+        assert!(!options_match("||foo.com$~subdocument", "main_frame"));
+
+        assert!(options_match("@@||foo.com$subdocument", "sub_frame"));
+        assert!(!options_match("@@||foo.com$subdocument", "main_frame"));
+        assert!(options_match("@@||foo.com$document", "main_frame"));
+        assert!(!options_match("@@||foo.com$document", "sub_frame"));
+        // Exceptions without any type restriction also apply to documents
+        assert!(options_match("@@||foo.com", "main_frame"));
+        assert!(options_match("@@||foo.com", "sub_frame"));
+    }
+
+    #[test]
+    fn check_untyped_filters_skip_pseudo_types() {
+        let filter = NetworkFilter::parse("||ads.com^", true, Default::default()).unwrap();
+        assert_eq!(filter.get_cpt_mask(), NetworkFilterMask::FROM_NETWORK_TYPES);
+        assert!(!filter.mask.intersects(NetworkFilterMask::FROM_DOCUMENT | NetworkFilterMask::FROM_POPUP | NetworkFilterMask::UNMATCHED));
+
+        let options_match = |request_type: &str| {
+            let request = request::Request::from_urls("https://ads.com/", "https://example.com", request_type).unwrap();
+            check_options(&filter, &request)
+        };
+        for request_type in &["script", "image", "stylesheet", "xhr", "sub_frame", "font", "media", "websocket", "ping", "other"] {
+            assert!(options_match(request_type), "{}", request_type);
+        }
+        assert!(!options_match("main_frame"));
+        assert!(!options_match("popup"));
+        assert!(!options_match("csp_report"));
+    }
+
     #[test]
     fn check_domain_option_subsetting_works() {
         {
@@ -3249,6 +5249,47 @@ mod match_tests {
         filter_match_url("Ѥ", "https://example.com/Ѥ/foo", true);
     }
 
+    #[test]
+    fn check_unicode_domain_option() {
+        let filter = NetworkFilter::parse("||ads.com^$domain=münchen.de|~www.münchen.de", true, Default::default()).unwrap();
+        assert_eq!(filter.opt_domains_text, Some(vec![Arc::from("~www.xn--mnchen-3ya.de"), Arc::from("xn--mnchen-3ya.de")]));
+        assert!(filter.matches(&request::Request::from_urls("https://ads.com/x.js", "https://xn--mnchen-3ya.de", "script").unwrap()));
+        assert!(filter.matches(&request::Request::from_urls("https://ads.com/x.js", "https://münchen.de", "script").unwrap()));
+        assert!(!filter.matches(&request::Request::from_urls("https://ads.com/x.js", "https://www.xn--mnchen-3ya.de", "script").unwrap()));
+
+        let filter = NetworkFilter::parse("||ads.com^$domain=MÜNCHEN.*", true, Default::default()).unwrap();
+        assert_eq!(filter.opt_domains_text, Some(vec![Arc::from("xn--mnchen-3ya.*")]));
+        assert!(filter.matches(&request::Request::from_urls("https://ads.com/x.js", "https://xn--mnchen-3ya.com", "script").unwrap()));
+
+        let filter = NetworkFilter::parse("/x.js$to=münchen.de", true, Default::default()).unwrap();
+        assert!(filter.matches(&request::Request::from_urls("https://xn--mnchen-3ya.de/x.js", "https://ads.com", "script").unwrap()));
+    }
+
+    #[test]
+    fn check_max_domains() {
+        let domains = (0..1000).map(|i| format!("site{}.com", i)).collect::<Vec<_>>().join("|");
+        let opts = crate::lists::ParseOptions { max_domains: Some(100), ..Default::default() };
+
+        // Unlimited by default
+        let filter = NetworkFilter::parse(&format!("||ads.com^$domain={}", domains), true, Default::default()).unwrap();
+        assert_eq!(filter.opt_domains.map(|domains| domains.len()), Some(1000));
+
+        for option in &["domain", "from", "to"] {
+            let filter = NetworkFilter::parse(&format!("||ads.com^${}={}", option, domains), true, opts);
+            assert_eq!(filter.err(), Some(NetworkFilterError::TooManyDomains(100)), "${}", option);
+        }
+
+        // Empty entries don't count towards the limit
+        let domains = (0..100).map(|i| format!("site{}.com", i)).collect::<Vec<_>>().join("||");
+        assert!(NetworkFilter::parse(&format!("||ads.com^$domain={}|", domains), true, opts).is_ok());
+
+        // Rejected filters are skipped without affecting the rest of the list
+        let mut filter_set = crate::lists::FilterSet::new(true);
+        filter_set.add_filters(&[format!("||ads.com^$domain={}", domains.replace("||", "|") + "|extra.com"), "||ads.net^".to_owned()], opts);
+        assert_eq!(filter_set.network_filters.len(), 1);
+        assert_eq!(filter_set.network_filters[0].raw_line.as_deref(), Some("||ads.net^"));
+    }
+
     #[test]
     fn check_regex_escaping_handled() {
         // A few rules that are not correctly escaped for rust Regex
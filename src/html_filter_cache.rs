@@ -0,0 +1,174 @@
+//! Provides storage for HTML filtering rules - that is, rules that remove elements from a
+//! document's HTML source before it is parsed by the browser.
+//!
+//! The primary API exposed by this module is the `HtmlFilterCache` struct, which stores parsed
+//! `HtmlFilter`s and allows them to be queried for those relevant to a particular hostname.
+
+use crate::filters::html::HtmlFilter;
+use crate::utils::Hash;
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Default, Clone, Deserialize, Serialize)]
+pub struct HtmlFilterCache {
+    /// Rules without any hostname constraint, or constrained only by excluded hostnames.
+    generic: Vec<HtmlFilter>,
+    /// Rules constrained to at least one hostname, stored under the hash of each hostname.
+    specific: HashMap<Hash, Vec<HtmlFilter>>,
+    /// Selectors of `$@$` exceptions, stored under the hash of each hostname.
+    exceptions: HashMap<Hash, Vec<String>>,
+}
+
+impl HtmlFilterCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_rules(rules: Vec<HtmlFilter>) -> Self {
+        let mut self_ = Self::new();
+
+        for rule in rules {
+            self_.add_filter(rule);
+        }
+
+        self_
+    }
+
+    pub fn add_filter(&mut self, rule: HtmlFilter) {
+        match (rule.exception, &rule.hostnames) {
+            (true, Some(hostnames)) => {
+                for hash in hostnames {
+                    self.exceptions.entry(*hash).or_default().push(rule.selector.clone());
+                }
+            }
+            // Generic exceptions are rejected while parsing.
+            (true, None) => (),
+            (false, Some(hostnames)) => {
+                for hash in hostnames.clone() {
+                    self.specific.entry(hash).or_default().push(rule.clone());
+                }
+            }
+            (false, None) => self.generic.push(rule),
+        }
+    }
+
+    /// Adds the rules of `other` that aren't stored in this cache already.
+    pub fn merge(&mut self, other: HtmlFilterCache) {
+        for rule in other.generic {
+            if !self.generic.contains(&rule) {
+                self.generic.push(rule);
+            }
+        }
+        for (hash, rules) in other.specific {
+            let bucket = self.specific.entry(hash).or_default();
+            for rule in rules {
+                if !bucket.contains(&rule) {
+                    bucket.push(rule);
+                }
+            }
+        }
+        for (hash, selectors) in other.exceptions {
+            let bucket = self.exceptions.entry(hash).or_default();
+            for selector in selectors {
+                if !bucket.contains(&selector) {
+                    bucket.push(selector);
+                }
+            }
+        }
+    }
+
+    /// Returns the HTML filtering rules that should be applied to documents loaded from the given
+    /// hostname, after accounting for excluded hostnames and `$@$` exceptions. Each selector is
+    /// returned at most once.
+    pub fn hostname_html_filters(&self, hostname: &str) -> Vec<HtmlFilter> {
+        let domain_str = {
+            let (start, end) = crate::url_parser::get_host_domain(hostname);
+            &hostname[start..end]
+        };
+        let request_hostnames = crate::filters::cosmetic::get_hostname_hashes_from_labels(hostname, domain_str);
+
+        let excluded = |rule: &HtmlFilter| {
+            rule.not_hostnames.as_ref()
+                .map(|not_hostnames| request_hostnames.iter().any(|hash| not_hostnames.binary_search(hash).is_ok()))
+                .unwrap_or(false)
+        };
+
+        let exceptions = request_hostnames.iter()
+            .filter_map(|hash| self.exceptions.get(hash))
+            .flatten()
+            .map(String::as_str)
+            .collect::<HashSet<_>>();
+
+        let mut seen = HashSet::new();
+        self.generic.iter()
+            .chain(request_hostnames.iter().filter_map(|hash| self.specific.get(hash)).flatten())
+            .filter(|rule| !excluded(rule) && !exceptions.contains(rule.selector.as_str()))
+            .filter(|rule| seen.insert(rule.selector.as_str()))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod html_cache_tests {
+    use super::*;
+
+    fn cache_from_rules(rules: Vec<&str>) -> HtmlFilterCache {
+        let parsed_rules = rules
+            .iter()
+            .map(|r| HtmlFilter::parse(r, false).unwrap())
+            .collect::<Vec<_>>();
+
+        HtmlFilterCache::from_rules(parsed_rules)
+    }
+
+    fn selectors(filters: Vec<HtmlFilter>) -> Vec<String> {
+        let mut selectors = filters.into_iter().map(|f| f.selector).collect::<Vec<_>>();
+        selectors.sort();
+        selectors
+    }
+
+    #[test]
+    fn hostname_specific() {
+        let cache = cache_from_rules(vec![
+            r#"example.com$$script[tag-content="ads"]"#,
+            r#"other.com$$div[id="banner"]"#,
+        ]);
+
+        let filters = cache.hostname_html_filters("sub.example.com");
+        assert_eq!(filters.len(), 1);
+        assert_eq!(filters[0].tag_name, "script");
+        assert_eq!(filters[0].attribute("tag-content"), Some("ads"));
+
+        assert!(cache.hostname_html_filters("example.org").is_empty());
+    }
+
+    #[test]
+    fn generic_and_excluded_hostnames() {
+        let cache = cache_from_rules(vec![
+            "$$iframe",
+            r#"~example.com$$script[tag-content="ads"]"#,
+            r#"example.com,~sub.example.com$$div[id="banner"]"#,
+        ]);
+
+        assert_eq!(selectors(cache.hostname_html_filters("example.com")), vec![r#"div[id="banner"]"#, "iframe"]);
+        assert_eq!(selectors(cache.hostname_html_filters("sub.example.com")), vec!["iframe"]);
+        assert_eq!(selectors(cache.hostname_html_filters("example.org")), vec!["iframe", r#"script[tag-content="ads"]"#]);
+    }
+
+    #[test]
+    fn exceptions() {
+        let cache = cache_from_rules(vec![
+            "$$iframe",
+            r#"example.com,example.org$$div[id="banner"]"#,
+            "sub.example.com$@$iframe",
+            r#"example.org$@$div[id="banner"]"#,
+        ]);
+
+        assert_eq!(selectors(cache.hostname_html_filters("example.com")), vec![r#"div[id="banner"]"#, "iframe"]);
+        assert_eq!(selectors(cache.hostname_html_filters("sub.example.com")), vec![r#"div[id="banner"]"#]);
+        assert_eq!(selectors(cache.hostname_html_filters("example.org")), vec!["iframe"]);
+    }
+}
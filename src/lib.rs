@@ -1,5 +1,7 @@
 #![allow(dead_code)]
-#![forbid(unsafe_code)]
+// Unsafe code is only needed for the C ABI exposed by the `ffi` feature.
+#![cfg_attr(not(feature = "ffi"), forbid(unsafe_code))]
+#![cfg_attr(feature = "ffi", deny(unsafe_code))]
 
 // Own modules, currently everything is exposed, will need to limit
 #[doc(hidden)]
@@ -13,6 +15,10 @@ pub mod url_parser;
 pub mod engine;
 pub mod resources;
 pub mod cosmetic_filter_cache;
+pub mod html_filter_cache;
 mod data_format;
+pub mod error;
 #[cfg(feature = "content-blocking")]
 pub mod content_blocking;
+#[cfg(feature = "ffi")]
+pub mod ffi;
@@ -2,6 +2,7 @@
 
 use crate::filters::network::{NetworkFilter, NetworkFilterError};
 use crate::filters::cosmetic::{CosmeticFilter, CosmeticFilterError};
+use crate::filters::html::{HtmlFilter, HtmlFilterError};
 
 use itertools::{Either, Itertools};
 use serde::{Deserialize, Serialize};
@@ -38,17 +39,45 @@ pub struct ParseOptions {
     /// `redirect-url` option will be ignored.
     #[serde(default)]
     pub include_redirect_urls: bool,
+    /// Marks every parsed filter as belonging to the given list, so that the list can later be
+    /// turned off and on again with `Engine::set_list_enabled`. Defaults to `None`, in which case
+    /// filters are always active.
+    #[serde(default)]
+    pub list_id: Option<ListId>,
+    /// Discards cosmetic and HTML filtering rules without parsing them, for embedders that only
+    /// ever use network blocking. Defaults to `false`.
+    #[serde(default)]
+    pub skip_cosmetic_filters: bool,
+    /// Keeps hostname-specific cosmetic rules using procedural operators that aren't supported,
+    /// like `example.com##div:upward(2)`, as plain hide rules for the CSS selector before the
+    /// operator. This is a best-effort fallback for content scripts that can't evaluate procedural
+    /// operators, and such rules are marked with `CosmeticFilterMask::PROCEDURAL_FALLBACK`.
+    /// Defaults to `false`, in which case these rules are discarded.
+    #[serde(default)]
+    pub procedural_fallback: bool,
+    /// Rejects network filters with more than this many domains in a single `$domain`, `$from`
+    /// or `$to` option, to bound the memory used by each filter. Such filters fail to parse with
+    /// `NetworkFilterError::TooManyDomains`. Defaults to `None`, in which case there is no limit.
+    #[serde(default)]
+    pub max_domains: Option<usize>,
 }
 
 impl Default for ParseOptions {
     fn default() -> Self {
         ParseOptions {
             format: FilterFormat::Standard,
-            include_redirect_urls: false
+            include_redirect_urls: false,
+            list_id: None,
+            skip_cosmetic_filters: false,
+            procedural_fallback: false,
+            max_domains: None,
         }
     }
 }
 
+/// Identifies the filter list that a filter was parsed from. See `ParseOptions::list_id`.
+pub type ListId = u32;
+
 #[cfg(feature = "content-blocking")]
 impl Default for RuleTypes {
     fn default() -> Self {
@@ -75,6 +104,76 @@ impl RuleTypes {
     }
 }
 
+/// Subscription information declared in the header of a filter list, using comments of the form
+/// `! Title: EasyList`. Missing fields are left as `None`.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FilterSetMetadata {
+    /// `! Title:`
+    pub title: Option<String>,
+    /// `! Homepage:`
+    pub homepage: Option<String>,
+    /// `! Expires:`, e.g. `4 days (update frequency)`. The value is kept as written.
+    pub expires: Option<String>,
+    /// `! Version:`
+    pub version: Option<String>,
+}
+
+impl FilterSetMetadata {
+    /// Records the value of a `! Key: value` metadata comment, if `line` is one with a recognized
+    /// key. Other directives like `! Redirect:` or `! Checksum:` are ignored.
+    fn parse_line(&mut self, line: &str) {
+        let (key, value) = match line.strip_prefix('!').and_then(|comment| comment.split_once(':')) {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => return,
+        };
+        if value.is_empty() {
+            return;
+        }
+
+        let field = match key.to_ascii_lowercase().as_str() {
+            "title" => &mut self.title,
+            "homepage" => &mut self.homepage,
+            "expires" => &mut self.expires,
+            "version" => &mut self.version,
+            _ => return,
+        };
+        if field.is_none() {
+            *field = Some(String::from(value));
+        }
+    }
+}
+
+/// Content hashes of the filter lists an `Engine` was built from, in the order they were added.
+/// Embedders can store this alongside a serialized engine with `Engine::set_list_fingerprint` to
+/// tell whether it is stale once the lists have been updated.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListFingerprint {
+    list_hashes: Vec<crate::utils::Hash>,
+}
+
+impl ListFingerprint {
+    /// Hashes the full text of each filter list.
+    pub fn from_lists<S: AsRef<str>>(lists: &[S]) -> Self {
+        Self {
+            list_hashes: lists.iter().map(|list| crate::utils::fast_hash(list.as_ref())).collect(),
+        }
+    }
+
+    pub fn list_hashes(&self) -> &[crate::utils::Hash] {
+        &self.list_hashes
+    }
+}
+
+/// Progress of adding a collection of rules to a `FilterSet`, as reported to the callback of
+/// `FilterSet::add_filters_with_progress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseProgress {
+    /// Lines processed so far, including comments and lines that couldn't be parsed.
+    pub lines_processed: usize,
+    /// Rules that were parsed successfully and added so far.
+    pub rules_added: usize,
+}
+
 /// Manages a set of rules to be added to an `Engine`.
 ///
 /// To be able to efficiently handle special options like `$badfilter`, and to allow optimizations,
@@ -85,6 +184,7 @@ pub struct FilterSet {
     debug: bool,
     pub(crate) network_filters: Vec<NetworkFilter>,
     pub(crate) cosmetic_filters: Vec<CosmeticFilter>,
+    pub(crate) html_filters: Vec<HtmlFilter>,
 }
 
 impl Default for FilterSet {
@@ -110,22 +210,81 @@ impl FilterSet {
             debug,
             network_filters: Vec::new(),
             cosmetic_filters: Vec::new(),
+            html_filters: Vec::new(),
+        }
+    }
+
+    /// Like `new`, but reserves space for at least `network_hint` network filters and
+    /// `cosmetic_hint` cosmetic filters, to avoid repeatedly growing storage while adding large
+    /// lists.
+    pub fn with_capacity(debug: bool, network_hint: usize, cosmetic_hint: usize) -> Self {
+        Self {
+            debug,
+            network_filters: Vec::with_capacity(network_hint),
+            cosmetic_filters: Vec::with_capacity(cosmetic_hint),
+            html_filters: Vec::new(),
         }
     }
 
     /// Adds the contents of an entire filter list to this `FilterSet`. Filters that cannot be
     /// parsed successfully are ignored.
     pub fn add_filter_list(&mut self, filter_list: &str, opts: ParseOptions) {
-        let rules = filter_list.lines().map(str::to_string).collect::<Vec<_>>();
-        self.add_filters(&rules, opts);
+        self.add_filter_list_with_metadata(filter_list, opts);
+    }
+
+    /// Like `add_filter_list`, but returns any metadata declared in the list's header, i.e. the
+    /// comments and `[Adblock Plus 2.0]`-style lines preceding the first rule. Metadata comments
+    /// are never parsed as filters.
+    pub fn add_filter_list_with_metadata(&mut self, filter_list: &str, opts: ParseOptions) -> FilterSetMetadata {
+        self.add_filter_list_with_progress(filter_list, opts, usize::MAX, |_| ())
+    }
+
+    /// Like `add_filter_list_with_metadata`, but reports progress like
+    /// `add_filters_with_progress`. Metadata comments in the list's header don't count as
+    /// processed lines.
+    pub fn add_filter_list_with_progress<F: FnMut(ParseProgress)>(&mut self, filter_list: &str, opts: ParseOptions, interval: usize, progress: F) -> FilterSetMetadata {
+        let mut metadata = FilterSetMetadata::default();
+        let mut in_header = true;
+        let mut rules = vec![];
+        for line in filter_list.lines() {
+            let trimmed = line.trim();
+            if in_header {
+                if trimmed.starts_with('!') {
+                    metadata.parse_line(trimmed);
+                    continue;
+                } else if !trimmed.is_empty() && !trimmed.starts_with('[') {
+                    in_header = false;
+                }
+            }
+            rules.push(line.to_string());
+        }
+        self.add_filters_with_progress(&rules, opts, interval, progress);
+        metadata
     }
 
     /// Adds a collection of filter rules to this `FilterSet`. Filters that cannot be parsed
     /// successfully are ignored.
     pub fn add_filters(&mut self, filters: &[String], opts: ParseOptions) {
-        let (mut parsed_network_filters, mut parsed_cosmetic_filters) = parse_filters(&filters, self.debug, opts);
-        self.network_filters.append(&mut parsed_network_filters);
-        self.cosmetic_filters.append(&mut parsed_cosmetic_filters);
+        for filter in filters {
+            let _ = self.add_filter(filter, opts);
+        }
+    }
+
+    /// Like `add_filters`, but calls `progress` after every `interval` lines, and after the last
+    /// one, e.g. to update a progress bar while loading a large list. An `interval` of 0 is
+    /// treated as 1.
+    pub fn add_filters_with_progress<F: FnMut(ParseProgress)>(&mut self, filters: &[String], opts: ParseOptions, interval: usize, mut progress: F) {
+        let interval = interval.max(1);
+        let mut rules_added = 0;
+        for (i, filter) in filters.iter().enumerate() {
+            if self.add_filter(filter, opts).is_ok() {
+                rules_added += 1;
+            }
+            let lines_processed = i + 1;
+            if lines_processed % interval == 0 || lines_processed == filters.len() {
+                progress(ParseProgress { lines_processed, rules_added });
+            }
+        }
     }
 
     /// Adds the string representation of a single filter rule to this `FilterSet`.
@@ -134,10 +293,77 @@ impl FilterSet {
         match filter_parsed? {
             ParsedFilter::Network(filter) => self.network_filters.push(filter),
             ParsedFilter::Cosmetic(filter) => self.cosmetic_filters.push(filter),
+            ParsedFilter::Html(filter) => self.html_filters.push(filter),
         }
         Ok(())
     }
 
+    /// Like `add_filter`, but tags the rule as if it had been written with a `$tag=` option, so
+    /// that it only applies once the tag is enabled with `Engine::use_tags` or
+    /// `Engine::enable_tags`. A `$tag=` option in the rule itself takes precedence. Cosmetic and
+    /// HTML filtering rules don't support tags, and are added as usual.
+    pub fn add_filter_with_tag(&mut self, filter: &str, opts: ParseOptions, tag: &str) -> Result<(), FilterParseError> {
+        let start = self.network_filters.len();
+        self.add_filter(filter, opts)?;
+        self.tag_network_filters_since(start, tag);
+        Ok(())
+    }
+
+    /// Like `add_filter_list`, but tags every network rule in the list. See
+    /// `add_filter_with_tag`.
+    pub fn add_filter_list_with_tag(&mut self, filter_list: &str, opts: ParseOptions, tag: &str) -> FilterSetMetadata {
+        let start = self.network_filters.len();
+        let metadata = self.add_filter_list_with_metadata(filter_list, opts);
+        self.tag_network_filters_since(start, tag);
+        metadata
+    }
+
+    /// Reports what kind of line `line` would be in a filter list, along with its parsed form if
+    /// it is a supported filter. Nothing is added to this `FilterSet`, but it is parsed in the same
+    /// debug mode.
+    pub fn classify_line(&self, line: &str, opts: ParseOptions) -> LineKind {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            return LineKind::Empty;
+        }
+        if trimmed.starts_with('!') {
+            let mut metadata = FilterSetMetadata::default();
+            metadata.parse_line(trimmed);
+            return if metadata == FilterSetMetadata::default() {
+                LineKind::Comment
+            } else {
+                LineKind::Metadata(metadata)
+            };
+        }
+        let is_comment = match opts.format {
+            FilterFormat::Standard | FilterFormat::Adguard => trimmed.starts_with("[Adblock")
+                || (trimmed.starts_with('#') && trimmed[1..].starts_with(char::is_whitespace)),
+            FilterFormat::Hosts => trimmed.starts_with('#'),
+        };
+        if is_comment {
+            return LineKind::Comment;
+        }
+
+        match parse_filter(trimmed, self.debug, opts) {
+            Ok(ParsedFilter::Network(filter)) => LineKind::Network(filter),
+            Ok(ParsedFilter::Cosmetic(filter)) => LineKind::Cosmetic(filter),
+            Ok(ParsedFilter::Html(filter)) => LineKind::Html(filter),
+            Err(error) => LineKind::Unsupported(error),
+        }
+    }
+
+    fn tag_network_filters_since(&mut self, start: usize, tag: &str) {
+        self.network_filters[start..].iter_mut()
+            .filter(|filter| filter.tag.is_none())
+            .for_each(|filter| filter.tag = Some(tag.to_string()));
+    }
+
+    /// Reports which network filters would be fused together by `Engine::from_filter_set` with
+    /// optimizations enabled, without building the engine.
+    pub fn optimization_report(&self) -> crate::optimizer::OptimizationReport {
+        crate::blocker::Blocker::optimization_report(self.network_filters.clone())
+    }
+
     /// Consumes this `FilterSet`, returning an equivalent list of content blocking rules and a
     /// corresponding new list containing the `String` representation of all filters that were
     /// successfully converted (as `FilterFormat::Standard` rules).
@@ -214,6 +440,9 @@ pub enum FilterFormat {
     /// For this option, `!` is accepted as a comment character at the beginning of a line, and `#`
     /// is accepted as a comment character anywhere in a line.
     Hosts,
+    /// Rules should be parsed in AdGuard-style format. This is the same as `Standard`, except that
+    /// HTML filtering rules like `example.com$$script[tag-content="ads"]` are also recognized.
+    Adguard,
 }
 
 /// Default to parsing lists in `Standard` format.
@@ -227,13 +456,34 @@ impl Default for FilterFormat {
 pub enum FilterType {
     Network,
     Cosmetic,
+    Html,
     NotSupported,
 }
 
+/// The kind of a single line of a filter list, as reported by `FilterSet::classify_line`.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum LineKind {
+    Network(NetworkFilter),
+    Cosmetic(CosmeticFilter),
+    Html(HtmlFilter),
+    /// A `! Key: value` comment declaring list metadata, e.g. `! Title: EasyList`. Only the
+    /// field for the key is set.
+    Metadata(FilterSetMetadata),
+    /// Any other comment, or an `[Adblock Plus 2.0]`-style header.
+    Comment,
+    Empty,
+    /// A line that is not a supported filter, either because its syntax isn't supported at all or
+    /// because it failed to parse.
+    Unsupported(FilterParseError),
+}
+
 /// Successful result of parsing a single filter rule
+#[allow(clippy::large_enum_variant)]
 pub enum ParsedFilter {
     Network(NetworkFilter),
     Cosmetic(CosmeticFilter),
+    Html(HtmlFilter),
 }
 
 impl From<NetworkFilter> for ParsedFilter {
@@ -248,11 +498,18 @@ impl From<CosmeticFilter> for ParsedFilter {
     }
 }
 
+impl From<HtmlFilter> for ParsedFilter {
+    fn from(v: HtmlFilter) -> Self {
+        ParsedFilter::Html(v)
+    }
+}
+
 /// Unsuccessful result of parsing a single filter rule.
 #[derive(Debug)]
 pub enum FilterParseError {
     Network(NetworkFilterError),
     Cosmetic(CosmeticFilterError),
+    Html(HtmlFilterError),
     Unsupported,
     Empty,
 }
@@ -269,6 +526,35 @@ impl From<CosmeticFilterError> for FilterParseError {
     }
 }
 
+impl From<HtmlFilterError> for FilterParseError {
+    fn from(v: HtmlFilterError) -> Self {
+        FilterParseError::Html(v)
+    }
+}
+
+impl std::fmt::Display for FilterParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Network(e) => write!(f, "invalid network filter: {}", e),
+            Self::Cosmetic(e) => write!(f, "invalid cosmetic filter: {}", e),
+            Self::Html(e) => write!(f, "invalid HTML filter: {}", e),
+            Self::Unsupported => write!(f, "unsupported filter syntax"),
+            Self::Empty => write!(f, "empty filter"),
+        }
+    }
+}
+
+impl std::error::Error for FilterParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Network(e) => Some(e),
+            Self::Cosmetic(e) => Some(e),
+            Self::Html(e) => Some(e),
+            Self::Unsupported | Self::Empty => None,
+        }
+    }
+}
+
 /// Parse a single filter rule
 pub fn parse_filter(
     line: &str,
@@ -282,12 +568,21 @@ pub fn parse_filter(
     }
 
     match opts.format {
-        FilterFormat::Standard => {
-            match detect_filter_type(filter) {
+        FilterFormat::Standard | FilterFormat::Adguard => {
+            match detect_filter_type(filter, opts.format) {
                 FilterType::Network => NetworkFilter::parse(filter, debug, opts)
                     .map(|f| f.into())
                     .map_err(|e| e.into()),
-                FilterType::Cosmetic => CosmeticFilter::parse(filter, debug)
+                FilterType::Cosmetic | FilterType::Html if opts.skip_cosmetic_filters => {
+                    Err(FilterParseError::Unsupported)
+                }
+                FilterType::Cosmetic => CosmeticFilter::parse_with_fallback(filter, debug, opts.procedural_fallback)
+                    .map(|mut f| {
+                        f.list_id = opts.list_id;
+                        f.into()
+                    })
+                    .map_err(|e| e.into()),
+                FilterType::Html => HtmlFilter::parse(filter, debug)
                     .map(|f| f.into())
                     .map_err(|e| e.into()),
                 _ => Err(FilterParseError::Unsupported),
@@ -329,13 +624,17 @@ pub fn parse_filter(
             }
 
             NetworkFilter::parse_hosts_style(hostname, debug)
-                .map(|f| f.into())
+                .map(|mut f| {
+                    f.list_id = opts.list_id;
+                    f.into()
+                })
                 .map_err(|e| e.into())
         }
     }
 }
 
-/// Parse an entire list of filters, ignoring any errors
+/// Parse an entire list of filters, ignoring any errors. HTML filtering rules are not returned; use
+/// a `FilterSet` to include them in an `Engine`.
 pub fn parse_filters(
     list: &[String],
     debug: bool,
@@ -345,11 +644,12 @@ pub fn parse_filters(
 
     let (network_filters, cosmetic_filters): (Vec<_>, Vec<_>) = list_iter
         .map(|line| parse_filter(line, debug, opts))
-        .filter_map(Result::ok)
-        .partition_map(|filter| match filter {
-            ParsedFilter::Network(f) => Either::Left(f),
-            ParsedFilter::Cosmetic(f) => Either::Right(f),
-        });
+        .filter_map(|filter| match filter {
+            Ok(ParsedFilter::Network(f)) => Some(Either::Left(f)),
+            Ok(ParsedFilter::Cosmetic(f)) => Some(Either::Right(f)),
+            _ => None,
+        })
+        .partition_map(|filter| filter);
 
     (network_filters, cosmetic_filters)
 }
@@ -357,8 +657,9 @@ pub fn parse_filters(
 /// Given a single line, checks if this would likely be a cosmetic filter, a
 /// network filter or something that is not supported. This check is performed
 /// before calling a more specific parser to create an instance of
-/// `NetworkFilter` or `CosmeticFilter`.
-fn detect_filter_type(filter: &str) -> FilterType {
+/// `NetworkFilter` or `CosmeticFilter`. HTML filtering rules are only recognized in
+/// `FilterFormat::Adguard` lists.
+fn detect_filter_type(filter: &str, format: FilterFormat) -> FilterType {
     // Ignore comments
     if filter.len() == 1
         || filter.starts_with('!')
@@ -372,9 +673,13 @@ fn detect_filter_type(filter: &str) -> FilterType {
         return FilterType::Network;
     }
 
-    // Ignore Adguard cosmetics
-    // `$$`
-    if filter.contains("$$") {
+    // Adguard HTML filtering rules
+    // `$$` `$@$`
+    if let FilterFormat::Adguard = format {
+        if filter.contains("$$") || filter.contains("$@$") {
+            return FilterType::Html;
+        }
+    } else if filter.contains("$$") {
         return FilterType::NotSupported;
     }
 
@@ -409,6 +714,7 @@ fn detect_filter_type(filter: &str) -> FilterType {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::filters::network::{NetworkMatchable, UnsupportedReason};
 
     #[test]
     fn parse_hosts_style() {
@@ -474,6 +780,160 @@ mod tests {
         }
     }
 
+    #[test]
+    fn add_filters_with_progress() {
+        let rules = (0..25).map(|i| if i % 5 == 0 {
+            format!("! comment {}", i)
+        } else {
+            format!("||ads{}.example.com^", i)
+        }).collect::<Vec<_>>();
+
+        let mut filter_set = FilterSet::new(false);
+        let mut reports = vec![];
+        filter_set.add_filters_with_progress(&rules, Default::default(), 10, |progress| reports.push(progress));
+        assert_eq!(reports, vec![
+            ParseProgress { lines_processed: 10, rules_added: 8 },
+            ParseProgress { lines_processed: 20, rules_added: 16 },
+            ParseProgress { lines_processed: 25, rules_added: 20 },
+        ]);
+        assert_eq!(filter_set.network_filters.len(), 20);
+
+        let mut calls = 0;
+        FilterSet::new(false).add_filters_with_progress(&rules, Default::default(), 5, |_| calls += 1);
+        assert_eq!(calls, 5);
+        FilterSet::new(false).add_filters_with_progress(&rules, Default::default(), 0, |_| calls += 1);
+        assert_eq!(calls, 30);
+
+        let list = format!("! Title: Ads\n{}", rules.join("\n"));
+        let mut last = None;
+        let metadata = FilterSet::new(false).add_filter_list_with_progress(&list, Default::default(), 100, |progress| last = Some(progress));
+        assert_eq!(metadata.title.as_deref(), Some("Ads"));
+        // The leading comment is part of the header
+        assert_eq!(last, Some(ParseProgress { lines_processed: 24, rules_added: 20 }));
+    }
+
+    #[test]
+    fn platform_unsupported_rule_does_not_abort_list() {
+        let result = parse_filter("||1.2.3.4^$network", true, Default::default());
+        match result {
+            Ok(ParsedFilter::Network(filter)) => assert_eq!(filter.unsupported_reason, Some(UnsupportedReason::Platform)),
+            _ => panic!("expected a network filter"),
+        }
+
+        // The rule is kept with its reason, but never matches
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filter_list("||1.2.3.4^$network\n||ads.example.com^\nexample.com##.ad", Default::default());
+        assert_eq!(filter_set.network_filters.len(), 2);
+        assert_eq!(filter_set.network_filters[0].unsupported_options, Some(vec!["network".to_owned()]));
+        assert_eq!(filter_set.network_filters[1].raw_line.as_deref(), Some("||ads.example.com^"));
+        assert_eq!(filter_set.cosmetic_filters.len(), 1);
+        let request = crate::request::Request::from_urls("https://1.2.3.4/", "https://example.com", "script").unwrap();
+        assert!(!filter_set.network_filters[0].matches(&request));
+    }
+
+    #[test]
+    fn classify_line() {
+        let filter_set = FilterSet::new(true);
+        let classify = |line: &str| filter_set.classify_line(line, Default::default());
+
+        match classify("||ads.example.com^$script") {
+            LineKind::Network(filter) => assert_eq!(filter.raw_line.as_deref(), Some("||ads.example.com^$script")),
+            other => panic!("Expected a network filter, got {:?}", other),
+        }
+        match classify("example.com##.ad") {
+            LineKind::Cosmetic(filter) => assert_eq!(filter.selector, ".ad"),
+            other => panic!("Expected a cosmetic filter, got {:?}", other),
+        }
+        let adguard = ParseOptions { format: FilterFormat::Adguard, ..Default::default() };
+        assert!(matches!(filter_set.classify_line(r#"example.com$$script[tag-content="ads"]"#, adguard), LineKind::Html(_)));
+        assert!(matches!(classify(r#"example.com$$script[tag-content="ads"]"#), LineKind::Unsupported(FilterParseError::Unsupported)));
+        match classify("! Title: EasyList") {
+            LineKind::Metadata(metadata) => assert_eq!(metadata.title.as_deref(), Some("EasyList")),
+            other => panic!("Expected metadata, got {:?}", other),
+        }
+        assert!(matches!(classify("! Just a comment"), LineKind::Comment));
+        assert!(matches!(classify("[Adblock Plus 2.0]"), LineKind::Comment));
+        assert!(matches!(classify("# a comment"), LineKind::Comment));
+        assert!(matches!(classify("   "), LineKind::Empty));
+        assert!(matches!(classify("example.com#?#.ad:-abp-has(.sponsored)"), LineKind::Unsupported(FilterParseError::Unsupported)));
+        assert!(matches!(classify("||example.com^$~tag=a"), LineKind::Unsupported(FilterParseError::Network(_))));
+
+        let hosts = ParseOptions { format: FilterFormat::Hosts, ..Default::default() };
+        assert!(matches!(filter_set.classify_line("#comment", hosts), LineKind::Comment));
+        assert!(matches!(filter_set.classify_line("0.0.0.0 malware.com", hosts), LineKind::Network(_)));
+
+        assert!(filter_set.network_filters.is_empty());
+        assert!(filter_set.cosmetic_filters.is_empty());
+    }
+
+    #[test]
+    fn with_capacity() {
+        let mut filter_set = FilterSet::with_capacity(true, 3, 2);
+        let network_capacity = filter_set.network_filters.capacity();
+        let cosmetic_capacity = filter_set.cosmetic_filters.capacity();
+        assert!(network_capacity >= 3);
+        assert!(cosmetic_capacity >= 2);
+
+        filter_set.add_filter_list("||ads.example.com^\n/banner/*\nexample.com##.ad\n@@||example.com^$generichide\n##.sponsored", Default::default());
+        assert_eq!(filter_set.network_filters.len(), 3);
+        assert_eq!(filter_set.cosmetic_filters.len(), 2);
+        assert_eq!(filter_set.network_filters.capacity(), network_capacity);
+        assert_eq!(filter_set.cosmetic_filters.capacity(), cosmetic_capacity);
+    }
+
+    #[test]
+    fn list_metadata() {
+        let list = [
+            "[Adblock Plus 2.0]",
+            "! Version: 202010141200",
+            "! Title: EasyList",
+            "! Last modified: 14 Oct 2026 12:00 UTC",
+            "! Expires: 4 days (update frequency)",
+            "! Homepage: https://easylist.to/",
+            "! Redirect: https://easylist.to/easylist/easylist.txt",
+            "!",
+            "||ads.example.com^",
+            "! Title: Not a header",
+            "example.com##.ad",
+        ].join("\n");
+
+        let mut filter_set = FilterSet::new(true);
+        let metadata = filter_set.add_filter_list_with_metadata(&list, Default::default());
+        assert_eq!(metadata, FilterSetMetadata {
+            title: Some(String::from("EasyList")),
+            homepage: Some(String::from("https://easylist.to/")),
+            expires: Some(String::from("4 days (update frequency)")),
+            version: Some(String::from("202010141200")),
+        });
+        assert_eq!(filter_set.network_filters.len(), 1);
+        assert_eq!(filter_set.cosmetic_filters.len(), 1);
+
+        let metadata = filter_set.add_filter_list_with_metadata("||tracker.example.com^", Default::default());
+        assert_eq!(metadata, FilterSetMetadata::default());
+    }
+
+    #[test]
+    fn html_filtering_rules_are_kept() {
+        let list = "example.com$$script[tag-content=\"banner\"]\nexample.com$@$iframe\n||ads.example.com^";
+        let adguard = ParseOptions { format: FilterFormat::Adguard, ..Default::default() };
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filter_list(list, adguard);
+        assert_eq!(filter_set.network_filters.len(), 1);
+        assert_eq!(filter_set.html_filters.len(), 2);
+        assert_eq!(filter_set.html_filters[0].attribute("tag-content"), Some("banner"));
+        assert!(filter_set.html_filters[1].exception);
+
+        let (network_filters, cosmetic_filters) = parse_filters(&["example.com$$script".to_string()], true, adguard);
+        assert!(network_filters.is_empty());
+        assert!(cosmetic_filters.is_empty());
+
+        // `$$` isn't HTML filtering syntax in other formats
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filter_list(list, Default::default());
+        assert!(filter_set.html_filters.is_empty());
+        assert_eq!(filter_set.network_filters.iter().filter(|filter| filter.raw_line.as_deref() == Some("||ads.example.com^")).count(), 1);
+    }
+
     #[test]
     fn parse_filter_failed_fuzz_1() {
         let input = "Ѥ";
@@ -503,4 +963,19 @@ mod tests {
             Default::default(),
         ).is_ok());
     }
+
+    #[test]
+    fn parse_filter_procedural_fallback() {
+        let rule = "example.com##div:upward(2)";
+        assert!(parse_filter(rule, true, Default::default()).is_err());
+
+        let opts = ParseOptions { procedural_fallback: true, ..Default::default() };
+        match parse_filter(rule, true, opts) {
+            Ok(ParsedFilter::Cosmetic(filter)) => {
+                assert_eq!(filter.selector, "div");
+                assert!(filter.mask.contains(crate::filters::cosmetic::CosmeticFilterMask::PROCEDURAL_FALLBACK));
+            }
+            _ => panic!("Expected a cosmetic filter"),
+        }
+    }
 }
@@ -1,6 +1,8 @@
-use crate::filters::network::{NetworkFilter, NetworkFilterMask, FilterPart};
+use crate::filters::network::{NetworkFilter, NetworkFilterMask, FilterId, FilterPart};
 use itertools::*;
+use std::borrow::Borrow;
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 trait Optimization {
     fn fusion(&self, filters: &[NetworkFilter]) -> NetworkFilter;
@@ -28,35 +30,81 @@ pub fn optimize(filters: Vec<NetworkFilter>) -> Vec<NetworkFilter> {
     optimized
 }
 
-fn apply_optimisation<T: Optimization>(
+/// A group of filters that `optimize` fuses into a single filter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergedFilters {
+    /// `NetworkFilter::filter_id` of each of the fused filters.
+    pub ids: Vec<FilterId>,
+    /// String representation of each of the fused filters, if debug information was retained.
+    pub filters: Vec<String>,
+}
+
+/// Describes the effect of optimizations on a set of filters, as returned by
+/// `Blocker::optimization_report`. The optimizer only fuses filters together; it never drops any.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OptimizationReport {
+    pub merged: Vec<MergedFilters>,
+}
+
+/// Returns the groups of filters that `optimize` would fuse together, without fusing them. This
+/// must apply the same optimizations as `optimize`.
+pub(crate) fn fusion_groups(filters: &[&NetworkFilter]) -> Vec<MergedFilters> {
+    let simple_pattern_group = SimplePatternGroup {};
+    let (groups, _) = group_for_fusion(&simple_pattern_group, filters.to_vec());
+
+    let mut groups: Vec<_> = groups
+        .into_iter()
+        .map(|mut group| {
+            group.sort_by_key(|f| f.id);
+            MergedFilters {
+                ids: group.iter().map(|f| f.filter_id()).collect(),
+                filters: group.iter().map(|f| f.to_string()).collect(),
+            }
+        })
+        .collect();
+    groups.sort_by_key(|group| group.ids.clone());
+    groups
+}
+
+/// Splits `filters` into the groups of more than one filter that `optimization` fuses together,
+/// and the remaining filters.
+fn group_for_fusion<T: Optimization, F: Borrow<NetworkFilter>>(
     optimization: &T,
-    filters: Vec<NetworkFilter>,
-) -> (Vec<NetworkFilter>, Vec<NetworkFilter>) {
-    let (positive, mut negative): (Vec<NetworkFilter>, Vec<NetworkFilter>) =
+    filters: Vec<F>,
+) -> (Vec<Vec<F>>, Vec<F>) {
+    let (positive, mut negative): (Vec<F>, Vec<F>) =
         filters.into_iter().partition_map(|f| {
-            if optimization.select(&f) {
+            if optimization.select(f.borrow()) {
                 Either::Left(f)
             } else {
                 Either::Right(f)
             }
         });
 
-    let mut to_fuse: HashMap<String, Vec<NetworkFilter>> = HashMap::with_capacity(positive.len());
+    let mut to_fuse: HashMap<String, Vec<F>> = HashMap::with_capacity(positive.len());
     positive
         .into_iter()
-        .for_each(|f| insert_dup(&mut to_fuse, optimization.group_by_criteria(&f), f));
+        .for_each(|f| insert_dup(&mut to_fuse, optimization.group_by_criteria(f.borrow()), f));
 
-    let mut fused = Vec::with_capacity(to_fuse.len());
+    let mut groups = Vec::with_capacity(to_fuse.len());
     for (_, group) in to_fuse {
         if group.len() > 1 {
-            // println!("Fusing {} filters together", group.len());
-            fused.push(optimization.fusion(group.as_slice()));
+            groups.push(group);
         } else {
-            group.into_iter().for_each(|f| negative.push(f));
+            negative.extend(group);
         }
     }
 
-    fused.shrink_to_fit();
+    (groups, negative)
+}
+
+fn apply_optimisation<T: Optimization>(
+    optimization: &T,
+    filters: Vec<NetworkFilter>,
+) -> (Vec<NetworkFilter>, Vec<NetworkFilter>) {
+    let (groups, negative) = group_for_fusion(optimization, filters);
+
+    let fused = groups.iter().map(|group| optimization.fusion(group.as_slice())).collect();
 
     (fused, negative)
 }
@@ -105,6 +153,20 @@ impl Optimization for SimplePatternGroup {
         let is_complete_regex = filters.iter().any(|f| f.is_complete_regex());
         filter.mask.set(NetworkFilterMask::IS_COMPLETE_REGEX, is_complete_regex);
 
+        // Keep track of which pattern came from which filter
+        filter.fused_ids = if let FilterPart::AnyOf(_) = filter.filter {
+            filters
+                .iter()
+                .map(|f| match (&f.filter, &f.fused_ids) {
+                    (FilterPart::AnyOf(_), ids) => ids.clone(),
+                    _ => Some(vec![f.filter_id()]),
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(|ids| ids.concat())
+        } else {
+            None
+        };
+
         if base_filter.raw_line.is_some() {
             filter.raw_line = Some(
                 filters
@@ -118,15 +180,19 @@ impl Optimization for SimplePatternGroup {
     }
 
     fn group_by_criteria(&self, filter: &NetworkFilter) -> String {
-        format!("{:b}:{:?}", filter.mask, filter.is_complete_regex())
+        format!("{:b}:{:?}:{:?}:{:?}:{:?}:{:?}", filter.mask, filter.is_complete_regex(), filter.list_id, filter.opt_ip_ranges, filter.opt_to_domains, filter.opt_header)
     }
     fn select(&self, filter: &NetworkFilter) -> bool {
-        filter.opt_domains.is_none()
+        // A filter without a pattern would make the fused filter match everything, so the other
+        // fused filters could no longer be told apart
+        !matches!(filter.filter, FilterPart::Empty)
+            && filter.opt_domains.is_none()
             && filter.opt_not_domains.is_none()
             && !filter.is_hostname_anchor()
             && !filter.is_redirect()
             && !filter.is_csp()
             && !filter.has_bug()
+            && !filter.has_unimplemented_option()
     }
 }
 
@@ -168,6 +234,17 @@ impl Optimization for UnionDomainGroup {
             filter.opt_not_domains_union = opt_not_domains_union;
         }
 
+        // Domain names are only kept if every fused filter still has them
+        filter.opt_domains_text = filters.iter()
+            .map(|f| f.opt_domains_text.as_ref())
+            .collect::<Option<Vec<_>>>()
+            .map(|texts| {
+                let mut domains: Vec<Arc<str>> = texts.into_iter().flatten().cloned().collect();
+                domains.sort_unstable();
+                domains.dedup();
+                domains
+            });
+
 
         if base_filter.raw_line.is_some() {
             filter.raw_line = Some(
@@ -182,12 +259,13 @@ impl Optimization for UnionDomainGroup {
     }
 
     fn group_by_criteria(&self, filter: &NetworkFilter) -> String {
-        format!("{:?}:{}:{:b}:{:?}", filter.hostname.as_ref(), filter.filter.string_view().unwrap_or_default(), filter.mask, filter.redirect.as_ref())
+        format!("{:?}:{}:{:b}:{:?}:{:?}:{:?}:{:?}:{:?}", filter.hostname.as_ref(), filter.filter.string_view().unwrap_or_default(), filter.mask, filter.redirect.as_ref(), filter.list_id, filter.opt_ip_ranges, filter.opt_to_domains, filter.opt_header)
     }
 
     fn select(&self, filter: &NetworkFilter) -> bool {
         !filter.is_csp()
             && !filter.has_bug()
+            && !filter.has_unimplemented_option()
             && (filter.opt_domains.is_some() || filter.opt_not_domains.is_some())
     }
 }
@@ -315,6 +393,43 @@ mod optimization_tests_pattern_group {
         assert!(filter.matches(&Request::from_urls("https://example.com/analytics/v1/foobar", "https://foo.leadpages.net", "").unwrap()))
     }
 
+    #[test]
+    fn dry_run_reports_merged_filters() {
+        let rules = vec![
+            String::from("/static/ad-"),
+            String::from("/static/ad."),
+            String::from("||example.com^"),
+        ];
+
+        let (filters, _) = lists::parse_filters(&rules, true, Default::default());
+        let ids: Vec<_> = filters.iter().map(|f| f.filter_id()).collect();
+
+        let report = crate::blocker::Blocker::optimization_report(filters);
+        assert_eq!(report.merged.len(), 1);
+        let merged = &report.merged[0];
+        let mut expected_ids = vec![ids[0], ids[1]];
+        expected_ids.sort_unstable();
+        let mut merged_ids = merged.ids.clone();
+        merged_ids.sort_unstable();
+        assert_eq!(merged_ids, expected_ids);
+        let mut merged_filters = merged.filters.clone();
+        merged_filters.sort_unstable();
+        assert_eq!(merged_filters, vec!["/static/ad-", "/static/ad."]);
+
+        // Tagged filters are fused among themselves
+        let rules = vec![
+            String::from("/static/ad-"),
+            String::from("/static/pop-$tag=annoyances"),
+            String::from("/static/pop.$tag=annoyances"),
+        ];
+        let (filters, _) = lists::parse_filters(&rules, true, Default::default());
+        let report = crate::blocker::Blocker::optimization_report(filters);
+        assert_eq!(report.merged.len(), 1);
+        let mut merged_filters = report.merged[0].filters.clone();
+        merged_filters.sort_unstable();
+        assert_eq!(merged_filters, vec!["/static/pop-$tag=annoyances", "/static/pop.$tag=annoyances"]);
+    }
+
 }
 
 
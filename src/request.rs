@@ -3,7 +3,13 @@
 use crate::url_parser;
 use crate::utils;
 
-#[derive(Clone, PartialEq, Debug)]
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::net::IpAddr;
+
+// `repr(C)` so that it can be passed over the C ABI, see `ffi::FfiBlockerResult`.
+#[repr(C)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize)]
 pub enum RequestType {
     Beacon,
     Csp,
@@ -16,6 +22,8 @@ pub enum RequestType {
     Object,
     Other,
     Ping,
+    /// A new window or tab opened by a document, which is only matched by `$popup` filters.
+    Popup,
     Script,
     Stylesheet,
     Subdocument,
@@ -29,6 +37,8 @@ pub enum RequestError {
     HostnameParseError,
     SourceHostnameParseError,
     UnicodeDecodingError,
+    /// Ancestor origins were given without the URL of the initiating frame they contain.
+    AncestorsWithoutSource,
 }
 
 impl From<idna::Errors> for RequestError {
@@ -43,16 +53,30 @@ impl From<url::ParseError> for RequestError {
     }
 }
 
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::HostnameParseError => write!(f, "request URL has no valid hostname"),
+            Self::SourceHostnameParseError => write!(f, "source URL has no valid hostname"),
+            Self::UnicodeDecodingError => write!(f, "hostname could not be converted to punycode"),
+            Self::AncestorsWithoutSource => write!(f, "ancestor origins were given without a source URL"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
 fn cpt_match_type(cpt: &str) -> RequestType {
     match cpt {
         "beacon" => RequestType::Ping,
         "csp_report" => RequestType::Csp,
         "document" | "main_frame" => RequestType::Document,
         "font" => RequestType::Font,
-        "image" | "imageset" => RequestType::Image,
+        "favicon" | "image" | "imageset" => RequestType::Image,
         "media" => RequestType::Media,
         "object" | "object_subrequest" => RequestType::Object,
         "ping" => RequestType::Ping,
+        "popup" => RequestType::Popup,
         "script" => RequestType::Script,
         "stylesheet" => RequestType::Stylesheet,
         "sub_frame" | "subdocument" => RequestType::Subdocument,
@@ -68,6 +92,47 @@ fn cpt_match_type(cpt: &str) -> RequestType {
     }
 }
 
+/// Guesses the type of a request from the file extension of the requested resource. HTML
+/// documents are left out, since they can be loaded either as a document or a subdocument.
+fn extension_match_type(extension: &str) -> Option<RequestType> {
+    match extension {
+        "js" | "mjs" => Some(RequestType::Script),
+        "css" => Some(RequestType::Stylesheet),
+        "apng" | "avif" | "bmp" | "gif" | "ico" | "jpeg" | "jpg" | "png" | "svg" | "webp" => Some(RequestType::Image),
+        "eot" | "otf" | "ttf" | "woff" | "woff2" => Some(RequestType::Font),
+        "flac" | "m4a" | "mp3" | "mp4" | "oga" | "ogg" | "ogv" | "wav" | "webm" => Some(RequestType::Media),
+        _ => None,
+    }
+}
+
+/// Maps the value of a `Sec-Fetch-Dest` request header to a request type. Values that don't
+/// correspond to a single type, like `empty` for both `fetch` and XHR requests, are left out.
+fn fetch_dest_match_type(dest: &str) -> Option<RequestType> {
+    match dest.trim().to_ascii_lowercase().as_str() {
+        "document" => Some(RequestType::Document),
+        "frame" | "iframe" | "fencedframe" => Some(RequestType::Subdocument),
+        "script" | "worker" | "sharedworker" | "serviceworker" | "audioworklet" | "paintworklet" => Some(RequestType::Script),
+        "style" => Some(RequestType::Stylesheet),
+        "image" => Some(RequestType::Image),
+        "font" => Some(RequestType::Font),
+        "audio" | "video" | "track" => Some(RequestType::Media),
+        "embed" | "object" => Some(RequestType::Object),
+        _ => None,
+    }
+}
+
+/// Determines which frame's hostname is used when evaluating `$domain` options for requests that
+/// carry an initiator chain.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DomainMatchingMode {
+    /// Evaluate `$domain` against the frame that initiated the request. Browsers attribute
+    /// requests to their immediate initiator, so this is the mode that matches browser behavior.
+    #[default]
+    ImmediateFrame,
+    /// Evaluate `$domain` against the top-level document of the initiator chain.
+    TopFrame,
+}
+
 #[derive(Clone, Debug)]
 pub struct Request {
     pub request_type: RequestType,
@@ -83,7 +148,101 @@ pub struct Request {
 
     // mutable fields, set later
     pub bug: Option<u32>,
-    hostname_end: usize
+    /// The resolved IP address of the requested host, if known. Filters with an `$ipaddress`
+    /// option never match requests without one.
+    pub ip_address: Option<IpAddr>,
+    /// The response headers of the request as name and value pairs, once they are known. Filters
+    /// with a `$header` option never match requests without them.
+    pub response_headers: Option<Vec<(String, String)>>,
+    /// Marks a request for the target of an earlier redirect. `$redirect` and `$redirect-url`
+    /// rules are never applied to such requests, so that an embedder re-checking a redirected
+    /// request cannot end up redirecting it in a loop. Block rules still apply.
+    pub is_redirect_target: bool,
+    /// Set if the document making the request has a `$genericblock` exception, in which case
+    /// generic blocking filters don't apply to it. See `NetworkFilter::is_generic`.
+    pub genericblock: bool,
+    /// Set for requests made by a dedicated, shared or service worker rather than a document.
+    /// See `with_worker_origin`.
+    pub is_worker: bool,
+    /// Whether a `RequestType::Document` request is an actual navigation, rather than e.g. a page
+    /// fetching a document-typed resource. Only navigations can be blocked; exceptions like
+    /// `@@$document` still apply to other document requests. Set for all document requests unless
+    /// cleared with `with_navigation`, and meaningless for requests of other types.
+    pub is_navigation: bool,
+    /// Restricts a document request to filters with an explicit `$document` option, for checking
+    /// whether a whole page is allowlisted. Other exceptions may still match document requests.
+    pub(crate) explicit_document_only: bool,
+    /// The URL of the document making the request, if known, for `Engine` to look up whether it
+    /// has a `$genericblock` exception. Only a hostname is known for requests built from
+    /// hostnames, in which case this is the root URL of that hostname.
+    pub(crate) source_url: Option<String>,
+    hostname_end: usize,
+    ancestor_hostnames: Vec<String>,
+    /// `url` with the original case of everything following the hostname. Only stored if it
+    /// differs from `url`.
+    url_case_sensitive: Option<String>,
+    /// The hostname that `$domain` options are evaluated against, as hashed into
+    /// `source_hostname_hashes`.
+    domain_hostname: String,
+    // See `source_entity_hashes`.
+    source_entity_hashes: OnceCell<Vec<utils::Hash>>,
+}
+
+/// Replaces everything following `hostname_end` in the normalized `url` with the same part of
+/// `original`, if the two only differ by case.
+fn case_preserved_url(url: &str, original: &str, hostname_end: usize) -> Option<String> {
+    let tail = url.get(hostname_end..)?;
+    let original_tail = original.get(original.len().checked_sub(tail.len())?..)?;
+    if original_tail == tail || !original_tail.eq_ignore_ascii_case(tail) {
+        return None;
+    }
+    Some(format!("{}{}", &url[..hostname_end], original_tail))
+}
+
+/// The URL standing in for a source document of which only the hostname is known.
+fn hostname_source_url(source_hostname: &str) -> Option<String> {
+    if source_hostname.is_empty() {
+        None
+    } else {
+        Some(format!("https://{}/", source_hostname))
+    }
+}
+
+fn hostname_hashes(hostname: &str) -> Option<Vec<utils::Hash>> {
+    if hostname.is_empty() {
+        return None;
+    }
+    let mut hashes = Vec::with_capacity(4);
+    hashes.push(utils::fast_hash(&hostname));
+    if utils::is_ip_literal(hostname) {
+        return Some(hashes);
+    }
+    for (i, c) in hostname.char_indices() {
+        if c == '.' && i + 1 < hostname.len() {
+            hashes.push(utils::fast_hash(&hostname[i + 1..]));
+        }
+    }
+    Some(hashes)
+}
+
+/// Entities like `example.*` in `$domain` options are hashed along with their wildcard, so the
+/// same goes for each part of the hostname preceding its public suffix.
+pub(crate) fn entity_hashes(hostname: &str) -> Vec<utils::Hash> {
+    let mut hashes = Vec::new();
+    if hostname.is_empty() || utils::is_ip_literal(hostname) {
+        return hashes;
+    }
+    let (domain_start, domain_end) = url_parser::get_host_domain(hostname);
+    if let Some(suffix_dot) = hostname[domain_start..domain_end].find('.') {
+        let without_suffix = &hostname[..domain_start + suffix_dot];
+        hashes.push(utils::fast_hash(&format!("{}.*", without_suffix)));
+        for (i, c) in without_suffix.char_indices() {
+            if c == '.' {
+                hashes.push(utils::fast_hash(&format!("{}.*", &without_suffix[i + 1..])));
+            }
+        }
+    }
+    hashes
 }
 
 impl<'a> Request {
@@ -98,6 +257,12 @@ impl<'a> Request {
         &self.url[self.hostname_end..]
     }
 
+    /// Returns the request URL with its original case preserved after the hostname, as used for
+    /// `$match-case` filters. `url` itself is normalized to lowercase.
+    pub fn url_case_sensitive(&self) -> &str {
+        self.url_case_sensitive.as_deref().unwrap_or(&self.url)
+    }
+
     pub fn new(
         raw_type: &str,
         url: &str,
@@ -115,7 +280,7 @@ impl<'a> Request {
 
         let hostname_end = twoway::find_str(url, hostname).unwrap_or_else(|| url.len()) + hostname.len();
 
-        Self::from_detailed_parameters(
+        let mut request = Self::from_detailed_parameters(
             raw_type,
             url,
             schema,
@@ -123,7 +288,9 @@ impl<'a> Request {
             source_hostname,
             third_party,
             hostname_end
-        )
+        );
+        request.source_url = hostname_source_url(source_hostname);
+        request
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -162,20 +329,9 @@ impl<'a> Request {
             }
         }
 
-        let source_hostname_hashes = if !source_hostname.is_empty() {
-            let mut hashes = Vec::with_capacity(4);
-            hashes.push(utils::fast_hash(&source_hostname));
-            for (i, c) in
-                source_hostname.char_indices()
-            {
-                if c == '.' && i + 1 < source_hostname.len() {
-                    hashes.push(utils::fast_hash(&source_hostname[i + 1..]));
-                }
-            }
-            Some(hashes)
-        } else {
-            None
-        };
+        let source_hostname_hashes = hostname_hashes(source_hostname);
+        let is_navigation = request_type == RequestType::Document;
+        let domain_hostname = source_hostname.to_owned();
 
         Request {
             request_type,
@@ -188,17 +344,39 @@ impl<'a> Request {
             is_https,
             is_supported,
             bug: None,
-            hostname_end
+            ip_address: None,
+            response_headers: None,
+            is_redirect_target: false,
+            genericblock: false,
+            is_worker: false,
+            is_navigation,
+            explicit_document_only: false,
+            source_url: None,
+            hostname_end,
+            ancestor_hostnames: Vec::new(),
+            url_case_sensitive: None,
+            domain_hostname,
+            source_entity_hashes: OnceCell::new(),
         }
     }
 
+    /// Builds a request for `url`, made by the document at `source_url`, of the given type.
+    ///
+    /// A top-level navigation, i.e. a request of type `document` or `main_frame`, has no document
+    /// making it, so `source_url` may be left empty for it. It is then treated as made by the
+    /// navigated page itself: it is first-party, and `$domain` options apply to its own hostname.
     pub fn from_urls(
         url: &str,
         source_url: &str,
         request_type: &str,
     ) -> Result<Request, RequestError> {
+        let source_url = if source_url.is_empty() && cpt_match_type(request_type) == RequestType::Document {
+            url
+        } else {
+            source_url
+        };
         if let Some(parsed_url) = url_parser::parse_url(&url) {
-            if let Some(parsed_source) = url_parser::parse_url(&source_url) {
+            let mut request = if let Some(parsed_source) = url_parser::parse_source_url(&source_url, &parsed_url) {
                 let source_domain = parsed_source.domain();
 
                 let third_party = if source_domain.is_empty() {
@@ -207,7 +385,7 @@ impl<'a> Request {
                     Some(source_domain != parsed_url.domain())
                 };
 
-                Ok(Request::from_detailed_parameters(
+                Request::from_detailed_parameters(
                     request_type,
                     &parsed_url.url,
                     parsed_url.schema(),
@@ -215,9 +393,9 @@ impl<'a> Request {
                     parsed_source.hostname(),
                     third_party,
                     parsed_url.hostname_pos.1
-                ))
+                )
             } else {
-                Ok(Request::from_detailed_parameters(
+                Request::from_detailed_parameters(
                     request_type,
                     &parsed_url.url,
                     parsed_url.schema(),
@@ -225,13 +403,20 @@ impl<'a> Request {
                     "",
                     None,
                     parsed_url.hostname_pos.1
-                ))
+                )
+            };
+            request.url_case_sensitive = case_preserved_url(&request.url, url, request.hostname_end);
+            if !source_url.is_empty() {
+                request.source_url = Some(source_url.to_owned());
             }
+            Ok(request)
         } else {
             Err(RequestError::HostnameParseError)
         }
     }
 
+    /// Like `from_urls`, but with the hostnames already extracted. An empty `source_hostname` for
+    /// a top-level navigation is treated the same way as an empty source URL there.
     pub fn from_urls_with_hostname(
         url: &str,
         hostname: &str,
@@ -239,6 +424,11 @@ impl<'a> Request {
         request_type: &str,
         third_party_request: Option<bool>
     ) -> Request {
+        let source_hostname = if source_hostname.is_empty() && cpt_match_type(request_type) == RequestType::Document {
+            hostname
+        } else {
+            source_hostname
+        };
         let url_norm = url.to_ascii_lowercase();
 
         let (source_domain_start, source_domain_end) = url_parser::get_host_domain(&source_hostname);
@@ -259,7 +449,7 @@ impl<'a> Request {
             third_party_request
         };
 
-        Request::from_detailed_parameters(
+        let mut request = Request::from_detailed_parameters(
             request_type,
             &url_norm,
             &schema,
@@ -267,12 +457,354 @@ impl<'a> Request {
             &source_hostname,
             third_party,
             splitter + 2 + hostname.len()
-        )
+        );
+        request.url_case_sensitive = case_preserved_url(&request.url, url, request.hostname_end);
+        request.source_url = hostname_source_url(source_hostname);
+        request
     }
 
     pub fn from_url(url: &str) -> Result<Request, RequestError> {
-        // Used in testing - assume empty source_url and default request type
-        Self::from_urls(url, "", "")
+        // Used in testing - assume empty source_url and infer the request type
+        Ok(Self::from_urls(url, "", "")?.with_inferred_type())
+    }
+
+    /// Infers the type of a request of type `RequestType::Other` from the file extension of its
+    /// URL's path, e.g. `.js` for `RequestType::Script`. This is useful when the actual type of
+    /// the request is unknown.
+    ///
+    /// Requests of any other type are returned unchanged, so an explicitly given type always
+    /// takes precedence. Unknown or missing extensions also leave the type as it is.
+    pub fn with_inferred_type(mut self) -> Request {
+        if self.request_type == RequestType::Other {
+            let path = self.url_after_hostname();
+            let path = &path[..path.find(['?', '#']).unwrap_or(path.len())];
+            let file_name = &path[path.rfind('/').map(|i| i + 1).unwrap_or(0)..];
+            if let Some(request_type) = file_name.rfind('.').and_then(|i| extension_match_type(&file_name[i + 1..])) {
+                self.request_type = request_type;
+            }
+        }
+        self
+    }
+
+    /// Sets the type of a request of type `RequestType::Other` from the value of its
+    /// `Sec-Fetch-Dest` header, e.g. `font` for `RequestType::Font`. Like `with_inferred_type`, an
+    /// explicitly given type always takes precedence, and unknown or ambiguous values like `empty`
+    /// leave the type as it is.
+    pub fn with_fetch_dest(mut self, dest: &str) -> Request {
+        if self.request_type == RequestType::Other {
+            if let Some(request_type) = fetch_dest_match_type(dest) {
+                self.is_navigation = request_type == RequestType::Document;
+                self.request_type = request_type;
+            }
+        }
+        self
+    }
+
+    /// Marks whether a document request is a navigation. See `is_navigation`.
+    pub fn with_navigation(mut self, is_navigation: bool) -> Request {
+        self.is_navigation = is_navigation;
+        self
+    }
+
+    /// Attaches the resolved IP address of the requested host, for matching `$ipaddress` options.
+    pub fn with_ip_address(mut self, ip_address: IpAddr) -> Request {
+        self.ip_address = Some(ip_address);
+        self
+    }
+
+    /// Attaches the response headers of the request, for matching `$header` options. Embedders
+    /// check a request again with its headers once the response arrives, so that `$header` rules
+    /// and their `@@$header` exceptions can apply to it.
+    pub fn with_response_headers(mut self, headers: &[(&str, &str)]) -> Request {
+        self.response_headers = Some(headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect());
+        self
+    }
+
+    /// Marks the request as the target of an earlier redirect. See `is_redirect_target`.
+    ///
+    /// Redirects to resources produce `data:` URLs, which are never blocked or redirected since
+    /// only HTTP(S) and WebSocket requests are supported. This is only needed when re-checking the
+    /// URL of a `Redirection::Url`.
+    pub fn with_redirect_target(mut self) -> Request {
+        self.is_redirect_target = true;
+        self
+    }
+
+    /// Marks the request as made by a worker. Embedders often only know the URL of the worker's
+    /// script, which doesn't necessarily belong to the document the worker acts on behalf of.
+    ///
+    /// Without a worker flag, `$domain` options are evaluated against the hostname of the source
+    /// URL. For workers, they are instead evaluated against `document_origin`, the origin of the
+    /// document that created the worker, or for service workers the origin they were registered
+    /// for. Third-partiness stays relative to the source URL. If `document_origin` cannot be
+    /// parsed, `$domain` matching is left unchanged.
+    pub fn with_worker_origin(mut self, document_origin: &str) -> Request {
+        self.is_worker = true;
+        if let Some(parsed) = url_parser::parse_url(document_origin) {
+            if !parsed.hostname().is_empty() {
+                self.set_domain_hostname(parsed.hostname());
+            }
+        }
+        self
+    }
+
+    /// Attaches the request's initiator chain, given as the origins of each frame containing the
+    /// initiating frame, ordered from its parent up to the top-level document. This is the same
+    /// order as `Location.ancestorOrigins` in the initiating frame.
+    ///
+    /// With `DomainMatchingMode::TopFrame`, `$domain` options are then evaluated against the
+    /// top-level document rather than the initiating frame. `DomainMatchingMode::ImmediateFrame`
+    /// leaves matching unchanged. Origins that cannot be parsed are ignored.
+    pub fn with_ancestor_origins(mut self, ancestor_origins: &[&str], mode: DomainMatchingMode) -> Request {
+        self.ancestor_hostnames = ancestor_origins.iter()
+            .filter_map(|origin| url_parser::parse_url(origin))
+            .map(|parsed| parsed.hostname().to_owned())
+            .filter(|hostname| !hostname.is_empty())
+            .collect();
+
+        if mode == DomainMatchingMode::TopFrame {
+            if let Some(top_frame) = self.ancestor_hostnames.last().cloned() {
+                self.set_domain_hostname(&top_frame);
+            }
+        }
+
+        self
+    }
+
+    /// Makes the request's party relative to the top-level document at `top_frame_origin`, rather
+    /// than to the initiating frame.
+    ///
+    /// By default, `$first-party` and `$third-party` compare the request's domain with that of the
+    /// source URL, i.e. the frame that initiated it, which is how content blockers classify
+    /// requests. A subresource of a third-party iframe is then first-party if it comes from the
+    /// iframe's own domain. Browsers partition storage by the top-level site instead, under which
+    /// that same subresource is third-party. Only the party changes: `$domain` options are still
+    /// evaluated as set by `with_ancestor_origins`. If `top_frame_origin` cannot be parsed, the
+    /// party is left unchanged.
+    pub fn with_top_frame_party(mut self, top_frame_origin: &str) -> Request {
+        if let Some(parsed) = url_parser::parse_url(top_frame_origin) {
+            let top_frame_domain = parsed.domain();
+            if !top_frame_domain.is_empty() {
+                let (domain_start, domain_end) = url_parser::get_host_domain(&self.hostname);
+                let third_party = top_frame_domain != &self.hostname[domain_start..domain_end];
+                self.is_third_party = Some(third_party);
+                self.is_first_party = Some(!third_party);
+            }
+        }
+        self
+    }
+
+    fn set_domain_hostname(&mut self, hostname: &str) {
+        self.source_hostname_hashes = hostname_hashes(hostname);
+        self.domain_hostname = hostname.to_owned();
+        self.source_entity_hashes = OnceCell::new();
+    }
+
+    /// Hashes of the hostname that `$domain` options are evaluated against as entities, like
+    /// `example.*`. Finding the public suffix is comparatively expensive, so these are only
+    /// computed once a filter with entity domains needs them.
+    pub(crate) fn source_entity_hashes(&self) -> &[utils::Hash] {
+        self.source_entity_hashes.get_or_init(|| entity_hashes(&self.domain_hostname))
+    }
+
+    /// Hostnames of the frames containing the initiating frame, as provided by
+    /// `with_ancestor_origins`.
+    pub fn ancestor_hostnames(&self) -> &[String] {
+        &self.ancestor_hostnames
+    }
+
+    /// Starts building a request for `url`. See `RequestBuilder`.
+    pub fn builder(url: &'a str) -> RequestBuilder<'a> {
+        RequestBuilder::new(url)
+    }
+}
+
+/// A `Request` together with the tokens of its URL, for checking the same request against several
+/// blockers without tokenizing it again for each one. See `Blocker::check_tokenized`.
+#[derive(Clone, Debug)]
+pub struct TokenizedRequest {
+    request: Request,
+    tokens: Vec<utils::Hash>,
+}
+
+impl TokenizedRequest {
+    pub fn new(request: Request) -> Self {
+        let mut tokens = vec![];
+        request.get_tokens(&mut tokens);
+        Self { request, tokens }
+    }
+
+    pub fn request(&self) -> &Request {
+        &self.request
+    }
+
+    pub(crate) fn tokens(&self) -> &[utils::Hash] {
+        &self.tokens
+    }
+
+    pub fn into_request(self) -> Request {
+        self.request
+    }
+}
+
+impl From<Request> for TokenizedRequest {
+    fn from(request: Request) -> Self {
+        Self::new(request)
+    }
+}
+
+/// Composes a `Request` from its optional parts, checking that they are consistent with each
+/// other when calling `build`. This is equivalent to calling `Request::from_urls` followed by the
+/// `with_*` methods for each part that is set.
+#[derive(Clone, Debug)]
+pub struct RequestBuilder<'a> {
+    url: &'a str,
+    source_url: Option<&'a str>,
+    request_type: &'a str,
+    fetch_dest: Option<&'a str>,
+    infer_type: bool,
+    ip_address: Option<IpAddr>,
+    response_headers: Option<Vec<(&'a str, &'a str)>>,
+    ancestor_origins: Vec<&'a str>,
+    domain_matching_mode: DomainMatchingMode,
+    redirect_target: bool,
+    worker_origin: Option<&'a str>,
+    top_frame_party: Option<&'a str>,
+    navigation: Option<bool>,
+}
+
+impl<'a> RequestBuilder<'a> {
+    pub fn new(url: &'a str) -> Self {
+        Self {
+            url,
+            source_url: None,
+            request_type: "",
+            fetch_dest: None,
+            infer_type: false,
+            ip_address: None,
+            response_headers: None,
+            ancestor_origins: Vec::new(),
+            domain_matching_mode: DomainMatchingMode::default(),
+            redirect_target: false,
+            worker_origin: None,
+            top_frame_party: None,
+            navigation: None,
+        }
+    }
+
+    /// The URL of the document or frame that initiated the request.
+    pub fn source_url(mut self, source_url: &'a str) -> Self {
+        self.source_url = Some(source_url);
+        self
+    }
+
+    /// The type of the request, as accepted by `Request::from_urls`.
+    pub fn request_type(mut self, request_type: &'a str) -> Self {
+        self.request_type = request_type;
+        self
+    }
+
+    /// The value of the request's `Sec-Fetch-Dest` header, used for its type if none is given. See
+    /// `Request::with_fetch_dest`. This takes precedence over `infer_type`.
+    pub fn fetch_dest(mut self, fetch_dest: &'a str) -> Self {
+        self.fetch_dest = Some(fetch_dest);
+        self
+    }
+
+    /// Infers the type of the request from its URL if it would otherwise be
+    /// `RequestType::Other`. See `Request::with_inferred_type`.
+    pub fn infer_type(mut self) -> Self {
+        self.infer_type = true;
+        self
+    }
+
+    /// See `Request::with_ip_address`.
+    pub fn ip_address(mut self, ip_address: IpAddr) -> Self {
+        self.ip_address = Some(ip_address);
+        self
+    }
+
+    /// See `Request::with_response_headers`.
+    pub fn response_headers(mut self, headers: &[(&'a str, &'a str)]) -> Self {
+        self.response_headers = Some(headers.to_vec());
+        self
+    }
+
+    /// See `Request::with_ancestor_origins`. Requires a `source_url`.
+    pub fn ancestor_origins(mut self, ancestor_origins: &[&'a str], mode: DomainMatchingMode) -> Self {
+        self.ancestor_origins = ancestor_origins.to_vec();
+        self.domain_matching_mode = mode;
+        self
+    }
+
+    /// See `Request::with_redirect_target`.
+    pub fn redirect_target(mut self) -> Self {
+        self.redirect_target = true;
+        self
+    }
+
+    /// See `Request::with_worker_origin`. Ancestor origins with `DomainMatchingMode::TopFrame`
+    /// take precedence for `$domain` matching.
+    pub fn worker_origin(mut self, document_origin: &'a str) -> Self {
+        self.worker_origin = Some(document_origin);
+        self
+    }
+
+    /// See `Request::with_top_frame_party`.
+    pub fn top_frame_party(mut self, top_frame_origin: &'a str) -> Self {
+        self.top_frame_party = Some(top_frame_origin);
+        self
+    }
+
+    /// See `Request::with_navigation`. Applied after the request type is set or inferred.
+    pub fn navigation(mut self, is_navigation: bool) -> Self {
+        self.navigation = Some(is_navigation);
+        self
+    }
+
+    /// Builds the request. Unlike `Request::from_urls`, which ignores a source URL that can't be
+    /// parsed, this fails with `RequestError::SourceHostnameParseError` in that case.
+    pub fn build(self) -> Result<Request, RequestError> {
+        let source_url = match self.source_url {
+            Some(source_url) => {
+                if url_parser::parse_url(source_url).is_none() {
+                    return Err(RequestError::SourceHostnameParseError);
+                }
+                source_url
+            }
+            None if !self.ancestor_origins.is_empty() => return Err(RequestError::AncestorsWithoutSource),
+            // A navigation typed by its `Sec-Fetch-Dest` header is made by the navigated page, just
+            // like one of type `document` in `Request::from_urls`
+            None if cpt_match_type(self.request_type) == RequestType::Other
+                && self.fetch_dest.and_then(fetch_dest_match_type) == Some(RequestType::Document) => self.url,
+            None => "",
+        };
+
+        let mut request = Request::from_urls(self.url, source_url, self.request_type)?;
+        if let Some(fetch_dest) = self.fetch_dest {
+            request = request.with_fetch_dest(fetch_dest);
+        }
+        if self.infer_type {
+            request = request.with_inferred_type();
+        }
+        if let Some(is_navigation) = self.navigation {
+            request = request.with_navigation(is_navigation);
+        }
+        request.ip_address = self.ip_address;
+        if let Some(headers) = &self.response_headers {
+            request = request.with_response_headers(headers);
+        }
+        if let Some(worker_origin) = self.worker_origin {
+            request = request.with_worker_origin(worker_origin);
+        }
+        if !self.ancestor_origins.is_empty() {
+            request = request.with_ancestor_origins(&self.ancestor_origins, self.domain_matching_mode);
+        }
+        if let Some(top_frame_origin) = self.top_frame_party {
+            request = request.with_top_frame_party(top_frame_origin);
+        }
+        request.is_redirect_target = self.redirect_target;
+        Ok(request)
     }
 }
 
@@ -398,6 +930,7 @@ mod tests {
             ], &[])
             .as_slice()
         );
+        assert_eq!(simple_example.source_entity_hashes(), tokenize(&["subdomain.example.*", "example.*"], &[]).as_slice());
         let mut tokens = Vec::new();
         simple_example.get_tokens(&mut tokens);
         assert_eq!(
@@ -458,8 +991,8 @@ mod tests {
             assert_eq!(parsed.is_third_party, Some(true));
         }
         {
-            // cannot parse domain
-            let parsed = Request::from_urls_with_hostname("https://subdomain.example.com/ad", "subdomain.example.com", "", "document", None);
+            // cannot parse domain. Documents would be first-party to themselves instead
+            let parsed = Request::from_urls_with_hostname("https://subdomain.example.com/ad", "subdomain.example.com", "", "script", None);
             assert_eq!(parsed.is_third_party, None);
         }
         {
@@ -474,6 +1007,221 @@ mod tests {
         }
     }
 
+    #[test]
+    fn url_case_sensitive() {
+        let request = Request::from_url("https://example.com/Path?Query").unwrap();
+        assert_eq!(request.url, "https://example.com/path?query");
+        assert_eq!(request.url_case_sensitive(), "https://example.com/Path?Query");
+
+        let request = Request::from_urls_with_hostname("https://Example.com/Path", "example.com", "", "", None);
+        assert_eq!(request.url, "https://example.com/path");
+        assert_eq!(request.url_case_sensitive(), "https://example.com/Path");
+
+        let request = Request::from_url("https://example.com/path").unwrap();
+        assert_eq!(request.url_case_sensitive(), "https://example.com/path");
+    }
+
+    #[test]
+    fn ancestor_origins() {
+        let request = Request::from_urls("https://ads.net/ad.js", "https://frame.com", "script").unwrap();
+        let frame_hashes = request.source_hostname_hashes.clone();
+        assert!(request.ancestor_hostnames().is_empty());
+
+        let immediate = request.clone().with_ancestor_origins(&["https://middle.com", "https://sub.top.com"], DomainMatchingMode::ImmediateFrame);
+        assert_eq!(immediate.ancestor_hostnames(), &["middle.com".to_owned(), "sub.top.com".to_owned()]);
+        assert_eq!(immediate.source_hostname_hashes, frame_hashes);
+
+        let top = request.with_ancestor_origins(&["https://middle.com", "https://sub.top.com"], DomainMatchingMode::TopFrame);
+        assert_eq!(top.source_hostname_hashes, Some(vec![utils::fast_hash("sub.top.com"), utils::fast_hash("top.com"), utils::fast_hash("com")]));
+        assert_eq!(top.source_entity_hashes(), &[utils::fast_hash("sub.top.*"), utils::fast_hash("top.*")]);
+        // Third-partiness is always relative to the initiating frame
+        assert_eq!(top.is_third_party, Some(true));
+    }
+
+    #[test]
+    fn top_frame_party() {
+        // A script from a third-party iframe's own domain, embedded within a page at top.com
+        let request = Request::from_urls("https://cdn.frame.com/embed.js", "https://frame.com/embed", "script").unwrap();
+        assert_eq!(request.is_third_party, Some(false));
+
+        let top = request.clone().with_top_frame_party("https://top.com");
+        assert_eq!(top.is_third_party, Some(true));
+        assert_eq!(top.is_first_party, Some(false));
+        assert_eq!(top.source_hostname_hashes, request.source_hostname_hashes);
+
+        // A script from the top-level site, loaded by the iframe
+        let request = Request::from_urls("https://static.top.com/lib.js", "https://frame.com/embed", "script").unwrap();
+        assert_eq!(request.is_third_party, Some(true));
+        assert_eq!(request.clone().with_top_frame_party("https://www.top.com").is_third_party, Some(false));
+
+        assert_eq!(request.clone().with_top_frame_party("not an origin").is_third_party, Some(true));
+
+        let built = Request::builder("https://static.top.com/lib.js")
+            .source_url("https://frame.com/embed")
+            .request_type("script")
+            .top_frame_party("https://top.com")
+            .build()
+            .unwrap();
+        assert_eq!(built.is_third_party, Some(false));
+    }
+
+    #[test]
+    fn navigation() {
+        let document = Request::from_urls("https://example.com/", "https://example.com/", "document").unwrap();
+        assert!(document.is_navigation);
+        assert!(!document.with_navigation(false).is_navigation);
+        assert!(!Request::from_urls("https://example.com/a.js", "https://example.com/", "script").unwrap().is_navigation);
+
+        let fetched = Request::from_urls("https://example.com/", "https://example.com/", "").unwrap().with_fetch_dest("document");
+        assert_eq!(fetched.request_type, RequestType::Document);
+        assert!(fetched.is_navigation);
+
+        let built = Request::builder("https://example.com/").request_type("document").navigation(false).build().unwrap();
+        assert!(!built.is_navigation);
+    }
+
+    #[test]
+    fn worker_origin() {
+        let page = Request::from_urls("https://tracker.net/collect", "https://app.com/index.html", "xhr").unwrap();
+        assert!(!page.is_worker);
+
+        let worker = Request::from_urls("https://tracker.net/collect", "https://cdn.app.com/worker.js", "xhr")
+            .unwrap()
+            .with_worker_origin("https://app.com");
+        assert!(worker.is_worker);
+        assert_eq!(worker.source_hostname_hashes, page.source_hostname_hashes);
+        assert_eq!(worker.is_third_party, Some(true));
+
+        let unparsed = Request::from_urls("https://tracker.net/collect", "https://cdn.app.com/worker.js", "xhr")
+            .unwrap()
+            .with_worker_origin("not an origin");
+        assert!(unparsed.is_worker);
+        assert_eq!(unparsed.source_hostname_hashes, Some(vec![utils::fast_hash("cdn.app.com"), utils::fast_hash("app.com"), utils::fast_hash("com")]));
+        assert_eq!(unparsed.source_entity_hashes(), &[utils::fast_hash("cdn.app.*"), utils::fast_hash("app.*")]);
+
+        let built = Request::builder("https://tracker.net/collect")
+            .source_url("https://cdn.app.com/worker.js")
+            .request_type("xhr")
+            .worker_origin("https://app.com")
+            .build()
+            .unwrap();
+        assert_eq!(format!("{:?}", built), format!("{:?}", worker));
+        assert_eq!(worker.source_entity_hashes(), page.source_entity_hashes());
+    }
+
+    #[test]
+    fn builder() {
+        let request = Request::builder("https://cdn.example.com/lib.js")
+            .source_url("https://frame.com/widget")
+            .request_type("script")
+            .ip_address("203.0.113.7".parse().unwrap())
+            .response_headers(&[("Content-Type", "text/javascript")])
+            .ancestor_origins(&["https://top.com"], DomainMatchingMode::TopFrame)
+            .redirect_target()
+            .build()
+            .unwrap();
+        let expected = Request::from_urls("https://cdn.example.com/lib.js", "https://frame.com/widget", "script")
+            .unwrap()
+            .with_ip_address("203.0.113.7".parse().unwrap())
+            .with_response_headers(&[("Content-Type", "text/javascript")])
+            .with_ancestor_origins(&["https://top.com"], DomainMatchingMode::TopFrame)
+            .with_redirect_target();
+        assert_eq!(format!("{:?}", request), format!("{:?}", expected));
+
+        let request = Request::builder("https://example.com/image.png").infer_type().build().unwrap();
+        assert_eq!(format!("{:?}", request), format!("{:?}", Request::from_url("https://example.com/image.png").unwrap()));
+
+        let request = Request::builder("https://example.com/image.png").request_type("xhr").infer_type().build().unwrap();
+        assert_eq!(request.request_type, RequestType::Xmlhttprequest);
+
+        assert_eq!(Request::builder("not a url").build().unwrap_err(), RequestError::HostnameParseError);
+        assert_eq!(
+            Request::builder("https://example.com").source_url("not a url").build().unwrap_err(),
+            RequestError::SourceHostnameParseError,
+        );
+        assert_eq!(
+            Request::builder("https://example.com")
+                .ancestor_origins(&["https://top.com"], DomainMatchingMode::ImmediateFrame)
+                .build()
+                .unwrap_err(),
+            RequestError::AncestorsWithoutSource,
+        );
+    }
+
+    #[test]
+    fn fetch_dest() {
+        let request_type = |url: &str, request_type: &str, dest: &str| {
+            Request::from_urls(url, "https://example.com", request_type).unwrap().with_fetch_dest(dest).request_type
+        };
+        assert_eq!(request_type("https://cdn.com/f", "", "font"), RequestType::Font);
+        assert_eq!(request_type("https://cdn.com/f", "other", "audio"), RequestType::Media);
+        assert_eq!(request_type("https://cdn.com/f", "", "video"), RequestType::Media);
+        assert_eq!(request_type("https://cdn.com/f", "", "image"), RequestType::Image);
+        assert_eq!(request_type("https://cdn.com/f", "", "style"), RequestType::Stylesheet);
+        assert_eq!(request_type("https://cdn.com/f", "", "iframe"), RequestType::Subdocument);
+        assert_eq!(request_type("https://cdn.com/f", "", "worker"), RequestType::Script);
+        assert_eq!(request_type("https://cdn.com/f", "", "Script"), RequestType::Script);
+
+        // Ambiguous or unknown values leave the type unchanged
+        assert_eq!(request_type("https://cdn.com/f", "", "empty"), RequestType::Other);
+        assert_eq!(request_type("https://cdn.com/f", "", "unknown"), RequestType::Other);
+
+        // An explicit type always wins
+        assert_eq!(request_type("https://cdn.com/f", "xhr", "font"), RequestType::Xmlhttprequest);
+        assert_eq!(request_type("https://cdn.com/f", "image", "script"), RequestType::Image);
+
+        // The hint takes precedence over the extension when both are used
+        let request = Request::builder("https://cdn.com/track.mp3").fetch_dest("font").infer_type().build().unwrap();
+        assert_eq!(request.request_type, RequestType::Font);
+        let request = Request::builder("https://cdn.com/track.mp3").fetch_dest("empty").infer_type().build().unwrap();
+        assert_eq!(request.request_type, RequestType::Media);
+    }
+
+    #[test]
+    fn ip_hosts() {
+        let ipv4 = Request::from_urls("http://192.0.2.1/ads.js", "http://192.0.2.2/", "script").unwrap();
+        assert_eq!(ipv4.hostname, "192.0.2.1");
+        assert_eq!(ipv4.url_after_hostname(), "/ads.js");
+        // Neighbouring addresses don't share a registrable domain
+        assert_eq!(ipv4.is_third_party, Some(true));
+        assert_eq!(ipv4.source_hostname_hashes, Some(vec![utils::fast_hash("192.0.2.2")]));
+
+        let ipv6 = Request::from_urls("http://[2001:db8::1]:8080/ads.js", "http://[2001:db8::1]/", "script").unwrap();
+        assert_eq!(ipv6.hostname, "[2001:db8::1]");
+        assert_eq!(ipv6.url_after_hostname(), ":8080/ads.js");
+        assert_eq!(ipv6.is_third_party, Some(false));
+        assert_eq!(ipv6.source_hostname_hashes, Some(vec![utils::fast_hash("[2001:db8::1]")]));
+
+        let loopback = Request::from_urls("http://[::1]/", "https://example.com", "document").unwrap();
+        assert_eq!(loopback.hostname, "[::1]");
+        assert_eq!(loopback.is_third_party, Some(true));
+    }
+
+    #[test]
+    fn infers_type_from_extension() {
+        let inferred = |url: &str| Request::from_url(url).unwrap().request_type;
+        assert_eq!(inferred("https://example.com/app.js"), RequestType::Script);
+        assert_eq!(inferred("https://example.com/js/module.mjs?v=2"), RequestType::Script);
+        assert_eq!(inferred("https://example.com/style.css#top"), RequestType::Stylesheet);
+        assert_eq!(inferred("https://example.com/img/Logo.PNG"), RequestType::Image);
+        assert_eq!(inferred("https://example.com/photo.jpeg"), RequestType::Image);
+        assert_eq!(inferred("https://example.com/font.woff2"), RequestType::Font);
+        assert_eq!(inferred("https://example.com/font.woff"), RequestType::Font);
+        assert_eq!(inferred("https://example.com/fonts/Sans.TTF?v=3"), RequestType::Font);
+        assert_eq!(inferred("https://example.com/video.mp4"), RequestType::Media);
+        assert_eq!(inferred("https://example.com/page.html"), RequestType::Other);
+        assert_eq!(inferred("https://example.com/file.js/"), RequestType::Other);
+        assert_eq!(inferred("https://example.com/download?file=app.js"), RequestType::Other);
+        assert_eq!(inferred("https://cdn.js"), RequestType::Other);
+        assert_eq!(inferred("wss://example.com/socket.js"), RequestType::Websocket);
+
+        // Explicit types always win
+        let request = Request::from_urls("https://example.com/app.js", "", "image").unwrap().with_inferred_type();
+        assert_eq!(request.request_type, RequestType::Image);
+        let request = Request::from_urls("https://example.com/app.js", "", "").unwrap();
+        assert_eq!(request.request_type, RequestType::Other);
+    }
+
     #[test]
     fn fuzzing_errors() {
         {
@@ -486,4 +1234,64 @@ mod tests {
             assert!(parsed.is_err());
         }
     }
+
+    #[test]
+    fn first_party_shared_host() {
+        let cases = [
+            ("https://www.example.com/app.js", "https://www.example.com/page.html", "script"),
+            ("https://a.b.co.uk/img.png", "https://a.b.co.uk", "image"),
+            ("http://127.0.0.1/api", "http://127.0.0.1:3000/", "xmlhttprequest"),
+            ("https://cdn.example.com/app.js", "https://www.example.com/", "script"),
+            ("https://tracker.net/pixel.gif", "https://www.example.com/", "image"),
+        ];
+        for (url, source_url, request_type) in cases.iter() {
+            let request = Request::from_urls(url, source_url, request_type).unwrap();
+            let hostname = url_parser::parse_url(url).unwrap().hostname().to_owned();
+            let source_hostname = url_parser::parse_url(source_url).unwrap().hostname().to_owned();
+            let expected = Request::from_urls_with_hostname(url, &hostname, &source_hostname, request_type, None);
+            assert_eq!(request.is_third_party, expected.is_third_party, "{}", url);
+            assert_eq!(request.is_first_party, expected.is_first_party, "{}", url);
+            assert_eq!(request.source_hostname_hashes, expected.source_hostname_hashes, "{}", url);
+            assert_eq!(request.hostname, expected.hostname, "{}", url);
+        }
+
+        let request = Request::from_urls("https://www.example.com/app.js", "https://www.example.com/", "script").unwrap();
+        assert_eq!(request.is_third_party, Some(false));
+        assert_eq!(request.source_hostname_hashes, hostname_hashes("www.example.com"));
+    }
+
+    #[test]
+    fn navigation_without_source() {
+        let request = Request::from_urls("https://www.example.com/page.html", "", "document").unwrap();
+        assert_eq!(request.is_third_party, Some(false));
+        assert_eq!(request.is_first_party, Some(true));
+        assert_eq!(request.source_hostname_hashes, hostname_hashes("www.example.com"));
+
+        let request = Request::from_urls("https://www.example.com/page.html", "", "main_frame").unwrap();
+        assert_eq!(request.is_third_party, Some(false));
+
+        // Other requests without a source are left without a party
+        let request = Request::from_urls("https://www.example.com/app.js", "", "script").unwrap();
+        assert_eq!(request.is_third_party, None);
+        assert_eq!(request.source_hostname_hashes, None);
+    }
+
+    #[test]
+    fn navigation_without_source_hostname() {
+        let request = Request::from_urls_with_hostname("https://www.example.com/page.html", "www.example.com", "", "document", None);
+        assert_eq!(request.is_third_party, Some(false));
+        assert_eq!(request.source_hostname_hashes, hostname_hashes("www.example.com"));
+
+        let request = Request::from_urls_with_hostname("https://www.example.com/app.js", "www.example.com", "", "script", None);
+        assert_eq!(request.is_third_party, None);
+        assert_eq!(request.source_hostname_hashes, None);
+
+        let request = Request::builder("https://www.example.com/page.html").fetch_dest("document").build().unwrap();
+        assert_eq!(request.request_type, RequestType::Document);
+        assert_eq!(request.is_third_party, Some(false));
+        assert_eq!(request.source_hostname_hashes, hostname_hashes("www.example.com"));
+
+        let request = Request::builder("https://www.example.com/page.html").request_type("script").fetch_dest("document").build().unwrap();
+        assert_eq!(request.is_third_party, None);
+    }
 }
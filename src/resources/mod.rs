@@ -7,11 +7,18 @@
 pub mod resource_assembler;
 
 mod scriptlet_resource_storage;
-pub(crate) use scriptlet_resource_storage::ScriptletResourceStorage;
+pub(crate) use scriptlet_resource_storage::{normalized_scriptlet_args, ScriptletResourceStorage};
+pub use scriptlet_resource_storage::ScriptletResourceError;
 
+use once_cell::sync::OnceCell;
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 
+/// Name of the built-in redirect resource used by the `$empty` filter option. If no resource with
+/// this name has been loaded, an empty body with a MIME type appropriate for the request is
+/// served instead.
+pub const EMPTY_REDIRECT_RESOURCE: &str = "empty";
+
 /// Struct representing a resource that can be used by an adblocking engine.
 ///
 /// - `name`: Represents the primary name of the resource, often a filename
@@ -74,6 +81,17 @@ impl From<std::string::FromUtf8Error> for AddResourceError {
     }
 }
 
+impl std::fmt::Display for AddResourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::InvalidBase64Content => write!(f, "resource content is not valid base64"),
+            Self::InvalidUtf8Content => write!(f, "resource content is not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for AddResourceError {}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct RedirectResource {
     pub content_type: String,
@@ -84,6 +102,58 @@ pub struct RedirectResource {
 pub struct RedirectResourceStorage {
     #[serde(serialize_with = "crate::data_format::utils::stabilize_hashmap_serialization")]
     pub resources: HashMap<String, RedirectResource>,
+    /// Resources whose bodies are only fetched when first used. These are never serialized, so the
+    /// provider must be set again after deserializing an engine.
+    #[serde(skip)]
+    lazy: Option<LazyResources>,
+}
+
+/// Fetches the raw body of a lazily loaded redirect resource, given its name. See
+/// `RedirectResourceStorage::lazy`.
+pub type ResourceProvider = Box<dyn Fn(&str) -> Option<Vec<u8>> + Send + Sync>;
+
+/// Describes a redirect resource whose body is provided on demand. See
+/// `RedirectResourceStorage::lazy`.
+#[derive(Debug, Clone)]
+pub struct LazyResource {
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub content_type: MimeType,
+}
+
+struct LazyResources {
+    provider: ResourceProvider,
+    /// Maps each name and alias to the name passed to `provider`.
+    names: HashMap<String, String>,
+    /// Each resource by name, which is filled in on first access.
+    resources: HashMap<String, (String, OnceCell<Option<RedirectResource>>)>,
+}
+
+impl LazyResources {
+    fn get_resource(&self, name: &str) -> Option<&RedirectResource> {
+        let name = self.names.get(name)?;
+        let (content_type, cell) = self.resources.get(name)?;
+        cell.get_or_init(|| (self.provider)(name).map(|body| RedirectResource {
+            content_type: content_type.clone(),
+            data: base64::encode(body),
+        })).as_ref()
+    }
+}
+
+impl std::fmt::Debug for LazyResources {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("LazyResources")
+            .field("names", &self.names)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Lazy resources can't be compared by their bodies without loading them, so they are considered
+/// equal if they have the same names.
+impl PartialEq for LazyResources {
+    fn eq(&self, other: &Self) -> bool {
+        self.names == other.names
+    }
 }
 
 
@@ -134,11 +204,67 @@ impl RedirectResourceStorage {
 
         Self {
             resources: redirectable_resources,
+            lazy: None,
+        }
+    }
+
+    /// Creates a storage which only holds the names and types of `resources`. The body of each
+    /// resource is fetched from `provider` by name the first time it is used, and then kept for
+    /// later uses. This avoids loading large resource sets up front when few resources end up
+    /// being used. If `provider` returns `None`, the resource is treated as missing, and
+    /// `provider` is not asked for it again.
+    pub fn lazy<F>(resources: &[LazyResource], provider: F) -> Self
+    where
+        F: Fn(&str) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        let mut lazy = LazyResources {
+            provider: Box::new(provider),
+            names: HashMap::new(),
+            resources: HashMap::new(),
+        };
+        for resource in resources {
+            for alias in resource.aliases.iter().chain(std::iter::once(&resource.name)) {
+                lazy.names.insert(alias.to_owned(), resource.name.to_owned());
+            }
+            lazy.resources.insert(resource.name.to_owned(), (resource.content_type.clone().into(), OnceCell::new()));
+        }
+
+        Self {
+            resources: HashMap::new(),
+            lazy: Some(lazy),
         }
     }
 
+    /// Resources added directly take precedence over lazily loaded ones of the same name.
     pub fn get_resource(&self, name: &str) -> Option<&RedirectResource> {
         self.resources.get(name)
+            .or_else(|| self.lazy.as_ref().and_then(|lazy| lazy.get_resource(name)))
+    }
+
+    /// Adds all resources of `other`, replacing any existing resources of the same name or alias.
+    /// Returns the sorted names and aliases that were replaced.
+    ///
+    /// Lazily loaded resources of `other` replace those of this storage, since only one provider
+    /// can be used at a time.
+    pub fn merge(&mut self, other: RedirectResourceStorage) -> Vec<String> {
+        let mut collisions = vec![];
+        if let Some(lazy) = other.lazy {
+            for name in lazy.names.keys() {
+                let replaced_lazy = matches!(&self.lazy, Some(existing) if existing.names.contains_key(name));
+                if self.resources.remove(name).is_some() || replaced_lazy {
+                    collisions.push(name.to_owned());
+                }
+            }
+            self.lazy = Some(lazy);
+        }
+        collisions.extend(other.resources.into_iter().filter_map(|(name, resource)| {
+            let shadowed_lazy = matches!(&self.lazy, Some(lazy) if lazy.names.contains_key(&name));
+            let replaced = self.resources.insert(name.clone(), resource).is_some();
+            if replaced || shadowed_lazy { Some(name) } else { None }
+        }));
+        collisions.sort();
+        collisions.dedup();
+        collisions
     }
 
     /// Adds a resource. Only has an effect for mimetyped scriptlets.
@@ -218,6 +344,71 @@ mod tests {
         }));
     }
 
+    #[test]
+    fn merge_replaces_existing_resources() {
+        let resource = |name: &str, aliases: &[&str], content: &str| Resource {
+            name: name.to_owned(),
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: base64::encode(content),
+        };
+        let mut storage = RedirectResourceStorage::from_resources(&[
+            resource("noop.js", &["noopjs"], "built-in"),
+            resource("other.js", &[], "built-in"),
+        ]);
+
+        let collisions = storage.merge(RedirectResourceStorage::from_resources(&[
+            resource("noop.js", &["noopjs"], "user"),
+            resource("user.js", &[], "user"),
+        ]));
+        assert_eq!(collisions, ["noop.js", "noopjs"]);
+
+        let data = |name: &str| storage.get_resource(name).map(|resource| base64::decode(&resource.data).unwrap());
+        assert_eq!(data("noop.js"), Some(b"user".to_vec()));
+        assert_eq!(data("noopjs"), Some(b"user".to_vec()));
+        assert_eq!(data("other.js"), Some(b"built-in".to_vec()));
+        assert_eq!(data("user.js"), Some(b"user".to_vec()));
+    }
+
+    #[test]
+    fn lazy_resources_are_loaded_once() {
+        use std::sync::{Arc, Mutex};
+
+        let requested = Arc::new(Mutex::new(vec![]));
+        let storage = {
+            let requested = requested.clone();
+            RedirectResourceStorage::lazy(&[
+                LazyResource {
+                    name: "noop.js".to_owned(),
+                    aliases: vec!["noopjs".to_owned()],
+                    content_type: MimeType::ApplicationJavascript,
+                },
+                LazyResource {
+                    name: "missing.js".to_owned(),
+                    aliases: vec![],
+                    content_type: MimeType::ApplicationJavascript,
+                },
+            ], move |name| {
+                requested.lock().unwrap().push(name.to_owned());
+                (name == "noop.js").then(|| b"(function() {})()".to_vec())
+            })
+        };
+        assert!(requested.lock().unwrap().is_empty());
+
+        let expected = RedirectResource {
+            content_type: "application/javascript".to_owned(),
+            data: base64::encode("(function() {})()"),
+        };
+        assert_eq!(storage.get_resource("noop.js"), Some(&expected));
+        assert_eq!(storage.get_resource("noopjs"), Some(&expected));
+        assert_eq!(storage.get_resource("noop.js"), Some(&expected));
+        assert_eq!(storage.get_resource("missing.js"), None);
+        assert_eq!(storage.get_resource("missing.js"), None);
+        assert_eq!(storage.get_resource("unknown.js"), None);
+
+        assert_eq!(*requested.lock().unwrap(), ["noop.js", "missing.js"]);
+    }
+
     #[test]
     fn get_resource_by_alias() {
         let mut storage = RedirectResourceStorage::default();
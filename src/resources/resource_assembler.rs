@@ -6,7 +6,8 @@ use once_cell::sync::Lazy;
 use std::io::Read;
 use std::fs::File;
 use std::path::Path;
-use crate::resources::{Resource, ResourceType, MimeType};
+use std::collections::HashMap;
+use crate::resources::{Resource, ResourceType, MimeType, RedirectResourceStorage};
 
 static TOP_COMMENT_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"^/\*[\S\s]+?\n\*/\s*"#).unwrap());
 static NON_EMPTY_LINE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"\S"#).unwrap());
@@ -111,7 +112,11 @@ fn read_redirectable_resource_mapping(mapfile_data: &str) -> Vec<ResourcePropert
     // It *should* be valid JSON now, so parse it with serde_json.
     let parsed: Vec<JsResourceEntry> = serde_json::from_str(&map).unwrap();
 
-    parsed.into_iter().filter_map(|(name, props)| {
+    resource_properties_from_entries(parsed)
+}
+
+fn resource_properties_from_entries(entries: Vec<JsResourceEntry>) -> Vec<ResourceProperties> {
+    entries.into_iter().filter_map(|(name, props)| {
         // Ignore resources with params for now, since there's no support for them currently.
         if props.params.is_some() {
             None
@@ -185,13 +190,13 @@ fn read_template_resources(scriptlets_data: &str) -> Vec<Resource> {
 
 /// Reads byte data from an arbitrary resource file, and assembles a `Resource` from it with the
 /// provided `resource_info`.
-fn build_resource_from_file_contents(resource_contents: &[u8], resource_info: &ResourceProperties) -> Resource {
+fn build_resource_from_file_contents(resource_contents: &[u8], resource_info: &ResourceProperties) -> Result<Resource, std::str::Utf8Error> {
     let name = resource_info.name.to_owned();
     let aliases = resource_info.alias.iter().map(|alias| alias.to_string()).collect();
     let mimetype = MimeType::from_extension(&resource_info.name[..]);
     let content = match mimetype {
         MimeType::ApplicationJavascript | MimeType::TextHtml | MimeType::TextPlain => {
-            let utf8string = std::str::from_utf8(resource_contents).unwrap();
+            let utf8string = std::str::from_utf8(resource_contents)?;
             base64::encode(&utf8string.replace('\r', ""))
         }
         _ => {
@@ -199,12 +204,12 @@ fn build_resource_from_file_contents(resource_contents: &[u8], resource_info: &R
         }
     };
 
-    Resource {
+    Ok(Resource {
         name,
         aliases,
         kind: ResourceType::Mime(mimetype),
         content,
-    }
+    })
 }
 
 /// Produces a `Resource` from the `web_accessible_resource_dir` directory according to the
@@ -218,7 +223,7 @@ fn read_resource_from_web_accessible_dir(web_accessible_resource_dir: &Path, res
     let mut resource_contents = Vec::new();
     resource_file.read_to_end(&mut resource_contents).expect("read resource file contents");
 
-    build_resource_from_file_contents(&resource_contents, resource_info)
+    build_resource_from_file_contents(&resource_contents, resource_info).unwrap()
 }
 
 /// Builds a `Vec` of `Resource`s from the specified paths on the filesystem:
@@ -241,6 +246,35 @@ pub fn assemble_web_accessible_resources(web_accessible_resource_dir: &Path, red
     }).collect()
 }
 
+impl RedirectResourceStorage {
+    /// Builds redirect resources from a JSON manifest in the format of uBlock Origin's
+    /// `redirect-resources.js` map, i.e. a list of `[name, properties]` entries whose properties
+    /// may declare an `alias` or a list of aliases:
+    ///
+    /// ```json
+    /// [["noop.js", {"alias": ["noopjs", "abp-resource:blank-js"], "data": "text"}]]
+    /// ```
+    ///
+    /// `bodies` provides the raw file contents of each resource, keyed by its name. The MIME type
+    /// of each resource is inferred from the extension of its name, and it can be referenced by
+    /// its name or any of its aliases. Entries without a body, entries with parameters, and text
+    /// entries that aren't valid UTF-8 are skipped.
+    pub fn from_manifest(manifest: &str, bodies: &HashMap<String, Vec<u8>>) -> Result<Self, serde_json::Error> {
+        let entries: Vec<JsResourceEntry> = serde_json::from_str(manifest)?;
+
+        let mut storage = Self::default();
+        resource_properties_from_entries(entries).iter()
+            .filter_map(|resource_info| {
+                let contents = bodies.get(&resource_info.name)?;
+                build_resource_from_file_contents(contents, resource_info).ok()
+            })
+            .for_each(|resource| {
+                let _ = storage.add_resource(&resource);
+            });
+        Ok(storage)
+    }
+}
+
 pub fn assemble_scriptlet_resources(scriptlets_path: &Path) -> Vec<Resource> {
     let scriptlets_data = std::fs::read_to_string(scriptlets_path).expect("read scriptlets path");
     read_template_resources(&scriptlets_data)
@@ -633,4 +667,44 @@ mod tests {
             "(function() {\nif ( window !== window.top ) {\nreturn;\n}\nvar tstart;\nvar ttl = 30000;\nvar delay = 0;\nvar delayStep = 50;\nvar buster = function() {\nvar docEl = document.documentElement,\nbodyEl = document.body,\nvw = Math.min(docEl.clientWidth, window.innerWidth),\nvh = Math.min(docEl.clientHeight, window.innerHeight),\ntol = Math.min(vw, vh) * 0.05,\nel = document.elementFromPoint(vw/2, vh/2),\nstyle, rect;\nfor (;;) {\nif ( el === null || el.parentNode === null || el === bodyEl ) {\nbreak;\n}\nstyle = window.getComputedStyle(el);\nif ( parseInt(style.zIndex, 10) >= 1000 || style.position === 'fixed' ) {\nrect = el.getBoundingClientRect();\nif ( rect.left <= tol && rect.top <= tol && (vw - rect.right) <= tol && (vh - rect.bottom) < tol ) {\nel.parentNode.removeChild(el);\ntstart = Date.now();\nel = document.elementFromPoint(vw/2, vh/2);\nbodyEl.style.setProperty('overflow', 'auto', 'important');\ndocEl.style.setProperty('overflow', 'auto', 'important');\ncontinue;\n}\n}\nel = el.parentNode;\n}\nif ( (Date.now() - tstart) < ttl ) {\ndelay = Math.min(delay + delayStep, 1000);\nsetTimeout(buster, delay);\n}\n};\nvar domReady = function(ev) {\nif ( ev ) {\ndocument.removeEventListener(ev.type, domReady);\n}\ntstart = Date.now();\nsetTimeout(buster, delay);\n};\nif ( document.readyState === 'loading' ) {\ndocument.addEventListener('DOMContentLoaded', domReady);\n} else {\ndomReady();\n}\n})();\n",
         );
     }
+
+    #[test]
+    fn redirect_resources_from_manifest() {
+        let manifest = r#"[
+            ["noop.js", {"alias": ["noopjs", "abp-resource:blank-js"], "data": "text"}],
+            ["1x1.gif", {"alias": "1x1-transparent.gif", "data": "blob"}],
+            ["missing.txt", {}],
+            ["set-constant.js", {"params": ["prop", "value"]}]
+        ]"#;
+        let mut bodies = HashMap::new();
+        bodies.insert("noop.js".to_owned(), b"(function() {\r\n})();".to_vec());
+        bodies.insert("1x1.gif".to_owned(), vec![0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0xff]);
+        bodies.insert("set-constant.js".to_owned(), b"".to_vec());
+
+        let storage = RedirectResourceStorage::from_manifest(manifest, &bodies).unwrap();
+        assert_eq!(storage.resources.len(), 5);
+
+        let noop = storage.get_resource("noop.js").unwrap();
+        assert_eq!(noop.content_type, "application/javascript");
+        assert_eq!(noop.data, base64::encode("(function() {\n})();"));
+        assert_eq!(storage.get_resource("noopjs"), Some(noop));
+        assert_eq!(storage.get_resource("abp-resource:blank-js"), Some(noop));
+
+        let gif = storage.get_resource("1x1.gif").unwrap();
+        assert_eq!(gif.content_type, "image/gif");
+        assert_eq!(storage.get_resource("1x1-transparent.gif"), Some(gif));
+
+        assert_eq!(storage.get_resource("missing.txt"), None);
+        assert_eq!(storage.get_resource("set-constant.js"), None);
+
+        let (network_filters, _) = crate::lists::parse_filters(&["||ads.com^$script,redirect=noopjs".to_owned()], false, Default::default());
+        let mut blocker = crate::blocker::Blocker::new(network_filters, &crate::blocker::BlockerOptions { enable_optimizations: false });
+        blocker.resources = storage;
+        let request = crate::request::Request::from_urls("https://ads.com/ad.js", "https://example.com", "script").unwrap();
+        assert_eq!(blocker.check(&request).redirect, Some(crate::blocker::Redirection::Resource(
+            format!("data:application/javascript;base64,{}", base64::encode("(function() {\n})();"))
+        )));
+
+        assert!(RedirectResourceStorage::from_manifest("{", &bodies).is_err());
+    }
 }
@@ -31,6 +31,17 @@ pub enum ScriptletResourceError {
     MissingScriptletName,
 }
 
+impl std::fmt::Display for ScriptletResourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::NoMatchingScriptlet => write!(f, "no scriptlet resource with that name"),
+            Self::MissingScriptletName => write!(f, "scriptlet injection has no scriptlet name"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptletResourceError {}
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct ScriptletResource {
     scriptlet: String,
@@ -90,6 +101,16 @@ impl ScriptletResourceStorage {
         Ok(())
     }
 
+    /// Adds the scriptlets of `other`, replacing any stored under the same name.
+    pub(crate) fn merge(&mut self, other: ScriptletResourceStorage) {
+        self.resources.extend(other.resources);
+    }
+
+    /// Checks whether a scriptlet is stored under `name`, with or without a `.js` extension.
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.resources.contains_key(without_js_extension(name))
+    }
+
     pub fn get_scriptlet(&self, scriptlet_args: &str) -> Result<String, ScriptletResourceError> {
         let scriptlet_args = parse_scriptlet_args(scriptlet_args);
         if scriptlet_args.is_empty() {
@@ -113,6 +134,18 @@ fn without_js_extension(scriptlet_name: &str) -> &str {
     }
 }
 
+/// Parses the inner contents of a `+js(...)` block into a form that is equal for equivalent
+/// scriptlet injections, regardless of whitespace, quoting, or a `.js` extension on the scriptlet
+/// name. An empty block gives an empty `Vec`.
+pub(crate) fn normalized_scriptlet_args(args: &str) -> Vec<String> {
+    let mut args = parse_scriptlet_args(args).into_iter().map(Cow::into_owned).collect::<Vec<_>>();
+    if let Some(name) = args.first_mut() {
+        let stripped_len = without_js_extension(name).len();
+        name.truncate(stripped_len);
+    }
+    args
+}
+
 /// Parses the inner contents of a `+js(...)` block into a Vec of its comma-delimited elements.
 ///
 /// A literal comma is produced by the '\,' pattern. Otherwise, all '\', '"', and ''' characters
@@ -166,6 +199,13 @@ mod tests {
         assert_eq!(args, vec!["scriptletname", "one, two, three", "four"]);
     }
 
+    #[test]
+    fn normalized_argslist() {
+        assert_eq!(normalized_scriptlet_args("set-constant.js, atob, trueFunc"), normalized_scriptlet_args("set-constant,atob,  'trueFunc'"));
+        assert_eq!(normalized_scriptlet_args("nowebrtc.js"), vec!["nowebrtc"]);
+        assert!(normalized_scriptlet_args("").is_empty());
+    }
+
     #[test]
     fn parse_argslist_badchars() {
         let args = parse_scriptlet_args(r##"scriptlet, "; window.location.href = bad.com; , '; alert("you're\, hacked");    ,    \u\r\l(bad.com) "##);
@@ -99,8 +99,13 @@ impl RequestUrl {
     }
 }
 
-/// Return the start and end indices of the domain of the given hostname.
+/// Return the start and end indices of the domain of the given hostname. IP addresses are their
+/// own domain.
 pub(crate) fn get_host_domain(host: &str) -> (usize, usize) {
+    if crate::utils::is_ip_literal(host) {
+        return (0, host.len());
+    }
+
     #[cfg(not(feature = "embedded-domain-resolver"))]
     let domain_resolver = DOMAIN_RESOLVER.get().expect("An external domain resolver must be set when the `embedded-domain-resolver` feature is disabled.");
     #[cfg(feature = "embedded-domain-resolver")]
@@ -116,14 +121,30 @@ pub(crate) fn get_host_domain(host: &str) -> (usize, usize) {
 /// UTF characters to plain ASCII ones.  Serialisation then contains this
 /// decoded URL that is used for further matching.
 pub fn parse_url(url: &str) -> Option<RequestUrl> {
-    let parsed = parser::Hostname::parse(&url).ok();
+    parse_url_with_domain(url, get_host_domain)
+}
+
+/// Like `parse_url`, but for the source URL of a request whose own URL has already been parsed
+/// into `request_url`. First-party requests usually share their hostname with the source, in which
+/// case the domain is reused rather than looked up again.
+pub(crate) fn parse_source_url(source_url: &str, request_url: &RequestUrl) -> Option<RequestUrl> {
+    parse_url_with_domain(source_url, |host| if host == request_url.hostname() {
+        request_url.domain
+    } else {
+        get_host_domain(host)
+    })
+}
+
+/// Parses `url`, using `host_domain` to find the position of the domain within its hostname.
+fn parse_url_with_domain<F: FnOnce(&str) -> (usize, usize)>(url: &str, host_domain: F) -> Option<RequestUrl> {
+    let parsed = parser::Hostname::parse(url).ok();
     parsed.and_then(|h| {
         match h.host_str() {
-            Some(_host) => Some(RequestUrl {
+            Some(host) => Some(RequestUrl {
                 url: h.url_str().to_owned(),
                 schema_end: h.scheme_end,
                 hostname_pos: (h.host_start, h.host_end),
-                domain: get_host_domain(&h.url_str()[h.host_start..h.host_end])
+                domain: host_domain(host),
             }),
             _ => None
         }
@@ -147,4 +168,29 @@ mod tests {
         assert_eq!(domain("a.b.co.uk"), "b.co.uk");
         assert_eq!(domain("foo.bar"), "foo.bar");
     }
+
+    #[test]
+    #[cfg(feature = "embedded-domain-resolver")]
+    fn parse_source_url_matches_parse_url() {
+        let pairs = [
+            ("https://a.b.co.uk/script.js", "https://a.b.co.uk/index.html"),
+            ("https://a.b.co.uk/script.js", "https://c.b.co.uk/"),
+            ("https://cdn.example.com/lib.js", "https://www.example.com"),
+            ("http://127.0.0.1:8080/x.png", "http://127.0.0.1/"),
+            ("https://localhost/a", "https://localhost/b"),
+            ("https://example.com/", "not a url"),
+        ];
+        for (url, source_url) in pairs.iter() {
+            let request_url = parse_url(url).unwrap();
+            let shared = parse_source_url(source_url, &request_url);
+            let full = parse_url(source_url);
+            assert_eq!(shared.is_some(), full.is_some(), "{}", source_url);
+            if let (Some(shared), Some(full)) = (shared, full) {
+                assert_eq!(shared.url, full.url);
+                assert_eq!(shared.schema(), full.schema());
+                assert_eq!(shared.hostname(), full.hostname());
+                assert_eq!(shared.domain(), full.domain(), "{}", source_url);
+            }
+        }
+    }
 }
@@ -136,6 +136,13 @@ pub(crate) fn bin_lookup<T: Ord>(arr: &[T], elt: T) -> bool {
 }
 
 const EXPECTED_RULES: usize = 75000;
+
+/// Returns `true` if `host` is an IPv4 address or a bracketed IPv6 address, as they appear in
+/// URLs. IP hosts have no registrable domain, so they are only ever compared literally.
+pub(crate) fn is_ip_literal(host: &str) -> bool {
+    (host.starts_with('[') && host.ends_with(']')) || host.parse::<std::net::Ipv4Addr>().is_ok()
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 pub fn read_file_lines(filename: &str) -> Vec<String> {
     let f = File::open(filename).unwrap_or_else(|_| panic!("File {} not found", filename));
@@ -0,0 +1,89 @@
+//! Checks that network request matching does not allocate once the engine has warmed up. This is
+//! done with a counting global allocator, so it lives in its own test binary.
+//!
+//! Without `object-pooling`, a fresh token buffer is allocated for every check.
+#![cfg(feature = "object-pooling")]
+
+use adblock::blocker::{Blocker, BlockerOptions};
+use adblock::lists::{parse_filters, ParseOptions};
+use adblock::request::Request;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns the number of allocations made by the current thread while running `f`.
+fn count_allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn steady_state_check_does_not_allocate() {
+    let rules = [
+        "||ads.example.com^",
+        "/banner/*/img^",
+        "/^https?:\\/\\/[a-z]+\\.tracker\\.net\\//",
+        "||example.org/ads.js$script,domain=news.com",
+        "@@||ads.example.com/allowed^",
+        "||cdn.com^$third-party",
+        "||example.net^$important",
+        "@@||example.com^$generichide",
+    ].iter().map(|r| r.to_string()).collect::<Vec<_>>();
+    let (network_filters, _) = parse_filters(&rules, false, ParseOptions::default());
+    let blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: true });
+
+    let requests = [
+        ("https://example.com/content/article.html", "https://example.com", "document"),
+        ("https://static.site.com/app.js", "https://site.com", "script"),
+        ("https://cdn.com/lib.js", "https://cdn.com", "script"),
+        ("https://example.org/ads.js", "https://blog.com", "script"),
+        ("https://www.site.com/banner/top/logo.png", "https://site.com", "image"),
+    ].iter()
+        .map(|(url, source, request_type)| Request::from_urls(url, source, request_type).unwrap())
+        .collect::<Vec<_>>();
+
+    // Warm up thread-local pools and regex caches.
+    for request in &requests {
+        blocker.check(request);
+    }
+
+    for request in &requests {
+        let mut matched = true;
+        let allocations = count_allocations(|| matched = blocker.check(request).matched);
+        assert!(!matched, "{} should not be blocked", request.url);
+        assert_eq!(allocations, 0, "Checking {} allocated", request.url);
+    }
+
+    let hostname_request = Request::from_urls("https://example.com", "https://example.com", "document").unwrap();
+    blocker.check_generic_hide(&hostname_request);
+    let mut generic_hide = false;
+    let allocations = count_allocations(|| generic_hide = blocker.check_generic_hide(&hostname_request));
+    assert!(generic_hide);
+    assert_eq!(allocations, 0);
+}
@@ -99,7 +99,6 @@ mod legacy_test_filters {
         test_filter(
             "||ads.example.com^",
             NetworkFilterMask::DEFAULT_OPTIONS
-                | NetworkFilterMask::FROM_DOCUMENT
                 | NetworkFilterMask::IS_RIGHT_ANCHOR
                 | NetworkFilterMask::IS_HOSTNAME_ANCHOR, // FTHostAnchored | FTHostOnly
             None,
@@ -529,9 +528,10 @@ mod legacy_check_options {
 
     #[test]
     fn check_options_popup() {
-        // Make sure we ignore popup rules for now
         check_option_rule(&["||example.com^$popup"], FilterFormat::Standard, &[
-               ("http://example.com", "http://example.com", "popup", false),
+               ("http://example.com", "http://example.com", "popup", true),
+               ("http://example.com", "http://example.com", "document", false),
+               ("http://example.com", "http://example.com", "other", false),
         ]);
     }
 